@@ -0,0 +1,297 @@
+//! Framing of 9P messages over byte streams.
+//!
+//! Every 9P message is prefixed with a little-endian `size[4]` field which counts the
+//! whole message including the size field itself. [`NinePCodec`] handles that framing
+//! and the (de)serialization of the message body, so any `AsyncRead + AsyncWrite` can
+//! be turned into a stream/sink of [`Msg`] with `tokio_util::codec::Framed`.
+//!
+//! # Example
+//! ```no_run
+//! use futures::{SinkExt, StreamExt};
+//! use rs9p::{codec::NinePCodec, FCall, Msg, NOTAG, P92000L};
+//! use tokio::net::TcpStream;
+//! use tokio_util::codec::Framed;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let stream = TcpStream::connect("127.0.0.1:564").await?;
+//! let mut framed = Framed::new(stream, NinePCodec::new());
+//!
+//! framed
+//!     .send(Msg {
+//!         tag: NOTAG,
+//!         body: FCall::TVersion {
+//!             msize: 8192,
+//!             version: P92000L.to_owned(),
+//!         },
+//!     })
+//!     .await?;
+//! let reply = framed.next().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use {
+    crate::{fcall::*, io_err, serialize},
+    bytes::{Buf, BufMut, BytesMut},
+    std::{io, mem::size_of},
+    tokio_util::codec::{Decoder, Encoder},
+};
+
+/// Default upper bound for the size of a single message
+pub const DEFAULT_MAX_MSIZE: u32 = 8 * 1024 * 1024;
+
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = size_of::<u32>() + size_of::<u8>() + size_of::<u16>();
+
+/// Protocol dialect spoken over a [`NinePCodec`]
+///
+/// The dialect decides which message types are accepted in both directions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Dialect {
+    /// 9P2000.L
+    #[default]
+    P92000L,
+}
+
+impl Dialect {
+    /// Get the dialect negotiated by the given version string, if supported
+    pub fn from_version(version: &str) -> Option<Dialect> {
+        match version {
+            P92000L => Some(Dialect::P92000L),
+            _ => None,
+        }
+    }
+
+    /// The version string exchanged in `TVersion`/`RVersion` for this dialect
+    pub fn version(&self) -> &'static str {
+        match *self {
+            Dialect::P92000L => P92000L,
+        }
+    }
+
+    /// If the message type belongs to this dialect
+    pub fn supports(&self, typ: MsgType) -> bool {
+        match *self {
+            Dialect::P92000L => typ != MsgType::TlError,
+        }
+    }
+}
+
+/// `Decoder`/`Encoder` for size-prefixed 9P messages
+///
+/// Messages larger than [`max_msize`](Self::max_msize) are rejected with
+/// `InvalidData` in both directions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NinePCodec {
+    max_msize: u32,
+    dialect: Dialect,
+}
+
+impl NinePCodec {
+    /// Create a codec speaking 9P2000.L with `DEFAULT_MAX_MSIZE`
+    pub fn new() -> NinePCodec {
+        NinePCodec {
+            max_msize: DEFAULT_MAX_MSIZE,
+            dialect: Dialect::default(),
+        }
+    }
+
+    /// Set the maximum message size, builder style
+    pub fn with_max_msize(mut self, max_msize: u32) -> NinePCodec {
+        self.max_msize = max_msize;
+        self
+    }
+
+    /// Set the dialect, builder style
+    pub fn with_dialect(mut self, dialect: Dialect) -> NinePCodec {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Get the maximum message size
+    pub fn max_msize(&self) -> u32 {
+        self.max_msize
+    }
+
+    /// Change the maximum message size, e.g. after `TVersion` negotiated a smaller one
+    pub fn set_max_msize(&mut self, max_msize: u32) {
+        self.max_msize = max_msize;
+    }
+
+    /// Get the dialect
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// Change the dialect
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    fn check_type(&self, typ: MsgType) -> io::Result<()> {
+        if self.dialect.supports(typ) {
+            Ok(())
+        } else {
+            Err(io_err!(
+                InvalidData,
+                format!("{:?} is not part of {}", typ, self.dialect.version())
+            ))
+        }
+    }
+
+    fn check_size(&self, size: usize) -> io::Result<()> {
+        if size < HEADER_SIZE {
+            Err(io_err!(InvalidData, format!("message too short: {}", size)))
+        } else if size > self.max_msize as usize {
+            Err(io_err!(
+                InvalidData,
+                format!("message too large: {} > {}", size, self.max_msize)
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for NinePCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for NinePCodec {
+    type Item = Msg;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Msg>> {
+        if src.len() < size_of::<u32>() {
+            return Ok(None);
+        }
+
+        let size = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        self.check_size(size)?;
+
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(size);
+        frame.advance(size_of::<u32>());
+
+        let mut reader = frame.reader();
+        let msg = serialize::read_msg(&mut reader)?;
+        let left = reader.into_inner().remaining();
+        if left != 0 {
+            return Err(io_err!(
+                InvalidData,
+                format!("{} bytes left after the message", left)
+            ));
+        }
+        self.check_type(MsgType::from(&msg.body))?;
+        Ok(Some(msg))
+    }
+}
+
+impl Encoder<Msg> for NinePCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Msg, dst: &mut BytesMut) -> io::Result<()> {
+        Encoder::<&Msg>::encode(self, &msg, dst)
+    }
+}
+
+impl<'a> Encoder<&'a Msg> for NinePCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: &'a Msg, dst: &mut BytesMut) -> io::Result<()> {
+        self.check_type(MsgType::from(&msg.body))?;
+
+        let start = dst.len();
+        dst.put_u32_le(0);
+        let mut writer = dst.writer();
+        let res = serialize::write_msg(&mut writer, msg);
+        let dst = writer.into_inner();
+
+        let size = match res.and_then(|n| {
+            let size = n + size_of::<u32>();
+            self.check_size(size).and(Ok(size))
+        }) {
+            Ok(size) => size,
+            Err(e) => {
+                dst.truncate(start);
+                return Err(e);
+            }
+        };
+
+        dst[start..start + size_of::<u32>()].copy_from_slice(&(size as u32).to_le_bytes());
+        Ok(())
+    }
+}
+
+#[test]
+fn codec_roundtrip() {
+    let expected = Msg {
+        tag: 3,
+        body: FCall::TWalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".to_owned(), "lib".to_owned()],
+        },
+    };
+
+    let mut codec = NinePCodec::new();
+    let mut buf = BytesMut::new();
+    Encoder::<Msg>::encode(&mut codec, expected.clone(), &mut buf).unwrap();
+    assert_eq!(
+        buf.len(),
+        u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize
+    );
+
+    // Nothing is produced until the whole frame has arrived
+    let mut partial = buf.split_to(buf.len() - 1);
+    assert_eq!(codec.decode(&mut partial).unwrap(), None);
+    partial.unsplit(buf);
+
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(expected));
+    assert!(partial.is_empty());
+}
+
+#[test]
+fn codec_rejects_large_messages() {
+    let msg = Msg {
+        tag: 5,
+        body: FCall::RRead {
+            data: Data(vec![0; 64]),
+        },
+    };
+
+    let mut buf = BytesMut::new();
+    Encoder::<&Msg>::encode(&mut NinePCodec::new(), &msg, &mut buf).unwrap();
+
+    let mut codec = NinePCodec::new().with_max_msize(32);
+    assert!(codec.decode(&mut buf.clone()).is_err());
+
+    let mut out = BytesMut::new();
+    assert!(Encoder::<&Msg>::encode(&mut codec, &msg, &mut out).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn codec_rejects_trailing_bytes() {
+    let msg = Msg {
+        tag: 1,
+        body: FCall::TClunk { fid: 2 },
+    };
+
+    let mut buf = BytesMut::new();
+    Encoder::<&Msg>::encode(&mut NinePCodec::new(), &msg, &mut buf).unwrap();
+    // One byte more in the frame than the message takes
+    let size = buf.len() as u32 + 1;
+    buf[..4].copy_from_slice(&size.to_le_bytes());
+    buf.put_u8(0);
+
+    let err = NinePCodec::new().decode(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
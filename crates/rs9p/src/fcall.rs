@@ -3,9 +3,11 @@
 //! # Protocol
 //! 9P2000.L
 
+use std::fmt::{self, Write as _};
 use std::fs;
 use std::mem::{size_of, size_of_val};
 use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bitflags::bitflags;
 use enum_primitive::*;
@@ -96,6 +98,7 @@ pub mod p92000 {
     ///
     /// NOTE: Defined as `Dir` in libc.h of Plan 9
     #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct Stat {
         /// Server type
         pub typ: u16,
@@ -144,6 +147,7 @@ pub mod p92000 {
 bitflags! {
     /// File lock type, Flock.typ
     #[derive(Copy, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct LockType: u8 {
         const RDLOCK    = 0;
         const WRLOCK    = 1;
@@ -154,6 +158,7 @@ bitflags! {
 bitflags! {
     /// File lock flags, Flock.flags
     #[derive(Copy, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct LockFlag: u32 {
         #[doc = "Blocking request"]
         const BLOCK     = 1;
@@ -165,6 +170,7 @@ bitflags! {
 bitflags! {
     /// File lock status
     #[derive(Copy, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct LockStatus: u8 {
         const SUCCESS   = 0;
         const BLOCKED   = 1;
@@ -181,6 +187,7 @@ bitflags! {
     /// # Protocol
     /// 9P2000/9P2000.L
     #[derive(Copy, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct QIdType: u8 {
         #[doc = "Type bit for directories"]
         const DIR       = 0x80;
@@ -225,12 +232,315 @@ impl<'a> From<&'a ::std::fs::FileType> for QIdType {
     }
 }
 
+bitflags! {
+    /// Open flags of `TlOpen` and `TlCreate`
+    ///
+    /// These are the protocol's own `P9_DOTL_*` values. They match the host's `O_*`
+    /// flags on x86 but not on every architecture, so convert them with the `From`
+    /// implementations for `nix::fcntl::OFlag` instead of passing the raw bits to `open`.
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct P9OpenFlags: u32 {
+        const RDONLY        = 0o00000000;
+        const WRONLY        = 0o00000001;
+        const RDWR          = 0o00000002;
+        const NOACCESS      = 0o00000003;
+        const CREATE        = 0o00000100;
+        const EXCL          = 0o00000200;
+        const NOCTTY        = 0o00000400;
+        const TRUNC         = 0o00001000;
+        const APPEND        = 0o00002000;
+        const NONBLOCK      = 0o00004000;
+        const DSYNC         = 0o00010000;
+        const FASYNC        = 0o00020000;
+        const DIRECT        = 0o00040000;
+        const LARGEFILE     = 0o00100000;
+        const DIRECTORY     = 0o00200000;
+        const NOFOLLOW      = 0o00400000;
+        const NOATIME       = 0o01000000;
+        const CLOEXEC       = 0o02000000;
+        const SYNC          = 0o04000000;
+    }
+}
+
+// Everything except the access mode, which is an enumeration in the low two bits
+const OPEN_FLAG_MAP: &[(P9OpenFlags, nix::fcntl::OFlag)] = {
+    use nix::fcntl::OFlag;
+    &[
+        (P9OpenFlags::CREATE, OFlag::O_CREAT),
+        (P9OpenFlags::EXCL, OFlag::O_EXCL),
+        (P9OpenFlags::NOCTTY, OFlag::O_NOCTTY),
+        (P9OpenFlags::TRUNC, OFlag::O_TRUNC),
+        (P9OpenFlags::APPEND, OFlag::O_APPEND),
+        (P9OpenFlags::NONBLOCK, OFlag::O_NONBLOCK),
+        (P9OpenFlags::DSYNC, OFlag::O_DSYNC),
+        (P9OpenFlags::FASYNC, OFlag::O_ASYNC),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (P9OpenFlags::DIRECT, OFlag::O_DIRECT),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (P9OpenFlags::LARGEFILE, OFlag::O_LARGEFILE),
+        (P9OpenFlags::DIRECTORY, OFlag::O_DIRECTORY),
+        (P9OpenFlags::NOFOLLOW, OFlag::O_NOFOLLOW),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (P9OpenFlags::NOATIME, OFlag::O_NOATIME),
+        (P9OpenFlags::CLOEXEC, OFlag::O_CLOEXEC),
+        // Linux's O_SYNC is __O_SYNC | O_DSYNC, only its own bit means SYNC
+        (P9OpenFlags::SYNC, OFlag::O_SYNC.difference(OFlag::O_DSYNC)),
+    ]
+};
+
+impl P9OpenFlags {
+    /// The access mode, one of `RDONLY`, `WRONLY`, `RDWR` or `NOACCESS`
+    pub fn access_mode(&self) -> P9OpenFlags {
+        *self & P9OpenFlags::NOACCESS
+    }
+
+    /// If the flags request write access or truncation
+    pub fn is_writable(&self) -> bool {
+        matches!(self.access_mode(), P9OpenFlags::WRONLY | P9OpenFlags::RDWR)
+            || self.contains(P9OpenFlags::TRUNC)
+    }
+}
+
+impl From<P9OpenFlags> for nix::fcntl::OFlag {
+    fn from(flags: P9OpenFlags) -> Self {
+        use nix::fcntl::OFlag;
+
+        let mut oflag = match flags.access_mode() {
+            P9OpenFlags::WRONLY => OFlag::O_WRONLY,
+            P9OpenFlags::RDWR => OFlag::O_RDWR,
+            P9OpenFlags::NOACCESS => OFlag::O_ACCMODE,
+            _ => OFlag::O_RDONLY,
+        };
+
+        for &(p9, host) in OPEN_FLAG_MAP {
+            if flags.contains(p9) {
+                oflag.insert(host);
+            }
+        }
+        oflag
+    }
+}
+
+impl From<nix::fcntl::OFlag> for P9OpenFlags {
+    fn from(oflag: nix::fcntl::OFlag) -> Self {
+        use nix::fcntl::OFlag;
+
+        let mut flags = match oflag & OFlag::O_ACCMODE {
+            OFlag::O_WRONLY => P9OpenFlags::WRONLY,
+            OFlag::O_RDWR => P9OpenFlags::RDWR,
+            OFlag::O_ACCMODE => P9OpenFlags::NOACCESS,
+            _ => P9OpenFlags::RDONLY,
+        };
+
+        for &(p9, host) in OPEN_FLAG_MAP {
+            if !host.is_empty() && oflag.contains(host) {
+                flags.insert(p9);
+            }
+        }
+        flags
+    }
+}
+
+bitflags! {
+    /// File type and permission bits of `mode` as in `st_mode`
+    ///
+    /// The file type is an enumeration stored in the `IFMT` bits, use
+    /// [`file_type`](Self::file_type) rather than `contains` to inspect it.
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct FileMode: u32 {
+        const IFMT      = 0o170000;
+        const IFSOCK    = 0o140000;
+        const IFLNK     = 0o120000;
+        const IFREG     = 0o100000;
+        const IFBLK     = 0o060000;
+        const IFDIR     = 0o040000;
+        const IFCHR     = 0o020000;
+        const IFIFO     = 0o010000;
+
+        const ISUID     = 0o4000;
+        const ISGID     = 0o2000;
+        const ISVTX     = 0o1000;
+
+        const IRWXU     = 0o0700;
+        const IRUSR     = 0o0400;
+        const IWUSR     = 0o0200;
+        const IXUSR     = 0o0100;
+        const IRWXG     = 0o0070;
+        const IRGRP     = 0o0040;
+        const IWGRP     = 0o0020;
+        const IXGRP     = 0o0010;
+        const IRWXO     = 0o0007;
+        const IROTH     = 0o0004;
+        const IWOTH     = 0o0002;
+        const IXOTH     = 0o0001;
+    }
+}
+
+impl FileMode {
+    /// The file type stored in the `IFMT` bits
+    pub fn file_type(&self) -> DirEntryType {
+        DirEntryType::from_mode(self.bits())
+    }
+
+    /// Permission bits including setuid, setgid and sticky
+    pub fn permissions(&self) -> FileMode {
+        *self & !FileMode::IFMT
+    }
+}
+
+impl From<FileMode> for nix::sys::stat::Mode {
+    fn from(mode: FileMode) -> Self {
+        nix::sys::stat::Mode::from_bits_truncate(mode.permissions().bits() as _)
+    }
+}
+
+impl From<nix::sys::stat::Mode> for FileMode {
+    fn from(mode: nix::sys::stat::Mode) -> Self {
+        FileMode::from_bits_retain(mode.bits())
+    }
+}
+
+impl From<FileMode> for nix::sys::stat::SFlag {
+    fn from(mode: FileMode) -> Self {
+        nix::sys::stat::SFlag::from_bits_truncate((mode & FileMode::IFMT).bits() as _)
+    }
+}
+
+impl From<nix::sys::stat::SFlag> for FileMode {
+    fn from(typ: nix::sys::stat::SFlag) -> Self {
+        FileMode::from_bits_retain(typ.bits()) & FileMode::IFMT
+    }
+}
+
+bitflags! {
+    /// Flags of `TUnlinkAt`
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct UnlinkFlags: u32 {
+        #[doc = "Remove a directory instead of a file, like `AT_REMOVEDIR`"]
+        const REMOVEDIR = 0x200;
+    }
+}
+
+bitflags! {
+    /// Flags of `TxAttrCreate`
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct XattrFlags: u32 {
+        #[doc = "Fail if the attribute already exists, like `XATTR_CREATE`"]
+        const CREATE    = 0x1;
+        #[doc = "Fail if the attribute does not exist, like `XATTR_REPLACE`"]
+        const REPLACE   = 0x2;
+    }
+}
+
+impl From<UnlinkFlags> for nix::unistd::UnlinkatFlags {
+    fn from(flags: UnlinkFlags) -> Self {
+        if flags.contains(UnlinkFlags::REMOVEDIR) {
+            nix::unistd::UnlinkatFlags::RemoveDir
+        } else {
+            nix::unistd::UnlinkatFlags::NoRemoveDir
+        }
+    }
+}
+
+enum_from_primitive! {
+    #[doc = "Type of a directory entry, `DirEntry.typ`, corresponding to `d_type`"]
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum DirEntryType {
+        #[default]
+        Unknown     = 0,
+        Fifo        = 1,
+        Chr         = 2,
+        Dir         = 4,
+        Blk         = 6,
+        Reg         = 8,
+        Lnk         = 10,
+        Sock        = 12,
+    }
+}
+
+impl DirEntryType {
+    /// Get the type from the `S_IFMT` bits of a mode
+    pub fn from_mode(mode: u32) -> DirEntryType {
+        // d_type is S_IFMT shifted down by 12 bits
+        DirEntryType::from_u32((mode & FileMode::IFMT.bits()) >> 12).unwrap_or_default()
+    }
+
+    /// The `S_IFMT` bits for this type, empty for `Unknown`
+    pub fn mode(&self) -> FileMode {
+        FileMode::from_bits_retain((*self as u32) << 12)
+    }
+}
+
+impl From<DirEntryType> for u8 {
+    fn from(typ: DirEntryType) -> Self {
+        typ as u8
+    }
+}
+
+impl From<::std::fs::FileType> for DirEntryType {
+    fn from(typ: ::std::fs::FileType) -> Self {
+        From::from(&typ)
+    }
+}
+
+impl<'a> From<&'a ::std::fs::FileType> for DirEntryType {
+    fn from(typ: &'a ::std::fs::FileType) -> Self {
+        use std::os::unix::fs::FileTypeExt;
+
+        if typ.is_file() {
+            DirEntryType::Reg
+        } else if typ.is_dir() {
+            DirEntryType::Dir
+        } else if typ.is_symlink() {
+            DirEntryType::Lnk
+        } else if typ.is_fifo() {
+            DirEntryType::Fifo
+        } else if typ.is_char_device() {
+            DirEntryType::Chr
+        } else if typ.is_block_device() {
+            DirEntryType::Blk
+        } else if typ.is_socket() {
+            DirEntryType::Sock
+        } else {
+            DirEntryType::Unknown
+        }
+    }
+}
+
+impl From<DirEntryType> for QIdType {
+    fn from(typ: DirEntryType) -> Self {
+        match typ {
+            DirEntryType::Dir => QIdType::DIR,
+            DirEntryType::Lnk => QIdType::SYMLINK,
+            _ => QIdType::FILE,
+        }
+    }
+}
+
 bitflags! {
     /// Bits in `mask` and `valid` of `TGetAttr` and `RGetAttr`.
     ///
     /// # Protocol
     /// 9P2000.L
     #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct GetAttrMask: u64 {
         const MODE          = 0x00000001;
         const NLINK         = 0x00000002;
@@ -264,6 +574,7 @@ bitflags! {
     /// # Protocol
     /// 9P2000.L
     #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct SetAttrMask: u32 {
         const MODE      = 0x00000001;
         const UID       = 0x00000002;
@@ -284,6 +595,7 @@ bitflags! {
 /// # Protocol
 /// 9P2000/9P2000.L
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QId {
     /// Specify whether the file is a directory, append-only file, etc.
     pub typ: QIdType,
@@ -297,6 +609,39 @@ impl QId {
     pub fn size(&self) -> u32 {
         (size_of::<QIdType>() + size_of::<u32>() + size_of::<u64>()) as u32
     }
+
+    /// QId of a plain file with the given path and version `0`
+    pub fn file(path: u64) -> QId {
+        QId {
+            typ: QIdType::FILE,
+            version: 0,
+            path,
+        }
+    }
+
+    /// QId of a directory with the given path and version `0`
+    pub fn dir(path: u64) -> QId {
+        QId {
+            typ: QIdType::DIR,
+            version: 0,
+            path,
+        }
+    }
+
+    /// QId of a symbolic link with the given path and version `0`
+    pub fn symlink(path: u64) -> QId {
+        QId {
+            typ: QIdType::SYMLINK,
+            version: 0,
+            path,
+        }
+    }
+
+    /// Set the version, builder style
+    pub fn version(mut self, version: u32) -> QId {
+        self.version = version;
+        self
+    }
 }
 
 /// Filesystem information corresponding to `struct statfs` of Linux.
@@ -304,6 +649,7 @@ impl QId {
 /// # Protocol
 /// 9P2000.L
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StatFs {
     /// Type of file system
     pub typ: u32,
@@ -345,19 +691,53 @@ impl From<nix::sys::statvfs::Statvfs> for StatFs {
 ///
 /// # Protocol
 /// 9P2000.L
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Time {
     pub sec: u64,
     pub nsec: u64,
 }
 
+impl Time {
+    /// The current system time
+    pub fn now() -> Time {
+        From::from(SystemTime::now())
+    }
+}
+
+// Times before the epoch are clamped to the epoch
+impl From<SystemTime> for Time {
+    fn from(t: SystemTime) -> Self {
+        let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Time {
+            sec: d.as_secs(),
+            nsec: d.subsec_nanos() as u64,
+        }
+    }
+}
+
+// Times come from clients, those the platform can't represent are clamped to the
+// latest one it can
+impl From<Time> for SystemTime {
+    fn from(t: Time) -> Self {
+        let sec = t.sec.saturating_add(t.nsec / 1_000_000_000);
+        let nsec = (t.nsec % 1_000_000_000) as u32;
+        UNIX_EPOCH
+            .checked_add(Duration::new(sec, nsec))
+            .or_else(|| UNIX_EPOCH.checked_add(Duration::new(i64::MAX as u64, 999_999_999)))
+            .unwrap_or(UNIX_EPOCH)
+    }
+}
+
 /// File attributes corresponding to `struct stat` of Linux.
 ///
-/// Stat can be constructed from `std::fs::Metadata` via From trait
+/// Stat can be constructed from `std::fs::Metadata` via From trait. That conversion fills
+/// `btime` when the platform reports it and leaves `generation` and `data_version` at `0`.
 ///
 /// # Protocol
 /// 9P2000.L
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stat {
     /// Protection
     pub mode: u32,
@@ -381,6 +761,179 @@ pub struct Stat {
     pub mtime: Time,
     /// Time of last status change
     pub ctime: Time,
+    /// Time of creation
+    pub btime: Time,
+    /// Inode generation number
+    pub generation: u64,
+    /// Changes whenever the file data changes, for cache validation
+    pub data_version: u64,
+}
+
+/// Block size reported by the `Stat` constructors
+const DEFAULT_BLKSIZE: u64 = 4096;
+
+/// Builders for synthetic files which have no `std::fs::Metadata` to convert from
+///
+/// ```
+/// use rs9p::{GetAttrMask, QId, Stat};
+///
+/// let stat = Stat::file(11).mode(0o600).uid(1000).gid(1000);
+/// let reply = stat.rgetattr(QId::file(1), GetAttrMask::BASIC);
+/// ```
+impl Stat {
+    /// Attributes of a regular file with the given size, mode `0644` and all times set to now
+    pub fn file(size: u64) -> Stat {
+        Stat::new(FileMode::IFREG | FileMode::from_bits_retain(0o644), 1).size(size)
+    }
+
+    /// Attributes of a directory with mode `0755` and all times set to now
+    pub fn dir() -> Stat {
+        Stat::new(FileMode::IFDIR | FileMode::from_bits_retain(0o755), 2)
+    }
+
+    /// Attributes of a symbolic link to a target of the given length
+    pub fn symlink(target_len: u64) -> Stat {
+        Stat::new(FileMode::IFLNK | FileMode::from_bits_retain(0o777), 1).size(target_len)
+    }
+
+    fn new(mode: FileMode, nlink: u64) -> Stat {
+        let now = Time::now();
+        Stat {
+            mode: mode.bits(),
+            nlink,
+            blksize: DEFAULT_BLKSIZE,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            btime: now,
+            ..Default::default()
+        }
+    }
+
+    /// Set the permission bits, keeping the file type
+    pub fn mode(mut self, mode: u32) -> Stat {
+        self.mode = (self.mode & FileMode::IFMT.bits()) | (mode & !FileMode::IFMT.bits());
+        self
+    }
+
+    /// Set the owner
+    pub fn uid(mut self, uid: u32) -> Stat {
+        self.uid = uid;
+        self
+    }
+
+    /// Set the group
+    pub fn gid(mut self, gid: u32) -> Stat {
+        self.gid = gid;
+        self
+    }
+
+    /// Set the number of hard links
+    pub fn nlink(mut self, nlink: u64) -> Stat {
+        self.nlink = nlink;
+        self
+    }
+
+    /// Set the device ID of a special file
+    pub fn rdev(mut self, rdev: u64) -> Stat {
+        self.rdev = rdev;
+        self
+    }
+
+    /// Set the size and the number of 512B blocks needed to hold it
+    pub fn size(mut self, size: u64) -> Stat {
+        self.size = size;
+        self.blocks = size.div_ceil(512);
+        self
+    }
+
+    /// Set the access, modification and status change times at once
+    pub fn times(mut self, time: Time) -> Stat {
+        self.atime = time;
+        self.mtime = time;
+        self.ctime = time;
+        self
+    }
+
+    /// Set the time of last access
+    pub fn atime(mut self, atime: Time) -> Stat {
+        self.atime = atime;
+        self
+    }
+
+    /// Set the time of last modification
+    pub fn mtime(mut self, mtime: Time) -> Stat {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Set the time of last status change
+    pub fn ctime(mut self, ctime: Time) -> Stat {
+        self.ctime = ctime;
+        self
+    }
+
+    /// Set the time of creation
+    pub fn btime(mut self, btime: Time) -> Stat {
+        self.btime = btime;
+        self
+    }
+
+    /// Set the inode generation number
+    pub fn generation(mut self, generation: u64) -> Stat {
+        self.generation = generation;
+        self
+    }
+
+    /// Set the data version
+    pub fn data_version(mut self, data_version: u64) -> Stat {
+        self.data_version = data_version;
+        self
+    }
+
+    /// Keep only the fields selected by `mask`, zeroing the others
+    pub fn masked(self, mask: GetAttrMask) -> Stat {
+        fn pick<T: Default>(mask: GetAttrMask, bit: GetAttrMask, value: T) -> T {
+            if mask.contains(bit) {
+                value
+            } else {
+                T::default()
+            }
+        }
+
+        Stat {
+            mode: pick(mask, GetAttrMask::MODE, self.mode),
+            uid: pick(mask, GetAttrMask::UID, self.uid),
+            gid: pick(mask, GetAttrMask::GID, self.gid),
+            nlink: pick(mask, GetAttrMask::NLINK, self.nlink),
+            rdev: pick(mask, GetAttrMask::RDEV, self.rdev),
+            size: pick(mask, GetAttrMask::SIZE, self.size),
+            blksize: self.blksize,
+            blocks: pick(mask, GetAttrMask::BLOCKS, self.blocks),
+            atime: pick(mask, GetAttrMask::ATIME, self.atime),
+            mtime: pick(mask, GetAttrMask::MTIME, self.mtime),
+            ctime: pick(mask, GetAttrMask::CTIME, self.ctime),
+            btime: pick(mask, GetAttrMask::BTIME, self.btime),
+            generation: pick(mask, GetAttrMask::GEN, self.generation),
+            data_version: pick(mask, GetAttrMask::DATA_VERSION, self.data_version),
+        }
+    }
+
+    /// Build an `RGetAttr` reply honoring `req_mask`
+    ///
+    /// Only the requested fields are filled in and reported in `valid`. The generation
+    /// is only reported if one was set, with [`Stat::generation`].
+    pub fn rgetattr(self, qid: QId, req_mask: GetAttrMask) -> FCall {
+        let mut valid = req_mask & GetAttrMask::ALL;
+        if self.generation == 0 {
+            valid -= GetAttrMask::GEN;
+        }
+        FCall::RGetAttr {
+            valid,
+            qid,
+            stat: self.masked(valid),
+        }
+    }
 }
 
 impl From<fs::Metadata> for Stat {
@@ -413,12 +966,16 @@ impl<'a> From<&'a fs::Metadata> for Stat {
                 sec: attr.ctime() as u64,
                 nsec: attr.ctime_nsec() as u64,
             },
+            btime: attr.created().map(Time::from).unwrap_or_default(),
+            generation: 0,
+            data_version: 0,
         }
     }
 }
 
 /// Subset of `Stat` used for `TSetAttr`
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetAttr {
     pub mode: u32,
     pub uid: u32,
@@ -433,14 +990,15 @@ pub struct SetAttr {
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DirEntry {
     /// QId for this directory
     pub qid: QId,
     /// The index of this entry
     pub offset: u64,
-    /// Corresponds to `d_type` of `struct dirent`
+    /// Corresponds to `d_type` of `struct dirent`, see [`DirEntryType`]
     ///
-    /// Use `0` if you can't set this properly. It might be enough.
+    /// Use `DirEntryType::Unknown` if you can't set this properly. It might be enough.
     pub typ: u8,
     /// Directory name
     pub name: String,
@@ -458,6 +1016,7 @@ impl DirEntry {
 
 /// Directory entry array
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DirEntryData {
     pub data: Vec<DirEntry>,
 }
@@ -497,11 +1056,53 @@ impl Default for DirEntryData {
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Data(pub Vec<u8>);
 
+// Human-readable formats get a lowercase hex string, binary formats the raw bytes
+#[cfg(feature = "serde")]
+impl serde::Serialize for Data {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut hex = String::with_capacity(self.0.len() * 2);
+            for b in &self.0 {
+                let _ = write!(hex, "{:02x}", b);
+            }
+            serializer.serialize_str(&hex)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Data {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            if hex.len() % 2 != 0 {
+                return Err(D::Error::custom("odd number of hex digits"));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .ok_or_else(|| D::Error::custom("invalid hex digit"))
+                })
+                .collect::<Result<_, _>>()
+                .map(Data)
+        } else {
+            serde::Deserialize::deserialize(deserializer).map(Data)
+        }
+    }
+}
+
 /// Similar to Linux `struct flock`
 ///
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Flock {
     pub typ: LockType,
     pub flags: LockFlag,
@@ -516,6 +1117,7 @@ pub struct Flock {
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Getlock {
     pub typ: LockType,
     pub start: u64,
@@ -528,6 +1130,7 @@ pub struct Getlock {
 enum_from_primitive! {
     #[doc = "Message type, 9P operations"]
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum MsgType {
         // 9P2000.L
         TlError         = 6,    // Illegal, never used
@@ -714,6 +1317,7 @@ impl<'a> From<&'a FCall> for MsgType {
 
 /// A data type encapsulating the various 9P messages
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FCall {
     // 9P2000.L
     RlError {
@@ -1011,6 +1615,7 @@ impl FCall {
 
 /// Envelope for 9P messages
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Msg {
     /// Chosen and used by the client to identify the message.
     /// The reply to the message will have the same tag
@@ -1018,3 +1623,773 @@ pub struct Msg {
     /// Message body encapsulating the various 9P messages
     pub body: FCall,
 }
+
+/*
+ * Human-readable rendering in the style of Plan 9's fcallfmt
+ */
+
+impl fmt::Display for MsgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use crate::MsgType::*;
+
+        f.write_str(match *self {
+            TlError => "Tlerror",
+            RlError => "Rlerror",
+            TStatFs => "Tstatfs",
+            RStatFs => "Rstatfs",
+            TlOpen => "Tlopen",
+            RlOpen => "Rlopen",
+            TlCreate => "Tlcreate",
+            RlCreate => "Rlcreate",
+            TSymlink => "Tsymlink",
+            RSymlink => "Rsymlink",
+            TMkNod => "Tmknod",
+            RMkNod => "Rmknod",
+            TRename => "Trename",
+            RRename => "Rrename",
+            TReadLink => "Treadlink",
+            RReadLink => "Rreadlink",
+            TGetAttr => "Tgetattr",
+            RGetAttr => "Rgetattr",
+            TSetAttr => "Tsetattr",
+            RSetAttr => "Rsetattr",
+            TxAttrWalk => "Txattrwalk",
+            RxAttrWalk => "Rxattrwalk",
+            TxAttrCreate => "Txattrcreate",
+            RxAttrCreate => "Rxattrcreate",
+            TReadDir => "Treaddir",
+            RReadDir => "Rreaddir",
+            TFSync => "Tfsync",
+            RFSync => "Rfsync",
+            TLock => "Tlock",
+            RLock => "Rlock",
+            TGetLock => "Tgetlock",
+            RGetLock => "Rgetlock",
+            TLink => "Tlink",
+            RLink => "Rlink",
+            TMkDir => "Tmkdir",
+            RMkDir => "Rmkdir",
+            TRenameAt => "Trenameat",
+            RRenameAt => "Rrenameat",
+            TUnlinkAt => "Tunlinkat",
+            RUnlinkAt => "Runlinkat",
+            TVersion => "Tversion",
+            RVersion => "Rversion",
+            TAuth => "Tauth",
+            RAuth => "Rauth",
+            TAttach => "Tattach",
+            RAttach => "Rattach",
+            TFlush => "Tflush",
+            RFlush => "Rflush",
+            TWalk => "Twalk",
+            RWalk => "Rwalk",
+            TRead => "Tread",
+            RRead => "Rread",
+            TWrite => "Twrite",
+            RWrite => "Rwrite",
+            TClunk => "Tclunk",
+            RClunk => "Rclunk",
+            TRemove => "Tremove",
+            RRemove => "Rremove",
+        })
+    }
+}
+
+/// Write the names of the set bits separated by `|`, `0` if none are set
+fn fmt_flags<B: bitflags::Flags>(f: &mut fmt::Formatter, flags: &B) -> fmt::Result
+where
+    B::Bits: fmt::LowerHex,
+{
+    let mut first = true;
+    for (name, _) in flags.iter_names() {
+        if !first {
+            f.write_char('|')?;
+        }
+        f.write_str(name)?;
+        first = false;
+    }
+
+    let unknown = flags.bits() & !B::all().bits();
+    if unknown != <B::Bits as bitflags::Bits>::EMPTY {
+        if !first {
+            f.write_char('|')?;
+        }
+        write!(f, "{:#x}", unknown)?;
+    } else if first {
+        f.write_char('0')?;
+    }
+    Ok(())
+}
+
+/// The access mode followed by the other bits, e.g. `O_RDWR|O_TRUNC`
+impl fmt::Display for P9OpenFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.access_mode() {
+            P9OpenFlags::WRONLY => "O_WRONLY",
+            P9OpenFlags::RDWR => "O_RDWR",
+            P9OpenFlags::NOACCESS => "O_NOACCESS",
+            _ => "O_RDONLY",
+        })?;
+
+        let rest = *self & !P9OpenFlags::NOACCESS;
+        for (name, _) in rest.iter_names() {
+            write!(f, "|O_{}", name)?;
+        }
+        let unknown = rest.bits() & !P9OpenFlags::all().bits();
+        if unknown != 0 {
+            write!(f, "|{:#o}", unknown)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rendered like `ls -l`, e.g. `drwxr-xr-x`
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char(match self.file_type() {
+            DirEntryType::Sock => 's',
+            DirEntryType::Lnk => 'l',
+            DirEntryType::Blk => 'b',
+            DirEntryType::Dir => 'd',
+            DirEntryType::Chr => 'c',
+            DirEntryType::Fifo => 'p',
+            DirEntryType::Reg | DirEntryType::Unknown => '-',
+        })?;
+
+        let bit = |mask: FileMode, c: char| if self.contains(mask) { c } else { '-' };
+        let special =
+            |x: FileMode, s: FileMode, set: char| match (self.contains(x), self.contains(s)) {
+                (true, true) => set,
+                (false, true) => set.to_ascii_uppercase(),
+                (true, false) => 'x',
+                (false, false) => '-',
+            };
+
+        for c in [
+            bit(FileMode::IRUSR, 'r'),
+            bit(FileMode::IWUSR, 'w'),
+            special(FileMode::IXUSR, FileMode::ISUID, 's'),
+            bit(FileMode::IRGRP, 'r'),
+            bit(FileMode::IWGRP, 'w'),
+            special(FileMode::IXGRP, FileMode::ISGID, 's'),
+            bit(FileMode::IROTH, 'r'),
+            bit(FileMode::IWOTH, 'w'),
+            special(FileMode::IXOTH, FileMode::ISVTX, 't'),
+        ] {
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DirEntryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DirEntryType::Unknown => "DT_UNKNOWN",
+            DirEntryType::Fifo => "DT_FIFO",
+            DirEntryType::Chr => "DT_CHR",
+            DirEntryType::Dir => "DT_DIR",
+            DirEntryType::Blk => "DT_BLK",
+            DirEntryType::Reg => "DT_REG",
+            DirEntryType::Lnk => "DT_LNK",
+            DirEntryType::Sock => "DT_SOCK",
+        })
+    }
+}
+
+/// At most `DUMPSOME` bytes of a payload, quoted if printable and in hex otherwise
+struct DumpSome<'a>(&'a [u8]);
+
+impl fmt::Display for DumpSome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const DUMPSOME: usize = 16;
+
+        let head = &self.0[..self.0.len().min(DUMPSOME)];
+        let printable = head
+            .iter()
+            .all(|&c| c.is_ascii_graphic() || matches!(c, b' ' | b'\t' | b'\n' | b'\r'));
+
+        if printable {
+            f.write_char('\'')?;
+            for &c in head {
+                match c {
+                    b'\t' => f.write_str("\\t")?,
+                    b'\n' => f.write_str("\\n")?,
+                    b'\r' => f.write_str("\\r")?,
+                    b'\'' => f.write_str("\\'")?,
+                    b'\\' => f.write_str("\\\\")?,
+                    c => f.write_char(c as char)?,
+                }
+            }
+            f.write_char('\'')?;
+        } else {
+            for c in head {
+                write!(f, "{:02x}", c)?;
+            }
+        }
+
+        if self.0.len() > DUMPSOME {
+            f.write_str("...")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for QId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const TYPES: &[(QIdType, char)] = &[
+            (QIdType::DIR, 'd'),
+            (QIdType::APPEND, 'a'),
+            (QIdType::EXCL, 'l'),
+            (QIdType::MOUNT, 'm'),
+            (QIdType::AUTH, 'A'),
+            (QIdType::TMP, 't'),
+            (QIdType::SYMLINK, 'L'),
+            (QIdType::LINK, 'H'),
+        ];
+
+        write!(f, "({:016x} {} ", self.path, self.version)?;
+        for &(typ, c) in TYPES {
+            if self.typ.contains(typ) {
+                f.write_char(c)?;
+            }
+        }
+        f.write_char(')')
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.sec, self.nsec)
+    }
+}
+
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mode {} uid {} gid {} nlink {} rdev {} size {} blksize {} blocks {} atime {} mtime {} ctime {} btime {} generation {} data_version {}",
+            FileMode::from_bits_retain(self.mode),
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.rdev,
+            self.size,
+            self.blksize,
+            self.blocks,
+            self.atime,
+            self.mtime,
+            self.ctime,
+            self.btime,
+            self.generation,
+            self.data_version
+        )
+    }
+}
+
+impl fmt::Display for StatFs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "type {:#x} bsize {} blocks {} bfree {} bavail {} files {} ffree {} fsid {:#x} namelen {}",
+            self.typ,
+            self.bsize,
+            self.blocks,
+            self.bfree,
+            self.bavail,
+            self.files,
+            self.ffree,
+            self.fsid,
+            self.namelen
+        )
+    }
+}
+
+impl fmt::Display for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} offset {} type {} '{}'",
+            self.qid,
+            self.offset,
+            DirEntryType::from_u8(self.typ).unwrap_or_default(),
+            self.name
+        )
+    }
+}
+
+impl fmt::Display for Flock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("type ")?;
+        fmt_lock_type(f, self.typ)?;
+        f.write_str(" flags ")?;
+        fmt_flags(f, &self.flags)?;
+        write!(
+            f,
+            " start {} length {} proc_id {} client_id '{}'",
+            self.start, self.length, self.proc_id, self.client_id
+        )
+    }
+}
+
+impl fmt::Display for Getlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("type ")?;
+        fmt_lock_type(f, self.typ)?;
+        write!(
+            f,
+            " start {} length {} proc_id {} client_id '{}'",
+            self.start, self.length, self.proc_id, self.client_id
+        )
+    }
+}
+
+// LockType and LockStatus are enumerations encoded as bitflags, print the matching name
+fn fmt_lock_type(f: &mut fmt::Formatter, typ: LockType) -> fmt::Result {
+    match typ {
+        LockType::RDLOCK => f.write_str("RDLOCK"),
+        LockType::WRLOCK => f.write_str("WRLOCK"),
+        LockType::UNLOCK => f.write_str("UNLOCK"),
+        _ => write!(f, "{}", typ.bits()),
+    }
+}
+
+fn fmt_lock_status(f: &mut fmt::Formatter, status: LockStatus) -> fmt::Result {
+    match status {
+        LockStatus::SUCCESS => f.write_str("SUCCESS"),
+        LockStatus::BLOCKED => f.write_str("BLOCKED"),
+        LockStatus::ERROR => f.write_str("ERROR"),
+        LockStatus::GRACE => f.write_str("GRACE"),
+        _ => write!(f, "{}", status.bits()),
+    }
+}
+
+impl FCall {
+    /// Write the fields of the message, each preceded by a space
+    fn fmt_fields(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use crate::FCall::*;
+
+        match *self {
+            RlError { ecode } => write!(
+                f,
+                " ecode {} ({:?})",
+                ecode,
+                nix::errno::Errno::from_raw(ecode as i32)
+            ),
+            TStatFs { fid } => write!(f, " fid {}", fid),
+            RStatFs { ref statfs } => write!(f, " {}", statfs),
+            TlOpen { fid, flags } => write!(
+                f,
+                " fid {} flags {}",
+                fid,
+                P9OpenFlags::from_bits_retain(flags)
+            ),
+            RlOpen { ref qid, iounit } => write!(f, " qid {} iounit {}", qid, iounit),
+            TlCreate {
+                fid,
+                ref name,
+                flags,
+                mode,
+                gid,
+            } => write!(
+                f,
+                " fid {} name '{}' flags {} mode {} gid {}",
+                fid,
+                name,
+                P9OpenFlags::from_bits_retain(flags),
+                FileMode::from_bits_retain(mode),
+                gid
+            ),
+            RlCreate { ref qid, iounit } => write!(f, " qid {} iounit {}", qid, iounit),
+            TSymlink {
+                fid,
+                ref name,
+                ref symtgt,
+                gid,
+            } => write!(
+                f,
+                " fid {} name '{}' symtgt '{}' gid {}",
+                fid, name, symtgt, gid
+            ),
+            RSymlink { ref qid } => write!(f, " qid {}", qid),
+            TMkNod {
+                dfid,
+                ref name,
+                mode,
+                major,
+                minor,
+                gid,
+            } => write!(
+                f,
+                " dfid {} name '{}' mode {} major {} minor {} gid {}",
+                dfid,
+                name,
+                FileMode::from_bits_retain(mode),
+                major,
+                minor,
+                gid
+            ),
+            RMkNod { ref qid } => write!(f, " qid {}", qid),
+            TRename {
+                fid,
+                dfid,
+                ref name,
+            } => {
+                write!(f, " fid {} dfid {} name '{}'", fid, dfid, name)
+            }
+            TReadLink { fid } => write!(f, " fid {}", fid),
+            RReadLink { ref target } => write!(f, " target '{}'", target),
+            TGetAttr { fid, req_mask } => {
+                write!(f, " fid {} req_mask ", fid)?;
+                fmt_flags(f, &req_mask)
+            }
+            RGetAttr {
+                valid,
+                ref qid,
+                ref stat,
+            } => {
+                f.write_str(" valid ")?;
+                fmt_flags(f, &valid)?;
+                write!(f, " qid {} {}", qid, stat)
+            }
+            TSetAttr {
+                fid,
+                valid,
+                ref stat,
+            } => {
+                write!(f, " fid {} valid ", fid)?;
+                fmt_flags(f, &valid)?;
+                write!(
+                    f,
+                    " mode {} uid {} gid {} size {} atime {} mtime {}",
+                    FileMode::from_bits_retain(stat.mode),
+                    stat.uid,
+                    stat.gid,
+                    stat.size,
+                    stat.atime,
+                    stat.mtime
+                )
+            }
+            TxAttrWalk {
+                fid,
+                newfid,
+                ref name,
+            } => write!(f, " fid {} newfid {} name '{}'", fid, newfid, name),
+            RxAttrWalk { size } => write!(f, " size {}", size),
+            TxAttrCreate {
+                fid,
+                ref name,
+                attr_size,
+                flags,
+            } => write!(
+                f,
+                " fid {} name '{}' attr_size {} flags {:#x}",
+                fid, name, attr_size, flags
+            ),
+            TReadDir { fid, offset, count } => {
+                write!(f, " fid {} offset {} count {}", fid, offset, count)
+            }
+            RReadDir { ref data } => {
+                write!(f, " count {}", data.size())?;
+                for (i, entry) in data.data().iter().enumerate() {
+                    write!(f, " {}:{}", i, entry)?;
+                }
+                Ok(())
+            }
+            TFSync { fid } => write!(f, " fid {}", fid),
+            TLock { fid, ref flock } => write!(f, " fid {} {}", fid, flock),
+            RLock { status } => {
+                f.write_str(" status ")?;
+                fmt_lock_status(f, status)
+            }
+            TGetLock { fid, ref flock } => write!(f, " fid {} {}", fid, flock),
+            RGetLock { ref flock } => write!(f, " {}", flock),
+            TLink {
+                dfid,
+                fid,
+                ref name,
+            } => {
+                write!(f, " dfid {} fid {} name '{}'", dfid, fid, name)
+            }
+            TMkDir {
+                dfid,
+                ref name,
+                mode,
+                gid,
+            } => write!(
+                f,
+                " dfid {} name '{}' mode {} gid {}",
+                dfid,
+                name,
+                FileMode::from_bits_retain(mode),
+                gid
+            ),
+            RMkDir { ref qid } => write!(f, " qid {}", qid),
+            TRenameAt {
+                olddirfid,
+                ref oldname,
+                newdirfid,
+                ref newname,
+            } => write!(
+                f,
+                " olddirfid {} oldname '{}' newdirfid {} newname '{}'",
+                olddirfid, oldname, newdirfid, newname
+            ),
+            TUnlinkAt {
+                dirfd,
+                ref name,
+                flags,
+            } => {
+                write!(f, " dirfd {} name '{}' flags ", dirfd, name)?;
+                fmt_flags(f, &UnlinkFlags::from_bits_retain(flags))
+            }
+            TAuth {
+                afid,
+                ref uname,
+                ref aname,
+                n_uname,
+            } => write!(
+                f,
+                " afid {} uname '{}' aname '{}' n_uname {}",
+                afid, uname, aname, n_uname
+            ),
+            RAuth { ref aqid } => write!(f, " aqid {}", aqid),
+            TAttach {
+                fid,
+                afid,
+                ref uname,
+                ref aname,
+                n_uname,
+            } => write!(
+                f,
+                " fid {} afid {} uname '{}' aname '{}' n_uname {}",
+                fid, afid, uname, aname, n_uname
+            ),
+            RAttach { ref qid } => write!(f, " qid {}", qid),
+            TVersion { msize, ref version } | RVersion { msize, ref version } => {
+                write!(f, " msize {} version '{}'", msize, version)
+            }
+            TFlush { oldtag } => write!(f, " oldtag {}", oldtag),
+            TWalk {
+                fid,
+                newfid,
+                ref wnames,
+            } => {
+                write!(f, " fid {} newfid {} nwname {}", fid, newfid, wnames.len())?;
+                for name in wnames {
+                    write!(f, " '{}'", name)?;
+                }
+                Ok(())
+            }
+            RWalk { ref wqids } => {
+                write!(f, " nwqid {}", wqids.len())?;
+                for qid in wqids {
+                    write!(f, " {}", qid)?;
+                }
+                Ok(())
+            }
+            TRead { fid, offset, count } => {
+                write!(f, " fid {} offset {} count {}", fid, offset, count)
+            }
+            RRead { ref data } => write!(f, " count {} {}", data.0.len(), DumpSome(&data.0)),
+            TWrite {
+                fid,
+                offset,
+                ref data,
+            } => write!(
+                f,
+                " fid {} offset {} count {} {}",
+                fid,
+                offset,
+                data.0.len(),
+                DumpSome(&data.0)
+            ),
+            RWrite { count } => write!(f, " count {}", count),
+            TClunk { fid } => write!(f, " fid {}", fid),
+            TRemove { fid } => write!(f, " fid {}", fid),
+            RRename | RSetAttr | RxAttrCreate | RFSync | RLink | RRenameAt | RUnlinkAt | RFlush
+            | RClunk | RRemove => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for FCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", MsgType::from(self))?;
+        self.fmt_fields(f)
+    }
+}
+
+/// Renders like Plan 9's fcallfmt, e.g. `Twalk tag 3 fid 1 newfid 2 nwname 2 'usr' 'lib'`
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tag {}", MsgType::from(&self.body), self.tag)?;
+        self.body.fmt_fields(f)
+    }
+}
+
+#[test]
+fn msg_display() {
+    let twalk = Msg {
+        tag: 3,
+        body: FCall::TWalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".to_owned(), "lib".to_owned()],
+        },
+    };
+    assert_eq!(
+        twalk.to_string(),
+        "Twalk tag 3 fid 1 newfid 2 nwname 2 'usr' 'lib'"
+    );
+
+    let rread = Msg {
+        tag: 5,
+        body: FCall::RRead {
+            data: Data(b"hello, world\nand more".to_vec()),
+        },
+    };
+    assert_eq!(
+        rread.to_string(),
+        "Rread tag 5 count 21 'hello, world\\nand'..."
+    );
+
+    let tlopen = FCall::TlOpen {
+        fid: 1,
+        flags: 0o2 | 0o1000 | 0o200000,
+    };
+    assert_eq!(
+        tlopen.to_string(),
+        "Tlopen fid 1 flags O_RDWR|O_TRUNC|O_DIRECTORY"
+    );
+
+    let rgetattr = FCall::TGetAttr {
+        fid: 4,
+        req_mask: GetAttrMask::MODE | GetAttrMask::SIZE,
+    };
+    assert_eq!(rgetattr.to_string(), "Tgetattr fid 4 req_mask MODE|SIZE");
+
+    let tmkdir = FCall::TMkDir {
+        dfid: 1,
+        name: "tmp".to_owned(),
+        mode: 0o041777,
+        gid: 0,
+    };
+    assert_eq!(
+        tmkdir.to_string(),
+        "Tmkdir dfid 1 name 'tmp' mode drwxrwxrwt gid 0"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_json_roundtrip() {
+    let msgs = vec![
+        Msg {
+            tag: 5,
+            body: FCall::RRead {
+                data: Data(vec![0xde, 0xad, 0xbe, 0xef]),
+            },
+        },
+        Msg {
+            tag: 7,
+            body: FCall::RWalk {
+                wqids: vec![QId {
+                    typ: QIdType::DIR | QIdType::TMP,
+                    version: 1,
+                    path: 42,
+                }],
+            },
+        },
+        Msg {
+            tag: 9,
+            body: FCall::RClunk,
+        },
+    ];
+
+    let json = serde_json::to_string(&msgs).unwrap();
+    assert!(json.contains(r#""data":"deadbeef""#));
+    assert!(json.contains(r#""typ":"DIR | TMP""#));
+
+    let actual: Vec<Msg> = serde_json::from_str(&json).unwrap();
+    assert_eq!(msgs, actual);
+}
+
+#[test]
+fn open_flags_and_modes() {
+    use nix::fcntl::OFlag;
+
+    let flags = P9OpenFlags::WRONLY | P9OpenFlags::CREATE | P9OpenFlags::NOFOLLOW;
+    let oflag = OFlag::from(flags);
+    assert_eq!(oflag, OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_NOFOLLOW);
+    assert_eq!(P9OpenFlags::from(oflag), flags);
+    assert!(flags.is_writable());
+    assert!(!P9OpenFlags::DIRECTORY.is_writable());
+
+    // O_SYNC carries O_DSYNC on Linux, both are set but each maps back on its own
+    #[cfg(target_os = "linux")]
+    {
+        let sync = P9OpenFlags::SYNC | P9OpenFlags::DSYNC;
+        assert_eq!(P9OpenFlags::from(OFlag::O_SYNC), sync);
+        assert_eq!(OFlag::from(sync), OFlag::O_SYNC);
+        assert_eq!(P9OpenFlags::from(OFlag::O_DSYNC), P9OpenFlags::DSYNC);
+        let own_bit = OFlag::O_SYNC.difference(OFlag::O_DSYNC);
+        assert_eq!(P9OpenFlags::from(own_bit), P9OpenFlags::SYNC);
+    }
+
+    let mode = FileMode::from_bits_retain(0o104755);
+    assert_eq!(mode.file_type(), DirEntryType::Reg);
+    assert_eq!(mode.permissions().bits(), 0o4755);
+    assert_eq!(mode.to_string(), "-rwsr-xr-x");
+    assert_eq!(DirEntryType::Dir.mode(), FileMode::IFDIR);
+    assert_eq!(DirEntryType::from_mode(0o120777), DirEntryType::Lnk);
+}
+
+#[test]
+fn stat_builders() {
+    let stat = Stat::file(1000)
+        .mode(0o600)
+        .uid(1000)
+        .times(Time { sec: 1, nsec: 2 });
+    assert_eq!(stat.mode, 0o100600);
+    assert_eq!(stat.blocks, 2);
+    assert_eq!(stat.uid, 1000);
+    assert_eq!(
+        SystemTime::from(stat.mtime),
+        UNIX_EPOCH + Duration::new(1, 2)
+    );
+    assert_eq!(
+        SystemTime::from(Time {
+            sec: 1,
+            nsec: 2_500_000_000
+        }),
+        UNIX_EPOCH + Duration::new(3, 500_000_000)
+    );
+    let max = SystemTime::from(Time {
+        sec: u64::MAX,
+        nsec: u64::MAX,
+    });
+    assert!(max > SystemTime::from(Time::now()));
+
+    match stat.rgetattr(QId::file(7), GetAttrMask::MODE | GetAttrMask::BTIME) {
+        FCall::RGetAttr { valid, qid, stat } => {
+            assert_eq!(valid, GetAttrMask::MODE | GetAttrMask::BTIME);
+            assert_eq!(qid, QId::file(7));
+            assert_eq!(stat.mode, 0o100600);
+            assert_eq!(stat.uid, 0);
+            assert_eq!(stat.mtime, Time::default());
+        }
+        _ => unreachable!(),
+    }
+
+    // No generation unless one was given
+    let valid = |stat: Stat| match stat.rgetattr(QId::file(7), GetAttrMask::ALL) {
+        FCall::RGetAttr { valid, .. } => valid,
+        _ => unreachable!(),
+    };
+    assert_eq!(valid(Stat::file(1)), GetAttrMask::ALL - GetAttrMask::GEN);
+    assert_eq!(valid(Stat::file(1).generation(3)), GetAttrMask::ALL);
+
+    assert_eq!(Stat::dir().mode, 0o40755);
+    assert_eq!(Stat::dir().nlink, 2);
+}
@@ -107,23 +107,40 @@
 //! - **TCP**: `"tcp!host!port"` (e.g., `"tcp!0.0.0.0!564"`)
 //! - **Unix Domain Sockets**: `"unix!path!suffix"` (e.g., `"unix!/tmp/socket!0"`)
 //!
+//! Clients and proxies can reuse the server's framing through [`codec::NinePCodec`],
+//! which turns any `AsyncRead + AsyncWrite` into a stream/sink of [`Msg`].
+//!
 //! # Feature Flags
 //!
 //! This crate uses workspace dependencies and requires:
 //! - `tokio` with `full` features for async runtime
 //! - `async-trait` for trait async methods
 //!
+//! Optional features:
+//! - `serde`: `Serialize`/`Deserialize` for all message types in [`fcall`], e.g. to record
+//!   sessions as JSON lines. Bitflags are rendered as named flag lists and `Data` as a hex
+//!   string in human-readable formats.
+//!
 //! # Safety
 //!
 //! This crate forbids unsafe code (`#![forbid(unsafe_code)]`) and relies on Rust's
 //! type system for memory safety. All filesystem operations are async and designed
 //! to be cancellation-safe.
+pub mod codec;
 pub mod error;
 pub mod fcall;
+pub mod lock;
+pub mod memfs;
+pub mod multiexport;
+pub mod overlay;
+pub mod proxy;
+pub mod readonly;
 pub mod serialize;
 pub mod srv;
+pub mod synth;
 #[macro_use]
 pub mod utils;
+pub mod xattr;
 
 pub use crate::error::Error;
 pub use crate::error::errno;
@@ -0,0 +1,491 @@
+//! Byte-range locks for `TLock`/`TGetLock`.
+//!
+//! The Linux client (mounted with `-o locks`) forwards `fcntl(2)` and `flock(2)` locks
+//! to the server, identifying the lock owner by the `client_id` (the client's hostname)
+//! and `proc_id` fields. [`LockManager`] implements POSIX record lock semantics on top
+//! of that:
+//!
+//! - Read locks are shared, write locks are exclusive between different owners.
+//! - Locking a range an owner already holds replaces the overlapping part, splitting
+//!   or merging its existing ranges as needed. `UNLOCK` removes the overlapping part.
+//! - Conflicting requests are answered with `BLOCKED`. The protocol has no way to wake
+//!   a client up, so blocking requests are retried by the client.
+//!
+//! Locks are tracked per file, identified by `QId.path`. Each fid keeps a [`FidLocks`],
+//! which releases the locks of all owners that locked through it when it is dropped,
+//! i.e. when the fid is clunked or the connection goes away.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{lock::{FidLocks, LockManager}, srv::FId, FCall, Flock, Result};
+//! # struct MyFs { locks: LockManager }
+//! # struct MyFId { locks: FidLocks, inode: u64 }
+//! # impl MyFs {
+//! async fn rlock(&self, fid: &FId<MyFId>, lock: &Flock) -> Result<FCall> {
+//!     Ok(FCall::RLock {
+//!         status: self.locks.lock(&fid.aux.locks, fid.aux.inode, lock),
+//!     })
+//! }
+//! # }
+//! ```
+
+use {
+    crate::fcall::*,
+    std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex, MutexGuard},
+    },
+};
+
+/// The owner of a lock, as identified by the client
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    pub client_id: String,
+    pub proc_id: u32,
+}
+
+impl From<&Flock> for LockOwner {
+    fn from(flock: &Flock) -> Self {
+        LockOwner {
+            client_id: flock.client_id.clone(),
+            proc_id: flock.proc_id,
+        }
+    }
+}
+
+impl From<&Getlock> for LockOwner {
+    fn from(getlock: &Getlock) -> Self {
+        LockOwner {
+            client_id: getlock.client_id.clone(),
+            proc_id: getlock.proc_id,
+        }
+    }
+}
+
+/// A locked range, `end` is inclusive
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Range {
+    owner: LockOwner,
+    typ: LockType,
+    start: u64,
+    end: u64,
+}
+
+impl Range {
+    fn new(owner: LockOwner, typ: LockType, start: u64, length: u64) -> Range {
+        // A length of 0 locks up to the end of the file, wherever it is
+        let end = match length {
+            0 => u64::MAX,
+            _ => start.saturating_add(length - 1),
+        };
+        Range {
+            owner,
+            typ,
+            start,
+            end,
+        }
+    }
+
+    fn length(&self) -> u64 {
+        match self.end {
+            u64::MAX => 0,
+            _ => self.end - self.start + 1,
+        }
+    }
+
+    fn overlaps(&self, other: &Range) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Range) -> bool {
+        self.typ != LockType::UNLOCK
+            && other.typ != LockType::UNLOCK
+            && self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == LockType::WRLOCK || other.typ == LockType::WRLOCK)
+    }
+}
+
+type Files = HashMap<u64, Vec<Range>>;
+
+/// Lock table shared by all the connections of a server
+///
+/// Cloning a `LockManager` gives another handle to the same table.
+#[derive(Clone, Debug, Default)]
+pub struct LockManager {
+    files: Arc<Mutex<Files>>,
+}
+
+impl LockManager {
+    /// Create an empty lock table
+    pub fn new() -> LockManager {
+        Default::default()
+    }
+
+    fn files(&self) -> MutexGuard<'_, Files> {
+        // The table is never left inconsistent, a panic elsewhere doesn't matter
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Serve a `TLock` on `file` through the fid owning `fid_locks`
+    ///
+    /// Returns the status to put in `RLock`: `SUCCESS`, `BLOCKED` if another owner
+    /// holds a conflicting lock, or `ERROR` for an invalid lock type.
+    pub fn lock(&self, fid_locks: &FidLocks, file: u64, flock: &Flock) -> LockStatus {
+        let mirror = |_: &Flock| Ok::<_, std::convert::Infallible>(true);
+        match self.lock_mirrored(fid_locks, file, flock, mirror) {
+            Ok(status) => status,
+        }
+    }
+
+    /// Serve a `TLock` like [`LockManager::lock`], mirroring it with `mirror`
+    ///
+    /// This lets a server also lock the file it serves, e.g. with OFD locks, so that
+    /// local processes see the locks of its clients. `mirror` is called with the table
+    /// locked, once the request is known not to conflict with another owner. For a
+    /// lock, if it returns `false` the table is left unchanged and `BLOCKED` is
+    /// returned. `UNLOCK` is mirrored once the table is updated.
+    ///
+    /// The mirror must be kept per owner, like the table: an owner may lock the same
+    /// file through several fids, and its locks must not conflict with each other.
+    pub fn lock_mirrored<E>(
+        &self,
+        fid_locks: &FidLocks,
+        file: u64,
+        flock: &Flock,
+        mut mirror: impl FnMut(&Flock) -> Result<bool, E>,
+    ) -> Result<LockStatus, E> {
+        if !matches!(
+            flock.typ,
+            LockType::RDLOCK | LockType::WRLOCK | LockType::UNLOCK
+        ) {
+            return Ok(LockStatus::ERROR);
+        }
+
+        let owner = LockOwner::from(flock);
+        let new = Range::new(owner.clone(), flock.typ, flock.start, flock.length);
+
+        let mut files = self.files();
+        let ranges = files.entry(file).or_default();
+        if ranges.iter().any(|r| r.conflicts(&new)) {
+            return Ok(LockStatus::BLOCKED);
+        }
+        if new.typ != LockType::UNLOCK && !mirror(flock)? {
+            if ranges.is_empty() {
+                files.remove(&file);
+            }
+            return Ok(LockStatus::BLOCKED);
+        }
+
+        // Carve the requested range out of the owner's existing locks
+        let mut rest = Vec::with_capacity(ranges.len() + 1);
+        for r in ranges.drain(..) {
+            if r.owner != owner || !r.overlaps(&new) {
+                rest.push(r);
+                continue;
+            }
+            if r.start < new.start {
+                rest.push(Range {
+                    end: new.start - 1,
+                    ..r.clone()
+                });
+            }
+            if r.end > new.end {
+                rest.push(Range {
+                    start: new.end + 1,
+                    ..r
+                });
+            }
+        }
+
+        if new.typ != LockType::UNLOCK {
+            rest.push(new);
+            merge(&mut rest, &owner);
+        }
+
+        *ranges = rest;
+        if ranges.is_empty() {
+            files.remove(&file);
+        }
+
+        if flock.typ == LockType::UNLOCK {
+            mirror(flock)?;
+        }
+        drop(files);
+
+        if flock.typ != LockType::UNLOCK {
+            fid_locks.track(self, file, owner);
+        }
+        Ok(LockStatus::SUCCESS)
+    }
+
+    /// Serve a `TGetLock` on `file`
+    ///
+    /// Returns the first lock conflicting with `getlock`, or `getlock` itself with its
+    /// type set to `UNLOCK` if it could be placed.
+    pub fn getlock(&self, file: u64, getlock: &Getlock) -> Getlock {
+        let test = Range::new(
+            LockOwner::from(getlock),
+            getlock.typ,
+            getlock.start,
+            getlock.length,
+        );
+
+        let files = self.files();
+        let conflict = files
+            .get(&file)
+            .and_then(|ranges| ranges.iter().find(|r| r.conflicts(&test)));
+
+        match conflict {
+            Some(r) => Getlock {
+                typ: r.typ,
+                start: r.start,
+                length: r.length(),
+                proc_id: r.owner.proc_id,
+                client_id: r.owner.client_id.clone(),
+            },
+            None => Getlock {
+                typ: LockType::UNLOCK,
+                ..getlock.clone()
+            },
+        }
+    }
+
+    /// Release all the locks `owner` holds on `file`
+    pub fn unlock_owner(&self, file: u64, owner: &LockOwner) {
+        let mut files = self.files();
+        if let Some(ranges) = files.get_mut(&file) {
+            ranges.retain(|r| r.owner != *owner);
+            if ranges.is_empty() {
+                files.remove(&file);
+            }
+        }
+    }
+
+    /// Release all the locks held by a client, e.g. after it rebooted
+    pub fn unlock_client(&self, client_id: &str) {
+        let mut files = self.files();
+        files.retain(|_, ranges| {
+            ranges.retain(|r| r.owner.client_id != client_id);
+            !ranges.is_empty()
+        });
+    }
+}
+
+// Join the owner's adjacent or overlapping ranges of the same type
+fn merge(ranges: &mut Vec<Range>, owner: &LockOwner) {
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+    for r in ranges.drain(..) {
+        if let Some(last) = merged
+            .iter_mut()
+            .rev()
+            .find(|l| l.owner == *owner && l.typ == r.typ)
+            && r.owner == *owner
+            && r.start <= last.end.saturating_add(1)
+        {
+            last.end = last.end.max(r.end);
+            continue;
+        }
+        merged.push(r);
+    }
+    *ranges = merged;
+}
+
+#[derive(Debug)]
+struct Tracked {
+    manager: LockManager,
+    file: u64,
+    owners: HashSet<LockOwner>,
+}
+
+/// Per-fid record of the owners which locked through the fid
+///
+/// Dropping it releases their locks on the file, like closing a file descriptor
+/// releases the POSIX locks of the process.
+#[derive(Debug, Default)]
+pub struct FidLocks {
+    tracked: Mutex<Option<Tracked>>,
+}
+
+impl FidLocks {
+    fn track(&self, manager: &LockManager, file: u64, owner: LockOwner) {
+        let mut tracked = self.tracked.lock().unwrap_or_else(|e| e.into_inner());
+        tracked
+            .get_or_insert_with(|| Tracked {
+                manager: manager.clone(),
+                file,
+                owners: HashSet::new(),
+            })
+            .owners
+            .insert(owner);
+    }
+
+    /// Release the locks taken through this fid now
+    pub fn release(&self) {
+        let tracked = self
+            .tracked
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(Tracked {
+            manager,
+            file,
+            owners,
+        }) = tracked
+        {
+            for owner in &owners {
+                manager.unlock_owner(file, owner);
+            }
+        }
+    }
+}
+
+impl Drop for FidLocks {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+fn flock(typ: LockType, start: u64, length: u64, proc_id: u32) -> Flock {
+    Flock {
+        typ,
+        flags: LockFlag::empty(),
+        start,
+        length,
+        proc_id,
+        client_id: "host".to_owned(),
+    }
+}
+
+#[test]
+fn lock_conflicts() {
+    let locks = LockManager::new();
+    let (a, b) = (FidLocks::default(), FidLocks::default());
+
+    let rd = flock(LockType::RDLOCK, 0, 100, 1);
+    assert_eq!(locks.lock(&a, 1, &rd), LockStatus::SUCCESS);
+    assert_eq!(
+        locks.lock(&b, 1, &flock(LockType::RDLOCK, 50, 0, 2)),
+        LockStatus::SUCCESS
+    );
+    assert_eq!(
+        locks.lock(&b, 1, &flock(LockType::WRLOCK, 99, 1, 3)),
+        LockStatus::BLOCKED
+    );
+    assert_eq!(
+        locks.lock(&b, 1, &flock(LockType::WRLOCK, 100, 10, 3)),
+        LockStatus::BLOCKED
+    );
+    assert_eq!(
+        locks.lock(&b, 2, &flock(LockType::WRLOCK, 0, 0, 3)),
+        LockStatus::SUCCESS
+    );
+
+    let test = Getlock {
+        typ: LockType::WRLOCK,
+        start: 10,
+        length: 1,
+        proc_id: 3,
+        client_id: "host".to_owned(),
+    };
+    assert_eq!(
+        locks.getlock(1, &test),
+        Getlock {
+            typ: LockType::RDLOCK,
+            start: 0,
+            length: 100,
+            proc_id: 1,
+            client_id: "host".to_owned(),
+        }
+    );
+
+    // Clunking the fid releases the locks of proc 2 and only those
+    drop(b);
+    assert_eq!(
+        locks.lock(&a, 1, &flock(LockType::WRLOCK, 0, 100, 1)),
+        LockStatus::SUCCESS
+    );
+    let test = Getlock { start: 100, ..test };
+    assert_eq!(locks.getlock(1, &test).typ, LockType::UNLOCK);
+}
+
+#[test]
+fn lock_split_and_merge() {
+    let locks = LockManager::new();
+    let fid = FidLocks::default();
+    let ranges = |locks: &LockManager| {
+        locks.files()[&7]
+            .iter()
+            .map(|r| (r.typ, r.start, r.length()))
+            .collect::<Vec<_>>()
+    };
+
+    locks.lock(&fid, 7, &flock(LockType::WRLOCK, 0, 0, 1));
+    locks.lock(&fid, 7, &flock(LockType::UNLOCK, 10, 10, 1));
+    locks.lock(&fid, 7, &flock(LockType::RDLOCK, 30, 5, 1));
+    assert_eq!(
+        ranges(&locks),
+        vec![
+            (LockType::WRLOCK, 0, 10),
+            (LockType::WRLOCK, 20, 10),
+            (LockType::RDLOCK, 30, 5),
+            (LockType::WRLOCK, 35, 0),
+        ]
+    );
+
+    locks.lock(&fid, 7, &flock(LockType::WRLOCK, 5, 30, 1));
+    assert_eq!(ranges(&locks), vec![(LockType::WRLOCK, 0, 0)]);
+
+    fid.release();
+    assert!(locks.files().is_empty());
+}
+
+#[test]
+fn lock_mirrored() {
+    let locks = LockManager::new();
+    let (a, b) = (FidLocks::default(), FidLocks::default());
+    fn mirror(
+        ok: bool,
+        calls: &mut Vec<(LockType, u64, u64)>,
+    ) -> impl FnMut(&Flock) -> Result<bool, ()> + '_ {
+        move |flock| {
+            calls.push((flock.typ, flock.start, flock.length));
+            Ok(ok)
+        }
+    }
+    let mut calls = Vec::new();
+
+    // A refused mirror leaves the table unchanged
+    let wr = flock(LockType::WRLOCK, 0, 10, 1);
+    assert_eq!(
+        locks.lock_mirrored(&a, 1, &wr, mirror(false, &mut calls)),
+        Ok(LockStatus::BLOCKED)
+    );
+    assert!(locks.files().is_empty());
+    assert_eq!(
+        locks.lock_mirrored(
+            &b,
+            1,
+            &flock(LockType::WRLOCK, 5, 1, 2),
+            mirror(true, &mut calls)
+        ),
+        Ok(LockStatus::SUCCESS)
+    );
+
+    // Unlocking is mirrored once the table is updated
+    calls.clear();
+    assert_eq!(
+        locks.lock_mirrored(
+            &b,
+            1,
+            &flock(LockType::UNLOCK, 0, 0, 2),
+            mirror(true, &mut calls)
+        ),
+        Ok(LockStatus::SUCCESS)
+    );
+    assert_eq!(calls, vec![(LockType::UNLOCK, 0, 0)]);
+    assert!(locks.files().is_empty());
+}
@@ -0,0 +1,1422 @@
+//! In-memory filesystem.
+//!
+//! [`MemFs`] keeps a whole tree in memory, like tmpfs: regular files, directories,
+//! symbolic links, device nodes, FIFOs and sockets, hard links and extended
+//! attributes. It serves as a scratch export, as a fixture for testing clients, and as
+//! a reference implementation of every method of [`Filesystem`].
+//!
+//! Semantics follow POSIX where the protocol allows:
+//!
+//! - Permissions are checked against the `n_uname` given in `TAttach` (`nobody` when
+//!   absent), uid 0 bypassing them. The client doesn't send the groups of the user, so
+//!   users other than the owner get both the group and other permission bits.
+//! - Directories with the sticky bit only let the owners of entries remove or rename
+//!   them, and the setgid bit is inherited by new entries.
+//! - Unlinked files stay readable and writable through the fids still referring to
+//!   them, and are freed when the last one is clunked.
+//! - Renaming follows `rename(2)`: a file replaces a file, a directory replaces an
+//!   empty directory, and a directory can't be moved beneath itself.
+//! - File contents count towards a capacity, reported by `TStatFs` and enforced with
+//!   `ENOSPC`, as does the number of files.
+//!
+//! Cloning a `MemFs` gives another handle to the same tree, so every connection of a
+//! server sees the same files.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{memfs::MemFs, srv::srv_async, Result};
+//! # async fn serve() -> Result<()> {
+//! let fs = MemFs::new().capacity(64 << 20).root(0o1777, 0, 0);
+//! srv_async(fs, "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        error::{self, errno::*},
+        fcall::*,
+        lock::{FidLocks, LockManager},
+        srv::{FId, Filesystem},
+        utils::Result,
+        xattr::{PendingXattr, XattrFid, encode_names},
+    },
+    async_trait::async_trait,
+    std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex, MutexGuard},
+    },
+};
+
+/// Capacity of a new [`MemFs`], in bytes
+pub const DEFAULT_CAPACITY: u64 = 1 << 30;
+
+/// Maximum number of files of a new [`MemFs`]
+pub const DEFAULT_MAX_FILES: u64 = 1 << 20;
+
+const ROOT: u64 = 1;
+const BLOCK_SIZE: u64 = 4096;
+const NAME_MAX: usize = 255;
+const TMPFS_MAGIC: u32 = 0x0102_1994;
+// uid of users attaching without n_uname
+const NOBODY: u32 = 65534;
+
+const READ: u32 = 4;
+const WRITE: u32 = 2;
+const EXEC: u32 = 1;
+
+/// A [`Filesystem`] keeping its files in memory, see the [module](self) documentation
+#[derive(Clone, Debug)]
+pub struct MemFs {
+    tree: Arc<Mutex<Tree>>,
+    locks: LockManager,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        MemFs::new()
+    }
+}
+
+impl MemFs {
+    /// An empty filesystem, whose root is owned by uid 0 with mode `0755`
+    pub fn new() -> MemFs {
+        let mut inodes = HashMap::new();
+        inodes.insert(
+            ROOT,
+            Inode::new(
+                FileMode::IFDIR.bits() | 0o755,
+                0,
+                0,
+                Kind::Dir {
+                    entries: BTreeMap::new(),
+                    parent: ROOT,
+                },
+            ),
+        );
+
+        MemFs {
+            tree: Arc::new(Mutex::new(Tree {
+                inodes,
+                orphans: Vec::new(),
+                next_ino: ROOT + 1,
+                used: 0,
+                capacity: DEFAULT_CAPACITY,
+                max_files: DEFAULT_MAX_FILES,
+            })),
+            locks: LockManager::new(),
+        }
+    }
+
+    /// Limit the total size of the file contents to `bytes`
+    pub fn capacity(self, bytes: u64) -> MemFs {
+        self.tree().capacity = bytes;
+        self
+    }
+
+    /// Limit the number of files, directories included, to `files`
+    pub fn max_files(self, files: u64) -> MemFs {
+        self.tree().max_files = files;
+        self
+    }
+
+    /// Set the permission bits and owner of the root directory
+    pub fn root(self, mode: u32, uid: u32, gid: u32) -> MemFs {
+        {
+            let mut tree = self.tree();
+            let stat = &mut tree.inodes.get_mut(&ROOT).expect("root inode").stat;
+            stat.mode = FileMode::IFDIR.bits() | (mode & 0o7777);
+            stat.uid = uid;
+            stat.gid = gid;
+        }
+        self
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        // Operations check everything before modifying the tree, a panic can't leave
+        // it inconsistent
+        self.tree.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct Tree {
+    inodes: HashMap<u64, Inode>,
+    // Unlinked inodes still referred to by fids
+    orphans: Vec<u64>,
+    next_ino: u64,
+    // Bytes of file contents and symbolic link targets
+    used: u64,
+    capacity: u64,
+    max_files: u64,
+}
+
+#[derive(Debug)]
+struct Inode {
+    stat: Stat,
+    kind: Kind,
+    xattrs: BTreeMap<String, Vec<u8>>,
+    // Cloned by the fids referring to the inode
+    refs: Arc<()>,
+}
+
+#[derive(Debug)]
+enum Kind {
+    File(Vec<u8>),
+    Dir {
+        entries: BTreeMap<String, u64>,
+        parent: u64,
+    },
+    Symlink(String),
+    // Device nodes, FIFOs and sockets, only opened on the client
+    Special,
+}
+
+impl Inode {
+    fn new(mode: u32, uid: u32, gid: u32, kind: Kind) -> Inode {
+        let now = Time::now();
+        let (nlink, size) = match &kind {
+            Kind::Dir { .. } => (2, BLOCK_SIZE),
+            Kind::Symlink(target) => (1, target.len() as u64),
+            _ => (1, 0),
+        };
+
+        Inode {
+            stat: Stat {
+                mode,
+                uid,
+                gid,
+                nlink,
+                size,
+                blksize: BLOCK_SIZE,
+                blocks: size.div_ceil(512),
+                atime: now,
+                mtime: now,
+                ctime: now,
+                btime: now,
+                ..Default::default()
+            },
+            kind,
+            xattrs: BTreeMap::new(),
+            refs: Arc::new(()),
+        }
+    }
+
+    fn qid(&self, ino: u64) -> QId {
+        QId {
+            typ: DirEntryType::from_mode(self.stat.mode).into(),
+            version: self.stat.data_version as u32,
+            path: ino,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir { .. })
+    }
+
+    fn entries(&self) -> Result<&BTreeMap<String, u64>> {
+        match &self.kind {
+            Kind::Dir { entries, .. } => Ok(entries),
+            _ => Err(error::Error::No(ENOTDIR)),
+        }
+    }
+
+    fn entries_mut(&mut self) -> Result<&mut BTreeMap<String, u64>> {
+        match &mut self.kind {
+            Kind::Dir { entries, .. } => Ok(entries),
+            _ => Err(error::Error::No(ENOTDIR)),
+        }
+    }
+
+    // Bytes counted towards the capacity
+    fn usage(&self) -> u64 {
+        match &self.kind {
+            Kind::File(data) => data.len() as u64,
+            Kind::Symlink(target) => target.len() as u64,
+            _ => 0,
+        }
+    }
+
+    /// Check that `uid` has the `want` permissions, a combination of `READ`, `WRITE`
+    /// and `EXEC`
+    fn access(&self, uid: u32, want: u32) -> Result<()> {
+        let mode = self.stat.mode;
+        let granted = if uid == 0 {
+            // Even root needs an execute bit to execute a file
+            if !self.is_dir() && mode & 0o111 == 0 {
+                READ | WRITE
+            } else {
+                READ | WRITE | EXEC
+            }
+        } else if uid == self.stat.uid {
+            (mode >> 6) & 7
+        } else {
+            ((mode >> 3) | mode) & 7
+        };
+
+        if want & !granted != 0 {
+            return Err(error::Error::No(EACCES));
+        }
+        Ok(())
+    }
+
+    fn is_owner(&self, uid: u32) -> Result<()> {
+        if uid != 0 && uid != self.stat.uid {
+            return Err(error::Error::No(EPERM));
+        }
+        Ok(())
+    }
+
+    fn changed(&mut self) {
+        self.stat.ctime = Time::now();
+    }
+
+    fn modified(&mut self) {
+        let now = Time::now();
+        self.stat.mtime = now;
+        self.stat.ctime = now;
+        self.stat.data_version += 1;
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(error::Error::No(EINVAL));
+    }
+    if name.len() > NAME_MAX {
+        return Err(error::Error::No(ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+impl Tree {
+    fn get(&self, ino: u64) -> Result<&Inode> {
+        self.inodes.get(&ino).ok_or(error::Error::No(ENOENT))
+    }
+
+    fn get_mut(&mut self, ino: u64) -> Result<&mut Inode> {
+        self.inodes.get_mut(&ino).ok_or(error::Error::No(ENOENT))
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64> {
+        self.get(dir)?
+            .entries()?
+            .get(name)
+            .copied()
+            .ok_or(error::Error::No(ENOENT))
+    }
+
+    // Parent and name of the directory `ino`
+    fn dir_entry(&self, ino: u64) -> Result<(u64, String)> {
+        let parent = match self.get(ino)?.kind {
+            Kind::Dir { parent, .. } => parent,
+            _ => return Err(error::Error::No(ENOTDIR)),
+        };
+        if ino == ROOT {
+            return Ok((ROOT, String::new()));
+        }
+
+        let name = self
+            .get(parent)?
+            .entries()?
+            .iter()
+            .find(|&(_, &child)| child == ino)
+            .map(|(name, _)| name.clone())
+            .ok_or(error::Error::No(ENOENT))?;
+        Ok((parent, name))
+    }
+
+    /// The current entry of `ino`, `hint` being where the fid was walked through
+    fn locate(&self, ino: u64, hint: &(u64, String)) -> Result<(u64, String)> {
+        if ino == ROOT {
+            return Err(error::Error::No(EBUSY));
+        }
+        if self.lookup(hint.0, &hint.1).ok() == Some(ino) {
+            return Ok(hint.clone());
+        }
+        if self.get(ino)?.is_dir() {
+            return self.dir_entry(ino);
+        }
+
+        // Renamed through another fid or by a rename of its directory
+        self.inodes
+            .iter()
+            .filter_map(|(&dir, inode)| Some((dir, inode.entries().ok()?)))
+            .find_map(|(dir, entries)| {
+                let (name, _) = entries.iter().find(|&(_, &child)| child == ino)?;
+                Some((dir, name.clone()))
+            })
+            .ok_or(error::Error::No(ENOENT))
+    }
+
+    fn sweep(&mut self) {
+        let inodes = &mut self.inodes;
+        let used = &mut self.used;
+        self.orphans.retain(|ino| match inodes.get(ino) {
+            Some(inode) if Arc::strong_count(&inode.refs) > 1 => true,
+            Some(_) => {
+                *used -= inodes.remove(ino).map_or(0, |inode| inode.usage());
+                false
+            }
+            None => false,
+        });
+    }
+
+    // Free `ino` once it has no link and no fid left
+    fn release(&mut self, ino: u64) {
+        if self
+            .inodes
+            .get(&ino)
+            .is_some_and(|inode| inode.stat.nlink == 0)
+        {
+            self.orphans.push(ino);
+            self.sweep();
+        }
+    }
+
+    fn reserve(&self, old: u64, new: u64) -> Result<()> {
+        if new > old && self.used - old + new > self.capacity {
+            return Err(error::Error::No(ENOSPC));
+        }
+        Ok(())
+    }
+
+    /// Create an entry `name` in `dir` as `uid`, with the full `mode` and `kind`
+    fn create(
+        &mut self,
+        dir: u64,
+        uid: u32,
+        name: &str,
+        mode: u32,
+        mut gid: u32,
+        kind: Kind,
+    ) -> Result<u64> {
+        check_name(name)?;
+        let parent = self.get(dir)?;
+        parent.access(uid, WRITE | EXEC)?;
+        if parent.entries()?.contains_key(name) {
+            return Err(error::Error::No(EEXIST));
+        }
+        if parent.stat.nlink == 0 {
+            return Err(error::Error::No(ENOENT));
+        }
+
+        let mut mode = mode;
+        if parent.stat.mode & FileMode::ISGID.bits() != 0 {
+            gid = parent.stat.gid;
+            if matches!(kind, Kind::Dir { .. }) {
+                mode |= FileMode::ISGID.bits();
+            }
+        }
+        self.sweep();
+        if self.inodes.len() as u64 >= self.max_files {
+            return Err(error::Error::No(ENOSPC));
+        }
+        let inode = Inode::new(mode, uid, gid, kind);
+        self.reserve(0, inode.usage())?;
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.used += inode.usage();
+        let is_dir = inode.is_dir();
+        self.inodes.insert(ino, inode);
+
+        let parent = self.get_mut(dir)?;
+        parent.entries_mut()?.insert(name.to_owned(), ino);
+        if is_dir {
+            parent.stat.nlink += 1;
+        }
+        parent.modified();
+        Ok(ino)
+    }
+
+    // Only the owners of a file or of its directory may remove it from a sticky one
+    fn check_sticky(&self, dir: u64, ino: u64, uid: u32) -> Result<()> {
+        let dir = self.get(dir)?;
+        if dir.stat.mode & FileMode::ISVTX.bits() != 0
+            && uid != 0
+            && uid != dir.stat.uid
+            && uid != self.get(ino)?.stat.uid
+        {
+            return Err(error::Error::No(EPERM));
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, dir: u64, name: &str, uid: u32, flags: UnlinkFlags) -> Result<()> {
+        let ino = self.lookup(dir, name)?;
+        self.get(dir)?.access(uid, WRITE | EXEC)?;
+        self.check_sticky(dir, ino, uid)?;
+
+        let child = self.get(ino)?;
+        match (child.is_dir(), flags.contains(UnlinkFlags::REMOVEDIR)) {
+            (true, false) => return Err(error::Error::No(EISDIR)),
+            (false, true) => return Err(error::Error::No(ENOTDIR)),
+            (true, true) if !child.entries()?.is_empty() => {
+                return Err(error::Error::No(ENOTEMPTY));
+            }
+            _ => (),
+        }
+
+        let is_dir = child.is_dir();
+        let parent = self.get_mut(dir)?;
+        parent.entries_mut()?.remove(name);
+        if is_dir {
+            parent.stat.nlink -= 1;
+        }
+        parent.modified();
+        let child = self.get_mut(ino)?;
+        child.stat.nlink = if is_dir { 0 } else { child.stat.nlink - 1 };
+        child.changed();
+        self.release(ino);
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        uid: u32,
+        olddir: u64,
+        oldname: &str,
+        newdir: u64,
+        newname: &str,
+    ) -> Result<()> {
+        check_name(oldname)?;
+        check_name(newname)?;
+        let ino = self.lookup(olddir, oldname)?;
+        self.get(olddir)?.access(uid, WRITE | EXEC)?;
+        self.get(newdir)?.access(uid, WRITE | EXEC)?;
+        self.check_sticky(olddir, ino, uid)?;
+
+        let target = self.get(newdir)?.entries()?.get(newname).copied();
+        if target == Some(ino) {
+            return Ok(());
+        }
+        let is_dir = self.get(ino)?.is_dir();
+        if is_dir {
+            // Not beneath itself
+            let mut dir = newdir;
+            while dir != ROOT {
+                if dir == ino {
+                    return Err(error::Error::No(EINVAL));
+                }
+                dir = self.dir_entry(dir)?.0;
+            }
+        }
+        if let Some(target) = target {
+            self.check_sticky(newdir, target, uid)?;
+            let replaced = self.get(target)?;
+            match (is_dir, replaced.is_dir()) {
+                (true, false) => return Err(error::Error::No(ENOTDIR)),
+                (false, true) => return Err(error::Error::No(EISDIR)),
+                (true, true) if !replaced.entries()?.is_empty() => {
+                    return Err(error::Error::No(ENOTEMPTY));
+                }
+                _ => (),
+            }
+            let flags = if is_dir {
+                UnlinkFlags::REMOVEDIR
+            } else {
+                UnlinkFlags::empty()
+            };
+            self.unlink(newdir, newname, uid, flags)?;
+        }
+
+        let parent = self.get_mut(olddir)?;
+        parent.entries_mut()?.remove(oldname);
+        if is_dir {
+            parent.stat.nlink -= 1;
+        }
+        parent.modified();
+        let parent = self.get_mut(newdir)?;
+        parent.entries_mut()?.insert(newname.to_owned(), ino);
+        if is_dir {
+            parent.stat.nlink += 1;
+        }
+        parent.modified();
+
+        let inode = self.get_mut(ino)?;
+        if let Kind::Dir { parent, .. } = &mut inode.kind {
+            *parent = newdir;
+        }
+        inode.changed();
+        Ok(())
+    }
+
+    fn resize(&mut self, ino: u64, size: u64) -> Result<()> {
+        let old = match &self.get(ino)?.kind {
+            Kind::File(data) => data.len() as u64,
+            Kind::Dir { .. } => return Err(error::Error::No(EISDIR)),
+            _ => return Err(error::Error::No(EINVAL)),
+        };
+        self.reserve(old, size)?;
+        let size_usize = usize::try_from(size).map_err(|_| error::Error::No(EFBIG))?;
+
+        self.used = self.used - old + size;
+        let inode = self.get_mut(ino)?;
+        if let Kind::File(data) = &mut inode.kind {
+            data.resize(size_usize, 0);
+        }
+        inode.stat.size = size;
+        inode.stat.blocks = size.div_ceil(512);
+        inode.modified();
+        Ok(())
+    }
+
+    fn write(&mut self, ino: u64, offset: u64, data: &[u8], uid: u32) -> Result<u32> {
+        let old = self.get(ino)?.usage();
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(error::Error::No(EFBIG))?;
+        if end > old {
+            self.resize(ino, end)?;
+        }
+
+        let inode = self.get_mut(ino)?;
+        if let Kind::File(contents) = &mut inode.kind {
+            contents[offset as usize..end as usize].copy_from_slice(data);
+        }
+        inode.modified();
+        // Like Linux, writes by other users than root drop setuid and setgid
+        if uid != 0 {
+            inode.stat.mode &= !(FileMode::ISUID | FileMode::ISGID).bits();
+        }
+        Ok(data.len() as u32)
+    }
+
+    fn set_xattr(&mut self, ino: u64, pending: PendingXattr) -> Result<()> {
+        let inode = self.get_mut(ino)?;
+        let exists = inode.xattrs.contains_key(&pending.name);
+        if pending.flags.contains(XattrFlags::CREATE) && exists {
+            return Err(error::Error::No(EEXIST));
+        }
+        if (pending.flags.contains(XattrFlags::REPLACE) || pending.is_remove()) && !exists {
+            return Err(error::Error::No(ENODATA));
+        }
+
+        if pending.is_remove() {
+            inode.xattrs.remove(&pending.name);
+        } else {
+            inode.xattrs.insert(pending.name, pending.value);
+        }
+        inode.changed();
+        Ok(())
+    }
+}
+
+/// Fid of a [`MemFs`]
+#[derive(Debug, Default)]
+pub struct MemFId {
+    handle: Mutex<Option<Handle>>,
+    xattr: XattrFid,
+    locks: FidLocks,
+}
+
+// The file a fid refers to
+#[derive(Debug)]
+struct Handle {
+    ino: u64,
+    uid: u32,
+    // The entry the fid was walked through, for TRename and TRemove
+    entry: (u64, String),
+    open: Option<Open>,
+    _ref: Arc<()>,
+}
+
+#[derive(Debug)]
+struct Open {
+    read: bool,
+    write: bool,
+    append: bool,
+    // Listing of a directory, taken when reading from offset 0
+    dirents: Vec<DirEntry>,
+}
+
+impl Handle {
+    fn new(tree: &Tree, ino: u64, uid: u32, entry: (u64, String)) -> Result<Handle> {
+        Ok(Handle {
+            ino,
+            uid,
+            entry,
+            open: None,
+            _ref: tree.get(ino)?.refs.clone(),
+        })
+    }
+}
+
+impl MemFId {
+    fn handle(&self) -> MutexGuard<'_, Option<Handle>> {
+        self.handle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The inode and user of the fid
+    fn id(&self) -> Result<(u64, u32)> {
+        match &*self.handle() {
+            Some(handle) => Ok((handle.ino, handle.uid)),
+            None => Err(error::Error::No(EBADF)),
+        }
+    }
+}
+
+#[async_trait]
+impl Filesystem for MemFs {
+    type FId = MemFId;
+
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        fid.aux.id()?;
+        let mut tree = self.tree();
+        tree.sweep();
+
+        let free = tree.capacity.saturating_sub(tree.used) / BLOCK_SIZE;
+        Ok(FCall::RStatFs {
+            statfs: StatFs {
+                typ: TMPFS_MAGIC,
+                bsize: BLOCK_SIZE as u32,
+                blocks: tree.capacity / BLOCK_SIZE,
+                bfree: free,
+                bavail: free,
+                files: tree.max_files,
+                ffree: tree.max_files.saturating_sub(tree.inodes.len() as u64),
+                fsid: 0,
+                namelen: NAME_MAX as u32,
+            },
+        })
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        let flags = P9OpenFlags::from_bits_truncate(flags);
+        let read = matches!(flags.access_mode(), P9OpenFlags::RDONLY | P9OpenFlags::RDWR);
+        let write = matches!(flags.access_mode(), P9OpenFlags::WRONLY | P9OpenFlags::RDWR);
+
+        let mut handle = fid.aux.handle();
+        let handle = handle.as_mut().ok_or(error::Error::No(EBADF))?;
+        let mut tree = self.tree();
+        let inode = tree.get(handle.ino)?;
+        match inode.kind {
+            Kind::Dir { .. } if write || flags.contains(P9OpenFlags::TRUNC) => {
+                return Err(error::Error::No(EISDIR));
+            }
+            Kind::File(_) if flags.contains(P9OpenFlags::DIRECTORY) => {
+                return Err(error::Error::No(ENOTDIR));
+            }
+            Kind::Symlink(_) => return Err(error::Error::No(ELOOP)),
+            Kind::Special => return Err(error::Error::No(ENXIO)),
+            _ => (),
+        }
+
+        let mut want = 0;
+        if read {
+            want |= READ;
+        }
+        if write || flags.contains(P9OpenFlags::TRUNC) {
+            want |= WRITE;
+        }
+        inode.access(handle.uid, want)?;
+        let qid = inode.qid(handle.ino);
+        if flags.contains(P9OpenFlags::TRUNC) && inode.usage() > 0 {
+            tree.resize(handle.ino, 0)?;
+        }
+
+        handle.open = Some(Open {
+            read,
+            write,
+            append: flags.contains(P9OpenFlags::APPEND),
+            dirents: Vec::new(),
+        });
+        Ok(FCall::RlOpen { qid, iounit: 0 })
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let flags = P9OpenFlags::from_bits_truncate(flags);
+        let mut handle = fid.aux.handle();
+        let current = handle.as_ref().ok_or(error::Error::No(EBADF))?;
+        let mut tree = self.tree();
+        let mode = FileMode::IFREG.bits() | (mode & 0o7777);
+        let ino = tree.create(
+            current.ino,
+            current.uid,
+            name,
+            mode,
+            gid,
+            Kind::File(Vec::new()),
+        )?;
+
+        // The fid now refers to the new file, open with the access asked for whatever
+        // the mode is
+        let mut new = Handle::new(&tree, ino, current.uid, (current.ino, name.to_owned()))?;
+        new.open = Some(Open {
+            read: matches!(flags.access_mode(), P9OpenFlags::RDONLY | P9OpenFlags::RDWR),
+            write: matches!(flags.access_mode(), P9OpenFlags::WRONLY | P9OpenFlags::RDWR),
+            append: flags.contains(P9OpenFlags::APPEND),
+            dirents: Vec::new(),
+        });
+        let qid = tree.get(ino)?.qid(ino);
+        *handle = Some(new);
+        Ok(FCall::RlCreate { qid, iounit: 0 })
+    }
+
+    async fn rsymlink(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        sym: &str,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (dir, uid) = fid.aux.id()?;
+        let mut tree = self.tree();
+        let mode = FileMode::IFLNK.bits() | 0o777;
+        let ino = tree.create(dir, uid, name, mode, gid, Kind::Symlink(sym.to_owned()))?;
+        Ok(FCall::RSymlink {
+            qid: tree.get(ino)?.qid(ino),
+        })
+    }
+
+    async fn rmknod(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (dir, uid) = fid.aux.id()?;
+        let kind = match DirEntryType::from_mode(mode) {
+            DirEntryType::Reg => Kind::File(Vec::new()),
+            DirEntryType::Fifo | DirEntryType::Sock => Kind::Special,
+            DirEntryType::Chr | DirEntryType::Blk if uid == 0 => Kind::Special,
+            DirEntryType::Chr | DirEntryType::Blk => return Err(error::Error::No(EPERM)),
+            _ => return Err(error::Error::No(EINVAL)),
+        };
+
+        let mut tree = self.tree();
+        let mode = mode & (FileMode::IFMT.bits() | 0o7777);
+        let ino = tree.create(dir, uid, name, mode, gid, kind)?;
+        let inode = tree.get_mut(ino)?;
+        // Linux's encoding of device numbers
+        let (major, minor) = (major as u64, minor as u64);
+        inode.stat.rdev = ((major & 0xfff) << 8)
+            | ((major & !0xfff) << 32)
+            | (minor & 0xff)
+            | ((minor & !0xff) << 12);
+        Ok(FCall::RMkNod {
+            qid: inode.qid(ino),
+        })
+    }
+
+    async fn rrename(
+        &self,
+        fid: &FId<Self::FId>,
+        dfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (newdir, _) = dfid.aux.id()?;
+        let mut handle = fid.aux.handle();
+        let handle = handle.as_mut().ok_or(error::Error::No(EBADF))?;
+        let mut tree = self.tree();
+        let (olddir, oldname) = tree.locate(handle.ino, &handle.entry)?;
+        tree.rename(handle.uid, olddir, &oldname, newdir, name)?;
+
+        handle.entry = (newdir, name.to_owned());
+        Ok(FCall::RRename)
+    }
+
+    async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (ino, _) = fid.aux.id()?;
+        match &self.tree().get(ino)?.kind {
+            Kind::Symlink(target) => Ok(FCall::RReadLink {
+                target: target.clone(),
+            }),
+            _ => Err(error::Error::No(EINVAL)),
+        }
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        let (ino, _) = fid.aux.id()?;
+        let tree = self.tree();
+        let inode = tree.get(ino)?;
+        Ok(inode.stat.rgetattr(inode.qid(ino), req_mask))
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &FId<Self::FId>,
+        valid: SetAttrMask,
+        stat: &SetAttr,
+    ) -> Result<FCall> {
+        let (ino, uid) = fid.aux.id()?;
+        let mut tree = self.tree();
+        let inode = tree.get(ino)?;
+
+        // Check everything first, so a refused request changes nothing
+        if valid.contains(SetAttrMask::MODE) {
+            inode.is_owner(uid)?;
+        }
+        if valid.contains(SetAttrMask::UID) && stat.uid != inode.stat.uid && uid != 0 {
+            return Err(error::Error::No(EPERM));
+        }
+        if valid.contains(SetAttrMask::GID) {
+            inode.is_owner(uid)?;
+        }
+        if valid.contains(SetAttrMask::SIZE) {
+            inode.access(uid, WRITE)?;
+        }
+        for (time, set) in [
+            (SetAttrMask::ATIME, SetAttrMask::ATIME_SET),
+            (SetAttrMask::MTIME, SetAttrMask::MTIME_SET),
+        ] {
+            if valid.contains(time) {
+                // Anyone who can write may set the times to now, like utimes(NULL)
+                if valid.contains(set) {
+                    inode.is_owner(uid)?;
+                } else if inode.is_owner(uid).is_err() {
+                    inode.access(uid, WRITE)?;
+                }
+            }
+        }
+
+        if valid.contains(SetAttrMask::SIZE) {
+            tree.resize(ino, stat.size)?;
+        }
+        let inode = tree.get_mut(ino)?;
+        let now = Time::now();
+        if valid.contains(SetAttrMask::MODE) {
+            inode.stat.mode = (inode.stat.mode & FileMode::IFMT.bits()) | (stat.mode & 0o7777);
+        }
+        if valid.contains(SetAttrMask::UID) {
+            inode.stat.uid = stat.uid;
+        }
+        if valid.contains(SetAttrMask::GID) {
+            inode.stat.gid = stat.gid;
+        }
+        if valid.contains(SetAttrMask::ATIME) {
+            let set = valid.contains(SetAttrMask::ATIME_SET);
+            inode.stat.atime = if set { stat.atime } else { now };
+        }
+        if valid.contains(SetAttrMask::MTIME) {
+            let set = valid.contains(SetAttrMask::MTIME_SET);
+            inode.stat.mtime = if set { stat.mtime } else { now };
+        }
+        if !valid.is_empty() {
+            inode.changed();
+        }
+        Ok(FCall::RSetAttr)
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (value, handle) = {
+            let handle = fid.aux.handle();
+            let handle = handle.as_ref().ok_or(error::Error::No(EBADF))?;
+            let tree = self.tree();
+            let inode = tree.get(handle.ino)?;
+            let value = if name.is_empty() {
+                encode_names(inode.xattrs.keys())
+            } else {
+                inode
+                    .xattrs
+                    .get(name)
+                    .cloned()
+                    .ok_or(error::Error::No(ENODATA))?
+            };
+            let new = Handle::new(&tree, handle.ino, handle.uid, handle.entry.clone())?;
+            (value, new)
+        };
+
+        *newfid.aux.handle() = Some(handle);
+        newfid.aux.xattr.walk(value).await
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<FCall> {
+        let (ino, uid) = fid.aux.id()?;
+        self.tree().get(ino)?.access(uid, WRITE)?;
+        fid.aux.xattr.create(name, attr_size, flags).await
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let mut handle = fid.aux.handle();
+        let handle = handle.as_mut().ok_or(error::Error::No(EBADF))?;
+        let open = handle.open.as_mut().ok_or(error::Error::No(EBADF))?;
+
+        if offset == 0 {
+            let tree = self.tree();
+            let dir = tree.get(handle.ino)?;
+            let (parent, _) = tree.dir_entry(handle.ino)?;
+            let entry = |name: &str, ino: u64| -> Result<DirEntry> {
+                let inode = tree.get(ino)?;
+                Ok(DirEntry {
+                    qid: inode.qid(ino),
+                    offset: 0,
+                    typ: DirEntryType::from_mode(inode.stat.mode).into(),
+                    name: name.to_owned(),
+                })
+            };
+
+            let mut dirents = vec![entry(".", handle.ino)?, entry("..", parent)?];
+            for (name, &ino) in dir.entries()? {
+                dirents.push(entry(name, ino)?);
+            }
+            for (i, dirent) in dirents.iter_mut().enumerate() {
+                dirent.offset = i as u64 + 1;
+            }
+            open.dirents = dirents;
+        }
+
+        // Offsets are indices in the listing
+        let mut data = DirEntryData::new();
+        for dirent in open.dirents.iter().skip(offset as usize) {
+            if data.size() + dirent.size() > count {
+                break;
+            }
+            data.push(dirent.clone());
+        }
+        Ok(FCall::RReadDir { data })
+    }
+
+    async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        fid.aux.id()?;
+        Ok(FCall::RFSync)
+    }
+
+    async fn rlock(&self, fid: &FId<Self::FId>, lock: &Flock) -> Result<FCall> {
+        let (ino, _) = fid.aux.id()?;
+        Ok(FCall::RLock {
+            status: self.locks.lock(&fid.aux.locks, ino, lock),
+        })
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock: &Getlock) -> Result<FCall> {
+        let (ino, _) = fid.aux.id()?;
+        Ok(FCall::RGetLock {
+            flock: self.locks.getlock(ino, lock),
+        })
+    }
+
+    async fn rlink(
+        &self,
+        dfid: &FId<Self::FId>,
+        fid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (dir, uid) = dfid.aux.id()?;
+        let (ino, _) = fid.aux.id()?;
+        let mut tree = self.tree();
+        check_name(name)?;
+        let target = tree.get(ino)?;
+        if target.is_dir() {
+            return Err(error::Error::No(EPERM));
+        }
+        if target.stat.nlink == 0 {
+            return Err(error::Error::No(ENOENT));
+        }
+        let parent = tree.get(dir)?;
+        parent.access(uid, WRITE | EXEC)?;
+        if parent.entries()?.contains_key(name) {
+            return Err(error::Error::No(EEXIST));
+        }
+
+        let parent = tree.get_mut(dir)?;
+        parent.entries_mut()?.insert(name.to_owned(), ino);
+        parent.modified();
+        let target = tree.get_mut(ino)?;
+        target.stat.nlink += 1;
+        target.changed();
+        Ok(FCall::RLink)
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (dir, uid) = dfid.aux.id()?;
+        let mut tree = self.tree();
+        let kind = Kind::Dir {
+            entries: BTreeMap::new(),
+            parent: dir,
+        };
+        let mode = FileMode::IFDIR.bits() | (mode & 0o7777);
+        let ino = tree.create(dir, uid, name, mode, gid, kind)?;
+        Ok(FCall::RMkDir {
+            qid: tree.get(ino)?.qid(ino),
+        })
+    }
+
+    async fn rrenameat(
+        &self,
+        olddirfid: &FId<Self::FId>,
+        oldname: &str,
+        newdirfid: &FId<Self::FId>,
+        newname: &str,
+    ) -> Result<FCall> {
+        let (olddir, uid) = olddirfid.aux.id()?;
+        let (newdir, _) = newdirfid.aux.id()?;
+        self.tree().rename(uid, olddir, oldname, newdir, newname)?;
+        Ok(FCall::RRenameAt)
+    }
+
+    async fn runlinkat(&self, dirfd: &FId<Self::FId>, name: &str, flags: u32) -> Result<FCall> {
+        let (dir, uid) = dirfd.aux.id()?;
+        check_name(name)?;
+        let flags = UnlinkFlags::from_bits_truncate(flags);
+        self.tree().unlink(dir, name, uid, flags)?;
+        Ok(FCall::RUnlinkAt)
+    }
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        _afid: Option<&FId<Self::FId>>,
+        _uname: &str,
+        _aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let uid = if n_uname == NONUNAME { NOBODY } else { n_uname };
+        let tree = self.tree();
+        let handle = Handle::new(&tree, ROOT, uid, (ROOT, String::new()))?;
+        let qid = tree.get(ROOT)?.qid(ROOT);
+        drop(tree);
+
+        *fid.aux.handle() = Some(handle);
+        Ok(FCall::RAttach { qid })
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        let (ino, uid, entry) = match &*fid.aux.handle() {
+            Some(handle) => (handle.ino, handle.uid, handle.entry.clone()),
+            None => return Err(error::Error::No(EBADF)),
+        };
+
+        let tree = self.tree();
+        let mut current = (ino, entry);
+        let mut wqids = Vec::new();
+        for name in wnames {
+            let step = || -> Result<(u64, (u64, String))> {
+                let dir = tree.get(current.0)?;
+                dir.entries()?;
+                dir.access(uid, EXEC)?;
+                match name.as_str() {
+                    "." => Ok(current.clone()),
+                    ".." => {
+                        let (parent, _) = tree.dir_entry(current.0)?;
+                        Ok((parent, tree.dir_entry(parent)?))
+                    }
+                    name => Ok((tree.lookup(current.0, name)?, (current.0, name.to_owned()))),
+                }
+            };
+            match step() {
+                Ok(next) => {
+                    wqids.push(tree.get(next.0)?.qid(next.0));
+                    current = next;
+                }
+                Err(e) if wqids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        // newfid is only affected by a complete walk
+        if wqids.len() == wnames.len() {
+            let handle = Handle::new(&tree, current.0, uid, current.1)?;
+            drop(tree);
+            *newfid.aux.handle() = Some(handle);
+        }
+        Ok(FCall::RWalk { wqids })
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        if let Some(reply) = fid.aux.xattr.read(offset, count).await? {
+            return Ok(reply);
+        }
+
+        let ino = match &*fid.aux.handle() {
+            Some(Handle {
+                ino,
+                open: Some(Open { read: true, .. }),
+                ..
+            }) => *ino,
+            _ => return Err(error::Error::No(EBADF)),
+        };
+        let mut tree = self.tree();
+        let inode = tree.get_mut(ino)?;
+        let data = match &inode.kind {
+            Kind::File(data) => {
+                let start = offset.min(data.len() as u64) as usize;
+                let end = start.saturating_add(count as usize).min(data.len());
+                data[start..end].to_vec()
+            }
+            Kind::Dir { .. } => return Err(error::Error::No(EISDIR)),
+            _ => return Err(error::Error::No(EINVAL)),
+        };
+        inode.stat.atime = Time::now();
+        Ok(FCall::RRead { data: Data(data) })
+    }
+
+    async fn rwrite(&self, fid: &FId<Self::FId>, offset: u64, data: &Data) -> Result<FCall> {
+        if let Some(reply) = fid.aux.xattr.write(offset, data).await? {
+            return Ok(reply);
+        }
+
+        let (ino, uid, append) = match &*fid.aux.handle() {
+            Some(Handle {
+                ino,
+                uid,
+                open:
+                    Some(Open {
+                        write: true,
+                        append,
+                        ..
+                    }),
+                ..
+            }) => (*ino, *uid, *append),
+            _ => return Err(error::Error::No(EBADF)),
+        };
+        let mut tree = self.tree();
+        let offset = if append {
+            tree.get(ino)?.usage()
+        } else {
+            offset
+        };
+        let count = tree.write(ino, offset, &data.0, uid)?;
+        Ok(FCall::RWrite { count })
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let pending = fid.aux.xattr.commit().await;
+        fid.aux.locks.release();
+        let handle = fid.aux.handle().take();
+
+        let mut tree = self.tree();
+        let res = match (pending, &handle) {
+            (Ok(Some(pending)), Some(handle)) => tree.set_xattr(handle.ino, pending),
+            (Err(e), _) => Err(e),
+            _ => Ok(()),
+        };
+        drop(handle);
+        tree.sweep();
+        res.map(|_| FCall::RClunk)
+    }
+
+    async fn rremove(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        fid.aux.locks.release();
+        let handle = fid.aux.handle().take();
+        let handle = handle.ok_or(error::Error::No(EBADF))?;
+
+        let mut tree = self.tree();
+        let res = tree
+            .locate(handle.ino, &handle.entry)
+            .and_then(|(dir, name)| {
+                let flags = if tree.get(handle.ino)?.is_dir() {
+                    UnlinkFlags::REMOVEDIR
+                } else {
+                    UnlinkFlags::empty()
+                };
+                tree.unlink(dir, &name, handle.uid, flags)
+            });
+        drop(handle);
+        tree.sweep();
+        res.map(|_| FCall::RRemove)
+    }
+}
+
+#[cfg(test)]
+async fn attach(fs: &MemFs, uid: u32) -> FId<MemFId> {
+    let fid = FId::new(0, MemFId::default());
+    fs.rattach(&fid, None, "", "", uid).await.unwrap();
+    fid
+}
+
+#[cfg(test)]
+async fn walk(fs: &MemFs, from: &FId<MemFId>, path: &[&str]) -> Result<FId<MemFId>> {
+    let fid = FId::new(1, MemFId::default());
+    let wnames: Vec<_> = path.iter().map(|name| name.to_string()).collect();
+    match fs.rwalk(from, &fid, &wnames).await? {
+        FCall::RWalk { wqids } if wqids.len() == wnames.len() => Ok(fid),
+        _ => Err(error::Error::No(ENOENT)),
+    }
+}
+
+#[cfg(test)]
+async fn create(fs: &MemFs, dir: &FId<MemFId>, name: &str, data: &[u8]) -> Result<FId<MemFId>> {
+    let fid = walk(fs, dir, &[]).await?;
+    fs.rlcreate(&fid, name, P9OpenFlags::RDWR.bits(), 0o644, 0)
+        .await?;
+    fs.rwrite(&fid, 0, &Data(data.to_vec())).await?;
+    Ok(fid)
+}
+
+#[cfg(test)]
+async fn read(fs: &MemFs, fid: &FId<MemFId>) -> Vec<u8> {
+    match fs.rread(fid, 0, 1 << 20).await.unwrap() {
+        FCall::RRead { data } => data.0,
+        reply => panic!("{}", reply),
+    }
+}
+
+#[cfg(test)]
+fn errno<T: std::fmt::Debug>(res: Result<T>) -> nix::errno::Errno {
+    res.unwrap_err().errno()
+}
+
+#[tokio::test]
+async fn memfs_files() {
+    let fs = MemFs::new();
+    let root = attach(&fs, 0).await;
+
+    let file = create(&fs, &root, "file", b"hello").await.unwrap();
+    assert_eq!(read(&fs, &file).await, b"hello");
+    let reply = fs.rgetattr(&file, GetAttrMask::ALL).await.unwrap();
+    assert!(matches!(reply, FCall::RGetAttr { stat, .. } if stat.size == 5 && stat.nlink == 1));
+    assert_eq!(errno(create(&fs, &root, "file", b"").await), EEXIST);
+
+    // Hard links share the contents, which outlive the last name while a fid is open
+    fs.rlink(&root, &file, "link").await.unwrap();
+    fs.runlinkat(&root, "file", 0).await.unwrap();
+    let link = walk(&fs, &root, &["link"]).await.unwrap();
+    fs.rlopen(&link, P9OpenFlags::RDONLY.bits()).await.unwrap();
+    assert_eq!(read(&fs, &link).await, b"hello");
+    fs.runlinkat(&root, "link", 0).await.unwrap();
+    assert_eq!(read(&fs, &link).await, b"hello");
+    fs.rclunk(&link).await.unwrap();
+    fs.rclunk(&file).await.unwrap();
+    assert!(fs.tree().inodes.len() == 1);
+
+    fs.rmkdir(&root, "dir", 0o755, 0).await.unwrap();
+    fs.rsymlink(&root, "sym", "dir", 0).await.unwrap();
+    let dir = walk(&fs, &root, &["dir"]).await.unwrap();
+    create(&fs, &dir, "a", b"a").await.unwrap();
+    let sym = walk(&fs, &root, &["sym"]).await.unwrap();
+    assert_eq!(
+        fs.rreadlink(&sym).await.unwrap(),
+        FCall::RReadLink {
+            target: "dir".to_owned()
+        }
+    );
+
+    // rename(2) semantics
+    let removedir = UnlinkFlags::REMOVEDIR.bits();
+    assert_eq!(
+        errno(fs.runlinkat(&root, "dir", removedir).await),
+        ENOTEMPTY
+    );
+    assert_eq!(errno(fs.rrenameat(&root, "dir", &dir, "sub").await), EINVAL);
+    assert_eq!(errno(fs.rrenameat(&dir, "a", &root, "dir").await), EISDIR);
+    fs.rrenameat(&dir, "a", &root, "sym").await.unwrap();
+    let moved = walk(&fs, &root, &["sym"]).await.unwrap();
+    fs.rlopen(&moved, P9OpenFlags::RDONLY.bits()).await.unwrap();
+    assert_eq!(read(&fs, &moved).await, b"a");
+    fs.rrename(&dir, &root, "renamed").await.unwrap();
+    let up = walk(&fs, &root, &["renamed", ".."]).await.unwrap();
+    assert!(matches!(
+        fs.rgetattr(&up, GetAttrMask::BASIC).await.unwrap(),
+        FCall::RGetAttr { qid, .. } if qid.path == ROOT
+    ));
+
+    fs.rlopen(&root, P9OpenFlags::RDONLY.bits()).await.unwrap();
+    let names: Vec<_> = match fs.rreaddir(&root, 0, 8192).await.unwrap() {
+        FCall::RReadDir { data } => data.data().iter().map(|e| e.name.clone()).collect(),
+        reply => panic!("{}", reply),
+    };
+    assert_eq!(names, [".", "..", "renamed", "sym"]);
+}
+
+#[tokio::test]
+async fn memfs_permissions() {
+    let fs = MemFs::new();
+    let root = attach(&fs, 0).await;
+    fs.rmkdir(&root, "private", 0o700, 0).await.unwrap();
+    fs.rmkdir(&root, "tmp", 0o1777, 0).await.unwrap();
+
+    let user = attach(&fs, 1000).await;
+    assert_eq!(errno(create(&fs, &user, "file", b"").await), EACCES);
+    let private = walk(&fs, &user, &["private"]).await.unwrap();
+    assert_eq!(errno(walk(&fs, &private, &["x"]).await), EACCES);
+
+    // Only owners remove entries of a sticky directory
+    let tmp = walk(&fs, &user, &["tmp"]).await.unwrap();
+    create(&fs, &tmp, "mine", b"").await.unwrap();
+    let other = attach(&fs, 1001).await;
+    let other_tmp = walk(&fs, &other, &["tmp"]).await.unwrap();
+    assert_eq!(errno(fs.runlinkat(&other_tmp, "mine", 0).await), EPERM);
+    fs.runlinkat(&tmp, "mine", 0).await.unwrap();
+
+    // The mode is the owner's to change
+    let file = create(&fs, &tmp, "file", b"").await.unwrap();
+    let chmod = SetAttr {
+        mode: 0o600,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        atime: Time::default(),
+        mtime: Time::default(),
+    };
+    let theirs = walk(&fs, &other_tmp, &["file"]).await.unwrap();
+    let res = fs.rsetattr(&theirs, SetAttrMask::MODE, &chmod).await;
+    assert_eq!(errno(res), EPERM);
+    fs.rsetattr(&file, SetAttrMask::MODE, &chmod).await.unwrap();
+    let res = fs.rlopen(&theirs, P9OpenFlags::RDONLY.bits()).await;
+    assert_eq!(errno(res), EACCES);
+}
+
+#[tokio::test]
+async fn memfs_capacity_xattr() {
+    let fs = MemFs::new().capacity(2 * BLOCK_SIZE).max_files(3);
+    let root = attach(&fs, 0).await;
+
+    let file = create(&fs, &root, "file", &[1; BLOCK_SIZE as usize])
+        .await
+        .unwrap();
+    let res = fs.rwrite(&file, BLOCK_SIZE, &Data(vec![2; 8192])).await;
+    assert_eq!(errno(res), ENOSPC);
+    match fs.rstatfs(&root).await.unwrap() {
+        FCall::RStatFs { statfs } => {
+            assert_eq!((statfs.blocks, statfs.bfree), (2, 1));
+            assert_eq!((statfs.files, statfs.ffree), (3, 1));
+        }
+        reply => panic!("{}", reply),
+    }
+    create(&fs, &root, "other", b"").await.unwrap();
+    assert_eq!(errno(create(&fs, &root, "full", b"").await), ENOSPC);
+
+    // Set, list, read and remove an attribute
+    let xattr = walk(&fs, &root, &["file"]).await.unwrap();
+    fs.rxattrcreate(&xattr, "user.a", 3, XattrFlags::CREATE.bits())
+        .await
+        .unwrap();
+    fs.rwrite(&xattr, 0, &Data(b"abc".to_vec())).await.unwrap();
+    fs.rclunk(&xattr).await.unwrap();
+
+    let names = FId::new(2, MemFId::default());
+    fs.rxattrwalk(&file, &names, "").await.unwrap();
+    assert_eq!(read(&fs, &names).await, b"user.a\0");
+    let value = FId::new(3, MemFId::default());
+    fs.rxattrwalk(&file, &value, "user.a").await.unwrap();
+    assert_eq!(read(&fs, &value).await, b"abc");
+    assert_eq!(errno(fs.rxattrwalk(&file, &value, "user.b").await), ENODATA);
+
+    let xattr = walk(&fs, &root, &["file"]).await.unwrap();
+    fs.rxattrcreate(&xattr, "user.a", 0, 0).await.unwrap();
+    fs.rclunk(&xattr).await.unwrap();
+    assert!(fs.tree().get(3).unwrap().xattrs.is_empty());
+}
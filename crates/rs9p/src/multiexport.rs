@@ -0,0 +1,600 @@
+//! Several filesystems served under different attach names.
+//!
+//! Clients pick the tree they attach to with the `aname` of `TAttach`, e.g.
+//! `mount -t 9p -o aname=home`. [`MultiExport`] maps each name to a [`Filesystem`],
+//! remembers in every fid which one it belongs to and forwards the requests there:
+//!
+//! - An `aname` no export is registered under is served by the export named `""`
+//!   if there is one, and fails with `ENOENT` otherwise.
+//! - Requests involving two fids of different exports (`TRename`, `TLink`,
+//!   `TRenameAt`) fail with `EXDEV`, like across mount points.
+//! - Exports can be read-only, which refuses modifications like
+//!   [`ReadOnly`](crate::readonly::ReadOnly) does.
+//! - The exports can be replaced while serving with [`MultiExport::reload`], fids keep
+//!   using the export they were attached to.
+//! - `TVersion` and `TFlush` are not tied to an export, they get the default answers
+//!   of [`Filesystem`].
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{multiexport::{Export, MultiExport}, srv::{Filesystem, srv_async}, Result};
+//! # async fn serve<Fs: 'static + Filesystem + Send + Sync + Clone>(home: Fs, data: Fs) -> Result<()> {
+//! let exports = MultiExport::new();
+//! exports.insert("home", Export::new(home));
+//! exports.insert("data", Export::new(data).read_only(true));
+//! srv_async(exports, "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        error::{self, errno::*},
+        fcall::*,
+        readonly::opens_for_write,
+        srv::{FId, Filesystem},
+        utils::Result,
+    },
+    async_trait::async_trait,
+    std::{
+        collections::HashMap,
+        sync::{Arc, OnceLock, RwLock},
+    },
+};
+
+/// A filesystem served by [`MultiExport`], and its options
+#[derive(Clone, Debug)]
+pub struct Export<Fs> {
+    pub fs: Fs,
+    pub read_only: bool,
+}
+
+impl<Fs> Export<Fs> {
+    /// Export `fs` for reading and writing
+    pub fn new(fs: Fs) -> Export<Fs> {
+        Export {
+            fs,
+            read_only: false,
+        }
+    }
+
+    /// Set whether modifications are refused with `EROFS`
+    pub fn read_only(mut self, read_only: bool) -> Export<Fs> {
+        self.read_only = read_only;
+        self
+    }
+}
+
+type Table<Fs> = HashMap<String, Arc<Export<Fs>>>;
+
+/// A [`Filesystem`] forwarding each fid to the export it was attached to
+///
+/// Cloning a `MultiExport` gives another handle to the same table of exports, so the
+/// server's per-connection clones see [`insert`](Self::insert) and
+/// [`reload`](Self::reload).
+pub struct MultiExport<Fs> {
+    exports: Arc<RwLock<Arc<Table<Fs>>>>,
+}
+
+impl<Fs> Clone for MultiExport<Fs> {
+    fn clone(&self) -> Self {
+        MultiExport {
+            exports: self.exports.clone(),
+        }
+    }
+}
+
+impl<Fs> Default for MultiExport<Fs> {
+    fn default() -> Self {
+        MultiExport {
+            exports: Default::default(),
+        }
+    }
+}
+
+impl<Fs> MultiExport<Fs> {
+    /// Create a server without any export
+    pub fn new() -> MultiExport<Fs> {
+        Default::default()
+    }
+
+    fn table(&self) -> Arc<Table<Fs>> {
+        // The table is only ever replaced whole, a panic elsewhere doesn't matter
+        self.exports
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Serve `export` to the clients attaching with `aname` set to `name`
+    ///
+    /// Returns the export previously registered under `name`, if any.
+    pub fn insert(&self, name: impl Into<String>, export: Export<Fs>) -> Option<Arc<Export<Fs>>> {
+        let mut exports = self.exports.write().unwrap_or_else(|e| e.into_inner());
+        Arc::make_mut(&mut exports).insert(name.into(), Arc::new(export))
+    }
+
+    /// Replace all the exports with those of `other`
+    ///
+    /// Fids attached before keep using the export they were attached to, until they
+    /// are clunked. Only new attaches see the new exports.
+    pub fn reload(&self, other: MultiExport<Fs>) {
+        let table = other.table();
+        *self.exports.write().unwrap_or_else(|e| e.into_inner()) = table;
+    }
+
+    /// The export serving `aname`
+    pub fn get(&self, aname: &str) -> Option<Arc<Export<Fs>>> {
+        self.lookup(aname).ok().map(|(_, export)| export)
+    }
+
+    /// Names of the exports
+    pub fn names(&self) -> Vec<String> {
+        self.table().keys().cloned().collect()
+    }
+
+    // The name and export serving `aname`
+    fn lookup(&self, aname: &str) -> Result<(String, Arc<Export<Fs>>)> {
+        let table = self.table();
+        let name = if table.contains_key(aname) { aname } else { "" };
+        let export = table.get(name).ok_or(error::Error::No(ENOENT))?;
+        Ok((name.to_owned(), export.clone()))
+    }
+}
+
+struct Routed<Fs: Filesystem> {
+    name: String,
+    export: Arc<Export<Fs>>,
+    fid: FId<Fs::FId>,
+}
+
+/// Fid of a [`MultiExport`]: the export it belongs to and the fid of that export
+pub struct MultiFId<Fs: Filesystem> {
+    routed: OnceLock<Routed<Fs>>,
+}
+
+impl<Fs: Filesystem> Default for MultiFId<Fs> {
+    fn default() -> Self {
+        MultiFId {
+            routed: OnceLock::new(),
+        }
+    }
+}
+
+impl<Fs: Filesystem> MultiFId<Fs> {
+    /// Name of the export the fid belongs to, once attached or walked to
+    pub fn export(&self) -> Option<&str> {
+        self.routed.get().map(|routed| routed.name.as_str())
+    }
+
+    /// The fid of the export
+    pub fn inner(&self) -> Option<&FId<Fs::FId>> {
+        self.routed.get().map(|routed| &routed.fid)
+    }
+}
+
+type MFId<Fs> = FId<MultiFId<Fs>>;
+type Pair<'a, Fs> = (
+    &'a Fs,
+    &'a FId<<Fs as Filesystem>::FId>,
+    &'a FId<<Fs as Filesystem>::FId>,
+);
+
+impl<Fs: Filesystem> MultiExport<Fs> {
+    // The export of `fid`, and the fid to forward
+    fn route(fid: &MFId<Fs>) -> Result<(&Export<Fs>, &FId<Fs::FId>)> {
+        let routed = fid.aux.routed.get().ok_or(error::Error::No(EBADF))?;
+        Ok((&routed.export, &routed.fid))
+    }
+
+    // Like `route`, refusing read-only exports
+    fn route_writable(fid: &MFId<Fs>) -> Result<(&Fs, &FId<Fs::FId>)> {
+        match Self::route(fid)? {
+            (export, _) if export.read_only => Err(error::Error::No(EROFS)),
+            (export, fid) => Ok((&export.fs, fid)),
+        }
+    }
+
+    // Route two fids, which must belong to the same export
+    fn route_pair<'a>(a: &'a MFId<Fs>, b: &'a MFId<Fs>) -> Result<Pair<'a, Fs>> {
+        let (export, a) = Self::route(a)?;
+        let (other, b) = Self::route(b)?;
+        if !std::ptr::eq(export, other) {
+            return Err(error::Error::No(EXDEV));
+        }
+        if export.read_only {
+            return Err(error::Error::No(EROFS));
+        }
+        Ok((&export.fs, a, b))
+    }
+
+    // Give `newfid` the export `export` and a fresh fid of it
+    fn bind(newfid: &MFId<Fs>, name: String, export: Arc<Export<Fs>>) -> &FId<Fs::FId> {
+        let routed = newfid.aux.routed.get_or_init(|| Routed {
+            name,
+            export,
+            fid: FId::new(newfid.fid(), Default::default()),
+        });
+        &routed.fid
+    }
+
+    // Give `newfid` the export of `fid`
+    fn bind_like<'a>(newfid: &'a MFId<Fs>, fid: &MFId<Fs>) -> Result<&'a FId<Fs::FId>> {
+        let routed = fid.aux.routed.get().ok_or(error::Error::No(EBADF))?;
+        Ok(Self::bind(
+            newfid,
+            routed.name.clone(),
+            routed.export.clone(),
+        ))
+    }
+}
+
+#[async_trait]
+impl<Fs> Filesystem for MultiExport<Fs>
+where
+    Fs: Filesystem + Sync,
+{
+    type FId = MultiFId<Fs>;
+
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rstatfs(fid).await
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        if export.read_only && opens_for_write(flags) {
+            return Err(error::Error::No(EROFS));
+        }
+        export.fs.rlopen(fid, flags).await
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rlcreate(fid, name, flags, mode, gid).await
+    }
+
+    async fn rsymlink(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        sym: &str,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rsymlink(fid, name, sym, gid).await
+    }
+
+    async fn rmknod(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rmknod(fid, name, mode, major, minor, gid).await
+    }
+
+    async fn rrename(
+        &self,
+        fid: &FId<Self::FId>,
+        dfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (fs, fid, dfid) = Self::route_pair(fid, dfid)?;
+        fs.rrename(fid, dfid, name).await
+    }
+
+    async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rreadlink(fid).await
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rgetattr(fid, req_mask).await
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &FId<Self::FId>,
+        valid: SetAttrMask,
+        stat: &SetAttr,
+    ) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rsetattr(fid, valid, stat).await
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (export, inner) = Self::route(fid)?;
+        export
+            .fs
+            .rxattrwalk(inner, Self::bind_like(newfid, fid)?, name)
+            .await
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rxattrcreate(fid, name, attr_size, flags).await
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rreaddir(fid, offset, count).await
+    }
+
+    async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rfsync(fid).await
+    }
+
+    async fn rlock(&self, fid: &FId<Self::FId>, lock: &Flock) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rlock(fid, lock).await
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock: &Getlock) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rgetlock(fid, lock).await
+    }
+
+    async fn rlink(
+        &self,
+        dfid: &FId<Self::FId>,
+        fid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (fs, dfid, fid) = Self::route_pair(dfid, fid)?;
+        fs.rlink(dfid, fid, name).await
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let (fs, dfid) = Self::route_writable(dfid)?;
+        fs.rmkdir(dfid, name, mode, gid).await
+    }
+
+    async fn rrenameat(
+        &self,
+        olddir: &FId<Self::FId>,
+        oldname: &str,
+        newdir: &FId<Self::FId>,
+        newname: &str,
+    ) -> Result<FCall> {
+        let (fs, olddir, newdir) = Self::route_pair(olddir, newdir)?;
+        fs.rrenameat(olddir, oldname, newdir, newname).await
+    }
+
+    async fn runlinkat(&self, dirfid: &FId<Self::FId>, name: &str, flags: u32) -> Result<FCall> {
+        let (fs, dirfid) = Self::route_writable(dirfid)?;
+        fs.runlinkat(dirfid, name, flags).await
+    }
+
+    async fn rauth(
+        &self,
+        afid: &FId<Self::FId>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let (name, export) = self.lookup(aname)?;
+        export
+            .fs
+            .rauth(
+                Self::bind(afid, name, export.clone()),
+                uname,
+                aname,
+                n_uname,
+            )
+            .await
+    }
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        afid: Option<&FId<Self::FId>>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let (name, export) = self.lookup(aname)?;
+        let afid = match afid {
+            Some(afid) => match Self::route(afid)? {
+                (afid_export, afid) if std::ptr::eq(afid_export, &*export) => Some(afid),
+                _ => return Err(error::Error::No(EINVAL)),
+            },
+            None => None,
+        };
+        export
+            .fs
+            .rattach(
+                Self::bind(fid, name, export.clone()),
+                afid,
+                uname,
+                aname,
+                n_uname,
+            )
+            .await
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        let (export, inner) = Self::route(fid)?;
+        export
+            .fs
+            .rwalk(inner, Self::bind_like(newfid, fid)?, wnames)
+            .await
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rread(fid, offset, count).await
+    }
+
+    async fn rwrite(&self, fid: &FId<Self::FId>, offset: u64, data: &Data) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rwrite(fid, offset, data).await
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (export, fid) = Self::route(fid)?;
+        export.fs.rclunk(fid).await
+    }
+
+    async fn rremove(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let (fs, fid) = Self::route_writable(fid)?;
+        fs.rremove(fid).await
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct Tagged(u64);
+
+#[cfg(test)]
+#[async_trait]
+impl Filesystem for Tagged {
+    type FId = ();
+
+    async fn rattach(
+        &self,
+        _: &FId<()>,
+        _afid: Option<&FId<()>>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> Result<FCall> {
+        Ok(FCall::RAttach {
+            qid: QId::dir(self.0),
+        })
+    }
+
+    async fn rwalk(&self, _: &FId<()>, _: &FId<()>, _wnames: &[String]) -> Result<FCall> {
+        Ok(FCall::RWalk {
+            wqids: vec![QId::dir(self.0)],
+        })
+    }
+
+    async fn rwrite(&self, _: &FId<()>, _offset: u64, data: &Data) -> Result<FCall> {
+        Ok(FCall::RWrite {
+            count: data.0.len() as u32,
+        })
+    }
+
+    async fn rlink(&self, _: &FId<()>, _: &FId<()>, _name: &str) -> Result<FCall> {
+        Ok(FCall::RLink)
+    }
+}
+
+#[tokio::test]
+async fn multiexport_routing() {
+    let exports = MultiExport::new();
+    exports.insert("a", Export::new(Tagged(1)));
+    exports.insert("b", Export::new(Tagged(2)).read_only(true));
+
+    let fid = |fid| FId::new(fid, MultiFId::default());
+    let attach = |fid, aname| exports.rattach(fid, None, "user", aname, NONUNAME);
+    let (a, b, c) = (fid(0), fid(1), fid(2));
+    assert_eq!(
+        attach(&a, "a").await.unwrap(),
+        FCall::RAttach { qid: QId::dir(1) }
+    );
+    assert_eq!(
+        attach(&b, "b").await.unwrap(),
+        FCall::RAttach { qid: QId::dir(2) }
+    );
+    assert_eq!(attach(&c, "c").await.unwrap_err().errno(), ENOENT);
+
+    let data = Data(b"x".to_vec());
+    assert!(exports.rwrite(&a, 0, &data).await.is_ok());
+    assert_eq!(
+        exports.rwrite(&b, 0, &data).await.unwrap_err().errno(),
+        EROFS
+    );
+
+    // Walked fids stay in the export of their origin
+    let a2 = fid(3);
+    exports.rwalk(&a, &a2, &[]).await.unwrap();
+    assert_eq!(a2.aux.export(), Some("a"));
+    assert!(exports.rlink(&a, &a2, "link").await.is_ok());
+    assert_eq!(
+        exports.rlink(&a, &b, "link").await.unwrap_err().errno(),
+        EXDEV
+    );
+
+    // The export named "" serves unknown anames
+    exports.insert("", Export::new(Tagged(3)));
+    assert_eq!(
+        exports
+            .rattach(&c, None, "user", "c", NONUNAME)
+            .await
+            .unwrap(),
+        FCall::RAttach { qid: QId::dir(3) }
+    );
+    assert_eq!(c.aux.export(), Some(""));
+}
+
+#[tokio::test]
+async fn multiexport_reload() {
+    let exports = MultiExport::new();
+    exports.insert("a", Export::new(Tagged(1)));
+    let fid = |fid| FId::new(fid, MultiFId::default());
+    let (a, b) = (fid(0), fid(1));
+    exports
+        .rattach(&a, None, "user", "a", NONUNAME)
+        .await
+        .unwrap();
+
+    let new = MultiExport::new();
+    new.insert("a", Export::new(Tagged(2)));
+    exports.reload(new);
+
+    // Existing fids keep their export, new attaches get the new one
+    let walk = |fid| exports.rwalk(fid, fid, &[]);
+    assert_eq!(
+        walk(&a).await.unwrap(),
+        FCall::RWalk {
+            wqids: vec![QId::dir(1)]
+        }
+    );
+    assert_eq!(
+        exports
+            .rattach(&b, None, "user", "a", NONUNAME)
+            .await
+            .unwrap(),
+        FCall::RAttach { qid: QId::dir(2) }
+    );
+    assert_eq!(
+        exports.rlink(&a, &b, "link").await.unwrap_err().errno(),
+        EXDEV
+    );
+}
@@ -0,0 +1,1547 @@
+//! Union of a read-only lower filesystem and a writable upper one.
+//!
+//! [`Overlay`] presents the files of two [`Filesystem`]s as one tree, like Linux's
+//! overlayfs:
+//!
+//! - Names are looked up in the upper layer first. A directory present in both layers
+//!   is merged: its listing holds the entries of both, the upper one winning when a
+//!   name is in both.
+//! - The lower layer is never modified. Opening a lower file for writing, changing
+//!   its attributes or extended attributes copies it up first: its parent directories
+//!   are created in the upper layer, then the file with its contents and attributes.
+//!   Fids walked to the file before, by this client or others, see the copy from then
+//!   on, even those already open for reading.
+//! - Removing or renaming away a lower file creates a whiteout, an empty file named
+//!   `.wh.<name>` in the upper directory, which hides it. A directory created where a
+//!   lower one was removed gets a `.wh..wh..opq` file marking it opaque, so the
+//!   contents of the lower one don't show through. Clients never see these names.
+//! - Renaming a directory which has lower contents fails with `EXDEV`, so `mv` falls
+//!   back to copying it.
+//!
+//! Both layers get the same `TAttach`. Qids are the ones of the layer serving a file,
+//! with bit 62 of the paths of lower files flipped so both layers don't collide, so a
+//! file gets a new qid when copied up.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{overlay::Overlay, readonly::ReadOnly, srv::{Filesystem, srv_async}, Result};
+//! # async fn serve<Fs: 'static + Filesystem + Send + Sync + Clone>(image: Fs, scratch: Fs) -> Result<()> {
+//! srv_async(Overlay::new(ReadOnly::new(image), scratch), "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        error::{self, errno::*},
+        fcall::*,
+        readonly::opens_for_write,
+        srv::{FId, Filesystem},
+        utils::Result,
+    },
+    async_trait::async_trait,
+    std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    },
+    tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard},
+};
+
+/// Prefix of the whiteouts hiding lower files in the upper layer
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the file marking an upper directory as hiding the lower one
+pub const OPAQUE: &str = ".wh..wh..opq";
+
+// Prefix of the temporary files lower files are copied to, before being renamed into
+// place once complete
+const COPY_PREFIX: &str = ".wh..wh..copy.";
+
+// Flipped in the qid paths of lower files
+const LOWER_QID: u64 = 1 << 62;
+
+// Size of the reads and writes copying files up, and of the listings of directories
+const CHUNK: u32 = 64 * 1024;
+
+// The paths being copied up, so each is copied once
+type CopyUps = std::sync::Mutex<HashMap<Vec<String>, Arc<Mutex<()>>>>;
+
+/// A [`Filesystem`] showing `upper` over `lower`, see the [module](self) documentation
+#[derive(Clone, Debug, Default)]
+pub struct Overlay<L, U> {
+    lower: L,
+    upper: U,
+    copy_ups: Arc<CopyUps>,
+}
+
+impl<L, U> Overlay<L, U> {
+    /// Show `upper` over `lower`
+    pub fn new(lower: L, upper: U) -> Overlay<L, U> {
+        Overlay {
+            lower,
+            upper,
+            copy_ups: Default::default(),
+        }
+    }
+
+    /// The lower layer
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+
+    /// The upper layer
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+}
+
+// The fids of the roots of both layers, shared by all the fids of an attach
+struct Roots<L: Filesystem, U: Filesystem> {
+    lower: FId<L::FId>,
+    upper: FId<U::FId>,
+}
+
+// A file of the overlay, and its fids in the layers it is in
+struct Node<L: Filesystem, U: Filesystem> {
+    roots: Arc<Roots<L, U>>,
+    path: Vec<String>,
+    lower: Option<FId<L::FId>>,
+    upper: Option<FId<U::FId>>,
+    qid: QId,
+    // The upper directory hides the lower one
+    opaque: bool,
+    // Merged listing of an open directory
+    dirents: Option<Vec<DirEntry>>,
+    // Flags the file was opened with
+    open: Option<u32>,
+    // Reads an extended attribute rather than the file
+    xattr: bool,
+}
+
+impl<L: Filesystem, U: Filesystem> Node<L, U> {
+    fn is_dir(&self) -> bool {
+        self.qid.typ.contains(QIdType::DIR)
+    }
+
+    fn child_path(&self, name: &str) -> Vec<String> {
+        let mut path = self.path.clone();
+        path.push(name.to_owned());
+        path
+    }
+}
+
+/// Fid of an [`Overlay`]
+pub struct OverlayFId<L: Filesystem, U: Filesystem> {
+    node: Mutex<Option<Node<L, U>>>,
+}
+
+impl<L: Filesystem, U: Filesystem> Default for OverlayFId<L, U> {
+    fn default() -> Self {
+        OverlayFId {
+            node: Mutex::new(None),
+        }
+    }
+}
+
+impl<L: Filesystem, U: Filesystem> OverlayFId<L, U> {
+    /// Path of the file from the root of the overlay, once attached or walked to
+    pub async fn path(&self) -> Option<Vec<String>> {
+        self.node
+            .lock()
+            .await
+            .as_ref()
+            .map(|node| node.path.clone())
+    }
+
+    /// If the file is in the upper layer, either created there or copied up
+    pub async fn is_upper(&self) -> bool {
+        matches!(&*self.node.lock().await, Some(node) if node.upper.is_some())
+    }
+}
+
+type OFId<L, U> = FId<OverlayFId<L, U>>;
+type Guard<'a, L, U> = MutexGuard<'a, Option<Node<L, U>>>;
+
+fn lower_qid(mut qid: QId) -> QId {
+    qid.path ^= LOWER_QID;
+    qid
+}
+
+// Give the qids of a reply of the lower layer their overlay paths
+fn from_lower(reply: FCall) -> FCall {
+    match reply {
+        FCall::RGetAttr { valid, qid, stat } => FCall::RGetAttr {
+            valid,
+            qid: lower_qid(qid),
+            stat,
+        },
+        FCall::RlOpen { qid, iounit } => FCall::RlOpen {
+            qid: lower_qid(qid),
+            iounit,
+        },
+        reply => reply,
+    }
+}
+
+fn unexpected() -> error::Error {
+    error::Error::No(EIO)
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with(WHITEOUT_PREFIX)
+}
+
+async fn lock<L: Filesystem, U: Filesystem>(fid: &OFId<L, U>) -> Result<Guard<'_, L, U>> {
+    let guard = fid.aux.node.lock().await;
+    match *guard {
+        Some(_) => Ok(guard),
+        None => Err(error::Error::No(EBADF)),
+    }
+}
+
+// Lock two distinct fids, in a consistent order to avoid deadlocks
+async fn lock_pair<'a, L: Filesystem, U: Filesystem>(
+    a: &'a OFId<L, U>,
+    b: &'a OFId<L, U>,
+) -> Result<(Guard<'a, L, U>, Guard<'a, L, U>)> {
+    if std::ptr::eq(a, b) {
+        return Err(error::Error::No(EINVAL));
+    }
+    if (a as *const OFId<L, U>) < (b as *const OFId<L, U>) {
+        let a = lock(a).await?;
+        Ok((a, lock(b).await?))
+    } else {
+        let b = lock(b).await?;
+        Ok((lock(a).await?, b))
+    }
+}
+
+// Walk `names` from `from` to a new fid, `None` if the file doesn't exist
+//
+// Also returns the qid of the file, unless `names` is empty.
+async fn walk<Fs: Filesystem + Sync>(
+    fs: &Fs,
+    from: &FId<Fs::FId>,
+    names: &[String],
+) -> Result<Option<(FId<Fs::FId>, Option<QId>)>> {
+    let new = FId::new(from.fid(), Default::default());
+    match fs.rwalk(from, &new, names).await {
+        Ok(FCall::RWalk { wqids }) if wqids.len() == names.len() => {
+            Ok(Some((new, wqids.last().copied())))
+        }
+        Ok(FCall::RWalk { .. }) => Ok(None),
+        Ok(_) => Err(unexpected()),
+        Err(e) if [ENOENT, ENOTDIR].contains(&e.errno()) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn clone<Fs: Filesystem + Sync>(fs: &Fs, fid: &FId<Fs::FId>) -> Result<FId<Fs::FId>> {
+    let (new, _) = walk(fs, fid, &[]).await?.ok_or(error::Error::No(ENOENT))?;
+    Ok(new)
+}
+
+async fn exists<Fs: Filesystem + Sync>(fs: &Fs, dir: &FId<Fs::FId>, name: &str) -> Result<bool> {
+    match walk(fs, dir, &[name.to_owned()]).await? {
+        Some((fid, _)) => {
+            let _ = fs.rclunk(&fid).await;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+async fn stat<Fs: Filesystem + Sync>(fs: &Fs, fid: &FId<Fs::FId>) -> Result<Stat> {
+    match fs.rgetattr(fid, GetAttrMask::BASIC).await? {
+        FCall::RGetAttr { stat, .. } => Ok(stat),
+        _ => Err(unexpected()),
+    }
+}
+
+// All the entries of the directory `dir`
+async fn list<Fs: Filesystem + Sync>(fs: &Fs, dir: &FId<Fs::FId>) -> Result<Vec<DirEntry>> {
+    let fid = clone(fs, dir).await?;
+    let flags = P9OpenFlags::RDONLY | P9OpenFlags::DIRECTORY;
+    let res = async {
+        fs.rlopen(&fid, flags.bits()).await?;
+        let mut entries = Vec::new();
+        loop {
+            let offset = entries.last().map_or(0, |entry: &DirEntry| entry.offset);
+            match fs.rreaddir(&fid, offset, CHUNK).await? {
+                FCall::RReadDir { data } if data.data().is_empty() => return Ok(entries),
+                FCall::RReadDir { data } => entries.extend(data.data().iter().cloned()),
+                _ => return Err(unexpected()),
+            }
+        }
+    }
+    .await;
+    let _ = fs.rclunk(&fid).await;
+    res
+}
+
+impl<L, U> Overlay<L, U>
+where
+    L: Filesystem + Sync,
+    U: Filesystem + Sync,
+{
+    async fn clunk(&self, node: Node<L, U>) {
+        if let Some(fid) = &node.lower {
+            let _ = self.lower.rclunk(fid).await;
+        }
+        if let Some(fid) = &node.upper {
+            let _ = self.upper.rclunk(fid).await;
+        }
+        if let Some(roots) = Arc::into_inner(node.roots) {
+            let _ = self.lower.rclunk(&roots.lower).await;
+            let _ = self.upper.rclunk(&roots.upper).await;
+        }
+    }
+
+    async fn clone_node(&self, node: &Node<L, U>) -> Result<Node<L, U>> {
+        let lower = match &node.lower {
+            Some(fid) => Some(clone(&self.lower, fid).await?),
+            None => None,
+        };
+        let upper = match &node.upper {
+            Some(fid) => Some(clone(&self.upper, fid).await?),
+            None => None,
+        };
+
+        Ok(Node {
+            roots: node.roots.clone(),
+            path: node.path.clone(),
+            lower,
+            upper,
+            qid: node.qid,
+            opaque: node.opaque,
+            dirents: None,
+            open: None,
+            xattr: false,
+        })
+    }
+
+    // Look `name` up in the directory `dir`
+    async fn lookup(&self, dir: &Node<L, U>, name: &str) -> Result<Option<Node<L, U>>> {
+        let names = [name.to_owned()];
+        let upper = match &dir.upper {
+            Some(fid) => walk(&self.upper, fid, &names).await?,
+            None => None,
+        };
+
+        // An upper file hides the lower one, unless both are directories
+        let upper_dir = upper
+            .as_ref()
+            .map(|(_, qid)| qid.is_some_and(|qid| qid.typ.contains(QIdType::DIR)));
+        let hidden = dir.opaque
+            || upper_dir == Some(false)
+            || match (&dir.upper, &upper) {
+                (Some(fid), None) => {
+                    exists(&self.upper, fid, &format!("{WHITEOUT_PREFIX}{name}")).await?
+                }
+                _ => false,
+            };
+        let mut lower = match &dir.lower {
+            Some(fid) if !hidden => walk(&self.lower, fid, &names).await?,
+            _ => None,
+        };
+        if let (Some(true), Some((fid, qid))) = (upper_dir, &lower)
+            && !qid.is_some_and(|qid| qid.typ.contains(QIdType::DIR))
+        {
+            let _ = self.lower.rclunk(fid).await;
+            lower = None;
+        }
+
+        let opaque = match (&upper, &lower) {
+            (Some((fid, _)), Some(_)) => exists(&self.upper, fid, OPAQUE).await?,
+            _ => false,
+        };
+        if opaque && let Some((fid, _)) = lower.take() {
+            let _ = self.lower.rclunk(&fid).await;
+        }
+
+        let qid = match (&upper, &lower) {
+            (Some((_, qid)), _) => qid.ok_or_else(unexpected)?,
+            (None, Some((_, qid))) => lower_qid(qid.ok_or_else(unexpected)?),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Node {
+            roots: dir.roots.clone(),
+            path: dir.child_path(name),
+            lower: lower.map(|(fid, _)| fid),
+            upper: upper.map(|(fid, _)| fid),
+            qid,
+            opaque,
+            dirents: None,
+            open: None,
+            xattr: false,
+        }))
+    }
+
+    // The node at `path`, looked up from the roots
+    async fn resolve(
+        &self,
+        roots: &Arc<Roots<L, U>>,
+        qid: QId,
+        path: &[String],
+    ) -> Result<Option<Node<L, U>>> {
+        let mut node = Node {
+            roots: roots.clone(),
+            path: Vec::new(),
+            lower: Some(clone(&self.lower, &roots.lower).await?),
+            upper: Some(clone(&self.upper, &roots.upper).await?),
+            qid,
+            opaque: exists(&self.upper, &roots.upper, OPAQUE).await?,
+            dirents: None,
+            open: None,
+            xattr: false,
+        };
+        for name in path {
+            let next = self.lookup(&node, name).await;
+            self.clunk(node).await;
+            match next? {
+                Some(next) => node = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(node))
+    }
+
+    // The upper directory at `path`, created from the lower ones where missing
+    async fn upper_dir(&self, roots: &Roots<L, U>, path: &[String]) -> Result<FId<U::FId>> {
+        let mut upper = clone(&self.upper, &roots.upper).await?;
+        let mut lower = Some(clone(&self.lower, &roots.lower).await?);
+
+        for name in path {
+            let names = [name.clone()];
+            let next_lower = match &lower {
+                Some(fid) => walk(&self.lower, fid, &names).await?.map(|(fid, _)| fid),
+                None => None,
+            };
+            let next_upper = match walk(&self.upper, &upper, &names).await? {
+                Some((fid, _)) => fid,
+                None => {
+                    let source = next_lower.as_ref().ok_or(error::Error::No(ENOENT))?;
+                    let stat = stat(&self.lower, source).await?;
+                    // Possibly made meanwhile by the copy up of another file
+                    match self
+                        .upper
+                        .rmkdir(&upper, name, stat.mode & 0o7777, stat.gid)
+                        .await
+                    {
+                        Err(e) if e.errno() != EEXIST => return Err(e),
+                        _ => (),
+                    }
+                    let (fid, _) = walk(&self.upper, &upper, &names)
+                        .await?
+                        .ok_or(error::Error::No(ENOENT))?;
+                    self.copy_attrs(&fid, &stat).await;
+                    fid
+                }
+            };
+
+            let _ = self.upper.rclunk(&upper).await;
+            if let Some(fid) = std::mem::replace(&mut lower, next_lower) {
+                let _ = self.lower.rclunk(&fid).await;
+            }
+            upper = next_upper;
+        }
+
+        if let Some(fid) = lower {
+            let _ = self.lower.rclunk(&fid).await;
+        }
+        Ok(upper)
+    }
+
+    // Give a copied up file the owner and times of the lower one, if allowed
+    async fn copy_attrs(&self, fid: &FId<U::FId>, stat: &Stat) {
+        let valid = SetAttrMask::UID
+            | SetAttrMask::GID
+            | SetAttrMask::ATIME
+            | SetAttrMask::MTIME
+            | SetAttrMask::ATIME_SET
+            | SetAttrMask::MTIME_SET;
+        let attrs = SetAttr {
+            mode: 0,
+            uid: stat.uid,
+            gid: stat.gid,
+            size: 0,
+            atime: stat.atime,
+            mtime: stat.mtime,
+        };
+        let _ = self.upper.rsetattr(fid, valid, &attrs).await;
+    }
+
+    async fn copy_data(&self, from: &FId<L::FId>, to: &FId<U::FId>) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let data = match self.lower.rread(from, offset, CHUNK).await? {
+                FCall::RRead { data } if data.0.is_empty() => return Ok(()),
+                FCall::RRead { data } => data,
+                _ => return Err(unexpected()),
+            };
+
+            let mut written = 0;
+            while written < data.0.len() {
+                let chunk = Data(data.0[written..].to_vec());
+                match self.upper.rwrite(to, offset, &chunk).await? {
+                    FCall::RWrite { count } if count > 0 => {
+                        written += count as usize;
+                        offset += count as u64;
+                    }
+                    _ => return Err(error::Error::No(EIO)),
+                }
+            }
+        }
+    }
+
+    // Lock `path` for copying it up
+    async fn lock_copy_up(&self, path: &[String]) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut copy_ups = self.copy_ups.lock().unwrap_or_else(|e| e.into_inner());
+            copy_ups.retain(|_, lock| Arc::strong_count(lock) > 1);
+            copy_ups.entry(path.to_vec()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    // Pick up the upper file of `node` if another fid copied it up
+    async fn refresh(&self, node: &mut Node<L, U>) -> Result<()> {
+        if node.upper.is_some() || node.path.is_empty() || node.xattr {
+            return Ok(());
+        }
+        if let Some((fid, qid)) = walk(&self.upper, &node.roots.upper, &node.path).await? {
+            self.set_upper(node, fid, qid.ok_or_else(unexpected)?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Switch `node` to its upper file, opened like the lower one if it was
+    async fn set_upper(&self, node: &mut Node<L, U>, fid: FId<U::FId>, qid: QId) -> Result<()> {
+        if let Some(flags) = node.open.filter(|_| !node.is_dir())
+            && let Err(e) = self.upper.rlopen(&fid, flags).await
+        {
+            let _ = self.upper.rclunk(&fid).await;
+            return Err(e);
+        }
+        node.qid = qid;
+        node.upper = Some(fid);
+        node.dirents = None;
+        Ok(())
+    }
+
+    // Make sure `node` is in the upper layer
+    async fn copy_up(&self, node: &mut Node<L, U>) -> Result<()> {
+        if node.upper.is_some() {
+            return Ok(());
+        }
+        let _copying = self.lock_copy_up(&node.path).await;
+        self.refresh(node).await?;
+        if node.upper.is_some() {
+            return Ok(());
+        }
+        if node.is_dir() {
+            let fid = self.upper_dir(&node.roots, &node.path).await?;
+            let qid = stat_qid(&self.upper, &fid).await?;
+            return self.set_upper(node, fid, qid).await;
+        }
+
+        let (name, parent) = node.path.split_last().ok_or_else(unexpected)?;
+        let lower = node.lower.as_ref().ok_or_else(unexpected)?;
+        let stat = stat(&self.lower, lower).await?;
+        let dir = self.upper_dir(&node.roots, parent).await?;
+        let res = self.copy_file(&dir, name, lower, &stat).await;
+        let _ = self.upper.rclunk(&dir).await;
+
+        let fid = res?;
+        let qid = stat_qid(&self.upper, &fid).await?;
+        self.set_upper(node, fid, qid).await
+    }
+
+    // Create `name` in the upper directory `dir` as a copy of the lower file `lower`
+    //
+    // Regular files are copied to a temporary file first, so that an interrupted copy
+    // never shows up as `name`.
+    async fn copy_file(
+        &self,
+        dir: &FId<U::FId>,
+        name: &str,
+        lower: &FId<L::FId>,
+        stat: &Stat,
+    ) -> Result<FId<U::FId>> {
+        let mode = FileMode::from_bits_truncate(stat.mode);
+        match mode.file_type() {
+            DirEntryType::Reg => {
+                let temp = format!("{COPY_PREFIX}{name}");
+                // Left by a copy which didn't complete, the path is locked meanwhile
+                let _ = self.upper.runlinkat(dir, &temp, 0).await;
+
+                let to = clone(&self.upper, dir).await?;
+                let from = clone(&self.lower, lower).await?;
+                let flags = P9OpenFlags::WRONLY | P9OpenFlags::CREATE | P9OpenFlags::EXCL;
+                let created = self
+                    .upper
+                    .rlcreate(&to, &temp, flags.bits(), stat.mode & 0o7777, stat.gid)
+                    .await;
+                let res = async {
+                    created?;
+                    self.lower.rlopen(&from, P9OpenFlags::RDONLY.bits()).await?;
+                    self.copy_data(&from, &to).await?;
+                    self.copy_attrs(&to, stat).await;
+                    self.upper.rrenameat(dir, &temp, dir, name).await
+                }
+                .await;
+                let _ = self.lower.rclunk(&from).await;
+                let _ = self.upper.rclunk(&to).await;
+                if let Err(e) = res {
+                    let _ = self.upper.runlinkat(dir, &temp, 0).await;
+                    return Err(e);
+                }
+            }
+            DirEntryType::Lnk => {
+                let target = match self.lower.rreadlink(lower).await? {
+                    FCall::RReadLink { target } => target,
+                    _ => return Err(unexpected()),
+                };
+                self.upper.rsymlink(dir, name, &target, stat.gid).await?;
+            }
+            _ => {
+                // Linux's encoding of device numbers
+                let major = ((stat.rdev >> 8) & 0xfff) | ((stat.rdev >> 32) & !0xfff);
+                let minor = (stat.rdev & 0xff) | ((stat.rdev >> 12) & !0xff);
+                self.upper
+                    .rmknod(dir, name, stat.mode, major as u32, minor as u32, stat.gid)
+                    .await?;
+            }
+        }
+
+        let (fid, _) = walk(&self.upper, dir, &[name.to_owned()])
+            .await?
+            .ok_or(error::Error::No(ENOENT))?;
+        if mode.file_type() != DirEntryType::Reg {
+            self.copy_attrs(&fid, stat).await;
+        }
+        Ok(fid)
+    }
+
+    async fn whiteout(&self, dir: &FId<U::FId>, name: &str) -> Result<()> {
+        let fid = clone(&self.upper, dir).await?;
+        let flags = P9OpenFlags::WRONLY | P9OpenFlags::CREATE | P9OpenFlags::EXCL;
+        let res = self
+            .upper
+            .rlcreate(
+                &fid,
+                &format!("{WHITEOUT_PREFIX}{name}"),
+                flags.bits(),
+                0o600,
+                0,
+            )
+            .await;
+        let _ = self.upper.rclunk(&fid).await;
+        res.map(|_| ())
+    }
+
+    // Get the directory `dir` ready for creating `name` in the upper layer
+    //
+    // Returns whether a whiteout of `name` was removed.
+    async fn prepare_create(&self, dir: &mut Node<L, U>, name: &str) -> Result<bool> {
+        if is_hidden(name) {
+            return Err(error::Error::No(EINVAL));
+        }
+        if let Some(existing) = self.lookup(dir, name).await? {
+            self.clunk(existing).await;
+            return Err(error::Error::No(EEXIST));
+        }
+
+        self.copy_up(dir).await?;
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        let whiteout = format!("{WHITEOUT_PREFIX}{name}");
+        if exists(&self.upper, upper, &whiteout).await? {
+            self.upper.runlinkat(upper, &whiteout, 0).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // The merged entries of the directory `node`
+    async fn dirents(&self, node: &Node<L, U>) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        let mut names = HashSet::new();
+        let mut whiteouts = HashSet::new();
+
+        if let Some(fid) = &node.upper {
+            for entry in list(&self.upper, fid).await? {
+                if let Some(name) = entry.name.strip_prefix(WHITEOUT_PREFIX) {
+                    whiteouts.insert(name.to_owned());
+                } else {
+                    names.insert(entry.name.clone());
+                    entries.push(entry);
+                }
+            }
+        }
+        if let Some(fid) = node.lower.as_ref().filter(|_| !node.opaque) {
+            for mut entry in list(&self.lower, fid).await? {
+                if !names.contains(&entry.name) && !whiteouts.contains(&entry.name) {
+                    entry.qid = lower_qid(entry.qid);
+                    entries.push(entry);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Remove `child`, the entry `name` of `dir`
+    async fn remove(&self, dir: &mut Node<L, U>, name: &str, child: &Node<L, U>) -> Result<()> {
+        if child.is_dir()
+            && self
+                .dirents(child)
+                .await?
+                .iter()
+                .any(|entry| entry.name != "." && entry.name != "..")
+        {
+            return Err(error::Error::No(ENOTEMPTY));
+        }
+
+        self.copy_up(dir).await?;
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        if let Some(fid) = &child.upper {
+            if child.is_dir() {
+                // Only whiteouts are left in it
+                for entry in list(&self.upper, fid).await? {
+                    if is_hidden(&entry.name) {
+                        self.upper.runlinkat(fid, &entry.name, 0).await?;
+                    }
+                }
+            }
+            let flags = if child.is_dir() {
+                UnlinkFlags::REMOVEDIR.bits()
+            } else {
+                0
+            };
+            self.upper.runlinkat(upper, name, flags).await?;
+        }
+        if self.in_lower(dir, name).await?
+            && !exists(&self.upper, upper, &format!("{WHITEOUT_PREFIX}{name}")).await?
+        {
+            self.whiteout(upper, name).await?;
+        }
+        Ok(())
+    }
+
+    // Whether the lower layer has `name` in `dir`, even if a copy up hides it
+    async fn in_lower(&self, dir: &Node<L, U>, name: &str) -> Result<bool> {
+        match &dir.lower {
+            Some(fid) if !dir.opaque => exists(&self.lower, fid, name).await,
+            _ => Ok(false),
+        }
+    }
+
+    // Rename `oldname` of `olddir` to `newname` of `newdir`, or of `olddir` if `None`
+    async fn rename(
+        &self,
+        olddir: &mut Node<L, U>,
+        oldname: &str,
+        mut newdir: Option<&mut Node<L, U>>,
+        newname: &str,
+    ) -> Result<()> {
+        if is_hidden(oldname) || is_hidden(newname) {
+            return Err(error::Error::No(ENOENT));
+        }
+        let mut child = self
+            .lookup(olddir, oldname)
+            .await?
+            .ok_or(error::Error::No(ENOENT))?;
+        let res = async {
+            if child.is_dir() && child.lower.is_some() {
+                return Err(error::Error::No(EXDEV));
+            }
+
+            let target = {
+                let dir = newdir.as_deref().unwrap_or(olddir);
+                self.lookup(dir, newname).await?
+            };
+            if let Some(target) = target {
+                let merged = target.is_dir() && target.lower.is_some();
+                self.clunk(target).await;
+                if merged {
+                    return Err(error::Error::No(EXDEV));
+                }
+            }
+
+            self.copy_up(olddir).await?;
+            if let Some(dir) = newdir.as_deref_mut() {
+                self.copy_up(dir).await?;
+            }
+            self.copy_up(&mut child).await?;
+
+            let old = olddir.upper.as_ref().ok_or_else(unexpected)?;
+            let new = match newdir.as_deref() {
+                Some(dir) => dir.upper.as_ref().ok_or_else(unexpected)?,
+                None => old,
+            };
+            let whiteout = format!("{WHITEOUT_PREFIX}{newname}");
+            if exists(&self.upper, new, &whiteout).await? {
+                self.upper.runlinkat(new, &whiteout, 0).await?;
+            }
+            // Whited out before the rename, so that the lower file can't show again if it
+            // is interrupted; the upper file hides the whiteout until then
+            let whiteout = format!("{WHITEOUT_PREFIX}{oldname}");
+            let whited = self.in_lower(olddir, oldname).await?
+                && !exists(&self.upper, old, &whiteout).await?;
+            if whited {
+                self.whiteout(old, oldname).await?;
+            }
+            let res = self.upper.rrenameat(old, oldname, new, newname).await;
+            if res.is_err() && whited {
+                let _ = self.upper.runlinkat(old, &whiteout, 0).await;
+            }
+            res.map(|_| ())
+        }
+        .await;
+        self.clunk(child).await;
+        res
+    }
+}
+
+async fn stat_qid<Fs: Filesystem + Sync>(fs: &Fs, fid: &FId<Fs::FId>) -> Result<QId> {
+    match fs.rgetattr(fid, GetAttrMask::BASIC).await? {
+        FCall::RGetAttr { qid, .. } => Ok(qid),
+        _ => Err(unexpected()),
+    }
+}
+
+#[async_trait]
+impl<L, U> Filesystem for Overlay<L, U>
+where
+    L: Filesystem + Sync,
+    U: Filesystem + Sync,
+{
+    type FId = OverlayFId<L, U>;
+
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let node = lock(fid).await?;
+        let node = node.as_ref().ok_or_else(unexpected)?;
+        self.upper.rstatfs(&node.roots.upper).await
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        let node = node.as_mut().ok_or_else(unexpected)?;
+        self.refresh(node).await?;
+        if node.is_dir() {
+            if opens_for_write(flags) {
+                return Err(error::Error::No(EISDIR));
+            }
+            node.dirents = None;
+            return Ok(FCall::RlOpen {
+                qid: node.qid,
+                iounit: 0,
+            });
+        }
+
+        if opens_for_write(flags) {
+            self.copy_up(node).await?;
+        }
+        let reply = match (&node.upper, &node.lower) {
+            (Some(upper), _) => self.upper.rlopen(upper, flags).await?,
+            (None, Some(lower)) => from_lower(self.lower.rlopen(lower, flags).await?),
+            (None, None) => return Err(unexpected()),
+        };
+        node.open = Some(flags);
+        Ok(reply)
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let mut guard = lock(fid).await?;
+        let dir = guard.as_mut().ok_or_else(unexpected)?;
+        self.prepare_create(dir, name).await?;
+
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        let new = clone(&self.upper, upper).await?;
+        let reply = match self.upper.rlcreate(&new, name, flags, mode, gid).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                let _ = self.upper.rclunk(&new).await;
+                return Err(e);
+            }
+        };
+        let qid = match reply {
+            FCall::RlCreate { qid, .. } => qid,
+            _ => return Err(unexpected()),
+        };
+
+        // The fid now refers to the new file
+        let file = Node {
+            roots: dir.roots.clone(),
+            path: dir.child_path(name),
+            lower: None,
+            upper: Some(new),
+            qid,
+            opaque: false,
+            dirents: None,
+            open: None,
+            xattr: false,
+        };
+        if let Some(dir) = guard.replace(file) {
+            self.clunk(dir).await;
+        }
+        Ok(reply)
+    }
+
+    async fn rsymlink(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        sym: &str,
+        gid: u32,
+    ) -> Result<FCall> {
+        let mut dir = lock(fid).await?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        self.prepare_create(dir, name).await?;
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        self.upper.rsymlink(upper, name, sym, gid).await
+    }
+
+    async fn rmknod(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let mut dir = lock(fid).await?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        self.prepare_create(dir, name).await?;
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        self.upper
+            .rmknod(upper, name, mode, major, minor, gid)
+            .await
+    }
+
+    async fn rrename(
+        &self,
+        fid: &FId<Self::FId>,
+        dfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (mut file, mut dir) = lock_pair(fid, dfid).await?;
+        let file = file.as_mut().ok_or_else(unexpected)?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        let (oldname, parent) = file.path.split_last().ok_or(error::Error::No(EBUSY))?;
+
+        let mut olddir = self
+            .resolve(&file.roots, QId::default(), parent)
+            .await?
+            .ok_or(error::Error::No(ENOENT))?;
+        let res = self.rename(&mut olddir, oldname, Some(dir), name).await;
+        self.clunk(olddir).await;
+        res?;
+
+        // The fid follows the file
+        if let Some(lower) = file.lower.take() {
+            let _ = self.lower.rclunk(&lower).await;
+        }
+        let moved = self
+            .lookup(dir, name)
+            .await?
+            .ok_or(error::Error::No(ENOENT))?;
+        let old = std::mem::replace(file, moved);
+        self.clunk(old).await;
+        Ok(FCall::RRename)
+    }
+
+    async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let node = lock(fid).await?;
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rreadlink(upper).await,
+            Some((None, Some(lower))) => self.lower.rreadlink(lower).await,
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        if let Some(node) = node.as_mut() {
+            self.refresh(node).await?;
+        }
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rgetattr(upper, req_mask).await,
+            Some((None, Some(lower))) => self.lower.rgetattr(lower, req_mask).await.map(from_lower),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &FId<Self::FId>,
+        valid: SetAttrMask,
+        stat: &SetAttr,
+    ) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        let node = node.as_mut().ok_or_else(unexpected)?;
+        self.copy_up(node).await?;
+        let upper = node.upper.as_ref().ok_or_else(unexpected)?;
+        self.upper.rsetattr(upper, valid, stat).await
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let mut guard = lock(fid).await?;
+        let node = guard.as_mut().ok_or_else(unexpected)?;
+        self.refresh(node).await?;
+        let (lower, upper, reply) = match (&node.upper, &node.lower) {
+            (Some(upper), _) => {
+                let new = FId::new(newfid.fid(), Default::default());
+                let reply = self.upper.rxattrwalk(upper, &new, name).await?;
+                (None, Some(new), reply)
+            }
+            (None, Some(lower)) => {
+                let new = FId::new(newfid.fid(), Default::default());
+                let reply = self.lower.rxattrwalk(lower, &new, name).await?;
+                (Some(new), None, reply)
+            }
+            (None, None) => return Err(unexpected()),
+        };
+
+        *newfid.aux.node.lock().await = Some(Node {
+            roots: node.roots.clone(),
+            path: node.path.clone(),
+            lower,
+            upper,
+            qid: node.qid,
+            opaque: false,
+            dirents: None,
+            open: None,
+            xattr: true,
+        });
+        Ok(reply)
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        let node = node.as_mut().ok_or_else(unexpected)?;
+        self.copy_up(node).await?;
+        let upper = node.upper.as_ref().ok_or_else(unexpected)?;
+        self.upper.rxattrcreate(upper, name, attr_size, flags).await
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        let node = node.as_mut().ok_or_else(unexpected)?;
+        if offset == 0 || node.dirents.is_none() {
+            node.dirents = Some(self.dirents(node).await?);
+        }
+
+        // Offsets are indices in the merged listing
+        let mut data = DirEntryData::new();
+        let entries = node.dirents.as_deref().unwrap_or_default();
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let entry = DirEntry {
+                offset: i as u64 + 1,
+                ..entry.clone()
+            };
+            if data.size() + entry.size() > count {
+                break;
+            }
+            data.push(entry);
+        }
+        Ok(FCall::RReadDir { data })
+    }
+
+    async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let node = lock(fid).await?;
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rfsync(upper).await,
+            Some((None, Some(lower))) => self.lower.rfsync(lower).await,
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rlock(&self, fid: &FId<Self::FId>, lock_: &Flock) -> Result<FCall> {
+        let node = lock(fid).await?;
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rlock(upper, lock_).await,
+            Some((None, Some(lower))) => self.lower.rlock(lower, lock_).await,
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock_: &Getlock) -> Result<FCall> {
+        let node = lock(fid).await?;
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rgetlock(upper, lock_).await,
+            Some((None, Some(lower))) => self.lower.rgetlock(lower, lock_).await,
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rlink(
+        &self,
+        dfid: &FId<Self::FId>,
+        fid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (mut dir, mut file) = lock_pair(dfid, fid).await?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        let file = file.as_mut().ok_or_else(unexpected)?;
+        if file.is_dir() {
+            return Err(error::Error::No(EPERM));
+        }
+
+        self.copy_up(file).await?;
+        self.prepare_create(dir, name).await?;
+        let (Some(dir), Some(file)) = (&dir.upper, &file.upper) else {
+            return Err(unexpected());
+        };
+        self.upper.rlink(dir, file, name).await
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let mut dir = lock(dfid).await?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        let whiteout = self.prepare_create(dir, name).await?;
+        let upper = dir.upper.as_ref().ok_or_else(unexpected)?;
+        let reply = self.upper.rmkdir(upper, name, mode, gid).await?;
+
+        // Replacing a removed lower directory, whose contents must not show
+        if whiteout {
+            let (new, _) = walk(&self.upper, upper, &[name.to_owned()])
+                .await?
+                .ok_or(error::Error::No(ENOENT))?;
+            let flags = P9OpenFlags::WRONLY | P9OpenFlags::CREATE | P9OpenFlags::EXCL;
+            let res = self
+                .upper
+                .rlcreate(&new, OPAQUE, flags.bits(), 0o600, gid)
+                .await;
+            let _ = self.upper.rclunk(&new).await;
+            res?;
+        }
+        Ok(reply)
+    }
+
+    async fn rrenameat(
+        &self,
+        olddirfid: &FId<Self::FId>,
+        oldname: &str,
+        newdirfid: &FId<Self::FId>,
+        newname: &str,
+    ) -> Result<FCall> {
+        if std::ptr::eq(olddirfid, newdirfid) {
+            let mut dir = lock(olddirfid).await?;
+            let dir = dir.as_mut().ok_or_else(unexpected)?;
+            self.rename(dir, oldname, None, newname).await?;
+        } else {
+            let (mut olddir, mut newdir) = lock_pair(olddirfid, newdirfid).await?;
+            let olddir = olddir.as_mut().ok_or_else(unexpected)?;
+            let newdir = newdir.as_mut().ok_or_else(unexpected)?;
+            self.rename(olddir, oldname, Some(newdir), newname).await?;
+        }
+        Ok(FCall::RRenameAt)
+    }
+
+    async fn runlinkat(&self, dirfd: &FId<Self::FId>, name: &str, flags: u32) -> Result<FCall> {
+        let mut dir = lock(dirfd).await?;
+        let dir = dir.as_mut().ok_or_else(unexpected)?;
+        if is_hidden(name) {
+            return Err(error::Error::No(ENOENT));
+        }
+
+        let child = self
+            .lookup(dir, name)
+            .await?
+            .ok_or(error::Error::No(ENOENT))?;
+        let res = match (child.is_dir(), flags & UnlinkFlags::REMOVEDIR.bits() != 0) {
+            (true, false) => Err(error::Error::No(EISDIR)),
+            (false, true) => Err(error::Error::No(ENOTDIR)),
+            _ => self.remove(dir, name, &child).await,
+        };
+        self.clunk(child).await;
+        res.map(|_| FCall::RUnlinkAt)
+    }
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        _afid: Option<&FId<Self::FId>>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let lower = FId::new(fid.fid(), Default::default());
+        self.lower
+            .rattach(&lower, None, uname, aname, n_uname)
+            .await?;
+        let upper = FId::new(fid.fid(), Default::default());
+        let reply = match self
+            .upper
+            .rattach(&upper, None, uname, aname, n_uname)
+            .await
+        {
+            Ok(reply) => reply,
+            Err(e) => {
+                let _ = self.lower.rclunk(&lower).await;
+                return Err(e);
+            }
+        };
+        let qid = match reply {
+            FCall::RAttach { qid } => qid,
+            _ => return Err(unexpected()),
+        };
+
+        let roots = Arc::new(Roots { lower, upper });
+        let root = self.resolve(&roots, qid, &[]).await?;
+        *fid.aux.node.lock().await = root;
+        Ok(reply)
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        let mut node = {
+            let start = lock(fid).await?;
+            self.clone_node(start.as_ref().ok_or_else(unexpected)?)
+                .await?
+        };
+
+        let mut wqids = Vec::new();
+        for (i, name) in wnames.iter().enumerate() {
+            let next = match name.as_str() {
+                "." => Some(self.clone_node(&node).await?),
+                ".." => {
+                    let parent = &node.path[..node.path.len().saturating_sub(1)];
+                    match self.resolve(&node.roots, QId::default(), parent).await {
+                        // Only the qid of the root isn't known from the walk
+                        Ok(Some(mut parent)) if parent.path.is_empty() => {
+                            let upper = parent.upper.as_ref().ok_or_else(unexpected)?;
+                            parent.qid = stat_qid(&self.upper, upper).await?;
+                            Some(parent)
+                        }
+                        res => res?,
+                    }
+                }
+                name if is_hidden(name) => None,
+                name => self.lookup(&node, name).await?,
+            };
+
+            match next {
+                Some(next) => {
+                    wqids.push(next.qid);
+                    let prev = std::mem::replace(&mut node, next);
+                    self.clunk(prev).await;
+                }
+                None => {
+                    if i == 0 {
+                        self.clunk(node).await;
+                        return Err(error::Error::No(ENOENT));
+                    }
+                    break;
+                }
+            }
+        }
+
+        *newfid.aux.node.lock().await = Some(node);
+        Ok(FCall::RWalk { wqids })
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let mut node = lock(fid).await?;
+        // Once copied up, read the copy
+        if let Some(node) = node.as_mut() {
+            self.refresh(node).await?;
+        }
+        match node.as_ref().map(|node| (&node.upper, &node.lower)) {
+            Some((Some(upper), _)) => self.upper.rread(upper, offset, count).await,
+            Some((None, Some(lower))) => self.lower.rread(lower, offset, count).await,
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn rwrite(&self, fid: &FId<Self::FId>, offset: u64, data: &Data) -> Result<FCall> {
+        let node = lock(fid).await?;
+        match node.as_ref().and_then(|node| node.upper.as_ref()) {
+            Some(upper) => self.upper.rwrite(upper, offset, data).await,
+            // Files are copied up when opened for writing
+            None => Err(error::Error::No(EBADF)),
+        }
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let node = fid.aux.node.lock().await.take();
+        let Some(node) = node else {
+            return Ok(FCall::RClunk);
+        };
+
+        // Clunking may commit an extended attribute write, report its result
+        let res = match (&node.upper, &node.lower) {
+            (Some(upper), _) => self.upper.rclunk(upper).await,
+            (None, Some(lower)) => self.lower.rclunk(lower).await,
+            (None, None) => Ok(FCall::RClunk),
+        };
+        let node = Node {
+            lower: node.lower.filter(|_| node.upper.is_some()),
+            upper: None,
+            ..node
+        };
+        self.clunk(node).await;
+        res
+    }
+
+    async fn rremove(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let node = fid.aux.node.lock().await.take();
+        let node = node.ok_or(error::Error::No(EBADF))?;
+        let res = async {
+            let (name, parent) = node.path.split_last().ok_or(error::Error::No(EBUSY))?;
+            let mut dir = self
+                .resolve(&node.roots, QId::default(), parent)
+                .await?
+                .ok_or(error::Error::No(ENOENT))?;
+            let res = self.remove(&mut dir, name, &node).await;
+            self.clunk(dir).await;
+            res
+        }
+        .await;
+        self.clunk(node).await;
+        res.map(|_| FCall::RRemove)
+    }
+}
+
+#[cfg(test)]
+type TestOverlay = Overlay<crate::memfs::MemFs, crate::memfs::MemFs>;
+
+#[cfg(test)]
+type TestFId = OFId<crate::memfs::MemFs, crate::memfs::MemFs>;
+
+#[cfg(test)]
+async fn walk_to(fs: &TestOverlay, from: &TestFId, path: &[&str]) -> TestFId {
+    let fid = FId::new(1, Default::default());
+    let wnames: Vec<_> = path.iter().map(|name| name.to_string()).collect();
+    let reply = fs.rwalk(from, &fid, &wnames).await.unwrap();
+    assert!(matches!(reply, FCall::RWalk { ref wqids } if wqids.len() == path.len()));
+    fid
+}
+
+#[cfg(test)]
+async fn read_all(fs: &TestOverlay, fid: &TestFId) -> Vec<u8> {
+    match fs.rread(fid, 0, CHUNK).await.unwrap() {
+        FCall::RRead { data } => data.0,
+        reply => panic!("{}", reply),
+    }
+}
+
+#[tokio::test]
+async fn overlay_copy_up_seen_by_other_fids() {
+    use crate::memfs::MemFs;
+
+    let (lower, upper) = (MemFs::new(), MemFs::new());
+    let root = root_of(&lower).await;
+    lower.rmkdir(&root, "dir", 0o755, 0).await.unwrap();
+    let file = FId::new(1, Default::default());
+    lower
+        .rwalk(&root, &file, &["dir".to_owned()])
+        .await
+        .unwrap();
+    let flags = P9OpenFlags::RDWR.bits();
+    lower.rlcreate(&file, "f", flags, 0o644, 0).await.unwrap();
+    lower
+        .rwrite(&file, 0, &Data(b"lower".to_vec()))
+        .await
+        .unwrap();
+
+    let fs = Overlay::new(lower.clone(), upper);
+    let root = FId::new(0, Default::default());
+    fs.rattach(&root, None, "", "", 0).await.unwrap();
+    // Walked before any copy up, like the fid of a dentry
+    let dentry = walk_to(&fs, &root, &["dir", "f"]).await;
+    let reader = walk_to(&fs, &dentry, &[]).await;
+    fs.rlopen(&reader, P9OpenFlags::RDONLY.bits())
+        .await
+        .unwrap();
+
+    // echo a >> f; echo b >> f
+    for (offset, data) in [(5, "a"), (6, "b")] {
+        let writer = walk_to(&fs, &dentry, &[]).await;
+        fs.rlopen(&writer, P9OpenFlags::WRONLY.bits())
+            .await
+            .unwrap();
+        fs.rwrite(&writer, offset, &Data(data.as_bytes().to_vec()))
+            .await
+            .unwrap();
+        fs.rclunk(&writer).await.unwrap();
+    }
+    assert_eq!(read_all(&fs, &reader).await, b"lowerab");
+    match fs.rgetattr(&dentry, GetAttrMask::ALL).await.unwrap() {
+        FCall::RGetAttr { qid, stat, .. } => {
+            assert_eq!(stat.size, 7);
+            assert_eq!(qid.path & LOWER_QID, 0);
+        }
+        reply => panic!("{}", reply),
+    }
+    assert!(dentry.aux.is_upper().await);
+
+    // The lower file is untouched
+    let lower_file = FId::new(2, Default::default());
+    let wnames = ["dir".to_owned(), "f".to_owned()];
+    lower
+        .rwalk(&root_of(&lower).await, &lower_file, &wnames)
+        .await
+        .unwrap();
+    lower
+        .rlopen(&lower_file, P9OpenFlags::RDONLY.bits())
+        .await
+        .unwrap();
+    let reply = lower.rread(&lower_file, 0, 100).await.unwrap();
+    assert_eq!(
+        reply,
+        FCall::RRead {
+            data: Data(b"lower".to_vec())
+        }
+    );
+}
+
+#[tokio::test]
+async fn overlay_concurrent_copy_ups() {
+    use crate::memfs::MemFs;
+
+    let lower = MemFs::new();
+    let root = root_of(&lower).await;
+    lower.rmkdir(&root, "dir", 0o755, 0).await.unwrap();
+    for name in ["a", "b"] {
+        let file = FId::new(1, Default::default());
+        lower
+            .rwalk(&root, &file, &["dir".to_owned()])
+            .await
+            .unwrap();
+        let flags = P9OpenFlags::RDWR.bits();
+        lower.rlcreate(&file, name, flags, 0o644, 0).await.unwrap();
+    }
+
+    // Copying two files of a directory, and one file twice, at the same time
+    let fs = Overlay::new(lower, MemFs::new());
+    let root = FId::new(0, Default::default());
+    fs.rattach(&root, None, "", "", 0).await.unwrap();
+    let fids = [
+        walk_to(&fs, &root, &["dir", "a"]).await,
+        walk_to(&fs, &root, &["dir", "a"]).await,
+        walk_to(&fs, &root, &["dir", "b"]).await,
+    ];
+    let opens = fids
+        .iter()
+        .map(|fid| fs.rlopen(fid, P9OpenFlags::WRONLY.bits()));
+    for reply in futures::future::join_all(opens).await {
+        reply.unwrap();
+    }
+    for fid in &fids {
+        assert!(fid.aux.is_upper().await);
+    }
+}
+
+#[tokio::test]
+async fn overlay_copy_up_through_temporary() {
+    use crate::memfs::MemFs;
+
+    let (lower, upper) = (MemFs::new(), MemFs::new());
+    let root = root_of(&lower).await;
+    let flags = P9OpenFlags::RDWR.bits();
+    lower.rlcreate(&root, "f", flags, 0o644, 0).await.unwrap();
+    lower
+        .rwrite(&root, 0, &Data(b"lower".to_vec()))
+        .await
+        .unwrap();
+
+    // Left behind by a copy up which was interrupted
+    let stale = root_of(&upper).await;
+    let temp = format!("{COPY_PREFIX}f");
+    upper
+        .rlcreate(&stale, &temp, flags, 0o644, 0)
+        .await
+        .unwrap();
+    upper
+        .rwrite(&stale, 0, &Data(b"partial".to_vec()))
+        .await
+        .unwrap();
+
+    let fs = Overlay::new(lower, upper.clone());
+    let root = FId::new(0, Default::default());
+    fs.rattach(&root, None, "", "", 0).await.unwrap();
+    let file = walk_to(&fs, &root, &["f"]).await;
+    fs.rlopen(&file, P9OpenFlags::RDWR.bits()).await.unwrap();
+    assert!(file.aux.is_upper().await);
+    assert_eq!(read_all(&fs, &file).await, b"lower");
+
+    let upper_root = root_of(&upper).await;
+    assert!(!exists(&upper, &upper_root, &temp).await.unwrap());
+    assert!(exists(&upper, &upper_root, "f").await.unwrap());
+}
+
+#[tokio::test]
+async fn overlay_rename_and_remove_copied_up() {
+    use crate::memfs::MemFs;
+
+    let (lower, upper) = (MemFs::new(), MemFs::new());
+    let root = root_of(&lower).await;
+    let flags = P9OpenFlags::RDWR.bits();
+    lower.rlcreate(&root, "f", flags, 0o644, 0).await.unwrap();
+    let root = root_of(&lower).await;
+    lower.rlcreate(&root, "h", flags, 0o644, 0).await.unwrap();
+    let root = root_of(&upper).await;
+    upper.rmkdir(&root, "d", 0o755, 0).await.unwrap();
+
+    let fs = Overlay::new(lower, upper.clone());
+    let root = FId::new(0, Default::default());
+    fs.rattach(&root, None, "", "", 0).await.unwrap();
+    fs.rrenameat(&root, "f", &root, "d").await.unwrap_err();
+    let file = walk_to(&fs, &root, &["f"]).await;
+    fs.rclunk(&file).await.unwrap();
+    let upper_root = root_of(&upper).await;
+    assert!(
+        !exists(&upper, &upper_root, &format!("{WHITEOUT_PREFIX}f"))
+            .await
+            .unwrap()
+    );
+
+    fs.rrenameat(&root, "f", &root, "g").await.unwrap();
+    assert!(walk(&fs, &root, &["f".to_owned()]).await.unwrap().is_none());
+    let file = walk_to(&fs, &root, &["g"]).await;
+    fs.rclunk(&file).await.unwrap();
+
+    let file = walk_to(&fs, &root, &["h"]).await;
+    fs.rlopen(&file, P9OpenFlags::WRONLY.bits()).await.unwrap();
+    fs.rclunk(&file).await.unwrap();
+    fs.runlinkat(&root, "h", 0).await.unwrap();
+    assert!(walk(&fs, &root, &["h".to_owned()]).await.unwrap().is_none());
+}
+
+#[cfg(test)]
+async fn root_of(fs: &crate::memfs::MemFs) -> FId<crate::memfs::MemFId> {
+    let root = FId::new(0, Default::default());
+    fs.rattach(&root, None, "", "", 0).await.unwrap();
+    root
+}
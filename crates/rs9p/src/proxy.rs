@@ -0,0 +1,1006 @@
+//! A filesystem forwarding the requests to another 9P server.
+//!
+//! [`Proxy`] is a client of an upstream server, sharing one connection between all the
+//! clients it serves, e.g. to put authentication or logging in front of a backend:
+//!
+//! - Every request is forwarded as the same message, the fids of the clients being
+//!   mapped to fids of the upstream connection. Fids which clients leave open when
+//!   disconnecting are clunked upstream.
+//! - A request the server cancels, because it was flushed or its client went away, is
+//!   flushed upstream. The fid it would create is only released once the upstream
+//!   server answered, and clunked if it was created after all.
+//! - `TVersion` is answered locally, the msize of the clients being bounded by the one
+//!   negotiated with the upstream server when connecting.
+//! - An [`intercept`](Proxy::intercept) hook can inspect, rewrite or deny the messages
+//!   on their way.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{proxy::Proxy, srv::srv_async, errno::*, Error, FCall, Result};
+//! # async fn serve() -> Result<()> {
+//! let proxy = Proxy::connect("unix!/run/backend.sock!0")
+//!     .await?
+//!     .intercept(|msg| match msg {
+//!         FCall::TRemove { .. } | FCall::TUnlinkAt { .. } => Err(Error::No(EPERM)),
+//!         _ => Ok(()),
+//!     });
+//! srv_async(proxy, "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        codec::{DEFAULT_MAX_MSIZE, NinePCodec},
+        error::{self, errno::*},
+        fcall::*,
+        io_err,
+        srv::{self, FId, Filesystem},
+        utils::{self, Result},
+    },
+    async_trait::async_trait,
+    futures::sink::SinkExt,
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::{TcpStream, UnixStream},
+        sync::{mpsc, oneshot},
+    },
+    tokio_stream::StreamExt,
+    tokio_util::codec::{FramedRead, FramedWrite},
+    tracing::{debug, error},
+};
+
+type Intercept = Arc<dyn Fn(&mut FCall) -> Result<()> + Send + Sync>;
+
+/// A [`Filesystem`] forwarding to an upstream server, see the [module](self)
+/// documentation
+///
+/// Clones share the upstream connection.
+#[derive(Clone)]
+pub struct Proxy {
+    upstream: Arc<Upstream>,
+    intercept: Option<Intercept>,
+}
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Proxy")
+            .field("msize", &self.upstream.msize)
+            .field("intercept", &self.intercept.is_some())
+            .finish()
+    }
+}
+
+impl Proxy {
+    /// Connect to the server at `addr`, given as `proto!address!port` like to
+    /// [`srv_async`](crate::srv::srv_async)
+    pub async fn connect(addr: &str) -> Result<Proxy> {
+        let (proto, addr, port) = utils::parse_proto(addr)
+            .ok_or_else(|| io_err!(InvalidInput, "Invalid protocol or address"))?;
+        let addr = format!("{}:{}", addr, port);
+
+        match proto {
+            "tcp" => {
+                let (reader, writer) = TcpStream::connect(&addr).await?.into_split();
+                Proxy::handshake(reader, writer).await
+            }
+            "unix" => {
+                let (reader, writer) = UnixStream::connect(&addr).await?.into_split();
+                Proxy::handshake(reader, writer).await
+            }
+            _ => Err(From::from(io_err!(InvalidInput, "Protocol not supported"))),
+        }
+    }
+
+    /// Use `stream`, already connected to the upstream server
+    pub async fn from_stream<S>(stream: S) -> Result<Proxy>
+    where
+        S: 'static + AsyncRead + AsyncWrite + Send,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        Proxy::handshake(reader, writer).await
+    }
+
+    async fn handshake<Reader, Writer>(reader: Reader, writer: Writer) -> Result<Proxy>
+    where
+        Reader: 'static + AsyncRead + Send + std::marker::Unpin,
+        Writer: 'static + AsyncWrite + Send + std::marker::Unpin,
+    {
+        let mut framedread = FramedRead::new(reader, NinePCodec::new());
+        let mut framedwrite = FramedWrite::new(writer, NinePCodec::new());
+
+        let version = Msg {
+            tag: NOTAG,
+            body: FCall::TVersion {
+                msize: DEFAULT_MAX_MSIZE,
+                version: P92000L.to_owned(),
+            },
+        };
+        framedwrite.send(&version).await?;
+        let msize = match framedread.next().await.transpose()? {
+            Some(Msg {
+                body: FCall::RVersion { msize, version },
+                ..
+            }) if version == P92000L => msize.min(DEFAULT_MAX_MSIZE),
+            Some(Msg {
+                body: FCall::RlError { ecode },
+                ..
+            }) => return Err(error::Error::No(nix::errno::Errno::from_raw(ecode as i32))),
+            _ => return Err(error::Error::No(EPROTONOSUPPORT)),
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Msg>();
+        let upstream = Arc::new(Upstream {
+            tx,
+            state: Default::default(),
+            msize,
+        });
+
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                debug!("\t⇢ {}", msg);
+                if let Err(e) = framedwrite.send(&msg).await {
+                    error!("Failed to send upstream: {:?}", e);
+                    break;
+                }
+            }
+        });
+        tokio::spawn({
+            let upstream = upstream.clone();
+            async move {
+                while let Some(msg) = framedread.next().await {
+                    match msg {
+                        Ok(msg) => upstream.received(msg),
+                        Err(e) => {
+                            error!("Failed to receive from upstream: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                // The requests still waiting fail with EIO
+                let mut state = upstream.state();
+                state.closed = true;
+                // Their fids are dropped once the state is unlocked
+                let tags = std::mem::take(&mut state.tags);
+                drop(state);
+                drop(tags);
+            }
+        });
+
+        Ok(Proxy {
+            upstream,
+            intercept: None,
+        })
+    }
+
+    /// Pass the messages to `intercept` before forwarding them
+    ///
+    /// It gets each request before it is sent upstream, and each successful reply
+    /// before it is returned to the client, and can modify them. Returning an error
+    /// denies the message: the client gets the error instead of a reply. The fids in
+    /// the messages are the ones of the upstream connection, and must not be changed.
+    pub fn intercept<F>(mut self, intercept: F) -> Proxy
+    where
+        F: Fn(&mut FCall) -> Result<()> + Send + Sync + 'static,
+    {
+        self.intercept = Some(Arc::new(intercept));
+        self
+    }
+
+    fn request(&self, mut request: FCall) -> Result<FCall> {
+        if let Some(intercept) = &self.intercept {
+            intercept(&mut request)?;
+        }
+        Ok(request)
+    }
+
+    // Send `request` upstream, without intercepting the reply
+    async fn forward(&self, request: FCall) -> Result<FCall> {
+        self.upstream.call(self.request(request)?).await
+    }
+
+    fn reply(&self, mut reply: FCall) -> Result<FCall> {
+        if let Some(intercept) = &self.intercept {
+            intercept(&mut reply)?;
+        }
+        Ok(reply)
+    }
+
+    async fn rpc(&self, request: FCall) -> Result<FCall> {
+        let reply = self.forward(request).await?;
+        self.reply(reply)
+    }
+
+    // Send a request creating `newfid` upstream, and bind it to `fid` if it succeeds
+    async fn create_fid(
+        &self,
+        fid: &FId<ProxyFId>,
+        mut newfid: UpFId,
+        request: FCall,
+    ) -> Result<FCall> {
+        let request = self.request(request).inspect_err(|_| {
+            newfid.exists = false;
+        })?;
+        let (reply, newfid) = self.upstream.create(request, newfid).await?;
+        fid.aux.bind(newfid);
+        self.reply(reply)
+    }
+
+    // Send a request releasing `fid` upstream, which is clunked anyway if it is denied
+    async fn release(&self, mut fid: UpFId, request: FCall) -> Result<FCall> {
+        let reply = self.upstream.call(self.request(request)?).await;
+        // Whatever the outcome, the fid is gone
+        fid.exists = false;
+        self.reply(reply?)
+    }
+}
+
+// A request sent upstream, by tag, and what to do with its reply
+#[derive(Debug)]
+enum Slot {
+    Waiting(oneshot::Sender<FCall>),
+    // Flushed, the tag stays in use until the flush is answered. So does the fid the
+    // request creates, if any, which might exist once the request is answered
+    Flushed(Option<UpFId>),
+    Flush(u16),
+    // Clunk of a fid released by its client, reusable once answered
+    Clunk(u32),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    tags: HashMap<u16, Slot>,
+    next_tag: u16,
+    free_fids: Vec<u32>,
+    next_fid: u32,
+    closed: bool,
+}
+
+// The connection to the upstream server
+#[derive(Debug)]
+struct Upstream {
+    tx: mpsc::UnboundedSender<Msg>,
+    state: Mutex<State>,
+    msize: u32,
+}
+
+impl Upstream {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, state: &mut State, body: FCall, slot: Slot) -> Result<u16> {
+        if state.closed || state.tags.len() >= NOTAG as usize {
+            return Err(error::Error::No(EIO));
+        }
+        while state.next_tag == NOTAG || state.tags.contains_key(&state.next_tag) {
+            state.next_tag = state.next_tag.wrapping_add(1);
+        }
+        let tag = state.next_tag;
+        state.next_tag = tag.wrapping_add(1);
+
+        self.tx
+            .send(Msg { tag, body })
+            .map_err(|_| error::Error::No(EIO))?;
+        state.tags.insert(tag, slot);
+        Ok(tag)
+    }
+
+    async fn call(&self, body: FCall) -> Result<FCall> {
+        let (reply, _) = self.wait(body, None).await?;
+        Ok(reply)
+    }
+
+    // Send a request creating `fid`
+    //
+    // If the request is cancelled, `fid` is only released once the upstream server
+    // answered it or its flush, as it might still be created until then.
+    async fn create(&self, body: FCall, fid: UpFId) -> Result<(FCall, UpFId)> {
+        let (reply, fid) = self.wait(body, Some(fid)).await?;
+        Ok((reply, fid.expect("fid of a created request")))
+    }
+
+    async fn wait(&self, body: FCall, mut fid: Option<UpFId>) -> Result<(FCall, Option<UpFId>)> {
+        let (tx, rx) = oneshot::channel();
+        let tag = self
+            .send(&mut self.state(), body, Slot::Waiting(tx))
+            .inspect_err(|_| {
+                if let Some(fid) = &mut fid {
+                    fid.exists = false;
+                }
+            })?;
+
+        let mut pending = Pending {
+            upstream: self,
+            tag,
+            fid,
+            answered: false,
+        };
+        // Flushed upstream if the request is flushed
+        let reply = srv::cancellable(rx)
+            .await?
+            .map_err(|_| error::Error::No(EIO))?;
+        pending.answered = true;
+        let mut fid = pending.fid.take();
+
+        match reply {
+            FCall::RlError { ecode } => {
+                if let Some(fid) = &mut fid {
+                    fid.exists = false;
+                }
+                Err(error::Error::No(nix::errno::Errno::from_raw(ecode as i32)))
+            }
+            reply => Ok((reply, fid)),
+        }
+    }
+
+    fn received(&self, msg: Msg) {
+        debug!("\t⇠ {}", msg);
+        let mut state = self.state();
+        // Dropped once the state is unlocked
+        let mut release = None;
+        match state.tags.remove(&msg.tag) {
+            Some(Slot::Waiting(tx)) => {
+                let _ = tx.send(msg.body);
+            }
+            Some(Slot::Flushed(fid)) => {
+                state.tags.insert(msg.tag, Slot::Flushed(None));
+                release = fid.map(|mut fid| {
+                    fid.exists = !matches!(msg.body, FCall::RlError { .. });
+                    fid
+                });
+            }
+            Some(Slot::Flush(oldtag)) => {
+                // Not answered before its flush, the request was not processed
+                if let Some(Slot::Flushed(Some(mut fid))) = state.tags.remove(&oldtag) {
+                    fid.exists = false;
+                    release = Some(fid);
+                }
+            }
+            Some(Slot::Clunk(fid)) => state.free_fids.push(fid),
+            None => error!("Reply from upstream with unknown tag {}", msg.tag),
+        }
+        drop(state);
+        drop(release);
+    }
+
+    fn fid(self: &Arc<Self>) -> UpFId {
+        let mut state = self.state();
+        let fid = state.free_fids.pop().unwrap_or_else(|| {
+            let fid = state.next_fid;
+            state.next_fid += 1;
+            fid
+        });
+        UpFId {
+            upstream: self.clone(),
+            fid,
+            exists: true,
+        }
+    }
+}
+
+// A request waiting for its reply, flushed if dropped before
+struct Pending<'a> {
+    upstream: &'a Upstream,
+    tag: u16,
+    // The fid the request creates
+    fid: Option<UpFId>,
+    answered: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.answered {
+            return;
+        }
+        let mut state = self.upstream.state();
+        // Dropped once the state is unlocked
+        let mut release = None;
+        if let Some(slot @ Slot::Waiting(_)) = state.tags.get_mut(&self.tag) {
+            *slot = Slot::Flushed(self.fid.take());
+            let flush = FCall::TFlush { oldtag: self.tag };
+            if self
+                .upstream
+                .send(&mut state, flush, Slot::Flush(self.tag))
+                .is_err()
+            {
+                release = state.tags.remove(&self.tag);
+            }
+        }
+        drop(state);
+        drop(release);
+    }
+}
+
+// A fid of the upstream connection, clunked when dropped unless known not to exist
+#[derive(Debug)]
+struct UpFId {
+    upstream: Arc<Upstream>,
+    fid: u32,
+    exists: bool,
+}
+
+impl Drop for UpFId {
+    fn drop(&mut self) {
+        let mut state = self.upstream.state();
+        let clunk = FCall::TClunk { fid: self.fid };
+        if !self.exists
+            || self
+                .upstream
+                .send(&mut state, clunk, Slot::Clunk(self.fid))
+                .is_err()
+        {
+            state.free_fids.push(self.fid);
+        }
+    }
+}
+
+/// Fid of a [`Proxy`], bound to a fid of the upstream connection
+#[derive(Debug, Default)]
+pub struct ProxyFId {
+    upstream: Mutex<Option<UpFId>>,
+}
+
+impl ProxyFId {
+    fn lock(&self) -> MutexGuard<'_, Option<UpFId>> {
+        self.upstream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self) -> Result<u32> {
+        self.lock()
+            .as_ref()
+            .map(|up| up.fid)
+            .ok_or(error::Error::No(EBADF))
+    }
+
+    fn bind(&self, up: UpFId) {
+        *self.lock() = Some(up);
+    }
+}
+
+#[async_trait]
+impl Filesystem for Proxy {
+    type FId = ProxyFId;
+
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TStatFs { fid }).await
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TlOpen { fid, flags }).await
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TlCreate {
+            fid,
+            name: name.to_owned(),
+            flags,
+            mode,
+            gid,
+        })
+        .await
+    }
+
+    async fn rsymlink(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        sym: &str,
+        gid: u32,
+    ) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TSymlink {
+            fid,
+            name: name.to_owned(),
+            symtgt: sym.to_owned(),
+            gid,
+        })
+        .await
+    }
+
+    async fn rmknod(
+        &self,
+        dfid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let dfid = dfid.aux.get()?;
+        self.rpc(FCall::TMkNod {
+            dfid,
+            name: name.to_owned(),
+            mode,
+            major,
+            minor,
+            gid,
+        })
+        .await
+    }
+
+    async fn rrename(
+        &self,
+        fid: &FId<Self::FId>,
+        dfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (fid, dfid) = (fid.aux.get()?, dfid.aux.get()?);
+        self.rpc(FCall::TRename {
+            fid,
+            dfid,
+            name: name.to_owned(),
+        })
+        .await
+    }
+
+    async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TReadLink { fid }).await
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TGetAttr { fid, req_mask }).await
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &FId<Self::FId>,
+        valid: SetAttrMask,
+        stat: &SetAttr,
+    ) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TSetAttr {
+            fid,
+            valid,
+            stat: *stat,
+        })
+        .await
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        let up = self.upstream.fid();
+        let request = FCall::TxAttrWalk {
+            fid,
+            newfid: up.fid,
+            name: name.to_owned(),
+        };
+        self.create_fid(newfid, up, request).await
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TxAttrCreate {
+            fid,
+            name: name.to_owned(),
+            attr_size,
+            flags,
+        })
+        .await
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TReadDir { fid, offset, count }).await
+    }
+
+    async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TFSync { fid }).await
+    }
+
+    async fn rlock(&self, fid: &FId<Self::FId>, lock: &Flock) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TLock {
+            fid,
+            flock: lock.clone(),
+        })
+        .await
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock: &Getlock) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TGetLock {
+            fid,
+            flock: lock.clone(),
+        })
+        .await
+    }
+
+    async fn rlink(
+        &self,
+        dfid: &FId<Self::FId>,
+        fid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        let (dfid, fid) = (dfid.aux.get()?, fid.aux.get()?);
+        self.rpc(FCall::TLink {
+            dfid,
+            fid,
+            name: name.to_owned(),
+        })
+        .await
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        let dfid = dfid.aux.get()?;
+        self.rpc(FCall::TMkDir {
+            dfid,
+            name: name.to_owned(),
+            mode,
+            gid,
+        })
+        .await
+    }
+
+    async fn rrenameat(
+        &self,
+        olddir: &FId<Self::FId>,
+        oldname: &str,
+        newdir: &FId<Self::FId>,
+        newname: &str,
+    ) -> Result<FCall> {
+        let (olddirfid, newdirfid) = (olddir.aux.get()?, newdir.aux.get()?);
+        self.rpc(FCall::TRenameAt {
+            olddirfid,
+            oldname: oldname.to_owned(),
+            newdirfid,
+            newname: newname.to_owned(),
+        })
+        .await
+    }
+
+    async fn runlinkat(&self, dir: &FId<Self::FId>, name: &str, flags: u32) -> Result<FCall> {
+        let dirfd = dir.aux.get()?;
+        self.rpc(FCall::TUnlinkAt {
+            dirfd,
+            name: name.to_owned(),
+            flags,
+        })
+        .await
+    }
+
+    async fn rauth(
+        &self,
+        afid: &FId<Self::FId>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let up = self.upstream.fid();
+        let request = FCall::TAuth {
+            afid: up.fid,
+            uname: uname.to_owned(),
+            aname: aname.to_owned(),
+            n_uname,
+        };
+        self.create_fid(afid, up, request).await
+    }
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        afid: Option<&FId<Self::FId>>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        let afid = match afid {
+            Some(afid) => afid.aux.get()?,
+            None => NOFID,
+        };
+        let up = self.upstream.fid();
+        let request = FCall::TAttach {
+            fid: up.fid,
+            afid,
+            uname: uname.to_owned(),
+            aname: aname.to_owned(),
+            n_uname,
+        };
+        self.create_fid(fid, up, request).await
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        let from = fid.aux.get()?;
+        let request = |newfid| FCall::TWalk {
+            fid: from,
+            newfid,
+            wnames: wnames.to_vec(),
+        };
+
+        // The server replaces the fid by newfid when the walk succeeds
+        if fid.fid() == newfid.fid() {
+            let reply = self.forward(request(from)).await?;
+            if let Some(up) = fid.aux.lock().take() {
+                newfid.aux.bind(up);
+            }
+            return self.reply(reply);
+        }
+
+        // Whether the walk is complete depends on the names actually sent
+        let mut up = self.upstream.fid();
+        let request = self.request(request(up.fid)).inspect_err(|_| {
+            up.exists = false;
+        })?;
+        let walked = match request {
+            FCall::TWalk { ref wnames, .. } => wnames.len(),
+            _ => wnames.len(),
+        };
+        let (reply, mut up) = self.upstream.create(request, up).await?;
+        match reply {
+            FCall::RWalk { ref wqids } if wqids.len() == walked => newfid.aux.bind(up),
+            _ => up.exists = false,
+        }
+        self.reply(reply)
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TRead { fid, offset, count }).await
+    }
+
+    async fn rwrite(&self, fid: &FId<Self::FId>, offset: u64, data: &Data) -> Result<FCall> {
+        let fid = fid.aux.get()?;
+        self.rpc(FCall::TWrite {
+            fid,
+            offset,
+            data: data.clone(),
+        })
+        .await
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let up = fid.aux.lock().take().ok_or(error::Error::No(EBADF))?;
+        let request = FCall::TClunk { fid: up.fid };
+        self.release(up, request).await
+    }
+
+    async fn rremove(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let up = fid.aux.lock().take().ok_or(error::Error::No(EBADF))?;
+        let request = FCall::TRemove { fid: up.fid };
+        self.release(up, request).await
+    }
+
+    async fn rversion(&self, msize: u32, ver: &str) -> Result<FCall> {
+        Ok(FCall::RVersion {
+            msize: msize.min(self.upstream.msize),
+            version: match ver {
+                P92000L => ver.to_owned(),
+                _ => VERSION_UNKNOWN.to_owned(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+async fn expect<S>(
+    upstream: &mut tokio_util::codec::Framed<S, NinePCodec>,
+    request: FCall,
+    reply: Option<FCall>,
+) -> u16
+where
+    S: AsyncRead + AsyncWrite + std::marker::Unpin,
+{
+    let msg = upstream.next().await.unwrap().unwrap();
+    assert_eq!(msg.body, request);
+    if let Some(body) = reply {
+        upstream.send(Msg { tag: msg.tag, body }).await.unwrap();
+    }
+    msg.tag
+}
+
+#[tokio::test]
+async fn proxy_forwarding() {
+    let (stream, upstream) = tokio::io::duplex(1 << 16);
+    let mut upstream = tokio_util::codec::Framed::new(upstream, NinePCodec::new());
+    let (proxy, _) = tokio::join!(
+        Proxy::from_stream(stream),
+        expect(
+            &mut upstream,
+            FCall::TVersion {
+                msize: DEFAULT_MAX_MSIZE,
+                version: P92000L.to_owned(),
+            },
+            Some(FCall::RVersion {
+                msize: 8192,
+                version: P92000L.to_owned(),
+            }),
+        )
+    );
+    let proxy = proxy.unwrap().intercept(|msg| match msg {
+        FCall::TRemove { .. } => Err(error::Error::No(EPERM)),
+        FCall::TWalk { wnames, .. } => {
+            wnames.retain(|name| name != "skip");
+            Ok(())
+        }
+        _ => Ok(()),
+    });
+    let reply = proxy.rversion(1 << 20, P92000L).await.unwrap();
+    assert!(matches!(reply, FCall::RVersion { msize: 8192, .. }));
+
+    // Client fids are mapped to upstream ones
+    let (root, file) = (
+        FId::new(5, ProxyFId::default()),
+        FId::new(6, ProxyFId::default()),
+    );
+    let attach = FCall::TAttach {
+        fid: 0,
+        afid: NOFID,
+        uname: "user".to_owned(),
+        aname: String::new(),
+        n_uname: 1000,
+    };
+    let (reply, _) = tokio::join!(
+        proxy.rattach(&root, None, "user", "", 1000),
+        expect(
+            &mut upstream,
+            attach,
+            Some(FCall::RAttach { qid: QId::dir(1) })
+        )
+    );
+    assert_eq!(reply.unwrap(), FCall::RAttach { qid: QId::dir(1) });
+    let walk = FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec!["a".to_owned()],
+    };
+    let wqids = vec![QId::file(2)];
+    let wnames = ["skip".to_owned(), "a".to_owned()];
+    let (reply, _) = tokio::join!(
+        proxy.rwalk(&root, &file, &wnames),
+        expect(&mut upstream, walk, Some(FCall::RWalk { wqids })),
+    );
+    assert!(matches!(reply.unwrap(), FCall::RWalk { wqids } if wqids.len() == 1));
+
+    // Cancelling a request flushes it
+    let read = proxy.rread(&file, 0, 10);
+    let tag = tokio::select! {
+        _ = read => unreachable!(),
+        tag = expect(&mut upstream, FCall::TRead { fid: 1, offset: 0, count: 10 }, None) => tag,
+    };
+    let flush = expect(&mut upstream, FCall::TFlush { oldtag: tag }, None).await;
+    upstream
+        .send(Msg {
+            tag,
+            body: FCall::RRead { data: Data(vec![]) },
+        })
+        .await
+        .unwrap();
+    upstream
+        .send(Msg {
+            tag: flush,
+            body: FCall::RFlush,
+        })
+        .await
+        .unwrap();
+
+    // A cancelled walk keeps its fid until answered, and clunks it if it was created
+    let other = FId::new(7, ProxyFId::default());
+    let wnames = ["b".to_owned()];
+    let walk = FCall::TWalk {
+        fid: 0,
+        newfid: 2,
+        wnames: wnames.to_vec(),
+    };
+    let tag = tokio::select! {
+        _ = proxy.rwalk(&root, &other, &wnames) => unreachable!(),
+        tag = expect(&mut upstream, walk, None) => tag,
+    };
+    let flush = expect(&mut upstream, FCall::TFlush { oldtag: tag }, None).await;
+    let (reply, _) = tokio::join!(
+        proxy.rgetattr(&root, GetAttrMask::ALL),
+        expect(
+            &mut upstream,
+            FCall::TGetAttr {
+                fid: 0,
+                req_mask: GetAttrMask::ALL,
+            },
+            Some(FCall::RlError {
+                ecode: EACCES as u32,
+            }),
+        )
+    );
+    assert_eq!(reply.unwrap_err().errno(), EACCES);
+    let wqids = vec![QId::file(3)];
+    upstream
+        .send(Msg {
+            tag,
+            body: FCall::RWalk { wqids },
+        })
+        .await
+        .unwrap();
+    expect(&mut upstream, FCall::TClunk { fid: 2 }, Some(FCall::RClunk)).await;
+    upstream
+        .send(Msg {
+            tag: flush,
+            body: FCall::RFlush,
+        })
+        .await
+        .unwrap();
+
+    // Flushed before being processed, it is freed without a clunk
+    let tag = tokio::select! {
+        _ = proxy.rwalk(&root, &other, &wnames) => unreachable!(),
+        msg = upstream.next() => msg.unwrap().unwrap().tag,
+    };
+    let flush = expect(&mut upstream, FCall::TFlush { oldtag: tag }, None).await;
+    upstream
+        .send(Msg {
+            tag: flush,
+            body: FCall::RFlush,
+        })
+        .await
+        .unwrap();
+
+    // A denied remove still releases the fid, which can then be reused
+    let reply = proxy.rremove(&file).await;
+    assert_eq!(reply.unwrap_err().errno(), EPERM);
+    expect(&mut upstream, FCall::TClunk { fid: 1 }, Some(FCall::RClunk)).await;
+    // Errors are forwarded, and once answered the clunk was processed
+    let reply = proxy.rgetattr(&root, GetAttrMask::ALL);
+    let getattr = expect(
+        &mut upstream,
+        FCall::TGetAttr {
+            fid: 0,
+            req_mask: GetAttrMask::ALL,
+        },
+        Some(FCall::RlError {
+            ecode: EACCES as u32,
+        }),
+    );
+    let (reply, _) = tokio::join!(reply, getattr);
+    assert_eq!(reply.unwrap_err().errno(), EACCES);
+
+    let walk = FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec![],
+    };
+    let wqids = vec![];
+    let (reply, _) = tokio::join!(
+        proxy.rwalk(&root, &file, &[]),
+        expect(&mut upstream, walk, Some(FCall::RWalk { wqids })),
+    );
+    reply.unwrap();
+
+    // Fids left open are clunked
+    drop(file);
+    expect(&mut upstream, FCall::TClunk { fid: 1 }, None).await;
+}
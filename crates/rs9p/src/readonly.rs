@@ -0,0 +1,264 @@
+//! Read-only view of a filesystem.
+//!
+//! [`ReadOnly`] wraps any [`Filesystem`] and answers every operation that would modify
+//! it with `EROFS`, without calling the wrapped filesystem:
+//!
+//! - creating, linking, renaming and removing files (`TlCreate`, `TSymlink`, `TMkNod`,
+//!   `TMkDir`, `TLink`, `TRename`, `TRenameAt`, `TUnlinkAt`, `TRemove`)
+//! - changing attributes (`TSetAttr`, `TxAttrCreate`)
+//! - `TWrite`, and `TlOpen` for writing or with `O_TRUNC`
+//!
+//! Everything else is forwarded as is. Since files can't be opened for writing, the
+//! wrapped filesystem never sees a fid that is.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{readonly::ReadOnly, srv::{Filesystem, srv_async}, Result};
+//! # async fn serve<Fs: 'static + Filesystem + Send + Sync + Clone>(fs: Fs) -> Result<()> {
+//! srv_async(ReadOnly::new(fs), "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        error::{self, errno::*},
+        fcall::*,
+        srv::{FId, Filesystem},
+        utils::Result,
+    },
+    async_trait::async_trait,
+};
+
+/// A [`Filesystem`] refusing all modifications of the one it wraps
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnly<Fs> {
+    inner: Fs,
+}
+
+impl<Fs> ReadOnly<Fs> {
+    /// Wrap `inner`
+    pub fn new(inner: Fs) -> ReadOnly<Fs> {
+        ReadOnly { inner }
+    }
+
+    /// The wrapped filesystem
+    pub fn inner(&self) -> &Fs {
+        &self.inner
+    }
+
+    /// Unwrap the filesystem
+    pub fn into_inner(self) -> Fs {
+        self.inner
+    }
+}
+
+/// If opening a file with `flags` could modify it
+pub fn opens_for_write(flags: u32) -> bool {
+    let flags = P9OpenFlags::from_bits_truncate(flags);
+    let access = flags & P9OpenFlags::NOACCESS;
+    access == P9OpenFlags::WRONLY
+        || access == P9OpenFlags::RDWR
+        || flags.intersects(P9OpenFlags::TRUNC | P9OpenFlags::CREATE)
+}
+
+#[async_trait]
+impl<Fs> Filesystem for ReadOnly<Fs>
+where
+    Fs: Filesystem + Sync,
+{
+    type FId = Fs::FId;
+
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        self.inner.rstatfs(fid).await
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        if opens_for_write(flags) {
+            return Err(error::Error::No(EROFS));
+        }
+        self.inner.rlopen(fid, flags).await
+    }
+
+    async fn rlcreate(
+        &self,
+        _: &FId<Self::FId>,
+        _name: &str,
+        _flags: u32,
+        _mode: u32,
+        _gid: u32,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rsymlink(
+        &self,
+        _: &FId<Self::FId>,
+        _name: &str,
+        _sym: &str,
+        _gid: u32,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rmknod(
+        &self,
+        _: &FId<Self::FId>,
+        _name: &str,
+        _mode: u32,
+        _major: u32,
+        _minor: u32,
+        _gid: u32,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rrename(&self, _: &FId<Self::FId>, _: &FId<Self::FId>, _name: &str) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        self.inner.rreadlink(fid).await
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        self.inner.rgetattr(fid, req_mask).await
+    }
+
+    async fn rsetattr(
+        &self,
+        _: &FId<Self::FId>,
+        _valid: SetAttrMask,
+        _stat: &SetAttr,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        self.inner.rxattrwalk(fid, newfid, name).await
+    }
+
+    async fn rxattrcreate(
+        &self,
+        _: &FId<Self::FId>,
+        _name: &str,
+        _attr_size: u64,
+        _flags: u32,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        self.inner.rreaddir(fid, offset, count).await
+    }
+
+    async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        self.inner.rfsync(fid).await
+    }
+
+    async fn rlock(&self, fid: &FId<Self::FId>, lock: &Flock) -> Result<FCall> {
+        self.inner.rlock(fid, lock).await
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock: &Getlock) -> Result<FCall> {
+        self.inner.rgetlock(fid, lock).await
+    }
+
+    async fn rlink(&self, _: &FId<Self::FId>, _: &FId<Self::FId>, _name: &str) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rmkdir(
+        &self,
+        _: &FId<Self::FId>,
+        _name: &str,
+        _mode: u32,
+        _gid: u32,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rrenameat(
+        &self,
+        _: &FId<Self::FId>,
+        _oldname: &str,
+        _: &FId<Self::FId>,
+        _newname: &str,
+    ) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn runlinkat(&self, _: &FId<Self::FId>, _name: &str, _flags: u32) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rauth(
+        &self,
+        afid: &FId<Self::FId>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        self.inner.rauth(afid, uname, aname, n_uname).await
+    }
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        afid: Option<&FId<Self::FId>>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<FCall> {
+        self.inner.rattach(fid, afid, uname, aname, n_uname).await
+    }
+
+    async fn rflush(&self, old: Option<&FCall>) -> Result<FCall> {
+        self.inner.rflush(old).await
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        self.inner.rwalk(fid, newfid, wnames).await
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        self.inner.rread(fid, offset, count).await
+    }
+
+    async fn rwrite(&self, _: &FId<Self::FId>, _offset: u64, _data: &Data) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        self.inner.rclunk(fid).await
+    }
+
+    async fn rremove(&self, _: &FId<Self::FId>) -> Result<FCall> {
+        Err(error::Error::No(EROFS))
+    }
+
+    async fn rversion(&self, msize: u32, ver: &str) -> Result<FCall> {
+        self.inner.rversion(msize, ver).await
+    }
+}
+
+#[test]
+fn open_flags_for_write() {
+    let flags = |f: P9OpenFlags| opens_for_write(f.bits());
+    assert!(!flags(P9OpenFlags::RDONLY));
+    assert!(!flags(
+        P9OpenFlags::RDONLY | P9OpenFlags::DIRECTORY | P9OpenFlags::NOATIME
+    ));
+    assert!(flags(P9OpenFlags::WRONLY));
+    assert!(flags(P9OpenFlags::RDWR | P9OpenFlags::APPEND));
+    assert!(flags(P9OpenFlags::RDONLY | P9OpenFlags::TRUNC));
+}
@@ -226,6 +226,9 @@ impl Encodable for Stat {
             << &self.atime
             << &self.mtime
             << &self.ctime
+            << &self.btime
+            << &self.generation
+            << &self.data_version
         {
             SResult(Ok(enc)) => Ok(enc.bytes_written()),
             SResult(Err(e)) => Err(e),
@@ -383,7 +386,7 @@ impl Encodable for Msg {
                 ref valid,
                 ref qid,
                 ref stat,
-            } => buf << &valid.bits() << qid << stat << &0u64 << &0u64 << &0u64 << &0u64,
+            } => buf << &valid.bits() << qid << stat,
             TSetAttr {
                 ref fid,
                 ref valid,
@@ -591,6 +594,9 @@ impl Decodable for Stat {
             atime: Decodable::decode(r)?,
             mtime: Decodable::decode(r)?,
             ctime: Decodable::decode(r)?,
+            btime: Decodable::decode(r)?,
+            generation: Decodable::decode(r)?,
+            data_version: Decodable::decode(r)?,
         })
     }
 }
@@ -621,10 +627,13 @@ impl Decodable for DirEntry {
 
 impl Decodable for DirEntryData {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
+        // The count is the size of the entries in bytes, not their number
         let count: u32 = Decodable::decode(r)?;
-        let mut data: Vec<DirEntry> = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            data.push(Decodable::decode(r)?);
+        let buf = read_exact(r, count as usize)?;
+        let mut entries = &buf[..];
+        let mut data: Vec<DirEntry> = Vec::new();
+        while !entries.is_empty() {
+            data.push(Decodable::decode(&mut entries)?);
         }
         Ok(DirEntryData::with(data))
     }
@@ -741,16 +750,11 @@ impl Decodable for Msg {
                 fid: decode!(buf),
                 req_mask: decode!(GetAttrMask, buf),
             },
-            Some(RGetAttr) => {
-                let r = FCall::RGetAttr {
-                    valid: decode!(GetAttrMask, buf),
-                    qid: decode!(buf),
-                    stat: decode!(buf),
-                };
-                let (_btime, _gen, _ver): (Time, u64, u64) =
-                    (decode!(buf), decode!(buf), decode!(buf));
-                r
-            }
+            Some(RGetAttr) => FCall::RGetAttr {
+                valid: decode!(GetAttrMask, buf),
+                qid: decode!(buf),
+                stat: decode!(buf),
+            },
             Some(TSetAttr) => FCall::TSetAttr {
                 fid: decode!(buf),
                 valid: decode!(SetAttrMask, buf),
@@ -938,3 +942,53 @@ fn msg_encode_decode1() {
 
     assert_eq!(expected, actual.unwrap());
 }
+
+#[test]
+fn msg_encode_decode_getattr() {
+    use std::io::Cursor;
+
+    let stat = Stat::file(42)
+        .btime(Time { sec: 10, nsec: 20 })
+        .generation(3)
+        .data_version(0xfeed);
+    let expected = Msg {
+        tag: 1,
+        body: stat.rgetattr(QId::file(9), GetAttrMask::ALL),
+    };
+    let mut buf = Vec::new();
+    let _ = expected.encode(&mut buf);
+
+    // type[1] tag[2] valid[8] qid[13] stat[...] btime[16] gen[8] data_version[8]
+    assert_eq!(
+        buf.len(),
+        1 + 2 + 8 + 13 + 4 * 3 + 8 * 5 + 16 * 3 + 16 + 8 + 8
+    );
+
+    let mut readbuf = Cursor::new(buf);
+    let actual: Msg = Decodable::decode(&mut readbuf).unwrap();
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn msg_encode_decode_readdir() {
+    use std::io::Cursor;
+
+    let entry = |name: &str, offset| DirEntry {
+        qid: QId::file(offset),
+        offset,
+        typ: DirEntryType::Reg.into(),
+        name: name.to_owned(),
+    };
+    let expected = Msg {
+        tag: 2,
+        body: FCall::RReadDir {
+            data: DirEntryData::with(vec![entry("a", 1), entry("bc", 2), entry("def", 3)]),
+        },
+    };
+    let mut buf = Vec::new();
+    let _ = expected.encode(&mut buf);
+
+    let mut readbuf = Cursor::new(buf);
+    let actual: Msg = Decodable::decode(&mut readbuf).unwrap();
+    assert_eq!(expected, actual);
+}
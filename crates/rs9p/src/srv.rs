@@ -5,29 +5,85 @@
 
 use {
     crate::{
+        codec::{DEFAULT_MAX_MSIZE, NinePCodec},
         error::{self, errno::*},
         fcall::*,
-        io_err, serialize,
+        io_err,
         utils::{self, Result},
     },
     async_trait::async_trait,
-    bytes::buf::{Buf, BufMut},
     futures::sink::SinkExt,
     std::{
         collections::HashMap,
         path::{Path, PathBuf},
-        sync::Arc,
+        sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        },
     },
     tokio::{
         io::{AsyncRead, AsyncWrite},
         net::{TcpListener, UnixListener},
-        sync::{Mutex, RwLock},
+        sync::{Mutex, RwLock, watch},
+        task::JoinHandle,
     },
     tokio_stream::StreamExt,
-    tokio_util::codec::length_delimited::LengthDelimitedCodec,
+    tokio_util::codec::{FramedRead, FramedWrite},
     tracing::{debug, error, info},
 };
 
+tokio::task_local! {
+    static MSIZE: u32;
+    static FLUSHED: watch::Receiver<bool>;
+}
+
+/// The msize negotiated by `TVersion` on the connection of the request being handled,
+/// or `None` when not called from a [`Filesystem`] method.
+pub fn msize() -> Option<u32> {
+    MSIZE.try_with(|msize| *msize).ok()
+}
+
+/// The `iounit` to return in `RlOpen`/`RlCreate` for a file whose optimal I/O size
+/// is `blksize` (`st_blksize`).
+///
+/// This is the largest multiple of `blksize` a `TRead`/`TWrite` can carry on the
+/// connection, or `msize - IOHDRSZ` if `blksize` is 0 or larger than that. Returns 0,
+/// which the server replaces with `msize - IOHDRSZ`, when not called from a
+/// [`Filesystem`] method.
+pub fn iounit(blksize: u32) -> u32 {
+    let Some(msize) = msize() else {
+        return 0;
+    };
+    let max = msize.saturating_sub(IOHDRSZ);
+    if blksize == 0 || blksize > max {
+        max
+    } else {
+        max - max % blksize
+    }
+}
+
+/// Run `fut` until the request being handled is flushed, then fail with `EINTR`
+///
+/// A flushed request otherwise runs to completion and only its reply is dropped, so
+/// that no operation is left half done. Waits which can last, like a read waiting for
+/// data, go through this to end with the flush, and must be safe to drop at any point.
+/// Just runs `fut` when not called from a [`Filesystem`] method.
+pub async fn cancellable<F: Future>(fut: F) -> Result<F::Output> {
+    let Ok(mut flushed) = FLUSHED.try_with(|flushed| flushed.clone()) else {
+        return Ok(fut.await);
+    };
+    let flushed = async move {
+        if flushed.wait_for(|flushed| *flushed).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        out = fut => Ok(out),
+        _ = flushed => Err(error::Error::No(EINTR)),
+    }
+}
+
 /// Represents a fid of clients holding associated `Filesystem::FId`.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FId<T> {
@@ -40,6 +96,14 @@ pub struct FId<T> {
 }
 
 impl<T> FId<T> {
+    /// Associate `aux` with the raw fid `fid`.
+    ///
+    /// The server creates the fids of a connection itself, this is for filesystems
+    /// forwarding requests to another `Filesystem`, like [`MultiExport`](crate::multiexport::MultiExport).
+    pub fn new(fid: u32, aux: T) -> FId<T> {
+        FId { fid, aux }
+    }
+
     /// Get the raw fid.
     pub fn fid(&self) -> u32 {
         self.fid
@@ -143,10 +207,12 @@ pub trait Filesystem: Send {
     ///
     /// # Arguments
     /// * `fid` - The file identifier to open
-    /// * `flags` - Open flags (O_RDONLY, O_WRONLY, O_RDWR, etc.)
+    /// * `flags` - Open flags in 9P2000.L encoding, see [`P9OpenFlags`]
     ///
     /// # Returns
-    /// `FCall::RLOpen` containing a qid and iounit, or an error.
+    /// `FCall::RLOpen` containing a qid and iounit, or an error. An iounit of 0, or
+    /// larger than the negotiated msize allows, is replaced with `msize - IOHDRSZ`;
+    /// see [`iounit`] to align it on the block size of the file.
     async fn rlopen(&self, _: &FId<Self::FId>, _flags: u32) -> Result<FCall> {
         Err(error::Error::No(EOPNOTSUPP))
     }
@@ -159,12 +225,13 @@ pub trait Filesystem: Send {
     /// # Arguments
     /// * `fid` - The directory fid where the file should be created
     /// * `name` - The name of the file to create
-    /// * `flags` - Open flags for the new file
+    /// * `flags` - Open flags for the new file, see [`P9OpenFlags`]
     /// * `mode` - File permissions mode
     /// * `gid` - Group ID for the new file
     ///
     /// # Returns
-    /// `FCall::RLCreate` containing a qid and iounit, or an error.
+    /// `FCall::RLCreate` containing a qid and iounit, or an error. The iounit is
+    /// handled like in [`rlopen`](Self::rlopen).
     async fn rlcreate(
         &self,
         _: &FId<Self::FId>,
@@ -303,6 +370,9 @@ pub trait Filesystem: Send {
     ///
     /// # Returns
     /// `FCall::RXAttrWalk` containing the size of the attribute, or an error.
+    ///
+    /// See [`xattr::XattrFid`](crate::xattr::XattrFid) for the state to keep in
+    /// the new fid.
     async fn rxattrwalk(
         &self,
         _: &FId<Self::FId>,
@@ -321,10 +391,13 @@ pub trait Filesystem: Send {
     /// * `fid` - The file fid to set an extended attribute on
     /// * `name` - The name of the extended attribute
     /// * `attr_size` - The size of the attribute value
-    /// * `flags` - Creation flags (XATTR_CREATE or XATTR_REPLACE)
+    /// * `flags` - Creation flags, see [`XattrFlags`]
     ///
     /// # Returns
     /// `FCall::RXAttrCreate` on success, or an error.
+    ///
+    /// The value is written with `TWrite` and applied on `TClunk`, see
+    /// [`xattr::XattrFid`](crate::xattr::XattrFid).
     async fn rxattrcreate(
         &self,
         _: &FId<Self::FId>,
@@ -374,6 +447,9 @@ pub trait Filesystem: Send {
     ///
     /// # Returns
     /// `FCall::RLock` containing lock status, or an error.
+    ///
+    /// [`lock::LockManager`](crate::lock::LockManager) implements the usual POSIX
+    /// semantics.
     async fn rlock(&self, _: &FId<Self::FId>, _lock: &Flock) -> Result<FCall> {
         Err(error::Error::No(EOPNOTSUPP))
     }
@@ -462,7 +538,7 @@ pub trait Filesystem: Send {
     /// # Arguments
     /// * `dirfid` - The parent directory fid
     /// * `name` - The name of the file or directory to remove
-    /// * `flags` - Flags such as AT_REMOVEDIR for directories
+    /// * `flags` - Flags such as `REMOVEDIR` for directories, see [`UnlinkFlags`]
     ///
     /// # Returns
     /// `FCall::RUnlinkAt` on success, or an error.
@@ -508,7 +584,8 @@ pub trait Filesystem: Send {
     /// * `fid` - The fid to associate with the filesystem root
     /// * `afid` - Optional authentication fid (if authentication was performed)
     /// * `uname` - The user name
-    /// * `aname` - The file tree to access (often "/" or empty)
+    /// * `aname` - The file tree to access (often "/" or empty), see
+    ///   [`MultiExport`](crate::multiexport::MultiExport) to serve several trees
     /// * `n_uname` - Numeric user ID
     ///
     /// # Returns
@@ -530,8 +607,10 @@ pub trait Filesystem: Send {
 
     /// Abort a pending operation (9P2000).
     ///
-    /// Requests that the server abandon a pending operation. This is typically used
-    /// to cancel long-running requests.
+    /// Requests that the server abandon a pending operation, such as a read waiting
+    /// for data. The server waits for the pending request to end before calling this,
+    /// interrupting what it runs through [`cancellable`], and drops its reply. The
+    /// default implementation just acknowledges the flush.
     ///
     /// # Arguments
     /// * `old` - The original request to cancel (if still pending)
@@ -539,7 +618,7 @@ pub trait Filesystem: Send {
     /// # Returns
     /// `FCall::RFlush` on success, or an error.
     async fn rflush(&self, _old: Option<&FCall>) -> Result<FCall> {
-        Err(error::Error::No(EOPNOTSUPP))
+        Ok(FCall::RFlush)
     }
 
     /// Walk the directory tree (9P2000).
@@ -614,6 +693,7 @@ pub trait Filesystem: Send {
     /// Remove a file and clunk the fid (9P2000).
     ///
     /// Removes the file represented by the fid from the filesystem, then clunks the fid.
+    /// The fid is clunked by the server even if the removal fails.
     /// This is an older operation; prefer `runlinkat` for new implementations.
     ///
     /// # Arguments
@@ -636,7 +716,9 @@ pub trait Filesystem: Send {
     /// * `ver` - Protocol version string (e.g., "9P2000.L")
     ///
     /// # Returns
-    /// `FCall::RVersion` with the negotiated msize and version.
+    /// `FCall::RVersion` with the negotiated msize and version. The server lowers the
+    /// msize to the one asked by the client if it is larger, and to what the server
+    /// accepts.
     async fn rversion(&self, msize: u32, ver: &str) -> Result<FCall> {
         Ok(FCall::RVersion {
             msize,
@@ -648,12 +730,16 @@ pub trait Filesystem: Send {
     }
 }
 
+// The fids of a connection, shared with the requests using them
+type FIds<T> = RwLock<HashMap<u32, Arc<FId<T>>>>;
+
 #[rustfmt::skip]
 async fn dispatch_once<Fs, FsFId>(
     msg: &Msg,
     fs: Arc<Fs>,
-    fsfids: Arc<RwLock<HashMap<u32, FId<FsFId>>>>,
-) -> Result<FCall>
+    fsfids: Arc<FIds<FsFId>>,
+    flushed: &watch::Receiver<bool>,
+) -> Result<Option<FCall>>
 where
     Fs: Filesystem<FId = FsFId> + Send + Sync,
     FsFId: Send + Sync + Default,
@@ -665,8 +751,14 @@ where
 
     use crate::FCall::*;
     let response = {
-        let fids = fsfids.read().await;
-        let get_fid = |fid: &u32| fids.get(fid).ok_or(error::Error::No(EBADF));
+        // Not holding the lock while handling, a request can wait for long
+        let fids: HashMap<u32, Arc<FId<FsFId>>> = {
+            let fids = fsfids.read().await;
+            msg.body.fids().into_iter()
+                .filter_map(|fid| Some((fid, fids.get(&fid)?.clone())))
+                .collect()
+        };
+        let get_fid = |fid: &u32| fids.get(fid).map(|fid| &**fid).ok_or(error::Error::No(EBADF));
         let get_newfid = || newfid.as_ref().ok_or(error::Error::No(EPROTO));
 
         let fut = match msg.body {
@@ -690,7 +782,7 @@ where
             TRenameAt { olddirfid, ref oldname, newdirfid, ref newname }        => fs.rrenameat(get_fid(&olddirfid)?, oldname, get_fid(&newdirfid)?, newname),
             TUnlinkAt { dirfd, ref name, ref flags }                            => fs.runlinkat(get_fid(&dirfd)?, name, *flags) ,
             TAuth { afid: _, ref uname, ref aname, ref n_uname }                => fs.rauth(get_newfid()?, uname, aname, *n_uname),
-            TAttach { fid: _, afid: _, ref uname, ref aname, ref n_uname }      => fs.rattach(get_newfid()?, None, uname, aname, *n_uname),
+            TAttach { fid: _, afid, ref uname, ref aname, ref n_uname }         => fs.rattach(get_newfid()?, if afid == NOFID { None } else { Some(get_fid(&afid)?) }, uname, aname, *n_uname),
             TVersion { ref msize, ref version }                                 => fs.rversion(*msize, version),
             TFlush { oldtag: _ }                                                => fs.rflush(None),
             TWalk { fid, newfid: _, ref wnames }                                => fs.rwalk(get_fid(&fid)?, get_newfid()?, wnames),
@@ -701,21 +793,76 @@ where
             _                                                                   => return Err(error::Error::No(EOPNOTSUPP)),
         };
 
-        fut.await?
+        fut.await
     };
 
-    /* Drop the fid which the TClunk contains */
-    if let TClunk { fid } = msg.body {
+    /* Drop the fid which the TClunk or TRemove contains, even if the operation failed */
+    if let TClunk { fid } | TRemove { fid } = msg.body {
         let mut fids = fsfids.write().await;
         fids.remove(&fid);
     }
 
+    let response = response?;
+
+    // The reply of a flushed request is dropped, the fid it created goes with it
+    if *flushed.borrow() {
+        return Ok(None);
+    }
+
     if let Some(newfid) = newfid {
         let mut fids = fsfids.write().await;
-        fids.insert(newfid.fid, newfid);
+        fids.insert(newfid.fid, Arc::new(newfid));
     }
 
-    Ok(response)
+    Ok(Some(response))
+}
+
+// A request being handled, and the flag telling it it was flushed
+struct Task {
+    id: u64,
+    handle: JoinHandle<()>,
+    flushed: watch::Sender<bool>,
+}
+
+impl Task {
+    // Flag the request as flushed, and wait for it to end
+    async fn flush(self) {
+        self.flushed.send_replace(true);
+        let _ = self.handle.await;
+    }
+}
+
+// The tasks handling the requests of a connection, by tag, so they can be flushed
+//
+// Each task is numbered, as a tag can be reused once its reply is sent, which is
+// before the task removes itself.
+#[derive(Default)]
+struct InFlight {
+    tasks: std::sync::Mutex<HashMap<u16, Task>>,
+}
+
+impl InFlight {
+    fn tasks(&self) -> std::sync::MutexGuard<'_, HashMap<u16, Task>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn take(&self, tag: u16) -> Option<Task> {
+        self.tasks().remove(&tag)
+    }
+
+    fn finish(&self, tag: u16, id: u64) {
+        let mut tasks = self.tasks();
+        if tasks.get(&tag).is_some_and(|task| task.id == id) {
+            tasks.remove(&tag);
+        }
+    }
+
+    // Flush the requests without waiting for them, they end in the background
+    fn flush_all(&self) {
+        for (_, task) in self.tasks().drain() {
+            task.flushed.send_replace(true);
+        }
+    }
 }
 
 async fn dispatch<Fs, Reader, Writer>(filesystem: Fs, reader: Reader, writer: Writer) -> Result<()>
@@ -726,65 +873,133 @@ where
 {
     let fsfids = Arc::new(RwLock::new(HashMap::new()));
     let filesystem = Arc::new(filesystem);
+    // Until the client sends TVersion, only bounded by what the codec accepts
+    let msize = Arc::new(AtomicU32::new(DEFAULT_MAX_MSIZE));
+    let inflight = Arc::new(InFlight::default());
 
-    let mut framedread = LengthDelimitedCodec::builder()
-        .length_field_offset(0)
-        .length_field_length(4)
-        .length_adjustment(-4)
-        .little_endian()
-        .new_read(reader);
-    let framedwrite = LengthDelimitedCodec::builder()
-        .length_field_offset(0)
-        .length_field_length(4)
-        .length_adjustment(-4)
-        .little_endian()
-        .new_write(writer);
+    let mut framedread = FramedRead::new(reader, NinePCodec::new());
+    let framedwrite = FramedWrite::new(writer, NinePCodec::new());
     let framedwrite = Arc::new(Mutex::new(framedwrite));
 
-    while let Some(bytes) = framedread.next().await {
-        let bytes = bytes?;
-
-        let msg = serialize::read_msg(&mut bytes.reader())?;
-        debug!("\t← {:?}", msg);
-
-        let fids = fsfids.clone();
-        let fs = filesystem.clone();
-        let framedwrite = framedwrite.clone();
+    let res = async {
+        let mut next_id = 0;
+        while let Some(msg) = framedread.next().await {
+            let msg = msg?;
+            debug!("\t← {}", msg);
+
+            let fids = fsfids.clone();
+            let fs = filesystem.clone();
+            let framedwrite = framedwrite.clone();
+            let msize = msize.clone();
+            let (tag, id) = (msg.tag, next_id);
+            next_id += 1;
+
+            // Answered before reading on, so that the next requests are decoded with the
+            // negotiated msize
+            if let FCall::TVersion { .. } = msg.body {
+                let (_, rx) = watch::channel(false);
+                handle(msg, fs, fids, framedwrite, msize.clone(), rx).await;
+                let msize = msize.load(Ordering::Relaxed);
+                framedread.decoder_mut().set_max_msize(msize);
+                continue;
+            }
 
-        tokio::spawn(async move {
-            let response_fcall = dispatch_once(&msg, fs, fids).await.unwrap_or_else(|e| {
-                error!("{:?}: Error: \"{}\": {:?}", MsgType::from(&msg.body), e, e);
-                FCall::RlError {
-                    ecode: e.errno() as u32,
+            // Taken now, so requests arriving after the flush are not affected
+            let flushed = match msg.body {
+                FCall::TFlush { oldtag } => inflight.take(oldtag),
+                _ => None,
+            };
+
+            let (tx, rx) = watch::channel(false);
+            let mut tasks = inflight.tasks();
+            let handle = tokio::spawn({
+                let inflight = inflight.clone();
+                async move {
+                    if let Some(flushed) = flushed {
+                        // Once it is gone, its reply was either sent or never will be
+                        flushed.flush().await;
+                    }
+                    handle(msg, fs, fids, framedwrite, msize, rx).await;
+                    inflight.finish(tag, id);
                 }
             });
+            tasks.insert(
+                tag,
+                Task {
+                    id,
+                    handle,
+                    flushed: tx,
+                },
+            );
+        }
+        Ok(())
+    }
+    .await;
 
-            if MsgType::from(&response_fcall).is_r() {
-                let response = Msg {
-                    tag: msg.tag,
-                    body: response_fcall,
-                };
+    // Nobody is left to read the replies, interrupt the requests still waiting
+    inflight.flush_all();
+    res
+}
 
-                let mut writer = bytes::BytesMut::with_capacity(4096).writer();
-                if let Err(e) = serialize::write_msg(&mut writer, &response) {
-                    error!("Failed to serialize response for tag {}: {:?}", msg.tag, e);
-                    return;
-                }
+async fn handle<Fs, Writer>(
+    msg: Msg,
+    fs: Arc<Fs>,
+    fids: Arc<FIds<Fs::FId>>,
+    framedwrite: Arc<Mutex<FramedWrite<Writer, NinePCodec>>>,
+    msize: Arc<AtomicU32>,
+    flushed: watch::Receiver<bool>,
+) where
+    Fs: 'static + Filesystem + Send + Sync,
+    Writer: 'static + AsyncWrite + Send + std::marker::Unpin,
+{
+    let negotiated = msize.load(Ordering::Relaxed);
+    let response = MSIZE
+        .scope(
+            negotiated,
+            FLUSHED.scope(flushed.clone(), dispatch_once(&msg, fs, fids, &flushed)),
+        )
+        .await;
+    let mut response_fcall = match response {
+        Ok(Some(response)) => response,
+        Ok(None) => return,
+        Err(_) if *flushed.borrow() => return,
+        Err(e) => {
+            error!("{:?}: Error: \"{}\": {:?}", MsgType::from(&msg.body), e, e);
+            FCall::RlError {
+                ecode: e.errno() as u32,
+            }
+        }
+    };
 
-                let frozen = writer.into_inner().freeze();
-                {
-                    let mut framedwrite_locked = framedwrite.lock().await;
-                    if let Err(e) = framedwrite_locked.send(frozen).await {
-                        error!("Failed to send response for tag {}: {:?}", msg.tag, e);
-                        return;
-                    }
-                }
-                debug!("\t→ {:?}", response);
+    match (&msg.body, &mut response_fcall) {
+        (FCall::TVersion { msize: asked, .. }, FCall::RVersion { msize: reply, .. }) => {
+            *reply = (*reply).min(*asked).min(DEFAULT_MAX_MSIZE);
+            msize.store(*reply, Ordering::Relaxed);
+        }
+        (_, FCall::RlOpen { iounit, .. } | FCall::RlCreate { iounit, .. }) => {
+            let max = negotiated.saturating_sub(IOHDRSZ);
+            if *iounit == 0 || *iounit > max {
+                *iounit = max;
             }
-        });
+        }
+        _ => (),
     }
 
-    Ok(())
+    if MsgType::from(&response_fcall).is_r() {
+        let response = Msg {
+            tag: msg.tag,
+            body: response_fcall,
+        };
+
+        {
+            let mut framedwrite_locked = framedwrite.lock().await;
+            if let Err(e) = framedwrite_locked.send(&response).await {
+                error!("Failed to send response for tag {}: {:?}", msg.tag, e);
+                return;
+            }
+        }
+        debug!("\t→ {}", response);
+    }
 }
 
 pub async fn srv_async_tcp<Fs>(filesystem: Fs, addr: &str) -> Result<()>
@@ -883,3 +1098,421 @@ where
         _ => Err(From::from(io_err!(InvalidInput, "Protocol not supported"))),
     }
 }
+
+#[tokio::test]
+async fn iounit_alignment() {
+    assert_eq!(msize(), None);
+    assert_eq!(iounit(4096), 0);
+
+    MSIZE
+        .scope(8192 + IOHDRSZ, async {
+            assert_eq!(iounit(0), 8192);
+            assert_eq!(iounit(4096), 8192);
+            assert_eq!(iounit(3000), 6000);
+            assert_eq!(iounit(1 << 20), 8192);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn negotiated_msize_bounds_requests() {
+    use tokio_util::codec::Framed;
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {
+        type FId = ();
+    }
+
+    let (client, server) = tokio::io::duplex(8192);
+    let (reader, writer) = tokio::io::split(server);
+    let server = tokio::spawn(dispatch(Empty, reader, writer));
+    let mut client = Framed::new(client, NinePCodec::new());
+
+    let version = FCall::TVersion {
+        msize: 512,
+        version: P92000L.to_owned(),
+    };
+    client
+        .send(Msg {
+            tag: NOTAG,
+            body: version,
+        })
+        .await
+        .unwrap();
+    let reply = client.next().await.unwrap().unwrap();
+    assert!(matches!(reply.body, FCall::RVersion { msize: 512, .. }));
+
+    let write = FCall::TWrite {
+        fid: 0,
+        offset: 0,
+        data: Data(vec![0; 1024]),
+    };
+    client
+        .send(Msg {
+            tag: 1,
+            body: write,
+        })
+        .await
+        .unwrap();
+    assert!(client.next().await.is_none());
+    assert!(server.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn flush_cancels_request() {
+    use tokio_util::codec::Framed;
+
+    // Reads never complete, until flushed
+    struct Stall;
+
+    #[async_trait]
+    impl Filesystem for Stall {
+        type FId = ();
+
+        async fn rattach(
+            &self,
+            _: &FId<Self::FId>,
+            _: Option<&FId<Self::FId>>,
+            _: &str,
+            _: &str,
+            _: u32,
+        ) -> Result<FCall> {
+            Ok(FCall::RAttach { qid: QId::dir(1) })
+        }
+
+        async fn rread(&self, _: &FId<Self::FId>, _: u64, _: u32) -> Result<FCall> {
+            cancellable(std::future::pending()).await?
+        }
+
+        async fn rclunk(&self, _: &FId<Self::FId>) -> Result<FCall> {
+            Ok(FCall::RClunk)
+        }
+    }
+
+    let (client, server) = tokio::io::duplex(8192);
+    let (reader, writer) = tokio::io::split(server);
+    let server = tokio::spawn(dispatch(Stall, reader, writer));
+    let mut client = Framed::new(client, NinePCodec::new());
+
+    let requests = [
+        (
+            1,
+            FCall::TAttach {
+                fid: 0,
+                afid: NOFID,
+                uname: String::new(),
+                aname: String::new(),
+                n_uname: 0,
+            },
+        ),
+        (
+            2,
+            FCall::TRead {
+                fid: 0,
+                offset: 0,
+                count: 10,
+            },
+        ),
+        (3, FCall::TFlush { oldtag: 2 }),
+        // Tags of flushed requests can be reused
+        (2, FCall::TClunk { fid: 0 }),
+    ];
+    let mut replies = Vec::new();
+    for (tag, body) in requests {
+        client.send(Msg { tag, body }).await.unwrap();
+        if tag != 2 || replies.len() == 2 {
+            let reply = client.next().await.unwrap().unwrap();
+            replies.push((reply.tag, reply.body));
+        }
+    }
+    assert_eq!(
+        replies,
+        [
+            (1, FCall::RAttach { qid: QId::dir(1) }),
+            (3, FCall::RFlush),
+            (2, FCall::RClunk),
+        ]
+    );
+
+    drop(client);
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn flush_lets_request_finish() {
+    use {
+        std::{sync::atomic::AtomicBool, time::Duration},
+        tokio::sync::Notify,
+        tokio_util::codec::Framed,
+    };
+
+    // Walks wait for the gate, and can't be interrupted
+    #[derive(Default)]
+    struct Slow {
+        gate: Notify,
+        walked: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Filesystem for Arc<Slow> {
+        type FId = ();
+
+        async fn rattach(
+            &self,
+            _: &FId<Self::FId>,
+            _: Option<&FId<Self::FId>>,
+            _: &str,
+            _: &str,
+            _: u32,
+        ) -> Result<FCall> {
+            Ok(FCall::RAttach { qid: QId::dir(1) })
+        }
+
+        async fn rwalk(
+            &self,
+            _: &FId<Self::FId>,
+            _: &FId<Self::FId>,
+            _: &[String],
+        ) -> Result<FCall> {
+            self.gate.notified().await;
+            self.walked.store(true, Ordering::Relaxed);
+            Ok(FCall::RWalk { wqids: vec![] })
+        }
+
+        async fn rclunk(&self, _: &FId<Self::FId>) -> Result<FCall> {
+            Ok(FCall::RClunk)
+        }
+    }
+
+    let fs = Arc::new(Slow::default());
+    let (client, server) = tokio::io::duplex(8192);
+    let (reader, writer) = tokio::io::split(server);
+    tokio::spawn(dispatch(fs.clone(), reader, writer));
+    let mut client = Framed::new(client, NinePCodec::new());
+
+    let attach = FCall::TAttach {
+        fid: 0,
+        afid: NOFID,
+        uname: String::new(),
+        aname: String::new(),
+        n_uname: 0,
+    };
+    client
+        .send(Msg {
+            tag: 1,
+            body: attach,
+        })
+        .await
+        .unwrap();
+    client.next().await.unwrap().unwrap();
+    let walk = FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec![],
+    };
+    client.send(Msg { tag: 2, body: walk }).await.unwrap();
+    let flush = FCall::TFlush { oldtag: 2 };
+    client
+        .send(Msg {
+            tag: 3,
+            body: flush,
+        })
+        .await
+        .unwrap();
+
+    // The flush waits for the walk, whose reply and fid are dropped
+    let early = tokio::time::timeout(Duration::from_millis(100), client.next()).await;
+    assert!(early.is_err());
+    fs.gate.notify_one();
+    let reply = client.next().await.unwrap().unwrap();
+    assert_eq!((reply.tag, reply.body), (3, FCall::RFlush));
+    assert!(fs.walked.load(Ordering::Relaxed));
+
+    let clunk = FCall::TClunk { fid: 1 };
+    client
+        .send(Msg {
+            tag: 4,
+            body: clunk,
+        })
+        .await
+        .unwrap();
+    let reply = client.next().await.unwrap().unwrap();
+    assert_eq!(
+        reply.body,
+        FCall::RlError {
+            ecode: EBADF as u32
+        }
+    );
+}
+
+#[tokio::test]
+async fn attach_with_afid() {
+    use tokio_util::codec::Framed;
+
+    // Attaches need the fid of an authentication
+    struct Auth;
+
+    #[async_trait]
+    impl Filesystem for Auth {
+        type FId = ();
+
+        async fn rauth(&self, _: &FId<Self::FId>, _: &str, _: &str, _: u32) -> Result<FCall> {
+            Ok(FCall::RAuth { aqid: QId::file(2) })
+        }
+
+        async fn rattach(
+            &self,
+            _: &FId<Self::FId>,
+            afid: Option<&FId<Self::FId>>,
+            _: &str,
+            _: &str,
+            _: u32,
+        ) -> Result<FCall> {
+            match afid {
+                Some(afid) if afid.fid() == 5 => Ok(FCall::RAttach { qid: QId::dir(1) }),
+                _ => Err(error::Error::No(EACCES)),
+            }
+        }
+    }
+
+    let (client, server) = tokio::io::duplex(8192);
+    let (reader, writer) = tokio::io::split(server);
+    tokio::spawn(dispatch(Auth, reader, writer));
+    let mut client = Framed::new(client, NinePCodec::new());
+
+    let attach = |fid, afid| FCall::TAttach {
+        fid,
+        afid,
+        uname: String::new(),
+        aname: String::new(),
+        n_uname: 0,
+    };
+    let auth = FCall::TAuth {
+        afid: 5,
+        uname: String::new(),
+        aname: String::new(),
+        n_uname: 0,
+    };
+    let requests = [attach(0, NOFID), attach(0, 6), auth, attach(0, 5)];
+    let mut replies = Vec::new();
+    for (tag, body) in requests.into_iter().enumerate() {
+        client
+            .send(Msg {
+                tag: tag as u16,
+                body,
+            })
+            .await
+            .unwrap();
+        replies.push(client.next().await.unwrap().unwrap().body);
+    }
+    assert_eq!(
+        replies,
+        [
+            FCall::RlError {
+                ecode: EACCES as u32
+            },
+            FCall::RlError {
+                ecode: EBADF as u32
+            },
+            FCall::RAuth { aqid: QId::file(2) },
+            FCall::RAttach { qid: QId::dir(1) },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn blocked_read_leaves_fids_usable() {
+    use {
+        crate::synth::{Events, Synth, SynthFile},
+        std::time::Duration,
+        tokio_util::codec::Framed,
+    };
+
+    let events = Events::new(4);
+    let fs = Synth::new()
+        .events("log", events.clone())
+        .file("status", SynthFile::new().read(|| async { "ok\n".into() }));
+    let (client, server) = tokio::io::duplex(8192);
+    let (reader, writer) = tokio::io::split(server);
+    tokio::spawn(dispatch(fs, reader, writer));
+    let mut client = Framed::new(client, NinePCodec::new());
+
+    let walk = |newfid, name: &str| FCall::TWalk {
+        fid: 0,
+        newfid,
+        wnames: vec![name.to_owned()],
+    };
+    let open = |fid| FCall::TlOpen { fid, flags: 0 };
+    let requests = [
+        FCall::TAttach {
+            fid: 0,
+            afid: NOFID,
+            uname: String::new(),
+            aname: String::new(),
+            n_uname: 0,
+        },
+        walk(1, "log"),
+        open(1),
+    ];
+    for (tag, body) in requests.into_iter().enumerate() {
+        client
+            .send(Msg {
+                tag: tag as u16,
+                body,
+            })
+            .await
+            .unwrap();
+        let reply = client.next().await.unwrap().unwrap();
+        assert!(MsgType::from(&reply.body).is_r(), "{}", reply.body);
+    }
+    let read = FCall::TRead {
+        fid: 1,
+        offset: 0,
+        count: 100,
+    };
+    client
+        .send(Msg {
+            tag: 10,
+            body: read,
+        })
+        .await
+        .unwrap();
+
+    // Other fids are created, used and clunked while the read waits
+    let requests = [
+        walk(2, "status"),
+        FCall::TGetAttr {
+            fid: 2,
+            req_mask: GetAttrMask::ALL,
+        },
+        open(2),
+        FCall::TClunk { fid: 2 },
+    ];
+    for (tag, body) in requests.into_iter().enumerate() {
+        let tag = 20 + tag as u16;
+        client.send(Msg { tag, body }).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(2), client.next())
+            .await
+            .expect("no reply while a read is blocked")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.tag, tag);
+        assert!(
+            !matches!(reply.body, FCall::RlError { .. }),
+            "{}",
+            reply.body
+        );
+    }
+
+    events.send("event\n");
+    let reply = client.next().await.unwrap().unwrap();
+    assert_eq!(reply.tag, 10);
+    assert_eq!(
+        reply.body,
+        FCall::RRead {
+            data: Data(b"event\n".to_vec())
+        }
+    );
+}
@@ -0,0 +1,752 @@
+//! Synthetic files backed by closures.
+//!
+//! Plan 9 style servers export their state and controls as small files: reading
+//! `status` reports something, writing a command to `ctl` does something. [`Synth`]
+//! builds such a tree from a description, taking care of walking, attributes and
+//! directory listings:
+//!
+//! - A file with a `read` closure calls it when opened for reading, and serves the
+//!   returned bytes to the reads on that fid. A client reading in several requests thus
+//!   sees a consistent snapshot, and opening the file again gets a fresh one.
+//! - A file with a `write` closure passes it the data of each `TWrite`. With
+//!   [`lines`](SynthFile::lines) the writes are buffered instead, and the closure gets
+//!   each complete line without its newline; a last unterminated line is passed when
+//!   the fid is clunked. Errors of the closure are returned to the client.
+//! - Files are reported with a size of 0, as their contents only exist once read.
+//!
+//! [`Events`] files stream data instead, like an event log: each fid opening one reads
+//! what is [sent](Events::send) from then on, a read waiting until something comes.
+//! Offsets are ignored. A waiting read ends when the request is flushed, when the fid
+//! is clunked (failing with `EINTR`) or when the client disconnects. A reader too slow
+//! to keep up with the capacity given to [`Events::new`] misses the oldest data.
+//!
+//! # Example
+//! ```no_run
+//! # use rs9p::{synth::{Synth, SynthFile}, srv::srv_async, Result};
+//! # use bytes::Bytes;
+//! # use rs9p::synth::Events;
+//! # async fn serve() -> Result<()> {
+//! let log = Events::new(64);
+//! let fs = Synth::new()
+//!     .file(
+//!         "status",
+//!         SynthFile::new().read(|| async { Bytes::from("running\n") }),
+//!     )
+//!     .file(
+//!         "net/ctl",
+//!         SynthFile::new()
+//!             .write(|line| async move {
+//!                 println!("command: {:?}", line);
+//!                 Ok(())
+//!             })
+//!             .lines(),
+//!     )
+//!     .events("log", log.clone());
+//! log.send("started\n");
+//! srv_async(fs, "tcp!0.0.0.0!564").await
+//! # }
+//! ```
+
+use {
+    crate::{
+        error::{self, errno::*},
+        fcall::*,
+        srv::{self, FId, Filesystem},
+        utils::Result,
+    },
+    async_trait::async_trait,
+    bytes::Bytes,
+    futures::future::BoxFuture,
+    std::{collections::BTreeMap, future::Future, sync::Arc},
+    tokio::sync::{Mutex, broadcast, watch},
+};
+
+type ReadFn = Arc<dyn Fn() -> BoxFuture<'static, Bytes> + Send + Sync>;
+type WriteFn = Arc<dyn Fn(Bytes) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A synthetic file, see the [module](self) documentation
+#[derive(Clone, Default)]
+pub struct SynthFile {
+    read: Option<ReadFn>,
+    write: Option<WriteFn>,
+    lines: bool,
+    mode: Option<u32>,
+}
+
+impl std::fmt::Debug for SynthFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SynthFile")
+            .field("read", &self.read.is_some())
+            .field("write", &self.write.is_some())
+            .field("lines", &self.lines)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl SynthFile {
+    /// A file which can be neither read nor written, until given closures
+    pub fn new() -> SynthFile {
+        Default::default()
+    }
+
+    /// Produce the contents of the file each time it is opened for reading
+    pub fn read<F, Fut>(mut self, read: F) -> SynthFile
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Bytes> + Send + 'static,
+    {
+        self.read = Some(Arc::new(move || Box::pin(read())));
+        self
+    }
+
+    /// Receive the data written to the file
+    pub fn write<F, Fut>(mut self, write: F) -> SynthFile
+    where
+        F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.write = Some(Arc::new(move |data| Box::pin(write(data))));
+        self
+    }
+
+    /// Pass the written data to the `write` closure one line at a time
+    pub fn lines(mut self) -> SynthFile {
+        self.lines = true;
+        self
+    }
+
+    /// Set the permission bits, by default read and write bits for the closures given
+    pub fn mode(mut self, mode: u32) -> SynthFile {
+        self.mode = Some(mode & 0o777);
+        self
+    }
+
+    fn permissions(&self) -> u32 {
+        self.mode.unwrap_or_else(|| {
+            let read = if self.read.is_some() { 0o444 } else { 0 };
+            let write = if self.write.is_some() { 0o200 } else { 0 };
+            read | write
+        })
+    }
+}
+
+/// A file streaming events to its readers, see the [module](self) documentation
+///
+/// Clones send to the same readers.
+#[derive(Clone, Debug)]
+pub struct Events {
+    tx: broadcast::Sender<Bytes>,
+}
+
+impl Events {
+    /// A stream keeping up to `capacity` messages for the slowest reader
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    pub fn new(capacity: usize) -> Events {
+        Events {
+            tx: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Send `data` to all the fids which have the file open
+    ///
+    /// Returns how many fids will read it.
+    pub fn send(&self, data: impl Into<Bytes>) -> usize {
+        self.tx.send(data.into()).unwrap_or(0)
+    }
+}
+
+// The position of an open fid in an events stream
+#[derive(Debug)]
+struct Cursor {
+    // The receiver, and the rest of a message larger than a read
+    rx: Mutex<(broadcast::Receiver<Bytes>, Bytes)>,
+    closed: watch::Sender<bool>,
+}
+
+impl Cursor {
+    async fn next(&self, count: u32) -> Bytes {
+        let mut rx = self.rx.lock().await;
+        let (rx, pending) = &mut *rx;
+        while pending.is_empty() {
+            match rx.recv().await {
+                Ok(data) => *pending = data,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Bytes::new(),
+            }
+        }
+        pending.split_to(pending.len().min(count as usize))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Entry {
+    Dir(BTreeMap<String, usize>),
+    File(Arc<SynthFile>),
+    Events(Events),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    parent: usize,
+    entry: Entry,
+}
+
+/// A [`Filesystem`] of synthetic files, see the [module](self) documentation
+///
+/// Files are identified by their index in the tree: the root is 0, and its qid path
+/// is 1.
+#[derive(Clone, Debug)]
+pub struct Synth {
+    nodes: Vec<Node>,
+    uid: u32,
+    gid: u32,
+    time: Time,
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Synth::new()
+    }
+}
+
+impl Synth {
+    /// An empty tree, owned by uid and gid 0
+    pub fn new() -> Synth {
+        Synth {
+            nodes: vec![Node {
+                parent: 0,
+                entry: Entry::Dir(BTreeMap::new()),
+            }],
+            uid: 0,
+            gid: 0,
+            time: Time::now(),
+        }
+    }
+
+    /// Set the owner reported for all the files
+    pub fn owner(mut self, uid: u32, gid: u32) -> Synth {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Add the directory at `path`, and its parents, if they don't exist
+    ///
+    /// # Panics
+    /// If a file is in the way.
+    pub fn dir(mut self, path: &str) -> Synth {
+        let components: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.mkdirs(path, &components);
+        self
+    }
+
+    /// Add `file` at `path`, creating its parent directories
+    ///
+    /// # Panics
+    /// If something is already at `path`, or a file is in the way.
+    pub fn file(self, path: &str, file: SynthFile) -> Synth {
+        self.insert(path, Entry::File(Arc::new(file)))
+    }
+
+    /// Add an [`Events`] file at `path`, creating its parent directories
+    ///
+    /// # Panics
+    /// If something is already at `path`, or a file is in the way.
+    pub fn events(self, path: &str, events: Events) -> Synth {
+        self.insert(path, Entry::Events(events))
+    }
+
+    fn insert(mut self, path: &str, entry: Entry) -> Synth {
+        let components: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let (name, parents) = components
+            .split_last()
+            .unwrap_or_else(|| panic!("synthetic file without a name: {:?}", path));
+        let parent = self.mkdirs(path, parents);
+
+        let index = self.nodes.len();
+        let Entry::Dir(entries) = &mut self.nodes[parent].entry else {
+            unreachable!()
+        };
+        if entries.insert(name.to_string(), index).is_some() {
+            panic!("synthetic file {:?} already exists", path);
+        }
+        self.nodes.push(Node { parent, entry });
+        self
+    }
+
+    // The index of the directory at `components`, created as needed
+    fn mkdirs(&mut self, path: &str, components: &[&str]) -> usize {
+        let mut dir = 0;
+        for name in components {
+            let next = self.nodes.len();
+            let Entry::Dir(entries) = &mut self.nodes[dir].entry else {
+                panic!("{:?} is beneath a synthetic file", path);
+            };
+            match entries.get(*name) {
+                Some(&index) => dir = index,
+                None => {
+                    entries.insert(name.to_string(), next);
+                    self.nodes.push(Node {
+                        parent: dir,
+                        entry: Entry::Dir(BTreeMap::new()),
+                    });
+                    dir = next;
+                }
+            }
+        }
+        if !matches!(self.nodes[dir].entry, Entry::Dir(_)) {
+            panic!("{:?} is beneath a synthetic file", path);
+        }
+        dir
+    }
+
+    fn qid(&self, index: usize) -> QId {
+        let path = index as u64 + 1;
+        match self.nodes[index].entry {
+            Entry::Dir(_) => QId::dir(path),
+            Entry::File(_) | Entry::Events(_) => QId::file(path),
+        }
+    }
+
+    fn stat(&self, index: usize) -> Stat {
+        let (mode, nlink) = match &self.nodes[index].entry {
+            Entry::Dir(_) => (FileMode::IFDIR.bits() | 0o555, 2),
+            Entry::File(file) => (FileMode::IFREG.bits() | file.permissions(), 1),
+            Entry::Events(_) => (FileMode::IFREG.bits() | 0o444, 1),
+        };
+        Stat {
+            mode,
+            uid: self.uid,
+            gid: self.gid,
+            nlink,
+            blksize: 4096,
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+            btime: self.time,
+            ..Default::default()
+        }
+    }
+
+    fn synth_file(&self, index: usize) -> Result<&Arc<SynthFile>> {
+        match &self.nodes[index].entry {
+            Entry::File(file) => Ok(file),
+            Entry::Events(_) => Err(error::Error::No(EBADF)),
+            Entry::Dir(_) => Err(error::Error::No(EISDIR)),
+        }
+    }
+}
+
+/// Fid of a [`Synth`]
+#[derive(Debug, Default)]
+pub struct SynthFId {
+    state: Mutex<Option<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    index: usize,
+    open: Option<Open>,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    // Contents returned by `read` when opened
+    snapshot: Option<Bytes>,
+    // Reading position of an events file
+    cursor: Option<Arc<Cursor>>,
+    writable: bool,
+    // Written data not forming a complete line yet
+    line: Vec<u8>,
+}
+
+impl SynthFId {
+    async fn index(&self) -> Result<usize> {
+        match &*self.state.lock().await {
+            Some(state) => Ok(state.index),
+            None => Err(error::Error::No(EBADF)),
+        }
+    }
+}
+
+#[async_trait]
+impl Filesystem for Synth {
+    type FId = SynthFId;
+
+    async fn rattach(
+        &self,
+        fid: &FId<Self::FId>,
+        _afid: Option<&FId<Self::FId>>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> Result<FCall> {
+        *fid.aux.state.lock().await = Some(State {
+            index: 0,
+            open: None,
+        });
+        Ok(FCall::RAttach { qid: self.qid(0) })
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        let mut index = fid.aux.index().await?;
+        let mut wqids = Vec::new();
+        for name in wnames {
+            let next = match (&self.nodes[index].entry, name.as_str()) {
+                (Entry::File(_) | Entry::Events(_), _) => Err(error::Error::No(ENOTDIR)),
+                (Entry::Dir(_), ".") => Ok(index),
+                (Entry::Dir(_), "..") => Ok(self.nodes[index].parent),
+                (Entry::Dir(entries), name) => {
+                    entries.get(name).copied().ok_or(error::Error::No(ENOENT))
+                }
+            };
+            match next {
+                Ok(next) => {
+                    index = next;
+                    wqids.push(self.qid(index));
+                }
+                Err(e) if wqids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if wqids.len() == wnames.len() {
+            *newfid.aux.state.lock().await = Some(State { index, open: None });
+        }
+        Ok(FCall::RWalk { wqids })
+    }
+
+    async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
+        let index = fid.aux.index().await?;
+        Ok(self.stat(index).rgetattr(self.qid(index), req_mask))
+    }
+
+    async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
+        let flags = P9OpenFlags::from_bits_truncate(flags);
+        let mut state = fid.aux.state.lock().await;
+        let state = state.as_mut().ok_or(error::Error::No(EBADF))?;
+        let (readable, writable) = match flags.access_mode() {
+            P9OpenFlags::RDONLY => (true, false),
+            P9OpenFlags::WRONLY => (false, true),
+            P9OpenFlags::RDWR => (true, true),
+            _ => (false, false),
+        };
+
+        let mut open = Open {
+            writable,
+            ..Default::default()
+        };
+        let file = match &self.nodes[state.index].entry {
+            Entry::Dir(_) if writable => return Err(error::Error::No(EISDIR)),
+            Entry::Dir(_) => None,
+            Entry::Events(_) if writable => return Err(error::Error::No(EACCES)),
+            Entry::Events(events) => {
+                open.cursor = Some(Arc::new(Cursor {
+                    rx: Mutex::new((events.tx.subscribe(), Bytes::new())),
+                    closed: watch::Sender::new(false),
+                }));
+                None
+            }
+            Entry::File(file) => Some(file),
+        };
+        if let Some(file) = file {
+            if (readable && file.read.is_none()) || (writable && file.write.is_none()) {
+                return Err(error::Error::No(EACCES));
+            }
+            if let Some(read) = file.read.as_ref().filter(|_| readable) {
+                open.snapshot = Some(read().await);
+            }
+        }
+
+        state.open = Some(open);
+        Ok(FCall::RlOpen {
+            qid: self.qid(state.index),
+            iounit: 0,
+        })
+    }
+
+    async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let guard = fid.aux.state.lock().await;
+        let state = guard.as_ref().ok_or(error::Error::No(EBADF))?;
+        let open = state.open.as_ref().ok_or(error::Error::No(EBADF))?;
+        if let Some(cursor) = open.cursor.clone() {
+            // Wait without the lock, so the fid can be clunked meanwhile
+            drop(guard);
+            let mut closed = cursor.closed.subscribe();
+            return srv::cancellable(async {
+                tokio::select! {
+                    data = cursor.next(count) => Ok(FCall::RRead {
+                        data: Data(data.to_vec()),
+                    }),
+                    _ = closed.wait_for(|closed| *closed) => Err(error::Error::No(EINTR)),
+                }
+            })
+            .await?;
+        }
+        self.synth_file(state.index)?;
+        let snapshot = state
+            .open
+            .as_ref()
+            .and_then(|open| open.snapshot.as_ref())
+            .ok_or(error::Error::No(EBADF))?;
+
+        let start = offset.min(snapshot.len() as u64) as usize;
+        let end = start.saturating_add(count as usize).min(snapshot.len());
+        Ok(FCall::RRead {
+            data: Data(snapshot[start..end].to_vec()),
+        })
+    }
+
+    async fn rwrite(&self, fid: &FId<Self::FId>, _offset: u64, data: &Data) -> Result<FCall> {
+        let mut state = fid.aux.state.lock().await;
+        let state = state.as_mut().ok_or(error::Error::No(EBADF))?;
+        let file = self.synth_file(state.index)?;
+        let open = state
+            .open
+            .as_mut()
+            .filter(|open| open.writable)
+            .ok_or(error::Error::No(EBADF))?;
+        let write = file.write.as_ref().ok_or(error::Error::No(EBADF))?;
+
+        if !file.lines {
+            write(Bytes::copy_from_slice(&data.0)).await?;
+        } else {
+            open.line.extend_from_slice(&data.0);
+            while let Some(end) = open.line.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = open.line.drain(..=end).collect();
+                line.pop();
+                write(Bytes::from(line)).await?;
+            }
+        }
+        Ok(FCall::RWrite {
+            count: data.0.len() as u32,
+        })
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
+        let state = fid.aux.state.lock().await;
+        let state = state.as_ref().ok_or(error::Error::No(EBADF))?;
+        if state.open.is_none() {
+            return Err(error::Error::No(EBADF));
+        }
+        let Entry::Dir(entries) = &self.nodes[state.index].entry else {
+            return Err(error::Error::No(ENOTDIR));
+        };
+
+        // Offsets are indices in the listing, which never changes
+        let parent = self.nodes[state.index].parent;
+        let listing = [(".", state.index), ("..", parent)]
+            .into_iter()
+            .chain(entries.iter().map(|(name, &index)| (name.as_str(), index)));
+        let mut data = DirEntryData::new();
+        for (i, (name, index)) in listing.enumerate().skip(offset as usize) {
+            let qid = self.qid(index);
+            let entry = DirEntry {
+                qid,
+                offset: i as u64 + 1,
+                typ: DirEntryType::from_mode(self.stat(index).mode).into(),
+                name: name.to_owned(),
+            };
+            if data.size() + entry.size() > count {
+                break;
+            }
+            data.push(entry);
+        }
+        Ok(FCall::RReadDir { data })
+    }
+
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let state = fid.aux.state.lock().await.take();
+        let Some(State {
+            index,
+            open: Some(open),
+        }) = state
+        else {
+            return Ok(FCall::RClunk);
+        };
+        if let Some(cursor) = &open.cursor {
+            cursor.closed.send_replace(true);
+        }
+
+        // The last line may lack its newline
+        if !open.line.is_empty()
+            && let Ok(file) = self.synth_file(index)
+            && let Some(write) = &file.write
+        {
+            write(Bytes::from(open.line)).await?;
+        }
+        Ok(FCall::RClunk)
+    }
+}
+
+#[cfg(test)]
+fn fid(n: u32) -> FId<SynthFId> {
+    FId::new(n, SynthFId::default())
+}
+
+#[cfg(test)]
+async fn open(fs: &Synth, path: &[&str], flags: P9OpenFlags) -> Result<FId<SynthFId>> {
+    let (root, file) = (fid(0), fid(1));
+    fs.rattach(&root, None, "", "", 0).await?;
+    let wnames: Vec<_> = path.iter().map(|name| name.to_string()).collect();
+    fs.rwalk(&root, &file, &wnames).await?;
+    fs.rlopen(&file, flags.bits()).await?;
+    Ok(file)
+}
+
+#[tokio::test]
+async fn synth_read() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let reads = Arc::new(AtomicU32::new(0));
+    let fs = Synth::new().dir("empty").file(
+        "dev/count",
+        SynthFile::new().read({
+            let reads = reads.clone();
+            move || {
+                let n = reads.fetch_add(1, Ordering::SeqCst);
+                async move { Bytes::from(format!("read {}\n", n)) }
+            }
+        }),
+    );
+
+    // The contents are taken when opening
+    let count = open(&fs, &["dev", "count"], P9OpenFlags::RDONLY)
+        .await
+        .unwrap();
+    let again = open(&fs, &["dev", "count"], P9OpenFlags::RDONLY)
+        .await
+        .unwrap();
+    assert_eq!(
+        fs.rread(&count, 2, 100).await.unwrap(),
+        FCall::RRead {
+            data: Data(b"ad 0\n".to_vec())
+        }
+    );
+    assert_eq!(
+        fs.rread(&again, 0, 100).await.unwrap(),
+        FCall::RRead {
+            data: Data(b"read 1\n".to_vec())
+        }
+    );
+    assert_eq!(
+        fs.rread(&count, 100, 100).await.unwrap(),
+        FCall::RRead { data: Data(vec![]) }
+    );
+    let res = open(&fs, &["dev", "count"], P9OpenFlags::WRONLY).await;
+    assert_eq!(res.unwrap_err().errno(), EACCES);
+
+    match fs.rgetattr(&count, GetAttrMask::ALL).await.unwrap() {
+        FCall::RGetAttr { qid, stat, .. } => {
+            assert_eq!(qid, QId::file(4));
+            assert_eq!(stat.mode, FileMode::IFREG.bits() | 0o444);
+        }
+        reply => panic!("{}", reply),
+    }
+
+    let root = open(&fs, &[], P9OpenFlags::RDONLY).await.unwrap();
+    let names: Vec<_> = match fs.rreaddir(&root, 0, 4096).await.unwrap() {
+        FCall::RReadDir { data } => data.data().iter().map(|e| e.name.clone()).collect(),
+        reply => panic!("{}", reply),
+    };
+    assert_eq!(names, [".", "..", "dev", "empty"]);
+}
+
+#[tokio::test]
+async fn synth_ctl() {
+    let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let fs = Synth::new().file(
+        "ctl",
+        SynthFile::new()
+            .write({
+                let lines = lines.clone();
+                move |line: Bytes| {
+                    let lines = lines.clone();
+                    async move {
+                        if &line[..] == b"fail" {
+                            return Err(error::Error::No(EINVAL));
+                        }
+                        lines.lock().unwrap().push(line);
+                        Ok(())
+                    }
+                }
+            })
+            .lines(),
+    );
+
+    let ctl = open(&fs, &["ctl"], P9OpenFlags::WRONLY | P9OpenFlags::TRUNC)
+        .await
+        .unwrap();
+    for data in ["start", " now\nst", "op\n", "fail\n", "last"] {
+        let _ = fs.rwrite(&ctl, 0, &Data(data.as_bytes().to_vec())).await;
+    }
+    assert_eq!(*lines.lock().unwrap(), ["start now", "stop"]);
+    fs.rclunk(&ctl).await.unwrap();
+    assert_eq!(*lines.lock().unwrap(), ["start now", "stop", "last"]);
+
+    let ctl = open(&fs, &["ctl"], P9OpenFlags::WRONLY).await.unwrap();
+    let res = fs.rwrite(&ctl, 0, &Data(b"fail\n".to_vec())).await;
+    assert_eq!(res.unwrap_err().errno(), EINVAL);
+    assert!(fs.rread(&ctl, 0, 10).await.is_err());
+}
+
+#[tokio::test]
+async fn synth_events() {
+    use std::time::Duration;
+
+    let events = Events::new(2);
+    let fs = Synth::new().events("log", events.clone());
+    assert_eq!(events.send("before\n"), 0);
+
+    let log = open(&fs, &["log"], P9OpenFlags::RDONLY).await.unwrap();
+    let res = open(&fs, &["log"], P9OpenFlags::RDWR).await;
+    assert_eq!(res.unwrap_err().errno(), EACCES);
+
+    // A read waits for the next message, and returns it in pieces if needed
+    let (read, sent) = tokio::join!(fs.rread(&log, 0, 4), async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        events.send("hello\n")
+    });
+    assert_eq!(sent, 1);
+    assert_eq!(
+        read.unwrap(),
+        FCall::RRead {
+            data: Data(b"hell".to_vec())
+        }
+    );
+    assert_eq!(
+        fs.rread(&log, 4, 100).await.unwrap(),
+        FCall::RRead {
+            data: Data(b"o\n".to_vec())
+        }
+    );
+
+    // A slow reader skips what it missed
+    for n in 0..4 {
+        events.send(format!("{}\n", n));
+    }
+    assert_eq!(
+        fs.rread(&log, 0, 100).await.unwrap(),
+        FCall::RRead {
+            data: Data(b"2\n".to_vec())
+        }
+    );
+    fs.rread(&log, 0, 100).await.unwrap();
+
+    // Clunking the fid ends a waiting read
+    let (read, clunk) = tokio::join!(fs.rread(&log, 0, 100), async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fs.rclunk(&log).await
+    });
+    clunk.unwrap();
+    assert_eq!(read.unwrap_err().errno(), EINTR);
+    assert_eq!(events.send("after\n"), 0);
+}
@@ -0,0 +1,248 @@
+//! Extended attribute fids.
+//!
+//! 9P2000.L accesses extended attributes through fids rather than dedicated
+//! get/set messages:
+//!
+//! - `TxAttrWalk` with a name clones the fid into one whose `TRead`s return the value
+//!   of that attribute. With an empty name the reads return the list of attribute
+//!   names instead, each terminated by a NUL byte.
+//! - `TxAttrCreate` turns the fid into one that receives the new value through
+//!   `TWrite`s. The attribute is only set once the fid is clunked, and a zero
+//!   `attr_size` asks for the attribute to be removed.
+//!
+//! [`XattrFid`] implements that state machine. Embed it in your `Filesystem::FId`,
+//! let `rxattrwalk`/`rxattrcreate` feed it, and consult it first in `rread`, `rwrite`
+//! and `rclunk`:
+//!
+//! ```no_run
+//! # use rs9p::{xattr::XattrFid, srv::FId, FCall, Result};
+//! # struct MyFId { xattr: XattrFid }
+//! # async fn lookup(name: &str) -> Result<Vec<u8>> { unimplemented!() }
+//! # async fn read_file(fid: &FId<MyFId>, offset: u64, count: u32) -> Result<FCall> { unimplemented!() }
+//! async fn rxattrwalk(newfid: &FId<MyFId>, name: &str) -> Result<FCall> {
+//!     let value = lookup(name).await?;
+//!     newfid.aux.xattr.walk(value).await
+//! }
+//!
+//! async fn rread(fid: &FId<MyFId>, offset: u64, count: u32) -> Result<FCall> {
+//!     if let Some(reply) = fid.aux.xattr.read(offset, count).await? {
+//!         return Ok(reply);
+//!     }
+//!     read_file(fid, offset, count).await
+//! }
+//! ```
+
+use {
+    crate::{error, error::errno::*, fcall::*, utils::Result},
+    tokio::sync::Mutex,
+};
+
+/// Largest attribute value accepted, like Linux's `XATTR_SIZE_MAX`
+pub const XATTR_SIZE_MAX: u64 = 65536;
+
+/// Largest attribute name list returned, like Linux's `XATTR_LIST_MAX`
+pub const XATTR_LIST_MAX: u64 = 65536;
+
+/// An attribute write that is ready to be applied
+///
+/// Returned by [`XattrFid::commit`] once all the bytes declared in `TxAttrCreate`
+/// have been written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingXattr {
+    /// Name of the attribute
+    pub name: String,
+    /// `XATTR_CREATE`/`XATTR_REPLACE` semantics requested by the client
+    pub flags: XattrFlags,
+    /// The new value of the attribute
+    pub value: Vec<u8>,
+    written: u64,
+}
+
+impl PendingXattr {
+    /// If the client asked for the attribute to be removed
+    ///
+    /// The Linux client implements `removexattr(2)` as a `TxAttrCreate` of size 0.
+    pub fn is_remove(&self) -> bool {
+        self.value.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    None,
+    Read(Vec<u8>),
+    Write(PendingXattr),
+}
+
+/// Per-fid extended attribute state
+#[derive(Debug, Default)]
+pub struct XattrFid {
+    state: Mutex<State>,
+}
+
+impl XattrFid {
+    /// Turn the fid into one reading `value`, and build the `RxAttrWalk` reply
+    ///
+    /// `value` is either the attribute's value or, for an empty name, the list of
+    /// names as produced by [`encode_names`].
+    pub async fn walk(&self, value: Vec<u8>) -> Result<FCall> {
+        let size = value.len() as u64;
+        if size > XATTR_SIZE_MAX.max(XATTR_LIST_MAX) {
+            return Err(error::Error::No(E2BIG));
+        }
+
+        *self.state.lock().await = State::Read(value);
+        Ok(FCall::RxAttrWalk { size })
+    }
+
+    /// Turn the fid into one receiving a new value, and build the `RxAttrCreate` reply
+    pub async fn create(&self, name: &str, attr_size: u64, flags: u32) -> Result<FCall> {
+        let flags = XattrFlags::from_bits(flags).ok_or(error::Error::No(EINVAL))?;
+        if name.is_empty() || flags.is_all() {
+            return Err(error::Error::No(EINVAL));
+        }
+        if attr_size > XATTR_SIZE_MAX {
+            return Err(error::Error::No(E2BIG));
+        }
+
+        *self.state.lock().await = State::Write(PendingXattr {
+            name: name.to_owned(),
+            flags,
+            value: vec![0; attr_size as usize],
+            written: 0,
+        });
+        Ok(FCall::RxAttrCreate)
+    }
+
+    /// If the fid has been turned into an extended attribute fid
+    pub async fn is_xattr(&self) -> bool {
+        !matches!(*self.state.lock().await, State::None)
+    }
+
+    /// Serve a `TRead` on the fid
+    ///
+    /// Returns `None` if this is not an extended attribute fid, so the caller can
+    /// fall back to reading the file.
+    pub async fn read(&self, offset: u64, count: u32) -> Result<Option<FCall>> {
+        match *self.state.lock().await {
+            State::None => Ok(None),
+            State::Write(_) => Err(error::Error::No(EBADF)),
+            State::Read(ref value) => {
+                let start = offset.min(value.len() as u64) as usize;
+                let end = start.saturating_add(count as usize).min(value.len());
+                Ok(Some(FCall::RRead {
+                    data: Data(value[start..end].to_vec()),
+                }))
+            }
+        }
+    }
+
+    /// Serve a `TWrite` on the fid
+    ///
+    /// Returns `None` if this is not an extended attribute fid. Writes past the
+    /// size declared in `TxAttrCreate` fail with `ENOSPC`.
+    pub async fn write(&self, offset: u64, data: &Data) -> Result<Option<FCall>> {
+        match *self.state.lock().await {
+            State::None => Ok(None),
+            State::Read(_) => Err(error::Error::No(EBADF)),
+            State::Write(ref mut pending) => {
+                let size = pending.value.len() as u64;
+                if offset > size || data.0.len() as u64 > size - offset {
+                    return Err(error::Error::No(ENOSPC));
+                }
+
+                let start = offset as usize;
+                pending.value[start..start + data.0.len()].copy_from_slice(&data.0);
+                pending.written += data.0.len() as u64;
+                Ok(Some(FCall::RWrite {
+                    count: data.0.len() as u32,
+                }))
+            }
+        }
+    }
+
+    /// Take the attribute write to apply when the fid is clunked
+    ///
+    /// Returns `None` if there is nothing to apply. Fails with `EINVAL` if fewer
+    /// bytes than declared have been written. The fid is reset in either case.
+    pub async fn commit(&self) -> Result<Option<PendingXattr>> {
+        match std::mem::take(&mut *self.state.lock().await) {
+            State::Write(pending) if pending.written != pending.value.len() as u64 => {
+                Err(error::Error::No(EINVAL))
+            }
+            State::Write(pending) => Ok(Some(pending)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Encode attribute names as returned by `listxattr(2)`: each followed by a NUL byte
+pub fn encode_names<I>(names: I) -> Vec<u8>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut buf = Vec::new();
+    for name in names {
+        buf.extend_from_slice(name.as_ref());
+        buf.push(0);
+    }
+    buf
+}
+
+#[tokio::test]
+async fn xattr_read() {
+    let fid = XattrFid::default();
+    assert_eq!(fid.read(0, 8).await.unwrap(), None);
+
+    let names = encode_names(["user.a", "security.selinux"]);
+    assert_eq!(
+        fid.walk(names.clone()).await.unwrap(),
+        FCall::RxAttrWalk { size: 24 }
+    );
+    assert!(fid.is_xattr().await);
+    assert_eq!(
+        fid.read(0, 64).await.unwrap(),
+        Some(FCall::RRead { data: Data(names) })
+    );
+    assert_eq!(
+        fid.read(20, 64).await.unwrap(),
+        Some(FCall::RRead {
+            data: Data(b"nux\0".to_vec())
+        })
+    );
+    assert!(fid.write(0, &Data(vec![1])).await.is_err());
+    assert_eq!(fid.commit().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn xattr_write() {
+    let fid = XattrFid::default();
+    assert!(fid.create("user.a", 4, 3).await.is_err());
+    assert!(fid.create("user.a", XATTR_SIZE_MAX + 1, 0).await.is_err());
+
+    fid.create("user.a", 4, XattrFlags::CREATE.bits())
+        .await
+        .unwrap();
+    fid.write(0, &Data(b"ab".to_vec())).await.unwrap();
+    assert!(fid.write(2, &Data(b"cde".to_vec())).await.is_err());
+    fid.write(2, &Data(b"cd".to_vec())).await.unwrap();
+
+    let pending = fid.commit().await.unwrap().unwrap();
+    assert_eq!(pending.name, "user.a");
+    assert_eq!(pending.flags, XattrFlags::CREATE);
+    assert_eq!(pending.value, b"abcd");
+    assert!(!pending.is_remove());
+    assert!(!fid.is_xattr().await);
+
+    // Clunking before the whole value has been written fails
+    fid.create("user.a", 4, 0).await.unwrap();
+    fid.write(0, &Data(b"ab".to_vec())).await.unwrap();
+    assert!(fid.commit().await.is_err());
+
+    fid.create("user.a", 0, XattrFlags::REPLACE.bits())
+        .await
+        .unwrap();
+    assert!(fid.commit().await.unwrap().unwrap().is_remove());
+}
@@ -0,0 +1,85 @@
+//! File I/O of unpfs, through the backend selected by the `io-uring` feature.
+//!
+//! Compare both backends with:
+//! ```text
+//! cargo bench -p unpfs --bench file_io -- --save-baseline pool
+//! cargo bench -p unpfs --bench file_io --features io-uring -- --baseline pool
+//! ```
+
+#[allow(dead_code)]
+#[path = "../src/file.rs"]
+mod file;
+
+use {
+    criterion::{Criterion, Throughput, criterion_group, criterion_main},
+    file::File,
+    futures::future::try_join_all,
+    std::{hint::black_box, os::fd::OwnedFd, sync::Arc},
+};
+
+const FILE_SIZE: u64 = 16 << 20;
+
+fn open(path: &std::path::Path) -> Arc<File> {
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    Arc::new(File::new(OwnedFd::from(file)).unwrap())
+}
+
+fn file_io(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+    std::fs::write(&path, vec![0x5a; FILE_SIZE as usize]).unwrap();
+    let file = open(&path);
+
+    let mut group = c.benchmark_group("file_io");
+    for size in [4u32 << 10, 128 << 10] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("read_{}k", size >> 10), |b| {
+            let mut offset = 0;
+            b.to_async(&runtime).iter(|| {
+                offset = (offset + size as u64) % FILE_SIZE;
+                let file = file.clone();
+                async move { black_box(file.read_at(offset, size).await.unwrap()) }
+            })
+        });
+
+        let data = vec![0xa5; size as usize];
+        group.bench_function(format!("write_{}k", size >> 10), |b| {
+            let mut offset = 0;
+            b.to_async(&runtime).iter(|| {
+                offset = (offset + size as u64) % FILE_SIZE;
+                let (file, data) = (file.clone(), &data);
+                async move { black_box(file.write_at(offset, data).await.unwrap()) }
+            })
+        });
+    }
+
+    // Readahead of a client: many reads in flight on one file
+    let (size, parallel) = (64u32 << 10, 32);
+    group.throughput(Throughput::Bytes(size as u64 * parallel));
+    group.bench_function("read_64k_x32", |b| {
+        b.to_async(&runtime).iter(|| {
+            let file = file.clone();
+            async move {
+                let reads = (0..parallel).map(|i| file.read_at(i * size as u64, size));
+                black_box(try_join_all(reads).await.unwrap())
+            }
+        })
+    });
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("fsync", |b| {
+        b.to_async(&runtime).iter(|| {
+            let file = file.clone();
+            async move { file.sync_all().await.unwrap() }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, file_io);
+criterion_main!(benches);
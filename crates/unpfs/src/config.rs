@@ -0,0 +1,228 @@
+//! Configuration file, given with `--config`.
+//!
+//! ```toml
+//! # Addresses to listen on
+//! listen = ["tcp!0.0.0.0!564", "unix!/run/unpfs.sock!0"]
+//! # env_logger filter, unless RUST_LOG is set
+//! log = "info"
+//!
+//! # Defaults for all the exports
+//! max_depth = 200
+//! ofd_locks = false
+//! # Refuse walking into other filesystems than the export's
+//! xdev = false
+//! identity = { mode = "squash", squash_uid = 65534, squash_gid = 65534 }
+//!
+//! # Served to clients mounting with -o aname=home
+//! [export.home]
+//! path = "/srv/home"
+//! identity = { mode = "passthrough" }
+//! # Users allowed to attach, by name or uid. Everyone if absent.
+//! allow = ["alice", "1000"]
+//!
+//! # Served for any other aname
+//! [export.""]
+//! path = "/srv/public"
+//! read_only = true
+//! max_depth = 50
+//! ```
+//!
+//! On `SIGHUP` the file is read again and the exports are replaced: clients attaching
+//! from then on see the new configuration, while the fids of existing attaches keep
+//! the export they were attached to. `listen` and `log` only take effect on restart.
+
+use {
+    crate::identity::IdentityMode,
+    serde::Deserialize,
+    std::{collections::BTreeMap, io, path::Path, path::PathBuf},
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub listen: Vec<String>,
+    pub log: Option<String>,
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    #[serde(default)]
+    pub ofd_locks: bool,
+    #[serde(default)]
+    pub xdev: bool,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub export: BTreeMap<String, ExportConfig>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct IdentityConfig {
+    pub mode: IdentityMode,
+    pub squash_uid: u32,
+    pub squash_gid: u32,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            mode: IdentityMode::None,
+            squash_uid: 65534,
+            squash_gid: 65534,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+    pub max_depth: Option<usize>,
+    pub ofd_locks: Option<bool>,
+    pub xdev: Option<bool>,
+    pub identity: Option<IdentityConfig>,
+    pub allow: Option<Vec<String>>,
+}
+
+/// The settings of an export, with the defaults of the file applied
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportSettings {
+    pub path: PathBuf,
+    pub read_only: bool,
+    pub max_depth: usize,
+    pub ofd_locks: bool,
+    pub xdev: bool,
+    pub identity: (IdentityMode, u32, u32),
+    pub allow: Vec<String>,
+}
+
+fn default_max_depth() -> usize {
+    200
+}
+
+fn invalid(key: &str, message: impl std::fmt::Display) -> String {
+    format!("{}: {}", key, message)
+}
+
+impl Config {
+    /// Read and validate the file at `path`
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        Config::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Parse and validate a configuration, errors name the offending key
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, address) in self.listen.iter().enumerate() {
+            if rs9p::utils::parse_proto(address).is_none() {
+                return Err(invalid(
+                    &format!("listen[{}]", i),
+                    format!("invalid address {:?}, expected proto!address!port", address),
+                ));
+            }
+        }
+        if self.max_depth == 0 {
+            return Err(invalid("max_depth", "must be at least 1"));
+        }
+        if self.export.is_empty() {
+            return Err(invalid("export", "no directory to export"));
+        }
+
+        for (name, export) in &self.export {
+            let key = |field: &str| format!("export.{:?}.{}", name, field);
+            if !export.path.is_absolute() {
+                return Err(invalid(&key("path"), "must be an absolute path"));
+            }
+            if export.max_depth == Some(0) {
+                return Err(invalid(&key("max_depth"), "must be at least 1"));
+            }
+            if export.allow.as_ref().is_some_and(|allow| allow.is_empty()) {
+                return Err(invalid(&key("allow"), "would refuse every user"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The exports and their settings
+    pub fn exports(&self) -> impl Iterator<Item = (&str, ExportSettings)> {
+        self.export.iter().map(|(name, export)| {
+            let identity = export.identity.unwrap_or(self.identity);
+            (
+                name.as_str(),
+                ExportSettings {
+                    path: export.path.clone(),
+                    read_only: export.read_only,
+                    max_depth: export.max_depth.unwrap_or(self.max_depth),
+                    ofd_locks: export.ofd_locks.unwrap_or(self.ofd_locks),
+                    xdev: export.xdev.unwrap_or(self.xdev),
+                    identity: (identity.mode, identity.squash_uid, identity.squash_gid),
+                    allow: export.allow.clone().unwrap_or_default(),
+                },
+            )
+        })
+    }
+}
+
+#[test]
+fn config_parse() {
+    let config = Config::parse(
+        r#"
+        listen = ["unix!/tmp/unpfs.sock!0"]
+        max_depth = 10
+        identity = { mode = "mapped" }
+
+        [export.home]
+        path = "/srv/home"
+        allow = ["alice"]
+
+        [export.""]
+        path = "/srv/public"
+        read_only = true
+        max_depth = 5
+        identity = { mode = "squash", squash_uid = 1000 }
+        "#,
+    )
+    .unwrap();
+
+    let exports: BTreeMap<_, _> = config.exports().collect();
+    assert_eq!(
+        exports["home"],
+        ExportSettings {
+            path: PathBuf::from("/srv/home"),
+            read_only: false,
+            max_depth: 10,
+            ofd_locks: false,
+            xdev: false,
+            identity: (IdentityMode::Mapped, 65534, 65534),
+            allow: vec!["alice".to_owned()],
+        }
+    );
+    assert_eq!(exports[""].max_depth, 5);
+    assert!(exports[""].read_only);
+    assert_eq!(exports[""].identity, (IdentityMode::Squash, 1000, 65534));
+}
+
+#[test]
+fn config_errors() {
+    let error = |text| Config::parse(text).unwrap_err();
+
+    assert!(error("[export.a]\npath = \"/a\"\nread_only = 1\n").contains("read_only"));
+    assert!(error("[export.a]\npath = \"/a\"\nreadonly = true\n").contains("readonly"));
+    assert!(error("[export.a]\npath = \"a\"\n").starts_with("export.\"a\".path:"));
+    assert!(error("listen = [\"564\"]\n[export.a]\npath = \"/a\"\n").starts_with("listen[0]:"));
+    assert!(error("identity = { mode = \"root\" }\n").contains("mode"));
+    assert!(error("").starts_with("export:"));
+}
@@ -0,0 +1,342 @@
+//! Reads, writes and syncs of the files opened by clients.
+//!
+//! All of them are positional, so the requests of a client on one fid proceed in
+//! parallel, such as the reads the Linux client issues for readahead. By default they
+//! run on the blocking thread pool as `pread`, `pwrite` and `fsync`.
+//!
+//! With the `io-uring` feature they are submitted to an io_uring driven by a
+//! dedicated thread instead, without thread pool hops. The ring is created on first
+//! use; if the kernel doesn't support io_uring, files fall back to the thread pool.
+//! Opening files and getting their attributes stay regular system calls, since the
+//! identity of the client is switched on the calling thread (see
+//! [`crate::identity`]) and would not apply to operations run by the ring.
+//!
+//! Writes are retried until all the data is written, so clients only see a short
+//! count when the file can't grow further.
+
+use std::{
+    io,
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::fs::FileExt,
+    },
+    sync::Arc,
+};
+
+/// An open file
+#[derive(Debug)]
+pub struct File {
+    file: Arc<std::fs::File>,
+}
+
+impl File {
+    pub fn new(fd: OwnedFd) -> io::Result<File> {
+        Ok(File {
+            file: Arc::new(fd.into()),
+        })
+    }
+
+    pub async fn read_at(&self, offset: u64, count: u32) -> io::Result<Vec<u8>> {
+        let buf = vec![0; count as usize];
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = uring::ring() {
+            let (bytes, mut buf) = ring
+                .submit(&self.file, offset, uring::Kind::Read, buf)
+                .await?;
+            buf.truncate(bytes);
+            return Ok(buf);
+        }
+
+        let file = self.file.clone();
+        blocking(move || {
+            let mut buf = buf;
+            let bytes = file.read_at(&mut buf, offset)?;
+            buf.truncate(bytes);
+            Ok(buf)
+        })
+        .await
+    }
+
+    /// Write all of `data` at `offset`
+    ///
+    /// Returns the number of bytes written, which is only less than the length of
+    /// `data` if an error occurs after writing some of it.
+    pub async fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = uring::ring() {
+            let mut buf = data.to_vec();
+            let mut written = 0;
+            while !buf.is_empty() {
+                let kind = uring::Kind::Write;
+                let res = ring
+                    .submit(&self.file, offset + written as u64, kind, buf)
+                    .await;
+                match res {
+                    Ok((0, _)) => return short_write(written, io::ErrorKind::WriteZero.into()),
+                    Ok((bytes, rest)) => {
+                        written += bytes;
+                        buf = rest;
+                        buf.drain(..bytes);
+                    }
+                    Err(e) => return short_write(written, e),
+                }
+            }
+            return Ok(written);
+        }
+
+        let (file, data) = (self.file.clone(), data.to_vec());
+        blocking(move || {
+            let mut written = 0;
+            while written < data.len() {
+                match file.write_at(&data[written..], offset + written as u64) {
+                    Ok(0) => return short_write(written, io::ErrorKind::WriteZero.into()),
+                    Ok(bytes) => written += bytes,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return short_write(written, e),
+                }
+            }
+            Ok(written)
+        })
+        .await
+    }
+
+    pub async fn sync_all(&self) -> io::Result<()> {
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = uring::ring() {
+            ring.submit(&self.file, 0, uring::Kind::Fsync, Vec::new())
+                .await?;
+            return Ok(());
+        }
+
+        let file = self.file.clone();
+        blocking(move || file.sync_all()).await
+    }
+}
+
+impl AsFd for File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+// Report what was written before an error, like write(2) does
+fn short_write(written: usize, e: io::Error) -> io::Result<usize> {
+    if written > 0 { Ok(written) } else { Err(e) }
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(feature = "io-uring")]
+mod uring {
+    use {
+        super::*,
+        io_uring::{IoUring, opcode, squeue, types},
+        std::{
+            os::fd::AsRawFd,
+            sync::{Mutex, OnceLock},
+        },
+        tokio::sync::oneshot,
+    };
+
+    const ENTRIES: u32 = 256;
+    // user_data of the read of the eventfd waking the ring thread
+    const WAKE: u64 = u64::MAX;
+
+    /// The ring shared by all the files, `None` if io_uring isn't available
+    pub fn ring() -> Option<&'static Ring> {
+        static RING: OnceLock<Option<Ring>> = OnceLock::new();
+        RING.get_or_init(|| match Ring::new() {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                eprintln!("[!] io_uring unavailable, using the thread pool: {}", e);
+                None
+            }
+        })
+        .as_ref()
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    pub enum Kind {
+        Read,
+        Write,
+        Fsync,
+    }
+
+    /// An operation, which owns its file and buffer until it completes
+    struct Op {
+        file: Arc<std::fs::File>,
+        offset: u64,
+        kind: Kind,
+        buf: Vec<u8>,
+        reply: oneshot::Sender<io::Result<(usize, Vec<u8>)>>,
+    }
+
+    impl Op {
+        fn entry(&mut self, user_data: u64) -> squeue::Entry {
+            let fd = types::Fd(self.file.as_raw_fd());
+            let len = self.buf.len() as u32;
+            match self.kind {
+                Kind::Read => opcode::Read::new(fd, self.buf.as_mut_ptr(), len)
+                    .offset(self.offset)
+                    .build(),
+                Kind::Write => opcode::Write::new(fd, self.buf.as_ptr(), len)
+                    .offset(self.offset)
+                    .build(),
+                Kind::Fsync => opcode::Fsync::new(fd).build(),
+            }
+            .user_data(user_data)
+        }
+    }
+
+    pub struct Ring {
+        queue: Arc<Mutex<Vec<Op>>>,
+        wake: Arc<OwnedFd>,
+    }
+
+    impl Ring {
+        fn new() -> io::Result<Ring> {
+            let ring = IoUring::new(ENTRIES)?;
+            let wake = Arc::new(rustix::event::eventfd(
+                0,
+                rustix::event::EventfdFlags::CLOEXEC,
+            )?);
+            let queue = Arc::new(Mutex::new(Vec::new()));
+
+            std::thread::Builder::new()
+                .name("unpfs-uring".to_owned())
+                .spawn({
+                    let (queue, wake) = (queue.clone(), wake.clone());
+                    move || {
+                        if let Err(e) = run(ring, &queue, &wake) {
+                            eprintln!("Error: io_uring thread failed: {}", e);
+                        }
+                    }
+                })?;
+
+            Ok(Ring { queue, wake })
+        }
+
+        pub async fn submit(
+            &self,
+            file: &Arc<std::fs::File>,
+            offset: u64,
+            kind: Kind,
+            buf: Vec<u8>,
+        ) -> io::Result<(usize, Vec<u8>)> {
+            let (reply, done) = oneshot::channel();
+            self.queue
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(Op {
+                    file: file.clone(),
+                    offset,
+                    kind,
+                    buf,
+                    reply,
+                });
+            rustix::io::write(&*self.wake, &1u64.to_ne_bytes())?;
+
+            done.await
+                .map_err(|_| io::Error::other("io_uring thread exited"))?
+        }
+    }
+
+    fn run(mut ring: IoUring, queue: &Mutex<Vec<Op>>, wake: &OwnedFd) -> io::Result<()> {
+        let mut wake_buf = Box::new([0u8; 8]);
+        let wake_entry = opcode::Read::new(types::Fd(wake.as_raw_fd()), wake_buf.as_mut_ptr(), 8)
+            .build()
+            .user_data(WAKE);
+        // Operations in flight, indexed by their user_data
+        let mut ops: Vec<Option<Op>> = Vec::new();
+        let mut pending = vec![wake_entry.clone()];
+
+        loop {
+            for entry in pending.drain(..) {
+                // SAFETY: the buffers of the operations are owned by `ops`, and the
+                // one of the eventfd by this function, until their completion
+                while unsafe { ring.submission().push(&entry) }.is_err() {
+                    ring.submit()?;
+                }
+            }
+            match ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            let completed: Vec<_> = ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (user_data, res) in completed {
+                if user_data == WAKE {
+                    let new = std::mem::take(&mut *queue.lock().unwrap_or_else(|e| e.into_inner()));
+                    for mut op in new {
+                        let slot = match ops.iter().position(Option::is_none) {
+                            Some(slot) => slot,
+                            None => {
+                                ops.push(None);
+                                ops.len() - 1
+                            }
+                        };
+                        pending.push(op.entry(slot as u64));
+                        ops[slot] = Some(op);
+                    }
+                    pending.push(wake_entry.clone());
+                    continue;
+                }
+
+                if let Some(op) = ops.get_mut(user_data as usize).and_then(Option::take) {
+                    let res = if res < 0 {
+                        Err(io::Error::from_raw_os_error(-res))
+                    } else {
+                        Ok((res as usize, op.buf))
+                    };
+                    let _ = op.reply.send(res);
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn positional_io() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    let file = Arc::new(File::new(file.into()).unwrap());
+
+    let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+    assert_eq!(file.write_at(4096, &data).await.unwrap(), data.len());
+    file.sync_all().await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap()[4096..], data[..]);
+
+    // Concurrent reads on the same file don't move each other's offset
+    let reads = (0..64u64).map(|i| {
+        let file = file.clone();
+        tokio::spawn(async move { (i, file.read_at(4096 + i * 1000, 1000).await.unwrap()) })
+    });
+    for read in futures::future::join_all(reads).await {
+        let (i, buf) = read.unwrap();
+        assert_eq!(buf, data[i as usize * 1000..][..1000]);
+    }
+
+    // Short read at the end of the file
+    let end = file
+        .read_at(4096 + data.len() as u64 - 10, 100)
+        .await
+        .unwrap();
+    assert_eq!(end, data[data.len() - 10..]);
+}
@@ -0,0 +1,363 @@
+//! Which user the operations of a client are performed as.
+//!
+//! - `none`: everything runs as the server's own user, the ids sent by the client are
+//!   only used for `chown` and the group of new files.
+//! - `passthrough`: every operation runs with the filesystem uid and gid switched to
+//!   the user the client attached as (`n_uname`, or `uname` looked up in the password
+//!   database), so the kernel enforces permissions. Requires root. The supplementary
+//!   groups are switched to the ones of that user in the group database as well, and
+//!   to none if it has no entry in the password database.
+//! - `squash`: every operation runs as one fixed user and group, like NFS's
+//!   `all_squash`. Requires root unless they are the server's own.
+//! - `mapped`: everything runs as the server's own user, and the owner, group, mode and
+//!   device number the client sees are stored in `user.virtfs.*` extended attributes,
+//!   like QEMU's `security_model=mapped-xattr`. Files are kept private to the server
+//!   user on disk, and special files are stored as regular files.
+//!
+//! The filesystem ids and supplementary groups are per thread, so the switch must never
+//! span an `.await`.
+//! [`Identity::run`] takes a closure for that reason.
+
+use {
+    crate::{
+        root::{Root, fd_path},
+        utils::{fset_gid, set_gid},
+    },
+    nix::{
+        sys::stat::Mode,
+        unistd::{Gid, Uid, UnlinkatFlags, User, getgrouplist, unlinkat},
+    },
+    rs9p::{error, error::errno::*, fcall::*, xattr::encode_names},
+    rustix::fs as rfs,
+    std::{
+        ffi::CString,
+        fs::Metadata,
+        io,
+        os::{
+            fd::{AsFd, OwnedFd},
+            unix::fs::MetadataExt,
+        },
+        sync::Arc,
+    },
+};
+
+const MAPPED_PREFIX: &str = "user.virtfs.";
+const MAPPED_UID: &str = "user.virtfs.uid";
+const MAPPED_GID: &str = "user.virtfs.gid";
+const MAPPED_MODE: &str = "user.virtfs.mode";
+const MAPPED_RDEV: &str = "user.virtfs.rdev";
+
+/// Identity mode, selected with `--identity`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityMode {
+    #[default]
+    None,
+    Passthrough,
+    Squash,
+    Mapped,
+}
+
+/// The user a fid was attached as
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, only installed in the passthrough mode
+    pub groups: Arc<[rustix::process::Gid]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    mode: IdentityMode,
+    squash: Cred,
+    // Whether operations run with other ids than the server's
+    switches: bool,
+}
+
+impl Identity {
+    pub fn new(mode: IdentityMode, squash_uid: u32, squash_gid: u32) -> io::Result<Identity> {
+        let switches = match mode {
+            IdentityMode::Passthrough => true,
+            IdentityMode::Squash => {
+                squash_uid != nix::unistd::geteuid().as_raw()
+                    || squash_gid != nix::unistd::getegid().as_raw()
+            }
+            _ => false,
+        };
+        if switches && !nix::unistd::geteuid().is_root() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("--identity {:?} requires running as root", mode),
+            ));
+        }
+
+        Ok(Identity {
+            mode,
+            squash: Cred {
+                uid: squash_uid,
+                gid: squash_gid,
+                groups: Arc::new([]),
+            },
+            switches,
+        })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mode == IdentityMode::Mapped
+    }
+
+    /// Find the credentials of a client attaching as `uname`/`n_uname`
+    pub fn attach(&self, uname: &str, n_uname: u32) -> rs9p::Result<Option<Cred>> {
+        let lookup = || -> rs9p::Result<Cred> {
+            let user = if n_uname != NONUNAME {
+                User::from_uid(Uid::from_raw(n_uname))?
+            } else {
+                User::from_name(uname)?
+            };
+
+            match user {
+                Some(user) => {
+                    let name = CString::new(user.name).map_err(|_| error::Error::No(EINVAL))?;
+                    let groups = getgrouplist(&name, user.gid)?
+                        .into_iter()
+                        .map(|gid| rustix::process::Gid::from_raw(gid.as_raw()))
+                        .collect();
+                    Ok(Cred {
+                        uid: user.uid.as_raw(),
+                        gid: user.gid.as_raw(),
+                        groups,
+                    })
+                }
+                // Numeric ids don't need to exist on the server
+                None if n_uname != NONUNAME => Ok(Cred {
+                    uid: n_uname,
+                    gid: n_uname,
+                    groups: Arc::new([]),
+                }),
+                None => Err(error::Error::No(EPERM)),
+            }
+        };
+
+        match self.mode {
+            IdentityMode::None => Ok(None),
+            IdentityMode::Squash => Ok(Some(self.squash.clone())),
+            IdentityMode::Passthrough | IdentityMode::Mapped => lookup().map(Some),
+        }
+    }
+
+    /// Run `f` as the user of `cred`
+    ///
+    /// Fails without running `f` if the ids can't be switched. The server aborts if
+    /// they can't be switched back, rather than serve other requests with them.
+    pub fn run<T>(&self, cred: Option<&Cred>, f: impl FnOnce() -> T) -> io::Result<T> {
+        let cred = match (self.switches, cred) {
+            (true, Some(cred)) => cred,
+            _ => return Ok(f()),
+        };
+
+        // The server's supplementary groups (root's group included) would otherwise
+        // grant access along with the fsgid
+        let old_groups = rustix::process::getgroups()?;
+        rustix::thread::set_thread_groups(&cred.groups)?;
+        let old_gid = nix::unistd::setfsgid(Gid::from_raw(cred.gid));
+        let old_uid = nix::unistd::setfsuid(Uid::from_raw(cred.uid));
+        let res = f();
+        nix::unistd::setfsuid(old_uid);
+        nix::unistd::setfsgid(old_gid);
+        if let Err(e) = rustix::thread::set_thread_groups(&old_groups) {
+            eprintln!("Error: can't restore the supplementary groups: {}", e);
+            std::process::abort();
+        }
+        Ok(res)
+    }
+
+    /// Permissions to create a file with on disk, for the `mode` asked by the client
+    pub fn create_mode(&self, mode: u32) -> Mode {
+        let mode = FileMode::from_bits_truncate(mode);
+        match self.mode {
+            IdentityMode::Mapped if mode.file_type() == DirEntryType::Dir => {
+                Mode::from_bits_truncate(0o700)
+            }
+            IdentityMode::Mapped => Mode::from_bits_truncate(0o600),
+            _ => mode.into(),
+        }
+    }
+
+    /// Apply the ownership asked by the client to the entry `name` of `dirfd`, just
+    /// created with `mode` (including the file type) and `rdev`
+    ///
+    /// The entry is removed if that fails, so that the creation fails as a whole.
+    pub fn created(
+        &self,
+        cred: Option<&Cred>,
+        dirfd: &OwnedFd,
+        name: &str,
+        mode: u32,
+        rdev: u64,
+        gid: u32,
+    ) -> io::Result<()> {
+        let res = match self.mode {
+            // New files belong to the squashed user
+            IdentityMode::Squash => Ok(()),
+            IdentityMode::None | IdentityMode::Passthrough => set_gid(dirfd, name, gid),
+            // Symlinks can't carry user.* attributes
+            IdentityMode::Mapped
+                if FileMode::from_bits_truncate(mode).file_type() == DirEntryType::Lnk =>
+            {
+                Ok(())
+            }
+            IdentityMode::Mapped => Root::open_at(dirfd.as_fd(), name.as_ref())
+                .and_then(|fd| set_mapped_owner(&fd, cred, mode, rdev, gid)),
+        };
+        if res.is_err() {
+            let flags = if FileMode::from_bits_truncate(mode).file_type() == DirEntryType::Dir {
+                UnlinkatFlags::RemoveDir
+            } else {
+                UnlinkatFlags::NoRemoveDir
+            };
+            let _ = unlinkat(dirfd, name, flags);
+        }
+        res
+    }
+
+    /// Like [`Identity::created`], for a regular file just created and opened as `file`
+    ///
+    /// The caller removes the file if that fails.
+    ///
+    /// Going through the open file rather than the name keeps a racing rename from
+    /// redirecting the change to another file.
+    pub fn created_file(
+        &self,
+        cred: Option<&Cred>,
+        file: &OwnedFd,
+        mode: u32,
+        gid: u32,
+    ) -> io::Result<()> {
+        match self.mode {
+            IdentityMode::Squash => Ok(()),
+            IdentityMode::None | IdentityMode::Passthrough => fset_gid(file, gid),
+            IdentityMode::Mapped => set_mapped_owner(file, cred, mode, 0, gid),
+        }
+    }
+
+    /// Overlay the attributes stored by the mapped mode on `stat`
+    ///
+    /// `attr` are the attributes on disk of the file `fd` refers to.
+    pub fn map_stat(&self, fd: &OwnedFd, attr: &Metadata, stat: &mut Stat) {
+        if !self.is_mapped() || attr.is_symlink() {
+            return;
+        }
+
+        if let Some(uid) = get_mapped(fd, MAPPED_UID) {
+            stat.uid = uid as u32;
+        }
+        if let Some(gid) = get_mapped(fd, MAPPED_GID) {
+            stat.gid = gid as u32;
+        }
+        if let Some(mode) = get_mapped(fd, MAPPED_MODE) {
+            stat.mode = mode as u32;
+        }
+        if let Some(rdev) = get_mapped(fd, MAPPED_RDEV) {
+            stat.rdev = rdev;
+        }
+    }
+
+    /// The file type the client sees, which differs from the one on disk for special
+    /// files in the mapped mode
+    pub fn file_type(&self, fd: &OwnedFd, attr: &Metadata) -> DirEntryType {
+        if !self.is_mapped() || attr.is_symlink() {
+            return attr.file_type().into();
+        }
+
+        get_mapped(fd, MAPPED_MODE)
+            .map(|mode| DirEntryType::from_mode(mode as u32))
+            .unwrap_or_else(|| attr.file_type().into())
+    }
+
+    /// Apply the mode and owner changes of a `TSetAttr` in the mapped mode
+    ///
+    /// Returns the part of `valid` left to apply to the file on disk.
+    pub fn map_setattr(
+        &self,
+        fd: &OwnedFd,
+        attr: &Metadata,
+        valid: SetAttrMask,
+        stat: &SetAttr,
+    ) -> io::Result<SetAttrMask> {
+        let ids = SetAttrMask::MODE | SetAttrMask::UID | SetAttrMask::GID;
+        if !self.is_mapped() || attr.is_symlink() {
+            return Ok(valid);
+        }
+
+        if valid.contains(SetAttrMask::MODE) {
+            // The client only changes the permissions, keep the stored file type
+            let old = get_mapped(fd, MAPPED_MODE).map_or(attr.mode(), |mode| mode as u32);
+            let mode = (old & FileMode::IFMT.bits()) | (stat.mode & !FileMode::IFMT.bits());
+            set_mapped(fd, MAPPED_MODE, &mode.to_ne_bytes())?;
+        }
+        if valid.contains(SetAttrMask::UID) {
+            set_mapped(fd, MAPPED_UID, &stat.uid.to_ne_bytes())?;
+        }
+        if valid.contains(SetAttrMask::GID) {
+            set_mapped(fd, MAPPED_GID, &stat.gid.to_ne_bytes())?;
+        }
+
+        Ok(valid - ids)
+    }
+
+    /// If the attribute `name` is one of the mapped mode's, which clients can't access
+    pub fn hides_xattr(&self, name: &str) -> bool {
+        self.is_mapped() && name.starts_with(MAPPED_PREFIX)
+    }
+
+    /// Remove the attributes of the mapped mode from a list of attribute names
+    pub fn filter_xattr_names(&self, names: Vec<u8>) -> Vec<u8> {
+        if !self.is_mapped() {
+            return names;
+        }
+
+        encode_names(
+            names
+                .split(|&b| b == 0)
+                .filter(|name| !name.is_empty() && !name.starts_with(MAPPED_PREFIX.as_bytes())),
+        )
+    }
+}
+
+// `fd` is never a symlink, so following the /proc link leads to the file itself
+fn get_mapped(fd: &OwnedFd, name: &str) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    match rfs::getxattr(fd_path(fd), name, &mut buf[..]).ok()? {
+        4 => Some(u32::from_ne_bytes(buf[..4].try_into().unwrap()) as u64),
+        8 => Some(u64::from_ne_bytes(buf)),
+        _ => None,
+    }
+}
+
+/// Store the owner, group, mode and device number of a new file in the mapped mode
+fn set_mapped_owner(
+    fd: &OwnedFd,
+    cred: Option<&Cred>,
+    mode: u32,
+    rdev: u64,
+    gid: u32,
+) -> io::Result<()> {
+    let uid = cred.map_or(nix::unistd::geteuid().as_raw(), |cred| cred.uid);
+    set_mapped(fd, MAPPED_UID, &uid.to_ne_bytes())?;
+    set_mapped(fd, MAPPED_GID, &gid.to_ne_bytes())?;
+    set_mapped(fd, MAPPED_MODE, &mode.to_ne_bytes())?;
+    if rdev != 0 {
+        set_mapped(fd, MAPPED_RDEV, &rdev.to_ne_bytes())?;
+    }
+    Ok(())
+}
+
+fn set_mapped(fd: &OwnedFd, name: &str, value: &[u8]) -> io::Result<()> {
+    Ok(rfs::setxattr(
+        fd_path(fd),
+        name,
+        value,
+        rfs::XattrFlags::empty(),
+    )?)
+}
@@ -1,27 +1,51 @@
 use {
     async_trait::async_trait,
     clap::Parser,
-    filetime::FileTime,
-    nix::libc::{O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY},
+    nix::{
+        fcntl::{AT_FDCWD, AtFlags, OFlag, openat},
+        sys::stat::Mode,
+    },
     rs9p::{
+        lock::{FidLocks, LockManager, LockOwner},
+        multiexport::{Export, MultiExport},
         srv::{FId, Filesystem, srv_async},
+        xattr::XattrFid,
         *,
     },
+    rustix::fs as rfs,
     std::{
-        io::{self, SeekFrom},
-        os::unix::fs::PermissionsExt,
-        path::PathBuf,
+        collections::{BTreeMap, HashMap, HashSet},
+        ffi::OsStr,
+        io,
+        os::{
+            fd::{AsFd, OwnedFd},
+            unix::{ffi::OsStrExt, fs::MetadataExt},
+        },
+        path::{Path, PathBuf},
+        sync::Arc,
     },
     tokio::{
         fs,
-        io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
         sync::{Mutex, RwLock},
     },
-    tokio_stream::{StreamExt, wrappers::ReadDirStream},
 };
 
+mod config;
+mod file;
+mod identity;
+mod qid;
+mod root;
+#[cfg(test)]
+mod tests;
 mod utils;
-use crate::utils::*;
+use crate::{
+    config::{Config, ExportConfig, IdentityConfig},
+    file::File,
+    identity::{Cred, Identity, IdentityMode},
+    qid::QIdMap,
+    root::{Root, fd_metadata, fd_path, proc_path, reopen},
+    utils::*,
+};
 
 // Some clients will incorrectly set bits in 9p flags that don't make sense.
 // For exmaple, the linux 9p kernel client propagates O_DIRECT to TCREATE and TOPEN
@@ -36,7 +60,12 @@ use crate::utils::*;
 // The fix is to enumerate the set of flags we support and then and that with
 // the flags received in a TCREATE or TOPEN. This nicely fixes a real problem
 // we are seeing with a file system benchmark.
-const UNIX_FLAGS: u32 = (O_WRONLY | O_RDONLY | O_RDWR | O_CREAT | O_TRUNC) as u32;
+const UNIX_FLAGS: P9OpenFlags = P9OpenFlags::WRONLY
+    .union(P9OpenFlags::RDONLY)
+    .union(P9OpenFlags::RDWR)
+    .union(P9OpenFlags::CREATE)
+    .union(P9OpenFlags::EXCL)
+    .union(P9OpenFlags::TRUNC);
 
 // Maximum depth protection:
 // Without a depth limit, it's possible to create infinite recursion by mounting
@@ -50,15 +79,137 @@ const UNIX_FLAGS: u32 = (O_WRONLY | O_RDONLY | O_RDWR | O_CREAT | O_TRUNC) as u3
 
 #[derive(Default)]
 struct UnpfsFId {
-    realpath: RwLock<PathBuf>,
-    file: Mutex<Option<fs::File>>,
+    /// `O_PATH` descriptor of the file, following it wherever it is renamed
+    fd: RwLock<Option<Arc<OwnedFd>>>,
+    file: RwLock<Option<Arc<File>>>,
+    /// Stream of the entries of an open directory
+    dir: Mutex<Option<DirStream>>,
     depth: RwLock<usize>,
+    /// User the client attached as, see [`Identity`]
+    cred: RwLock<Option<Cred>>,
+    xattr: XattrFid,
+    locks: FidLocks,
+    ofd: std::sync::Mutex<Option<FidOfd>>,
+}
+
+/// Descriptors holding the OFD locks which mirror the locks of clients, by file and
+/// lock owner
+///
+/// An open file description doesn't conflict with itself: sharing one between all the
+/// fids an owner locks a file through keeps its locks from conflicting with each
+/// other, as in the lock table.
+type OfdFiles = Arc<std::sync::Mutex<HashMap<(u64, LockOwner), OwnedFd>>>;
+
+fn ofd_files(files: &OfdFiles) -> std::sync::MutexGuard<'_, HashMap<(u64, LockOwner), OwnedFd>> {
+    files.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The owners which took OFD locks through a fid, released with it
+struct FidOfd {
+    files: OfdFiles,
+    file: u64,
+    owners: HashSet<LockOwner>,
+}
+
+impl Drop for UnpfsFId {
+    fn drop(&mut self) {
+        let ofd = self.ofd.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(ofd) = ofd {
+            // Release the locks in the table and their OFD locks together
+            let mut files = ofd_files(&ofd.files);
+            self.locks.release();
+            for owner in ofd.owners {
+                files.remove(&(ofd.file, owner));
+            }
+        }
+    }
+}
+
+/// Position in a directory kept between `TReadDir`s
+struct DirStream {
+    dir: rfs::Dir,
+    /// The offset the stream is at, which is the `d_off` of the last entry returned,
+    /// or `None` after reading an entry that didn't fit in the reply
+    pos: Option<u64>,
+}
+
+impl DirStream {
+    fn open(fd: &OwnedFd, identity: &Identity, cred: Option<&Cred>) -> io::Result<DirStream> {
+        let dir = identity.run(cred, || reopen(fd, OFlag::O_RDONLY | OFlag::O_DIRECTORY))??;
+        Ok(DirStream {
+            dir: rfs::Dir::new(dir)?,
+            pos: Some(0),
+        })
+    }
+}
+
+impl UnpfsFId {
+    async fn fd(&self) -> Result<Arc<OwnedFd>> {
+        let fd = self.fd.read().await;
+        fd.clone().ok_or_else(|| INVALID_FID!().into())
+    }
+
+    async fn set_fd(&self, fd: Arc<OwnedFd>) {
+        let mut self_fd = self.fd.write().await;
+        *self_fd = Some(fd);
+    }
+
+    async fn file(&self) -> Result<Arc<File>> {
+        let file = self.file.read().await;
+        file.clone().ok_or_else(|| INVALID_FID!().into())
+    }
+
+    async fn cred(&self) -> Option<Cred> {
+        self.cred.read().await.clone()
+    }
+
+    async fn set_cred(&self, cred: Option<Cred>) {
+        let mut self_cred = self.cred.write().await;
+        *self_cred = cred;
+    }
 }
 
 #[derive(Clone)]
 struct Unpfs {
-    realroot: PathBuf,
+    root: Arc<Root>,
     max_depth: usize,
+    locks: LockManager,
+    ofd_locks: bool,
+    ofd_files: OfdFiles,
+    identity: Identity,
+    // Users allowed to attach, everyone if empty
+    allow: Arc<Vec<String>>,
+    qids: Arc<QIdMap>,
+    // Refuse walking to other filesystems than the root's
+    xdev: bool,
+}
+
+impl Unpfs {
+    /// Export `root`, with the default settings
+    fn new(root: Root, identity: Identity) -> Unpfs {
+        Unpfs {
+            qids: Arc::new(QIdMap::new(root.dev())),
+            root: Arc::new(root),
+            max_depth: 200,
+            locks: LockManager::new(),
+            ofd_locks: false,
+            ofd_files: Default::default(),
+            identity,
+            allow: Arc::new(Vec::new()),
+            xdev: false,
+        }
+    }
+
+    /// Share the state of `old`, which exports the same directory, so that the locks
+    /// held through it stay held and the qids of its files don't change
+    fn carry_over(self, old: &Unpfs) -> Unpfs {
+        Unpfs {
+            locks: old.locks.clone(),
+            ofd_files: old.ofd_files.clone(),
+            qids: old.qids.clone(),
+            ..self
+        }
+    }
 }
 
 #[async_trait]
@@ -69,22 +220,26 @@ impl Filesystem for Unpfs {
         &self,
         fid: &FId<Self::FId>,
         _afid: Option<&FId<Self::FId>>,
-        _uname: &str,
+        uname: &str,
         _aname: &str,
-        _n_uname: u32,
+        n_uname: u32,
     ) -> Result<FCall> {
-        {
-            let mut realpath = fid.aux.realpath.write().await;
-            *realpath = PathBuf::from(&self.realroot);
+        if !allowed(&self.allow, uname, n_uname) {
+            return Err(error::Error::No(error::errno::EACCES));
         }
+
+        let cred = self.identity.attach(uname, n_uname)?;
+        let fd = self.root.try_clone_fd()?;
+        let qid = self.qids.qid(&fd_metadata(&fd)?);
+
+        fid.aux.set_fd(Arc::new(fd)).await;
+        fid.aux.set_cred(cred).await;
         {
             let mut depth = fid.aux.depth.write().await;
             *depth = 0;
         }
 
-        Ok(FCall::RAttach {
-            qid: get_qid(&self.realroot).await?,
-        })
+        Ok(FCall::RAttach { qid })
     }
 
     async fn rwalk(
@@ -94,10 +249,8 @@ impl Filesystem for Unpfs {
         wnames: &[String],
     ) -> Result<FCall> {
         let mut wqids = Vec::new();
-        let mut path = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.clone()
-        };
+        let mut fd = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
 
         let current_depth = {
             let depth = fid.aux.depth.read().await;
@@ -107,10 +260,32 @@ impl Filesystem for Unpfs {
         let mut new_depth = current_depth;
 
         for (i, name) in wnames.iter().enumerate() {
-            // Check for ".." which decreases depth
-            if name == ".." {
-                new_depth = new_depth.saturating_sub(1);
-            } else if name != "." {
+            root::check_name(name)?;
+
+            let next = if name == "." {
+                Ok(fd.clone())
+            } else if name == ".." {
+                // ".." at the root is the root, like in "/"
+                match fd_metadata(&fd) {
+                    Ok(attr) if self.root.is_root(&attr) => Ok(fd.clone()),
+                    Ok(_) => {
+                        new_depth = new_depth.saturating_sub(1);
+                        self.identity
+                            .run(cred.as_ref(), || {
+                                openat(
+                                    &*fd,
+                                    "..",
+                                    OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                                    Mode::empty(),
+                                )
+                                .map_err(io::Error::from)
+                            })
+                            .flatten()
+                            .map(Arc::new)
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
                 // Any path component other than "." or ".." increases depth
                 new_depth += 1;
 
@@ -118,15 +293,35 @@ impl Filesystem for Unpfs {
                 if new_depth > self.max_depth {
                     return Err(error::Error::No(error::errno::ELOOP));
                 }
-            }
 
-            path.push(name);
+                self.identity
+                    .run(cred.as_ref(), || {
+                        openat(
+                            &*fd,
+                            name.as_str(),
+                            OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                            Mode::empty(),
+                        )
+                        .map_err(io::Error::from)
+                    })
+                    .flatten()
+                    .map(Arc::new)
+            };
 
-            let qid = match get_qid(&path).await {
-                Ok(qid) => qid,
+            let qid = match next.and_then(|next| {
+                let attr = fd_metadata(&next)?;
+                if self.xdev && self.qids.is_foreign(&attr) {
+                    return Err(io::Error::from_raw_os_error(error::errno::EXDEV as i32));
+                }
+                Ok((self.qids.qid(&attr), next))
+            }) {
+                Ok((qid, next)) => {
+                    fd = next;
+                    qid
+                }
                 Err(e) => {
                     if i == 0 {
-                        return Err(e);
+                        return Err(e.into());
                     } else {
                         break;
                     }
@@ -136,10 +331,8 @@ impl Filesystem for Unpfs {
             wqids.push(qid);
         }
 
-        {
-            let mut new_realpath = newfid.aux.realpath.write().await;
-            *new_realpath = path;
-        }
+        newfid.aux.set_fd(fd).await;
+        newfid.aux.set_cred(cred).await;
         {
             let mut depth = newfid.aux.depth.write().await;
             *depth = new_depth;
@@ -149,15 +342,21 @@ impl Filesystem for Unpfs {
     }
 
     async fn rgetattr(&self, fid: &FId<Self::FId>, req_mask: GetAttrMask) -> Result<FCall> {
-        let attr = {
-            let realpath = fid.aux.realpath.read().await;
-            fs::symlink_metadata(&*realpath).await?
-        };
+        let fd = fid.aux.fd().await?;
+        let attr = fd_metadata(&fd)?;
 
+        let (mut valid, mut stat) = stat_from_attr(&attr);
+        if req_mask.contains(GetAttrMask::GEN)
+            && let Some(generation) = generation(&fd, &attr)
+        {
+            stat.generation = generation;
+            valid |= GetAttrMask::GEN;
+        }
+        self.identity.map_stat(&fd, &attr, &mut stat);
         Ok(FCall::RGetAttr {
-            valid: req_mask,
-            qid: qid_from_attr(&attr),
-            stat: From::from(attr),
+            valid: req_mask & valid,
+            qid: self.qids.qid(&attr),
+            stat,
         })
     }
 
@@ -167,13 +366,31 @@ impl Filesystem for Unpfs {
         valid: SetAttrMask,
         stat: &SetAttr,
     ) -> Result<FCall> {
-        let filepath = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.clone()
+        use nix::sys::{
+            stat::{FchmodatFlags, UtimensatFlags},
+            time::TimeSpec,
         };
 
+        let fd = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+        let attr = fd_metadata(&fd)?;
+        // Symlinks can't be reopened through /proc, they are handled by name
+        let is_symlink = attr.is_symlink();
+
+        let valid = self.identity.map_setattr(&fd, &attr, valid, stat)?;
+
         if valid.contains(SetAttrMask::MODE) {
-            fs::set_permissions(&filepath, PermissionsExt::from_mode(stat.mode)).await?;
+            if is_symlink {
+                return Err(error::Error::No(error::errno::EOPNOTSUPP));
+            }
+            self.identity.run(cred.as_ref(), || {
+                nix::sys::stat::fchmodat(
+                    AT_FDCWD,
+                    &fd_path(&fd),
+                    FileMode::from_bits_truncate(stat.mode).into(),
+                    FchmodatFlags::FollowSymlink,
+                )
+            })??;
         }
 
         if valid.intersects(SetAttrMask::UID | SetAttrMask::GID) {
@@ -187,101 +404,290 @@ impl Filesystem for Unpfs {
             } else {
                 None
             };
-            nix::unistd::chown(&filepath, uid, gid)?;
+            self.identity.run(cred.as_ref(), || {
+                nix::unistd::fchownat(&*fd, "", uid, gid, AtFlags::AT_EMPTY_PATH)
+            })??;
         }
 
         if valid.contains(SetAttrMask::SIZE) {
-            let _ = fs::OpenOptions::new()
-                .write(true)
-                .create(false)
-                .open(&filepath)
-                .await?
-                .set_len(stat.size)
-                .await?;
+            let file = self
+                .identity
+                .run(cred.as_ref(), || reopen(&fd, OFlag::O_WRONLY))??;
+            fs::File::from_std(file.into()).set_len(stat.size).await?;
         }
 
-        if valid.intersects(SetAttrMask::ATIME_SET | SetAttrMask::MTIME_SET) {
-            let attr = fs::metadata(&filepath).await?;
-            let atime = if valid.contains(SetAttrMask::ATIME_SET) {
-                FileTime::from_unix_time(stat.atime.sec as i64, stat.atime.nsec as u32)
-            } else {
-                FileTime::from_last_access_time(&attr)
+        if valid.intersects(SetAttrMask::ATIME | SetAttrMask::MTIME) {
+            let time = |set, given: &Time, now| {
+                if valid.contains(set) {
+                    TimeSpec::new(given.sec as i64, given.nsec as i64)
+                } else if valid.contains(now) {
+                    TimeSpec::UTIME_NOW
+                } else {
+                    TimeSpec::UTIME_OMIT
+                }
             };
-
-            let mtime = if valid.contains(SetAttrMask::MTIME_SET) {
-                FileTime::from_unix_time(stat.mtime.sec as i64, stat.mtime.nsec as u32)
+            let atime = time(SetAttrMask::ATIME_SET, &stat.atime, SetAttrMask::ATIME);
+            let mtime = time(SetAttrMask::MTIME_SET, &stat.mtime, SetAttrMask::MTIME);
+
+            if is_symlink {
+                let (dir, name) = self.root.locate(&fd)?;
+                self.identity.run(cred.as_ref(), || {
+                    nix::sys::stat::utimensat(
+                        &dir,
+                        name.as_os_str(),
+                        &atime,
+                        &mtime,
+                        UtimensatFlags::NoFollowSymlink,
+                    )
+                })??;
             } else {
-                FileTime::from_last_modification_time(&attr)
-            };
-
-            let _ = tokio::task::spawn_blocking(move || {
-                filetime::set_file_times(filepath, atime, mtime)
-            })
-            .await;
+                self.identity.run(cred.as_ref(), || {
+                    nix::sys::stat::utimensat(
+                        AT_FDCWD,
+                        &fd_path(&fd),
+                        &atime,
+                        &mtime,
+                        UtimensatFlags::FollowSymlink,
+                    )
+                })??;
+            }
         }
 
         Ok(FCall::RSetAttr)
     }
 
+    async fn rsymlink(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        sym: &str,
+        gid: u32,
+    ) -> Result<FCall> {
+        root::check_entry(name)?;
+        let dir = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+
+        self.identity.run(cred.as_ref(), || -> io::Result<()> {
+            nix::unistd::symlinkat(sym, &*dir, name)?;
+            let mode = (FileMode::IFLNK | FileMode::from_bits_truncate(0o777)).bits();
+            self.identity
+                .created(cred.as_ref(), &dir, name, mode, 0, gid)
+        })??;
+
+        Ok(FCall::RSymlink {
+            qid: self
+                .qids
+                .qid(&Root::metadata_at(dir.as_fd(), name.as_ref())?),
+        })
+    }
+
+    async fn rmknod(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<FCall> {
+        root::check_entry(name)?;
+        let dir = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+
+        let dev = nix::sys::stat::makedev(major as u64, minor as u64);
+        self.identity.run(cred.as_ref(), || -> io::Result<()> {
+            if self.identity.is_mapped() {
+                // Special files are stored as regular files, their type lives in the
+                // mapped mode
+                openat(
+                    &*dir,
+                    name,
+                    OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+                    self.identity.create_mode(mode),
+                )?;
+            } else {
+                let mode = FileMode::from_bits_truncate(mode);
+                nix::sys::stat::mknodat(&*dir, name, mode.into(), mode.into(), dev)?;
+            }
+            self.identity
+                .created(cred.as_ref(), &dir, name, mode, dev, gid)
+        })??;
+
+        Ok(FCall::RMkNod {
+            qid: self
+                .qids
+                .qid(&Root::metadata_at(dir.as_fd(), name.as_ref())?),
+        })
+    }
+
+    async fn rrename(
+        &self,
+        fid: &FId<Self::FId>,
+        dfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        root::check_entry(name)?;
+        let newdir = dfid.aux.fd().await?;
+
+        // The fid keeps referring to the file, wherever it goes
+        let (olddir, oldname) = self.root.locate(&*fid.aux.fd().await?)?;
+        self.identity.run(fid.aux.cred().await.as_ref(), || {
+            nix::fcntl::renameat(&olddir, oldname.as_os_str(), &*newdir, name)
+        })??;
+
+        Ok(FCall::RRename)
+    }
+
     async fn rreadlink(&self, fid: &FId<Self::FId>) -> Result<FCall> {
-        let link = {
-            let realpath = fid.aux.realpath.read().await;
-            fs::read_link(&*realpath).await?
-        };
+        let link = nix::fcntl::readlinkat(&*fid.aux.fd().await?, "")?;
 
         Ok(FCall::RReadLink {
             target: link.to_string_lossy().into_owned(),
         })
     }
 
-    async fn rreaddir(&self, fid: &FId<Self::FId>, off: u64, count: u32) -> Result<FCall> {
-        let mut dirents = DirEntryData::new();
+    async fn rxattrwalk(
+        &self,
+        fid: &FId<Self::FId>,
+        newfid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        if self.identity.hides_xattr(name) {
+            return Err(error::Error::No(error::errno::ENODATA));
+        }
 
-        let offset = if off == 0 {
-            dirents.push(get_dirent_from(".", 0).await?);
-            dirents.push(get_dirent_from("..", 1).await?);
-            off
+        let fd = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+
+        let value = {
+            let (dir, file) = self.root.locate(&fd)?;
+            let name = name.to_owned();
+            let (identity, cred) = (self.identity.clone(), cred.clone());
+            tokio::task::spawn_blocking(move || {
+                identity
+                    .run(cred.as_ref(), || get_xattr(&proc_path(&dir, &file), &name))
+                    .flatten()
+            })
+            .await
+            .map_err(|e| Error::Io(io::Error::other(e)))??
+        };
+        let value = if name.is_empty() {
+            self.identity.filter_xattr_names(value)
         } else {
-            off - 1
-        } as usize;
+            value
+        };
 
-        let mut entries = {
-            let realpath = fid.aux.realpath.read().await;
-            ReadDirStream::new(fs::read_dir(&*realpath).await?).skip(offset)
+        newfid.aux.set_fd(fd).await;
+        newfid.aux.set_cred(cred).await;
+
+        newfid.aux.xattr.walk(value).await
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &FId<Self::FId>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<FCall> {
+        if self.identity.hides_xattr(name) {
+            return Err(error::Error::No(error::errno::EACCES));
+        }
+
+        fid.aux.xattr.create(name, attr_size, flags).await
+    }
+
+    async fn rreaddir(&self, fid: &FId<Self::FId>, off: u64, count: u32) -> Result<FCall> {
+        let fd = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+        let mut stream = fid.aux.dir.lock().await;
+        // Some clients read directories they didn't open
+        let stream = match &mut *stream {
+            Some(stream) => stream,
+            None => stream.insert(DirStream::open(&fd, &self.identity, cred.as_ref())?),
         };
 
-        let mut i = offset;
-        while let Some(entry) = entries.next().await {
-            let dirent = get_dirent(&entry?, 2 + i as u64).await?;
+        // Offsets are the telldir() cookies of the entries
+        if stream.pos != Some(off) {
+            stream.dir.seek(off as i64).map_err(io::Error::from)?;
+            stream.pos = Some(off);
+        }
+
+        let attr = fd_metadata(&fd)?;
+        let mut dirents = DirEntryData::new();
+        while let Some(entry) = stream.dir.read() {
+            let entry = entry.map_err(io::Error::from)?;
+            let name = OsStr::from_bytes(entry.file_name().to_bytes());
+            let offset = entry.offset() as u64;
+            let typ = DirEntryType::from_mode(entry.file_type().as_raw_mode());
+
+            let dirent = match name.as_bytes() {
+                b"." => self.qids.dirent(name, &attr, offset),
+                b".." if self.root.is_root(&attr) => self.qids.dirent(name, &attr, offset),
+                b".." => self
+                    .qids
+                    .dirent(name, &Root::metadata_at(fd.as_fd(), name)?, offset),
+                // The mapped mode stores the type the client sees in an attribute
+                _ if typ == DirEntryType::Unknown || self.identity.is_mapped() => {
+                    let (entry_fd, attr) = match Root::open_at(fd.as_fd(), name)
+                        .and_then(|entry_fd| Ok((fd_metadata(&entry_fd)?, entry_fd)))
+                    {
+                        Ok((attr, entry_fd)) => (entry_fd, attr),
+                        // Removed since it was read
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            stream.pos = Some(offset);
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    let mut dirent = self.qids.dirent(name, &attr, offset);
+                    dirent.typ = self.identity.file_type(&entry_fd, &attr).into();
+                    dirent
+                }
+                _ => DirEntry {
+                    qid: self.qids.entry_qid(attr.dev(), entry.ino(), typ),
+                    offset,
+                    typ: typ.into(),
+                    name: name.to_string_lossy().into_owned(),
+                },
+            };
+
             if dirents.size() + dirent.size() > count {
+                stream.pos = None;
                 break;
             }
+            stream.pos = Some(offset);
             dirents.push(dirent);
-            i += 1;
         }
 
         Ok(FCall::RReadDir { data: dirents })
     }
 
     async fn rlopen(&self, fid: &FId<Self::FId>, flags: u32) -> Result<FCall> {
-        let realpath = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.clone()
-        };
-
-        let qid = get_qid(&realpath).await?;
-        if !qid.typ.contains(QIdType::DIR) {
-            let oflags = nix::fcntl::OFlag::from_bits_truncate((flags & UNIX_FLAGS) as i32);
-            let omode = nix::sys::stat::Mode::from_bits_truncate(0);
-            let fd = nix::fcntl::open(&realpath, oflags, omode)?;
+        let fd = fid.aux.fd().await?;
+
+        let attr = fd_metadata(&fd)?;
+        let qid = self.qids.qid(&attr);
+        if qid.typ.contains(QIdType::DIR) {
+            let stream = DirStream::open(&fd, &self.identity, fid.aux.cred().await.as_ref())?;
+            let mut dir = fid.aux.dir.lock().await;
+            *dir = Some(stream);
+        } else {
+            let oflags = From::from(P9OpenFlags::from_bits_truncate(flags) & UNIX_FLAGS);
+            let file = self
+                .identity
+                .run(fid.aux.cred().await.as_ref(), || reopen(&fd, oflags))??;
 
             {
-                let mut file = fid.aux.file.lock().await;
-                *file = Some(fs::File::from_std(fd.into()));
+                let mut fid_file = fid.aux.file.write().await;
+                *fid_file = Some(Arc::new(File::new(file)?));
             }
         }
 
-        Ok(FCall::RlOpen { qid, iounit: 0 })
+        Ok(FCall::RlOpen {
+            qid,
+            iounit: srv::iounit(attr.blksize() as u32),
+        })
     }
 
     async fn rlcreate(
@@ -290,74 +696,202 @@ impl Filesystem for Unpfs {
         name: &str,
         flags: u32,
         mode: u32,
-        _gid: u32,
+        gid: u32,
     ) -> Result<FCall> {
-        let path = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.join(name)
-        };
-        let oflags = nix::fcntl::OFlag::from_bits_truncate((flags & UNIX_FLAGS) as i32);
-        let omode = nix::sys::stat::Mode::from_bits_truncate(mode);
-        let fd = nix::fcntl::open(&path, oflags, omode)?;
+        root::check_entry(name)?;
+        let dir = fid.aux.fd().await?;
+        let cred = fid.aux.cred().await;
+
+        let oflags: OFlag = From::from(P9OpenFlags::from_bits_truncate(flags) & UNIX_FLAGS);
+        let mode = (FileMode::IFREG | FileMode::from_bits_truncate(mode).permissions()).bits();
+        let oflags = oflags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let file = self
+            .identity
+            .run(cred.as_ref(), || -> io::Result<OwnedFd> {
+                // Only a file created here gets the group and owner asked by the client, an
+                // existing one is opened as is unless the client asked for O_EXCL
+                loop {
+                    match openat(
+                        &*dir,
+                        name,
+                        oflags | OFlag::O_CREAT | OFlag::O_EXCL,
+                        self.identity.create_mode(mode),
+                    ) {
+                        Ok(file) => {
+                            let res = self.identity.created_file(cred.as_ref(), &file, mode, gid);
+                            if res.is_err() {
+                                let _ = nix::unistd::unlinkat(
+                                    &*dir,
+                                    name,
+                                    nix::unistd::UnlinkatFlags::NoRemoveDir,
+                                );
+                            }
+                            return res.map(|_| file);
+                        }
+                        Err(nix::errno::Errno::EEXIST) if !oflags.contains(OFlag::O_EXCL) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+
+                    match openat(&*dir, name, oflags - OFlag::O_CREAT, Mode::empty()) {
+                        // Removed in between, try creating it again
+                        Err(nix::errno::Errno::ENOENT) => continue,
+                        res => return Ok(res?),
+                    }
+                }
+            })??;
 
-        let qid = get_qid(&path).await?;
+        // The fid now refers to the new file
+        let fd = file.try_clone()?;
+        let attr = fd_metadata(&fd)?;
+        let qid = self.qids.qid(&attr);
+        fid.aux.set_fd(Arc::new(fd)).await;
         {
-            let mut realpath = fid.aux.realpath.write().await;
-            *realpath = path;
+            let mut depth = fid.aux.depth.write().await;
+            *depth += 1;
         }
         {
-            let mut file = fid.aux.file.lock().await;
-            *file = Some(fs::File::from_std(fd.into()));
+            let mut fid_file = fid.aux.file.write().await;
+            *fid_file = Some(Arc::new(File::new(file)?));
         }
 
-        Ok(FCall::RlCreate { qid, iounit: 0 })
+        Ok(FCall::RlCreate {
+            qid,
+            iounit: srv::iounit(attr.blksize() as u32),
+        })
     }
 
     async fn rread(&self, fid: &FId<Self::FId>, offset: u64, count: u32) -> Result<FCall> {
-        let buf = {
-            let mut file = fid.aux.file.lock().await;
-            let file = file.as_mut().ok_or_else(|| INVALID_FID!())?;
-            file.seek(SeekFrom::Start(offset)).await?;
-
-            let mut buf = vec![0; count as usize];
-            let bytes = file.read(&mut buf[..]).await?;
-            buf.truncate(bytes);
-            buf
-        };
+        if let Some(reply) = fid.aux.xattr.read(offset, count).await? {
+            return Ok(reply);
+        }
 
+        let buf = fid.aux.file().await?.read_at(offset, count).await?;
         Ok(FCall::RRead { data: Data(buf) })
     }
 
     async fn rwrite(&self, fid: &FId<Self::FId>, offset: u64, data: &Data) -> Result<FCall> {
-        let count = {
-            let mut file = fid.aux.file.lock().await;
-            let file = file.as_mut().ok_or_else(|| INVALID_FID!())?;
-            file.seek(SeekFrom::Start(offset)).await?;
-            file.write(&data.0).await? as u32
-        };
+        if let Some(reply) = fid.aux.xattr.write(offset, data).await? {
+            return Ok(reply);
+        }
+
+        let count = fid.aux.file().await?.write_at(offset, &data.0).await? as u32;
 
         Ok(FCall::RWrite { count })
     }
 
+    async fn rlock(&self, fid: &FId<Self::FId>, lock: &Flock) -> Result<FCall> {
+        let fd = fid.aux.fd().await?;
+        let qid = self.qids.qid(&fd_metadata(&fd)?);
+
+        let file = fid.aux.file.read().await.clone();
+        let Some(file) = file.filter(|_| self.ofd_locks) else {
+            return Ok(FCall::RLock {
+                status: self.locks.lock(&fid.aux.locks, qid.path, lock),
+            });
+        };
+
+        // Taken with the lock table locked, so that both always agree
+        let owner = LockOwner::from(lock);
+        let key = (qid.path, owner.clone());
+        let mut files = ofd_files(&self.ofd_files);
+        let shared = match files.get(&key) {
+            Some(shared) => shared.try_clone()?,
+            None => ofd_file(&fd, &file)?,
+        };
+        let status = self
+            .locks
+            .lock_mirrored(&fid.aux.locks, qid.path, lock, |flock| {
+                set_ofd_lock(&shared, flock)
+            })?;
+
+        if status == LockStatus::SUCCESS && lock.typ != LockType::UNLOCK {
+            files.entry(key).or_insert(shared);
+            let mut ofd = fid.aux.ofd.lock().unwrap_or_else(|e| e.into_inner());
+            ofd.get_or_insert_with(|| FidOfd {
+                files: self.ofd_files.clone(),
+                file: qid.path,
+                owners: HashSet::new(),
+            })
+            .owners
+            .insert(owner);
+        }
+
+        Ok(FCall::RLock { status })
+    }
+
+    async fn rgetlock(&self, fid: &FId<Self::FId>, lock: &Getlock) -> Result<FCall> {
+        let qid = self.qids.qid(&fd_metadata(&*fid.aux.fd().await?)?);
+
+        let flock = self.locks.getlock(qid.path, lock);
+        let file = fid.aux.file.read().await.clone();
+        if let Some(file) = file.filter(|_| self.ofd_locks)
+            && flock.typ == LockType::UNLOCK
+            && lock.typ != LockType::UNLOCK
+        {
+            // The owner's own locks are not a conflict
+            let files = ofd_files(&self.ofd_files);
+            let conflict = match files.get(&(qid.path, LockOwner::from(lock))) {
+                Some(shared) => get_ofd_lock(shared, lock)?,
+                None => get_ofd_lock(&*file, lock)?,
+            };
+            if let Some(conflict) = conflict {
+                return Ok(FCall::RGetLock { flock: conflict });
+            }
+        }
+
+        Ok(FCall::RGetLock { flock })
+    }
+
     async fn rmkdir(
         &self,
         dfid: &FId<Self::FId>,
         name: &str,
-        _mode: u32,
-        _gid: u32,
+        mode: u32,
+        gid: u32,
     ) -> Result<FCall> {
-        let path = {
-            let realpath = dfid.aux.realpath.read().await;
-            realpath.join(name)
-        };
+        root::check_entry(name)?;
+        let dir = dfid.aux.fd().await?;
+        let cred = dfid.aux.cred().await;
 
-        fs::create_dir(&path).await?;
+        let mode = (FileMode::IFDIR | FileMode::from_bits_truncate(mode).permissions()).bits();
+        self.identity.run(cred.as_ref(), || -> io::Result<()> {
+            nix::sys::stat::mkdirat(&*dir, name, self.identity.create_mode(mode))?;
+            self.identity
+                .created(cred.as_ref(), &dir, name, mode, 0, gid)
+        })??;
 
         Ok(FCall::RMkDir {
-            qid: get_qid(&path).await?,
+            qid: self
+                .qids
+                .qid(&Root::metadata_at(dir.as_fd(), name.as_ref())?),
         })
     }
 
+    async fn rlink(
+        &self,
+        dfid: &FId<Self::FId>,
+        fid: &FId<Self::FId>,
+        name: &str,
+    ) -> Result<FCall> {
+        root::check_entry(name)?;
+        let newdir = dfid.aux.fd().await?;
+        let fd = fid.aux.fd().await?;
+
+        // Linking an O_PATH descriptor through /proc needs no privileges,
+        // unlike AT_EMPTY_PATH
+        self.identity.run(dfid.aux.cred().await.as_ref(), || {
+            nix::unistd::linkat(
+                AT_FDCWD,
+                &fd_path(&fd),
+                &*newdir,
+                name,
+                AtFlags::AT_SYMLINK_FOLLOW,
+            )
+        })??;
+
+        Ok(FCall::RLink)
+    }
+
     async fn rrenameat(
         &self,
         olddir: &FId<Self::FId>,
@@ -365,58 +899,75 @@ impl Filesystem for Unpfs {
         newdir: &FId<Self::FId>,
         newname: &str,
     ) -> Result<FCall> {
-        let oldpath = {
-            let realpath = olddir.aux.realpath.read().await;
-            realpath.join(oldname)
-        };
+        root::check_entry(oldname)?;
+        root::check_entry(newname)?;
 
-        let newpath = {
-            let realpath = newdir.aux.realpath.read().await;
-            realpath.join(newname)
-        };
+        let cred = olddir.aux.cred().await;
+        let olddir = olddir.aux.fd().await?;
+        let newdir = newdir.aux.fd().await?;
 
-        fs::rename(&oldpath, &newpath).await?;
+        self.identity.run(cred.as_ref(), || {
+            nix::fcntl::renameat(&*olddir, oldname, &*newdir, newname)
+        })??;
 
         Ok(FCall::RRenameAt)
     }
 
-    async fn runlinkat(&self, dirfid: &FId<Self::FId>, name: &str, _flags: u32) -> Result<FCall> {
-        let path = {
-            let realpath = dirfid.aux.realpath.read().await;
-            realpath.join(name)
-        };
+    async fn runlinkat(&self, dirfid: &FId<Self::FId>, name: &str, flags: u32) -> Result<FCall> {
+        root::check_entry(name)?;
+        let dir = dirfid.aux.fd().await?;
 
-        match fs::symlink_metadata(&path).await? {
-            ref attr if attr.is_dir() => fs::remove_dir(&path).await?,
-            _ => fs::remove_file(&path).await?,
-        };
+        self.identity.run(dirfid.aux.cred().await.as_ref(), || {
+            nix::unistd::unlinkat(&*dir, name, UnlinkFlags::from_bits_truncate(flags).into())
+        })??;
 
         Ok(FCall::RUnlinkAt)
     }
 
     async fn rfsync(&self, fid: &FId<Self::FId>) -> Result<FCall> {
-        {
-            let mut file = fid.aux.file.lock().await;
-            file.as_mut()
-                .ok_or_else(|| INVALID_FID!())?
-                .sync_all()
-                .await?;
-        }
+        fid.aux.file().await?.sync_all().await?;
 
         Ok(FCall::RFSync)
     }
 
-    async fn rclunk(&self, _: &FId<Self::FId>) -> Result<FCall> {
+    async fn rclunk(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        if let Some(xattr) = fid.aux.xattr.commit().await? {
+            let (dir, name) = self.root.locate(&*fid.aux.fd().await?)?;
+            let cred = fid.aux.cred().await;
+            let identity = self.identity.clone();
+
+            tokio::task::spawn_blocking(move || {
+                identity
+                    .run(cred.as_ref(), || set_xattr(&proc_path(&dir, &name), &xattr))
+                    .flatten()
+            })
+            .await
+            .map_err(|e| Error::Io(io::Error::other(e)))??;
+        }
+
         Ok(FCall::RClunk)
     }
 
-    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
-        let path = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.clone()
+    async fn rremove(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let fd = fid.aux.fd().await?;
+        let (dir, name) = self.root.locate(&fd)?;
+
+        let flags = if fd_metadata(&fd)?.is_dir() {
+            nix::unistd::UnlinkatFlags::RemoveDir
+        } else {
+            nix::unistd::UnlinkatFlags::NoRemoveDir
         };
+        self.identity.run(fid.aux.cred().await.as_ref(), || {
+            nix::unistd::unlinkat(&dir, name.as_os_str(), flags)
+        })??;
+
+        Ok(FCall::RRemove)
+    }
 
-        let fs = tokio::task::spawn_blocking(move || nix::sys::statvfs::statvfs(&path))
+    async fn rstatfs(&self, fid: &FId<Self::FId>) -> Result<FCall> {
+        let fd = fid.aux.fd().await?;
+
+        let fs = tokio::task::spawn_blocking(move || nix::sys::statvfs::fstatvfs(&*fd))
             .await
             .map_err(|e| Error::Io(io::Error::other(e)))??;
 
@@ -426,51 +977,331 @@ impl Filesystem for Unpfs {
     }
 }
 
+/// A directory exported under an attach name,
+/// `name=/path[:ro][:identity=mode][:max_depth=n]`
+#[derive(Clone, Debug)]
+struct ExportArg {
+    name: String,
+    path: PathBuf,
+    read_only: bool,
+    identity: Option<IdentityMode>,
+    max_depth: Option<usize>,
+}
+
+fn parse_export(arg: &str) -> std::result::Result<ExportArg, String> {
+    let (name, mut path) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=/path[:ro], got {:?}", arg))?;
+    let mut export = ExportArg {
+        name: name.to_owned(),
+        path: PathBuf::new(),
+        read_only: false,
+        identity: None,
+        max_depth: None,
+    };
+
+    // Options are suffixes, anything else is part of the path
+    while let Some((rest, option)) = path.rsplit_once(':') {
+        match option.split_once('=') {
+            None if option == "ro" => export.read_only = true,
+            None if option == "rw" => export.read_only = false,
+            Some(("identity", mode)) => {
+                export.identity = Some(
+                    <IdentityMode as clap::ValueEnum>::from_str(mode, false)
+                        .map_err(|_| format!("invalid identity {:?}", mode))?,
+                );
+            }
+            Some(("max_depth", depth)) => {
+                export.max_depth = Some(
+                    depth
+                        .parse()
+                        .map_err(|_| format!("invalid max_depth {:?}", depth))?,
+                );
+            }
+            _ => break,
+        }
+        path = rest;
+    }
+    if path.is_empty() {
+        return Err(format!("missing directory for export {:?}", name));
+    }
+
+    export.path = PathBuf::from(path);
+    Ok(export)
+}
+
+/// If the user attaching as `uname`/`n_uname` is in `allow`, by name or uid
+///
+/// When `n_uname` is set it decides who the client is, as the credentials come from
+/// it: names in `allow` match if they are that user's, and an attach whose `uname`
+/// names another user is refused.
+fn allowed(allow: &[String], uname: &str, n_uname: u32) -> bool {
+    if n_uname == NONUNAME {
+        return allow.is_empty() || allow.iter().any(|user| user == uname);
+    }
+
+    let uid_of = |name: &str| {
+        nix::unistd::User::from_name(name)
+            .ok()
+            .flatten()
+            .map(|user| user.uid.as_raw())
+    };
+    if uid_of(uname).is_some_and(|uid| uid != n_uname) {
+        return false;
+    }
+    allow.is_empty()
+        || allow
+            .iter()
+            .any(|user| user.parse::<u32>() == Ok(n_uname) || uid_of(user) == Some(n_uname))
+}
+
 #[derive(Debug, clap::Parser)]
 struct Cli {
     /// proto!address!port
     /// where: proto = tcp | unix
-    address: String,
-
-    /// Directory to export
-    exportdir: PathBuf,
+    #[arg(required_unless_present = "config")]
+    address: Option<String>,
+
+    /// Directory to export, to clients attaching with an aname no --export matches
+    #[arg(required_unless_present_any = ["export", "config"])]
+    exportdir: Option<PathBuf>,
+
+    /// Also export a directory to clients attaching with aname set to name,
+    /// read-only with the :ro suffix, with its own --identity or --max-depth
+    /// with the :identity=MODE and :max_depth=N suffixes (repeatable)
+    #[arg(
+        long,
+        value_name = "NAME=/PATH[:ro][:identity=MODE][:max_depth=N]",
+        value_parser = parse_export
+    )]
+    export: Vec<ExportArg>,
+
+    /// Read the listeners and exports from a TOML file, reloaded on SIGHUP
+    #[arg(
+        long,
+        conflicts_with_all = ["exportdir", "export", "max_depth", "ofd_locks", "identity", "xdev", "read_only"]
+    )]
+    config: Option<PathBuf>,
 
     /// Maximum directory depth to traverse
     #[arg(long, default_value_t = 200)]
     max_depth: usize,
+
+    /// Also take OFD locks on the exported files, so that processes
+    /// on the server see the locks of 9P clients
+    #[arg(long)]
+    ofd_locks: bool,
+
+    /// Which user operations are performed as: none (the server's own user),
+    /// passthrough (the attached user), squash (--squash-uid/--squash-gid)
+    /// or mapped (ownership and mode stored in user.virtfs.* xattrs)
+    #[arg(long, value_enum, default_value_t = IdentityMode::None)]
+    identity: IdentityMode,
+
+    /// User all operations are performed as with --identity squash
+    #[arg(long, default_value_t = 65534)]
+    squash_uid: u32,
+
+    /// Group all operations are performed as with --identity squash
+    #[arg(long, default_value_t = 65534)]
+    squash_gid: u32,
+
+    /// Refuse walking into other filesystems than the exported directory's,
+    /// such as mount points and btrfs subvolumes
+    #[arg(long)]
+    xdev: bool,
+
+    /// Refuse all modifications of the export given as exportdir
+    #[arg(long)]
+    read_only: bool,
 }
 
-async fn unpfs_main(
-    Cli {
-        address,
-        exportdir,
-        max_depth,
-    }: Cli,
-) -> rs9p::Result<i32> {
-    if !fs::try_exists(&exportdir).await? {
-        fs::create_dir_all(&exportdir).await?;
-    }
-    if !fs::metadata(&exportdir).await?.is_dir() {
+impl Cli {
+    /// The configuration given on the command line, or in the file of --config
+    fn config(self) -> io::Result<Config> {
+        let mut config = match self.config {
+            Some(path) => Config::load(&path)?,
+            None => {
+                let default = self.exportdir.map(|path| ExportArg {
+                    name: String::new(),
+                    path,
+                    read_only: self.read_only,
+                    identity: None,
+                    max_depth: None,
+                });
+                let mut export = BTreeMap::new();
+                for arg in default.into_iter().chain(self.export) {
+                    let name = arg.name.clone();
+                    let config = ExportConfig {
+                        path: arg.path,
+                        read_only: arg.read_only,
+                        max_depth: arg.max_depth,
+                        ofd_locks: None,
+                        xdev: None,
+                        identity: arg.identity.map(|mode| IdentityConfig {
+                            mode,
+                            squash_uid: self.squash_uid,
+                            squash_gid: self.squash_gid,
+                        }),
+                        allow: None,
+                    };
+                    if export.insert(name.clone(), config).is_some() {
+                        return Err(io_err!(Other, format!("export {:?} given twice", name)));
+                    }
+                }
+
+                Config {
+                    listen: Vec::new(),
+                    log: None,
+                    max_depth: self.max_depth,
+                    ofd_locks: self.ofd_locks,
+                    xdev: self.xdev,
+                    identity: IdentityConfig {
+                        mode: self.identity,
+                        squash_uid: self.squash_uid,
+                        squash_gid: self.squash_gid,
+                    },
+                    export,
+                }
+            }
+        };
+
+        config.listen.extend(self.address);
+        if config.listen.is_empty() {
+            return Err(io_err!(Other, "no address to listen on"));
+        }
+        Ok(config)
+    }
+}
+
+async fn open_export(path: &Path) -> rs9p::Result<Root> {
+    if !fs::try_exists(path).await? {
+        fs::create_dir_all(path).await?;
+    }
+    if !fs::metadata(path).await?.is_dir() {
         return res!(io_err!(Other, "mount point must be a directory"));
     }
 
-    println!("[*] Maximum depth limit: {}", max_depth);
-    println!("[*] Ready to accept clients: {}", address);
-    srv_async(
-        Unpfs {
-            realroot: exportdir,
-            max_depth,
-        },
-        &address,
-    )
-    .await
-    .and(Ok(0))
+    Ok(Root::open(path)?)
+}
+
+/// Build the exports of `config`, carrying the state of the exports of `current` over
+/// to those of the same directories
+async fn build_exports(
+    config: &Config,
+    current: &MultiExport<Unpfs>,
+) -> rs9p::Result<MultiExport<Unpfs>> {
+    let exports = MultiExport::new();
+    for (name, settings) in config.exports() {
+        let (mode, squash_uid, squash_gid) = settings.identity;
+        let mut unpfs = Unpfs {
+            max_depth: settings.max_depth,
+            ofd_locks: settings.ofd_locks,
+            allow: Arc::new(settings.allow),
+            xdev: settings.xdev,
+            ..Unpfs::new(
+                open_export(&settings.path).await?,
+                Identity::new(mode, squash_uid, squash_gid)?,
+            )
+        };
+        // The export of the same name first, in case others export the same directory
+        let mut names = current.names();
+        names.sort_by_key(|old| old != name);
+        let old = names
+            .iter()
+            .filter_map(|old| current.get(old))
+            .find(|old| old.fs.root.same_as(&unpfs.root));
+        if let Some(old) = old {
+            unpfs = unpfs.carry_over(&old.fs);
+        }
+        println!(
+            "[*] Exporting {:?} as {:?}{}, maximum depth {}",
+            settings.path,
+            name,
+            if settings.read_only {
+                " (read-only)"
+            } else {
+                ""
+            },
+            settings.max_depth
+        );
+        exports.insert(
+            name.to_owned(),
+            Export::new(unpfs).read_only(settings.read_only),
+        );
+    }
+
+    Ok(exports)
+}
+
+/// Apply the file at `path` to `exports` on every SIGHUP, keeping the current
+/// configuration if it is invalid
+async fn reload_on_hangup(path: PathBuf, mut config: Config, exports: MultiExport<Unpfs>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!("Error: can't watch SIGHUP, reloading disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        println!("[*] Reloading {:?}", path);
+        let new = match Config::load(&path) {
+            Ok(new) => new,
+            Err(e) => {
+                eprintln!("Error: {}, keeping the current configuration", e);
+                continue;
+            }
+        };
+        match build_exports(&new, &exports).await {
+            Ok(new_exports) => exports.reload(new_exports),
+            Err(e) => {
+                eprintln!("Error: {:?}, keeping the current configuration", e);
+                continue;
+            }
+        }
+
+        if new
+            .listen
+            .iter()
+            .any(|address| !config.listen.contains(address))
+            || new.log != config.log
+        {
+            eprintln!("[!] Changes to listen and log take effect on restart");
+        }
+        config = new;
+    }
+}
+
+async fn unpfs_main(cli: Cli) -> rs9p::Result<i32> {
+    let path = cli.config.clone();
+    let config = cli.config()?;
+
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.log.as_deref().unwrap_or("error")),
+    );
+    builder.init();
+
+    let exports = build_exports(&config, &MultiExport::new()).await?;
+    for address in &config.listen {
+        println!("[*] Ready to accept clients: {}", address);
+    }
+    if let Some(path) = path {
+        tokio::spawn(reload_on_hangup(path, config.clone(), exports.clone()));
+    }
+
+    let servers = config
+        .listen
+        .iter()
+        .map(|address| srv_async(exports.clone(), address));
+    futures::future::try_join_all(servers).await.and(Ok(0))
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-
     let exit_code = unpfs_main(Cli::parse()).await.unwrap_or_else(|e| {
         eprintln!("Error: {:?}", e);
         -1
@@ -478,3 +1309,53 @@ async fn main() {
 
     std::process::exit(exit_code);
 }
+
+#[test]
+fn export_args() {
+    let export = parse_export("data=/srv/data:ro").unwrap();
+    assert_eq!(
+        (export.name.as_str(), export.path, export.read_only),
+        ("data", PathBuf::from("/srv/data"), true)
+    );
+    let export = parse_export("home=/a:b").unwrap();
+    assert_eq!(
+        (export.path, export.read_only),
+        (PathBuf::from("/a:b"), false)
+    );
+    assert!(parse_export("/srv/data").is_err());
+    assert!(parse_export("data=:ro").is_err());
+
+    let export = parse_export("data=/srv/data:ro:identity=mapped:max_depth=5").unwrap();
+    assert_eq!(
+        (export.path, export.read_only),
+        (PathBuf::from("/srv/data"), true)
+    );
+    assert_eq!(
+        (export.identity, export.max_depth),
+        (Some(IdentityMode::Mapped), Some(5))
+    );
+    let export = parse_export("data=/srv/data:max_depth=5:rw").unwrap();
+    assert_eq!(
+        (export.identity, export.max_depth, export.read_only),
+        (None, Some(5), false)
+    );
+    assert!(parse_export("data=/srv/data:identity=root").is_err());
+    assert!(parse_export("data=/srv/data:max_depth=deep").is_err());
+}
+
+#[test]
+fn allow_list() {
+    let allow = vec!["alice".to_owned(), "1000".to_owned()];
+    assert!(allowed(&allow, "alice", NONUNAME));
+    assert!(allowed(&allow, "bob", 1000));
+    assert!(!allowed(&allow, "bob", 1001));
+    assert!(allowed(&[], "bob", 1001));
+
+    // Names match the user of n_uname, which must agree with uname
+    let allow = vec!["root".to_owned()];
+    assert!(allowed(&allow, "", 0));
+    assert!(!allowed(&allow, "root", 65534));
+    assert!(!allowed(&[], "root", 65534));
+    assert!(!allowed(&["nobody".to_owned()], "root", 65534));
+    assert!(allowed(&["nobody".to_owned()], "nobody", 65534));
+}
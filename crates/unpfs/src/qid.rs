@@ -0,0 +1,141 @@
+//! Qids of the exported files.
+//!
+//! `qid.path` must be unique among all the files of an export, but an export can span
+//! several filesystems (mount points, btrfs subvolumes) whose inode numbers collide.
+//! Like QEMU's `multidevs=remap`, the device number is folded into the path:
+//!
+//! - files whose inode number fits in 48 bits get `prefix << 48 | ino`, where the
+//!   prefix is a 15 bit number allocated to each device in the order they're seen.
+//!   The device of the export root gets prefix 0, so exports on a single filesystem
+//!   keep the inode number as the path.
+//! - other files, or files on a device seen after all prefixes are taken, get the next
+//!   number of a counter, with the top bit set so they can't collide with the above.
+//!
+//! The table lives as long as the export, so a file keeps its qid across sessions
+//! until the server restarts.
+//!
+//! `qid.version` changes with mtime and ctime, so clients caching file contents see
+//! modifications made on the server or through another client.
+
+use {
+    crate::utils::data_version,
+    rs9p::fcall::*,
+    std::{collections::HashMap, ffi::OsStr, fs::Metadata, os::unix::fs::MetadataExt, sync::Mutex},
+};
+
+const INO_BITS: u32 = 48;
+const PREFIXES: u64 = 1 << (63 - INO_BITS);
+const REMAPPED: u64 = 1 << 63;
+
+#[derive(Debug)]
+pub struct QIdMap {
+    root_dev: u64,
+    tables: Mutex<Tables>,
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    prefixes: HashMap<u64, u64>,
+    remapped: HashMap<(u64, u64), u64>,
+}
+
+impl QIdMap {
+    /// Map the files of an export whose root is on the device `root_dev`
+    pub fn new(root_dev: u64) -> QIdMap {
+        let mut tables = Tables::default();
+        tables.prefixes.insert(root_dev, 0);
+
+        QIdMap {
+            root_dev,
+            tables: Mutex::new(tables),
+        }
+    }
+
+    /// If the file of `attr` is on another filesystem than the export root
+    pub fn is_foreign(&self, attr: &Metadata) -> bool {
+        attr.dev() != self.root_dev
+    }
+
+    pub fn qid(&self, attr: &Metadata) -> QId {
+        let version = data_version(attr);
+        QId {
+            typ: From::from(attr.file_type()),
+            version: (version ^ (version >> 32)) as u32,
+            path: self.path(attr.dev(), attr.ino()),
+        }
+    }
+
+    pub fn dirent<S: AsRef<OsStr> + ?Sized>(
+        &self,
+        name: &S,
+        attr: &Metadata,
+        offset: u64,
+    ) -> DirEntry {
+        DirEntry {
+            qid: self.qid(attr),
+            offset,
+            typ: DirEntryType::from(attr.file_type()).into(),
+            name: name.as_ref().to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Qid of a directory entry, from what `getdents` returns without a `stat`
+    ///
+    /// `dev` is the device of the directory. There's no version, clients only use the
+    /// type and path of the qids of directory entries.
+    pub fn entry_qid(&self, dev: u64, ino: u64, typ: DirEntryType) -> QId {
+        QId {
+            typ: typ.into(),
+            version: 0,
+            path: self.path(dev, ino),
+        }
+    }
+
+    fn path(&self, dev: u64, ino: u64) -> u64 {
+        let mut tables = self.tables.lock().unwrap_or_else(|e| e.into_inner());
+
+        if ino >> INO_BITS == 0 {
+            let next = tables.prefixes.len() as u64;
+            let prefix = match tables.prefixes.get(&dev) {
+                Some(&prefix) => Some(prefix),
+                None if next < PREFIXES => {
+                    tables.prefixes.insert(dev, next);
+                    Some(next)
+                }
+                None => None,
+            };
+            if let Some(prefix) = prefix {
+                return (prefix << INO_BITS) | ino;
+            }
+        }
+
+        let next = REMAPPED | tables.remapped.len() as u64;
+        *tables.remapped.entry((dev, ino)).or_insert(next)
+    }
+}
+
+#[test]
+fn qid_paths() {
+    let qids = QIdMap::new(10);
+
+    // The root device keeps its inode numbers
+    assert_eq!(qids.path(10, 42), 42);
+    // Same inode on other devices
+    assert_eq!(qids.path(11, 42), (1 << INO_BITS) | 42);
+    assert_eq!(qids.path(12, 42), (2 << INO_BITS) | 42);
+    assert_eq!(qids.path(11, 43), (1 << INO_BITS) | 43);
+
+    // Inode numbers too large to share the path with a prefix
+    let large = qids.path(10, 1 << INO_BITS);
+    assert_eq!(large, REMAPPED);
+    assert_eq!(qids.path(11, 1 << INO_BITS), REMAPPED | 1);
+    assert_eq!(qids.path(10, 1 << INO_BITS), large);
+
+    // Devices beyond the last prefix
+    for dev in 13..10 + PREFIXES {
+        assert_eq!(qids.path(dev, 1) >> INO_BITS, dev - 10);
+    }
+    assert_eq!(qids.path(10 + PREFIXES, 1), REMAPPED | 2);
+    assert_eq!(qids.path(10 + PREFIXES, 2), REMAPPED | 3);
+    assert_eq!(qids.path(10 + PREFIXES, 1), REMAPPED | 2);
+}
@@ -0,0 +1,273 @@
+//! Path resolution confined to the exported directory.
+//!
+//! Fids hold an `O_PATH` descriptor of their file and are walked one validated
+//! component at a time with `O_NOFOLLOW`, `..` stopping at the export root. The few
+//! operations which need the name of the file in its directory find its current path
+//! through `/proc/self/fd` and resolve it again beneath the root with
+//! `openat2(RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS)`, so neither `..` nor a symlink
+//! created by a client (or on the server) can lead outside of the export. Kernels
+//! without `openat2` fall back to walking the path one component at a time with
+//! `O_NOFOLLOW`, refusing symlinks anywhere but in the last component.
+
+use {
+    nix::{
+        errno::Errno,
+        fcntl::{OFlag, openat},
+        sys::stat::Mode,
+    },
+    rs9p::error::{self, errno::*},
+    std::{
+        ffi::{OsStr, OsString},
+        fs::{File, Metadata},
+        io,
+        os::{
+            fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+            unix::fs::MetadataExt,
+        },
+        path::{Component, Path, PathBuf},
+        sync::atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// The exported directory
+#[derive(Debug)]
+pub struct Root {
+    fd: OwnedFd,
+    path: PathBuf,
+    id: (u64, u64),
+    openat2: AtomicBool,
+}
+
+impl Root {
+    /// Open the directory to export
+    pub fn open(path: &Path) -> io::Result<Root> {
+        let fd = nix::fcntl::open(
+            path,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let attr = fd_metadata(&fd)?;
+
+        Ok(Root {
+            path: std::fs::read_link(fd_path(&fd))?,
+            id: (attr.dev(), attr.ino()),
+            fd,
+            openat2: AtomicBool::new(cfg!(any(target_os = "linux", target_os = "android"))),
+        })
+    }
+
+    /// Get a new `O_PATH` descriptor of the root
+    pub fn try_clone_fd(&self) -> io::Result<OwnedFd> {
+        self.fd.try_clone()
+    }
+
+    /// The device the root is on
+    pub fn dev(&self) -> u64 {
+        self.id.0
+    }
+
+    /// If `attr` are the attributes of the root
+    pub fn is_root(&self, attr: &Metadata) -> bool {
+        (attr.dev(), attr.ino()) == self.id
+    }
+
+    /// If `other` is the same directory
+    pub fn same_as(&self, other: &Root) -> bool {
+        self.id == other.id
+    }
+
+    /// Open `path`, relative to the root, without following a symlink in its last
+    /// component
+    ///
+    /// `mode` is only used with `O_CREAT`.
+    pub fn open_beneath(&self, path: &Path, flags: OFlag, mode: Mode) -> io::Result<OwnedFd> {
+        let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let mode = if flags.contains(OFlag::O_CREAT) {
+            mode
+        } else {
+            Mode::empty()
+        };
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.openat2.load(Ordering::Relaxed) {
+            use nix::fcntl::{OpenHow, ResolveFlag, openat2};
+
+            let how = OpenHow::new()
+                .flags(flags)
+                .mode(mode)
+                .resolve(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+            match openat2(&self.fd, path, how) {
+                Err(Errno::ENOSYS) => self.openat2.store(false, Ordering::Relaxed),
+                res => return Ok(res?),
+            }
+        }
+
+        self.open_components(path, flags, mode)
+    }
+
+    // Resolve the path by hand, for kernels older than 5.6
+    fn open_components(&self, path: &Path, flags: OFlag, mode: Mode) -> io::Result<OwnedFd> {
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => {}
+                _ => return Err(Errno::EXDEV.into()),
+            }
+        }
+
+        let Some((last, dirs)) = names.split_last() else {
+            return Ok(openat(&self.fd, ".", flags, mode)?);
+        };
+
+        let mut dir: Option<OwnedFd> = None;
+        for name in dirs {
+            let parent = dir.as_ref().map_or(self.fd.as_fd(), |fd| fd.as_fd());
+            dir = Some(openat(
+                parent,
+                *name,
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?);
+        }
+
+        let parent = dir.as_ref().map_or(self.fd.as_fd(), |fd| fd.as_fd());
+        Ok(openat(parent, *last, flags, mode)?)
+    }
+
+    /// Open the directory containing `path`, and get the name of `path` in it
+    ///
+    /// The root itself is returned as `.` in the root.
+    pub fn parent<'a>(&self, path: &'a Path) -> io::Result<(OwnedFd, &'a OsStr)> {
+        let dir_flags = OFlag::O_PATH | OFlag::O_DIRECTORY;
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                Ok((self.open_beneath(parent, dir_flags, Mode::empty())?, name))
+            }
+            _ => Ok((self.fd.try_clone()?, OsStr::new("."))),
+        }
+    }
+
+    /// Find the directory containing the file `fd` refers to, and its name in it
+    ///
+    /// Fails with `ENOENT` if the file has been removed, `EXDEV` if it was moved out of
+    /// the export and `ESTALE` if it was replaced while looking it up.
+    pub fn locate(&self, fd: &OwnedFd) -> io::Result<(OwnedFd, OsString)> {
+        let attr = fd_metadata(fd)?;
+        if self.is_root(&attr) {
+            return Ok((self.fd.try_clone()?, OsString::from(".")));
+        }
+
+        let path = std::fs::read_link(fd_path(fd))?;
+        let path = path
+            .strip_prefix(&self.path)
+            .map_err(|_| io::Error::from(Errno::EXDEV))?;
+        let (dir, name) = self.parent(path)?;
+
+        let found = Self::metadata_at(dir.as_fd(), name)?;
+        if (found.dev(), found.ino()) != (attr.dev(), attr.ino()) {
+            return Err(Errno::ESTALE.into());
+        }
+        Ok((dir, name.to_owned()))
+    }
+
+    /// Get an `O_PATH` descriptor of the entry `name` of the directory `dirfd`
+    pub fn open_at(dirfd: BorrowedFd, name: &OsStr) -> io::Result<OwnedFd> {
+        Ok(openat(
+            dirfd,
+            name,
+            OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?)
+    }
+
+    /// Get the attributes of the entry `name` of the directory `dirfd`
+    pub fn metadata_at(dirfd: BorrowedFd, name: &OsStr) -> io::Result<Metadata> {
+        File::from(Self::open_at(dirfd, name)?).metadata()
+    }
+}
+
+/// Get the attributes of the file `fd` refers to, which may be an `O_PATH` descriptor
+pub fn fd_metadata(fd: &OwnedFd) -> io::Result<Metadata> {
+    File::from(fd.try_clone()?).metadata()
+}
+
+/// Open the file `fd` refers to again, e.g. to turn an `O_PATH` descriptor into one
+/// which can be read or written
+pub fn reopen(fd: &OwnedFd, flags: OFlag) -> io::Result<OwnedFd> {
+    let flags = (flags | OFlag::O_CLOEXEC) - OFlag::O_CREAT - OFlag::O_NOFOLLOW;
+    Ok(nix::fcntl::open(&fd_path(fd), flags, Mode::empty())?)
+}
+
+/// Path of the magic link to `fd` in `/proc/self/fd`
+pub fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Refuse names which are not a single path component
+///
+/// Names come from the client and are joined to paths, a `/` would walk several
+/// directories at once.
+pub fn check_name(name: &str) -> rs9p::Result<()> {
+    if name.is_empty() || name.contains(['/', '\0']) {
+        return Err(error::Error::No(EINVAL));
+    }
+    Ok(())
+}
+
+/// Like [`check_name`], also refusing `.` and `..` for entries to create, rename or remove
+pub fn check_entry(name: &str) -> rs9p::Result<()> {
+    check_name(name)?;
+    if name == "." || name == ".." {
+        return Err(error::Error::No(EINVAL));
+    }
+    Ok(())
+}
+
+/// Path of the entry `name` of `dirfd` through `/proc/self/fd`
+///
+/// For the calls which have no `*at` variant, like the xattr ones. Only `dirfd` is a
+/// magic link, `name` is resolved like by the `*at` calls.
+pub fn proc_path<S: AsRef<OsStr> + ?Sized>(dirfd: &OwnedFd, name: &S) -> PathBuf {
+    fd_path(dirfd).join(name.as_ref())
+}
+
+#[test]
+fn check_names() {
+    assert!(check_name("..").is_ok());
+    assert!(check_name("../etc").is_err());
+    assert!(check_name("/etc").is_err());
+    assert!(check_name("a\0b").is_err());
+    assert!(check_entry("..").is_err());
+}
+
+#[test]
+fn open_beneath_refuses_escapes() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::os::unix::fs::symlink("/etc", dir.path().join("sub/etc")).unwrap();
+    std::os::unix::fs::symlink("..", dir.path().join("sub/up")).unwrap();
+
+    let root = Root::open(&dir.path().join("sub")).unwrap();
+    let metadata = |path: &str| {
+        root.open_beneath(Path::new(path), OFlag::O_PATH, Mode::empty())
+            .and_then(|fd| fd_metadata(&fd))
+    };
+    for fallback in [false, true] {
+        if fallback {
+            root.openat2.store(false, Ordering::Relaxed);
+        }
+
+        // The symlink itself is fine, going through it is not
+        assert!(metadata("etc").unwrap().is_symlink());
+        assert!(metadata("etc/passwd").is_err());
+        assert!(metadata("up/sub").is_err());
+        assert!(metadata("../sub").is_err());
+        assert!(metadata("").unwrap().is_dir());
+    }
+}
@@ -0,0 +1,1336 @@
+//! Protocol level tests: unpfs is served on a unix socket and driven by raw 9P messages.
+
+use {
+    super::*,
+    futures::{SinkExt, StreamExt},
+    rs9p::{
+        codec::NinePCodec,
+        overlay::Overlay,
+        proxy::Proxy,
+        readonly::ReadOnly,
+        srv::{srv_async, srv_async_unix},
+    },
+    std::{
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::Path,
+        time::Duration,
+    },
+    tempfile::TempDir,
+    tokio::net::UnixStream,
+    tokio_util::codec::Framed,
+};
+
+struct Session {
+    framed: Framed<UnixStream, NinePCodec>,
+    tag: u16,
+    root: PathBuf,
+    _dir: TempDir,
+}
+
+impl Session {
+    /// Export an empty directory and attach to it as fid 0
+    async fn new() -> Session {
+        Session::with_identity(Identity::new(IdentityMode::None, 0, 0).unwrap(), 0).await
+    }
+
+    async fn with_identity(identity: Identity, n_uname: u32) -> Session {
+        Session::with_fs(
+            |root| Unpfs::new(Root::open(root).unwrap(), identity),
+            n_uname,
+        )
+        .await
+    }
+
+    /// Export an empty directory through the filesystem built by `make`
+    async fn with_fs<Fs>(make: impl FnOnce(&Path) -> Fs, n_uname: u32) -> Session
+    where
+        Fs: 'static + Filesystem + Send + Sync + Clone,
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("export");
+        std::fs::create_dir(&root).unwrap();
+
+        let sock = dir.path().join("sock");
+        tokio::spawn(srv_async_unix(make(&root), sock.clone()));
+
+        let stream = loop {
+            match UnixStream::connect(&sock).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let mut session = Session {
+            framed: Framed::new(stream, NinePCodec::new()),
+            tag: 0,
+            root,
+            _dir: dir,
+        };
+        session
+            .rpc(FCall::TVersion {
+                msize: 8192,
+                version: P92000L.to_owned(),
+            })
+            .await;
+        let attached = session
+            .rpc(FCall::TAttach {
+                fid: 0,
+                afid: NOFID,
+                uname: "test".to_owned(),
+                aname: String::new(),
+                n_uname,
+            })
+            .await;
+        assert!(matches!(attached, FCall::RAttach { .. }), "{}", attached);
+        session
+    }
+
+    async fn rpc(&mut self, body: FCall) -> FCall {
+        self.tag += 1;
+        self.framed
+            .send(Msg {
+                tag: self.tag,
+                body,
+            })
+            .await
+            .unwrap();
+
+        let reply = self.framed.next().await.unwrap().unwrap();
+        assert_eq!(reply.tag, self.tag);
+        reply.body
+    }
+
+    async fn walk(&mut self, newfid: u32, name: &str) {
+        let reply = self
+            .rpc(FCall::TWalk {
+                fid: 0,
+                newfid,
+                wnames: vec![name.to_owned()],
+            })
+            .await;
+        assert!(matches!(reply, FCall::RWalk { ref wqids } if wqids.len() == 1));
+    }
+}
+
+fn gid() -> u32 {
+    nix::unistd::getegid().as_raw()
+}
+
+#[tokio::test]
+async fn conformance_symlink() {
+    let mut s = Session::new().await;
+    let reply = s
+        .rpc(FCall::TSymlink {
+            fid: 0,
+            name: "link".to_owned(),
+            symtgt: "target".to_owned(),
+            gid: gid(),
+        })
+        .await;
+
+    assert!(matches!(reply, FCall::RSymlink { qid } if qid.typ == QIdType::SYMLINK));
+    let path = s.root.join("link");
+    assert_eq!(std::fs::read_link(&path).unwrap(), PathBuf::from("target"));
+    assert_eq!(std::fs::symlink_metadata(&path).unwrap().gid(), gid());
+}
+
+#[tokio::test]
+async fn conformance_mknod() {
+    let mut s = Session::new().await;
+    let reply = s
+        .rpc(FCall::TMkNod {
+            dfid: 0,
+            name: "fifo".to_owned(),
+            mode: (FileMode::IFIFO | FileMode::IRUSR | FileMode::IWUSR | FileMode::IRGRP).bits(),
+            major: 0,
+            minor: 0,
+            gid: gid(),
+        })
+        .await;
+
+    assert!(matches!(reply, FCall::RMkNod { .. }), "{}", reply);
+    let attr = std::fs::symlink_metadata(s.root.join("fifo")).unwrap();
+    assert!(std::os::unix::fs::FileTypeExt::is_fifo(&attr.file_type()));
+    assert_eq!(attr.mode() & 0o777, 0o640);
+    assert_eq!(attr.gid(), gid());
+}
+
+#[tokio::test]
+async fn conformance_mkdir() {
+    let mut s = Session::new().await;
+    let reply = s
+        .rpc(FCall::TMkDir {
+            dfid: 0,
+            name: "dir".to_owned(),
+            mode: 0o750,
+            gid: gid(),
+        })
+        .await;
+
+    assert!(matches!(reply, FCall::RMkDir { qid } if qid.typ == QIdType::DIR));
+    let attr = std::fs::metadata(s.root.join("dir")).unwrap();
+    assert_eq!(attr.mode() & 0o777, 0o750);
+    assert_eq!(attr.gid(), gid());
+}
+
+#[tokio::test]
+async fn conformance_create_existing() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    let create = |flags: P9OpenFlags| FCall::TlCreate {
+        fid: 1,
+        name: "file".to_owned(),
+        flags: (P9OpenFlags::RDWR | P9OpenFlags::CREATE | flags).bits(),
+        mode: 0o644,
+        gid: gid() + 1,
+    };
+
+    s.rpc(FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec![],
+    })
+    .await;
+    let reply = s.rpc(create(P9OpenFlags::EXCL)).await;
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::EEXIST as u32
+        }
+    );
+
+    // The existing file is opened, but keeps its group
+    let reply = s.rpc(create(P9OpenFlags::empty())).await;
+    assert!(matches!(reply, FCall::RlCreate { .. }), "{}", reply);
+    let attr = std::fs::metadata(s.root.join("file")).unwrap();
+    assert_eq!(attr.gid(), gid());
+    assert_eq!(std::fs::read(s.root.join("file")).unwrap(), b"data");
+}
+
+#[tokio::test]
+async fn conformance_link() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    s.walk(1, "file").await;
+
+    let reply = s
+        .rpc(FCall::TLink {
+            dfid: 0,
+            fid: 1,
+            name: "hardlink".to_owned(),
+        })
+        .await;
+
+    assert_eq!(reply, FCall::RLink);
+    assert_eq!(
+        std::fs::read_to_string(s.root.join("hardlink")).unwrap(),
+        "data"
+    );
+    assert_eq!(std::fs::metadata(s.root.join("file")).unwrap().nlink(), 2);
+}
+
+#[tokio::test]
+async fn conformance_rename() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    s.walk(1, "file").await;
+
+    let reply = s
+        .rpc(FCall::TRename {
+            fid: 1,
+            dfid: 0,
+            name: "moved".to_owned(),
+        })
+        .await;
+
+    assert_eq!(reply, FCall::RRename);
+    assert!(!s.root.join("file").exists());
+    assert_eq!(
+        std::fs::read_to_string(s.root.join("moved")).unwrap(),
+        "data"
+    );
+
+    // The fid follows the file to its new name
+    let reply = s
+        .rpc(FCall::TGetAttr {
+            fid: 1,
+            req_mask: GetAttrMask::SIZE,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RGetAttr { stat, .. } if stat.size == 4));
+}
+
+#[tokio::test]
+async fn conformance_remove() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    s.walk(1, "file").await;
+
+    assert_eq!(s.rpc(FCall::TRemove { fid: 1 }).await, FCall::RRemove);
+    assert!(!s.root.join("file").exists());
+
+    // TRemove clunks the fid
+    assert_eq!(
+        s.rpc(FCall::TClunk { fid: 1 }).await,
+        FCall::RlError {
+            ecode: errno::EBADF as u32
+        }
+    );
+}
+
+#[tokio::test]
+async fn conformance_walk_beneath_root() {
+    let mut s = Session::new().await;
+    std::os::unix::fs::symlink("/etc", s.root.join("etc")).unwrap();
+
+    let walk = |wnames: &[&str]| FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: wnames.iter().map(|name| name.to_string()).collect(),
+    };
+
+    // ".." at the root is the root
+    assert!(matches!(s.rpc(walk(&[".."])).await, FCall::RWalk { ref wqids } if wqids.len() == 1));
+    let parent = s
+        .rpc(FCall::TGetAttr {
+            fid: 1,
+            req_mask: GetAttrMask::INO,
+        })
+        .await;
+    assert!(
+        matches!(parent, FCall::RGetAttr { qid, .. } if qid.path == std::fs::metadata(&s.root).unwrap().ino())
+    );
+
+    let einval = FCall::RlError {
+        ecode: errno::EINVAL as u32,
+    };
+    assert_eq!(s.rpc(walk(&["../etc"])).await, einval);
+    assert_eq!(s.rpc(walk(&["/etc"])).await, einval);
+
+    // The symlink can be walked to, but not through
+    assert_eq!(
+        s.rpc(walk(&["etc", "passwd"])).await,
+        FCall::RWalk {
+            wqids: vec![QId::symlink(
+                std::fs::symlink_metadata(s.root.join("etc")).unwrap().ino()
+            )]
+        }
+    );
+}
+
+#[tokio::test]
+async fn conformance_fid_follows_server_rename() {
+    let mut s = Session::new().await;
+    std::fs::create_dir(s.root.join("dir")).unwrap();
+    std::fs::write(s.root.join("dir/file"), "data").unwrap();
+    let reply = s
+        .rpc(FCall::TWalk {
+            fid: 0,
+            newfid: 1,
+            wnames: vec!["dir".to_owned(), "file".to_owned()],
+        })
+        .await;
+    assert!(matches!(reply, FCall::RWalk { ref wqids } if wqids.len() == 2));
+
+    std::fs::rename(s.root.join("dir"), s.root.join("moved")).unwrap();
+
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: P9OpenFlags::RDONLY.bits(),
+        })
+        .await;
+    assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+    let reply = s
+        .rpc(FCall::TRead {
+            fid: 1,
+            offset: 0,
+            count: 16,
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RRead {
+            data: Data(b"data".to_vec())
+        }
+    );
+
+    assert_eq!(s.rpc(FCall::TRemove { fid: 1 }).await, FCall::RRemove);
+    assert!(!s.root.join("moved/file").exists());
+}
+
+#[tokio::test]
+async fn conformance_xattr() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    s.walk(1, "file").await;
+
+    let reply = s
+        .rpc(FCall::TxAttrCreate {
+            fid: 1,
+            name: "user.test".to_owned(),
+            attr_size: 3,
+            flags: XattrFlags::CREATE.bits(),
+        })
+        .await;
+    assert_eq!(reply, FCall::RxAttrCreate);
+    let reply = s
+        .rpc(FCall::TWrite {
+            fid: 1,
+            offset: 0,
+            data: Data(b"abc".to_vec()),
+        })
+        .await;
+    assert_eq!(reply, FCall::RWrite { count: 3 });
+    match s.rpc(FCall::TClunk { fid: 1 }).await {
+        FCall::RClunk => {}
+        // user.* attributes are not supported by every filesystem
+        FCall::RlError { ecode } if ecode == errno::EOPNOTSUPP as u32 => return,
+        reply => panic!("{}", reply),
+    }
+
+    s.walk(1, "file").await;
+    let reply = s
+        .rpc(FCall::TxAttrWalk {
+            fid: 1,
+            newfid: 2,
+            name: "user.test".to_owned(),
+        })
+        .await;
+    assert_eq!(reply, FCall::RxAttrWalk { size: 3 });
+    let reply = s
+        .rpc(FCall::TRead {
+            fid: 2,
+            offset: 0,
+            count: 16,
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RRead {
+            data: Data(b"abc".to_vec())
+        }
+    );
+}
+
+#[tokio::test]
+async fn conformance_mapped_identity() {
+    let mut s =
+        Session::with_identity(Identity::new(IdentityMode::Mapped, 0, 0).unwrap(), 4242).await;
+    // user.* attributes are not supported by every filesystem
+    if rustix::fs::setxattr(&s.root, "user.probe", b"", rustix::fs::XattrFlags::empty()).is_err() {
+        return;
+    }
+
+    let reply = s
+        .rpc(FCall::TMkNod {
+            dfid: 0,
+            name: "dev".to_owned(),
+            mode: (FileMode::IFCHR | FileMode::from_bits_truncate(0o644)).bits(),
+            major: 1,
+            minor: 3,
+            gid: 4343,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RMkNod { .. }), "{}", reply);
+
+    // On disk it is a private regular file
+    let attr = std::fs::metadata(s.root.join("dev")).unwrap();
+    assert!(attr.is_file());
+    assert_eq!(attr.mode() & 0o777, 0o600);
+
+    s.walk(1, "dev").await;
+    let reply = s
+        .rpc(FCall::TGetAttr {
+            fid: 1,
+            req_mask: GetAttrMask::BASIC,
+        })
+        .await;
+    let FCall::RGetAttr { stat, .. } = reply else {
+        panic!("{}", reply);
+    };
+    assert_eq!((stat.uid, stat.gid), (4242, 4343));
+    assert_eq!(
+        stat.mode,
+        (FileMode::IFCHR | FileMode::from_bits_truncate(0o644)).bits()
+    );
+    assert_eq!(stat.rdev, nix::sys::stat::makedev(1, 3));
+
+    let reply = s
+        .rpc(FCall::TSetAttr {
+            fid: 1,
+            valid: SetAttrMask::MODE | SetAttrMask::UID,
+            stat: SetAttr {
+                mode: 0o600,
+                uid: 7,
+                gid: 0,
+                size: 0,
+                atime: Time { sec: 0, nsec: 0 },
+                mtime: Time { sec: 0, nsec: 0 },
+            },
+        })
+        .await;
+    assert_eq!(reply, FCall::RSetAttr);
+    let reply = s
+        .rpc(FCall::TGetAttr {
+            fid: 1,
+            req_mask: GetAttrMask::BASIC,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RGetAttr { stat, .. }
+        if stat.uid == 7 && stat.mode == (FileMode::IFCHR | FileMode::IRUSR | FileMode::IWUSR).bits()));
+
+    // The attributes are hidden from the client
+    let reply = s
+        .rpc(FCall::TxAttrWalk {
+            fid: 1,
+            newfid: 2,
+            name: "user.virtfs.uid".to_owned(),
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::ENODATA as u32
+        }
+    );
+    let reply = s
+        .rpc(FCall::TxAttrWalk {
+            fid: 1,
+            newfid: 2,
+            name: String::new(),
+        })
+        .await;
+    assert_eq!(reply, FCall::RxAttrWalk { size: 0 });
+
+    // Opening an existing file through a create leaves its attributes alone
+    s.rpc(FCall::TWalk {
+        fid: 0,
+        newfid: 3,
+        wnames: vec![],
+    })
+    .await;
+    let reply = s
+        .rpc(FCall::TlCreate {
+            fid: 3,
+            name: "dev".to_owned(),
+            flags: (P9OpenFlags::RDWR | P9OpenFlags::CREATE).bits(),
+            mode: 0o666,
+            gid: 1,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RlCreate { .. }), "{}", reply);
+    let reply = s
+        .rpc(FCall::TGetAttr {
+            fid: 3,
+            req_mask: GetAttrMask::BASIC,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RGetAttr { stat, .. }
+        if stat.uid == 7 && stat.gid == 4343 && stat.rdev == nix::sys::stat::makedev(1, 3)));
+}
+
+#[tokio::test]
+async fn conformance_passthrough_groups() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let mut s = Session::with_identity(
+        Identity::new(IdentityMode::Passthrough, 0, 0).unwrap(),
+        4242,
+    )
+    .await;
+
+    // Only readable by a group the server is a member of but the client isn't
+    let groups = nix::unistd::getgroups().unwrap();
+    nix::unistd::setgroups(&[nix::unistd::Gid::from_raw(4343)]).unwrap();
+    let path = s.root.join("secret");
+    std::fs::write(&path, "data").unwrap();
+    std::os::unix::fs::chown(&path, None, Some(4343)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o040)).unwrap();
+
+    s.walk(1, "secret").await;
+    let reply = s.rpc(FCall::TlOpen { fid: 1, flags: 0 }).await;
+    nix::unistd::setgroups(&groups).unwrap();
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::EACCES as u32
+        }
+    );
+}
+
+#[tokio::test]
+async fn conformance_passthrough_foreign_group() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let mut s = Session::with_identity(
+        Identity::new(IdentityMode::Passthrough, 0, 0).unwrap(),
+        4242,
+    )
+    .await;
+    std::fs::set_permissions(&s.root, std::fs::Permissions::from_mode(0o777)).unwrap();
+
+    // A group the user isn't a member of fails the creation as a whole
+    s.rpc(FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec![],
+    })
+    .await;
+    let reply = s
+        .rpc(FCall::TMkDir {
+            dfid: 0,
+            name: "dir".to_owned(),
+            mode: 0o755,
+            gid: 4343,
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::EPERM as u32
+        }
+    );
+    let reply = s
+        .rpc(FCall::TlCreate {
+            fid: 1,
+            name: "file".to_owned(),
+            flags: (P9OpenFlags::RDWR | P9OpenFlags::CREATE).bits(),
+            mode: 0o644,
+            gid: 4343,
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::EPERM as u32
+        }
+    );
+    assert!(!s.root.join("dir").exists());
+    assert!(!s.root.join("file").exists());
+}
+
+#[tokio::test]
+async fn conformance_passthrough_supplementary_groups() {
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    // Any user of the system with a supplementary group
+    let member = std::fs::read_to_string("/etc/group")
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| {
+            let fields: Vec<_> = line.split(':').collect();
+            let gid = fields.get(2)?.parse::<u32>().ok()?;
+            let user = fields
+                .get(3)?
+                .split(',')
+                .find_map(|name| nix::unistd::User::from_name(name).ok().flatten())?;
+            (!user.uid.is_root() && user.gid.as_raw() != gid).then_some((user.uid, gid))
+        });
+    let Some((uid, gid)) = member else {
+        return;
+    };
+    let mut s = Session::with_identity(
+        Identity::new(IdentityMode::Passthrough, 0, 0).unwrap(),
+        uid.as_raw(),
+    )
+    .await;
+
+    let path = s.root.join("shared");
+    std::fs::write(&path, "data").unwrap();
+    std::os::unix::fs::chown(&path, None, Some(gid)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o040)).unwrap();
+
+    s.walk(1, "shared").await;
+    let reply = s.rpc(FCall::TlOpen { fid: 1, flags: 0 }).await;
+    assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+}
+
+#[tokio::test]
+async fn conformance_ofd_locks() {
+    let mut s = Session::with_fs(
+        |root| Unpfs {
+            ofd_locks: true,
+            ..Unpfs::new(
+                Root::open(root).unwrap(),
+                Identity::new(IdentityMode::None, 0, 0).unwrap(),
+            )
+        },
+        0,
+    )
+    .await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    // A process on the server, with its own open file description
+    let local = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(s.root.join("file"))
+        .unwrap();
+    let flock = |typ, start, proc_id| Flock {
+        typ,
+        flags: LockFlag::empty(),
+        start,
+        length: 10,
+        proc_id,
+        client_id: "host".to_owned(),
+    };
+    let getlock = |typ, start, proc_id| Getlock {
+        typ,
+        start,
+        length: 10,
+        proc_id,
+        client_id: "host".to_owned(),
+    };
+
+    for fid in [1, 2] {
+        s.walk(fid, "file").await;
+        let reply = s
+            .rpc(FCall::TlOpen {
+                fid,
+                flags: P9OpenFlags::RDWR.bits(),
+            })
+            .await;
+        assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+    }
+
+    // Locks of clients are seen by the server process
+    let reply = s
+        .rpc(FCall::TLock {
+            fid: 1,
+            flock: flock(LockType::WRLOCK, 0, 1),
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::SUCCESS
+        }
+    );
+    assert!(!set_ofd_lock(&local, &flock(LockType::RDLOCK, 0, 0)).unwrap());
+
+    // And the other way around, without leaving anything in the lock table
+    assert!(set_ofd_lock(&local, &flock(LockType::RDLOCK, 100, 0)).unwrap());
+    let reply = s
+        .rpc(FCall::TGetLock {
+            fid: 2,
+            flock: getlock(LockType::WRLOCK, 100, 2),
+        })
+        .await;
+    assert!(
+        matches!(reply, FCall::RGetLock { ref flock } if flock.typ == LockType::RDLOCK),
+        "{}",
+        reply
+    );
+    let reply = s
+        .rpc(FCall::TLock {
+            fid: 2,
+            flock: flock(LockType::WRLOCK, 100, 2),
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::BLOCKED
+        }
+    );
+    assert!(set_ofd_lock(&local, &flock(LockType::UNLOCK, 100, 0)).unwrap());
+    let reply = s
+        .rpc(FCall::TGetLock {
+            fid: 1,
+            flock: getlock(LockType::WRLOCK, 100, 3),
+        })
+        .await;
+    assert!(
+        matches!(reply, FCall::RGetLock { ref flock } if flock.typ == LockType::UNLOCK),
+        "{}",
+        reply
+    );
+
+    // Unlocking keeps the locks other owners took through the same fid
+    for proc_id in [1, 4] {
+        let reply = s
+            .rpc(FCall::TLock {
+                fid: 1,
+                flock: flock(LockType::RDLOCK, 200, proc_id),
+            })
+            .await;
+        assert_eq!(
+            reply,
+            FCall::RLock {
+                status: LockStatus::SUCCESS
+            }
+        );
+    }
+    let reply = s
+        .rpc(FCall::TLock {
+            fid: 1,
+            flock: flock(LockType::UNLOCK, 200, 1),
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::SUCCESS
+        }
+    );
+    assert!(!set_ofd_lock(&local, &flock(LockType::WRLOCK, 200, 0)).unwrap());
+
+    // An owner locking through two fids doesn't conflict with itself
+    for fid in [1, 2] {
+        let reply = s
+            .rpc(FCall::TLock {
+                fid,
+                flock: flock(LockType::WRLOCK, 300, 5),
+            })
+            .await;
+        assert_eq!(
+            reply,
+            FCall::RLock {
+                status: LockStatus::SUCCESS
+            }
+        );
+    }
+    assert!(!set_ofd_lock(&local, &flock(LockType::RDLOCK, 300, 0)).unwrap());
+    let reply = s
+        .rpc(FCall::TLock {
+            fid: 2,
+            flock: flock(LockType::UNLOCK, 300, 5),
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::SUCCESS
+        }
+    );
+    assert!(set_ofd_lock(&local, &flock(LockType::RDLOCK, 300, 0)).unwrap());
+
+    // Clunking a fid releases the locks taken through it
+    s.rpc(FCall::TClunk { fid: 1 }).await;
+    assert!(set_ofd_lock(&local, &flock(LockType::WRLOCK, 0, 0)).unwrap());
+    assert!(set_ofd_lock(&local, &flock(LockType::WRLOCK, 200, 0)).unwrap());
+}
+
+#[tokio::test]
+async fn conformance_read_only() {
+    let identity = Identity::new(IdentityMode::None, 0, 0).unwrap();
+    let mut s = Session::with_fs(
+        |root| ReadOnly::new(Unpfs::new(Root::open(root).unwrap(), identity)),
+        0,
+    )
+    .await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    let erofs = FCall::RlError {
+        ecode: errno::EROFS as u32,
+    };
+
+    s.walk(1, "file").await;
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: (P9OpenFlags::RDONLY | P9OpenFlags::TRUNC).bits(),
+        })
+        .await;
+    assert_eq!(reply, erofs);
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: P9OpenFlags::RDWR.bits(),
+        })
+        .await;
+    assert_eq!(reply, erofs);
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: P9OpenFlags::RDONLY.bits(),
+        })
+        .await;
+    assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+    let reply = s
+        .rpc(FCall::TWrite {
+            fid: 1,
+            offset: 0,
+            data: Data(b"x".to_vec()),
+        })
+        .await;
+    assert_eq!(reply, erofs);
+
+    let reply = s
+        .rpc(FCall::TMkDir {
+            dfid: 0,
+            name: "dir".to_owned(),
+            mode: 0o755,
+            gid: gid(),
+        })
+        .await;
+    assert_eq!(reply, erofs);
+    let reply = s
+        .rpc(FCall::TUnlinkAt {
+            dirfd: 0,
+            name: "file".to_owned(),
+            flags: 0,
+        })
+        .await;
+    assert_eq!(reply, erofs);
+
+    assert_eq!(
+        std::fs::read_to_string(s.root.join("file")).unwrap(),
+        "data"
+    );
+    assert!(!s.root.join("dir").exists());
+}
+
+#[tokio::test]
+async fn conformance_qid_version() {
+    let mut s = Session::new().await;
+    let file = s.root.join("file");
+    std::fs::write(&file, "data").unwrap();
+    let qid = async |s: &mut Session| match s
+        .rpc(FCall::TGetAttr {
+            fid: 1,
+            req_mask: GetAttrMask::BASIC,
+        })
+        .await
+    {
+        FCall::RGetAttr { qid, .. } => qid,
+        reply => panic!("{}", reply),
+    };
+
+    s.walk(1, "file").await;
+    let before = qid(&mut s).await;
+    assert_eq!(before.path, std::fs::metadata(&file).unwrap().ino());
+    assert_eq!(qid(&mut s).await, before);
+
+    let modified = std::time::SystemTime::now() + Duration::from_secs(60);
+    std::fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let after = qid(&mut s).await;
+    assert_eq!(after.path, before.path);
+    assert_ne!(after.version, before.version);
+}
+
+#[tokio::test]
+async fn conformance_generation() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    std::os::unix::fs::symlink("file", s.root.join("link")).unwrap();
+    let path = s.root.join("file");
+    let fd = OwnedFd::from(std::fs::File::open(&path).unwrap());
+    let expected = generation(&fd, &std::fs::metadata(&path).unwrap());
+
+    // Only reported if the filesystem keeps one
+    s.walk(1, "file").await;
+    s.walk(2, "link").await;
+    let getattr = |fid| FCall::TGetAttr {
+        fid,
+        req_mask: GetAttrMask::ALL,
+    };
+    let reply = s.rpc(getattr(1)).await;
+    let FCall::RGetAttr { valid, stat, .. } = reply else {
+        panic!("{}", reply);
+    };
+    assert_eq!(valid.contains(GetAttrMask::GEN), expected.is_some());
+    assert_eq!(stat.generation, expected.unwrap_or(0));
+    let reply = s.rpc(getattr(2)).await;
+    assert!(matches!(reply, FCall::RGetAttr { valid, .. } if !valid.contains(GetAttrMask::GEN)));
+}
+
+#[tokio::test]
+async fn conformance_readdir() {
+    let mut s = Session::new().await;
+    std::fs::create_dir(s.root.join("dir")).unwrap();
+    std::os::unix::fs::symlink("dir", s.root.join("link")).unwrap();
+    for i in 0..100 {
+        std::fs::write(s.root.join(format!("file{}", i)), "").unwrap();
+    }
+    s.rpc(FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: Vec::new(),
+    })
+    .await;
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: (P9OpenFlags::RDONLY | P9OpenFlags::DIRECTORY).bits(),
+        })
+        .await;
+    assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+
+    // Small replies, resuming from the offset of the last entry
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let data = match s
+            .rpc(FCall::TReadDir {
+                fid: 1,
+                offset,
+                count: 256,
+            })
+            .await
+        {
+            FCall::RReadDir { data } => data,
+            reply => panic!("{}", reply),
+        };
+        match data.data().last() {
+            Some(last) => offset = last.offset,
+            None => break,
+        }
+        entries.extend(data.data().iter().cloned());
+    }
+
+    let mut names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names.len(), 104);
+    names.dedup();
+    assert_eq!(names.len(), 104);
+
+    let entry = |name: &str| entries.iter().find(|e| e.name == name).unwrap();
+    let ino = |name: &str| std::fs::symlink_metadata(s.root.join(name)).unwrap().ino();
+    assert_eq!(entry(".").qid.path, ino(""));
+    assert_eq!(entry("..").qid.path, ino(""));
+    assert_eq!(entry("dir").typ, DirEntryType::Dir as u8);
+    assert_eq!(entry("dir").qid, QId::dir(ino("dir")));
+    assert_eq!(entry("link").typ, DirEntryType::Lnk as u8);
+    assert_eq!(entry("file7").typ, DirEntryType::Reg as u8);
+    assert_eq!(entry("file7").qid.path, ino("file7"));
+
+    // Going back to an earlier offset
+    let third = &entries[2];
+    let reply = s
+        .rpc(FCall::TReadDir {
+            fid: 1,
+            offset: entries[1].offset,
+            count: 256,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RReadDir { data } if data.data()[0] == *third));
+}
+
+#[tokio::test]
+async fn conformance_iounit() {
+    let mut s = Session::new().await;
+    std::fs::write(s.root.join("file"), "data").unwrap();
+    let blksize = std::fs::metadata(s.root.join("file")).unwrap().blksize() as u32;
+
+    s.walk(1, "file").await;
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: P9OpenFlags::RDONLY.bits(),
+        })
+        .await;
+    let FCall::RlOpen { iounit, .. } = reply else {
+        panic!("{}", reply);
+    };
+    // The session negotiated an msize of 8192
+    assert!(iounit > 0 && iounit <= 8192 - IOHDRSZ, "{}", iounit);
+    if blksize <= 8192 - IOHDRSZ {
+        assert_eq!(iounit % blksize, 0);
+    }
+}
+
+#[tokio::test]
+async fn conformance_overlay() {
+    let identity = Identity::new(IdentityMode::None, 0, 0).unwrap();
+    let mut s = Session::with_fs(
+        |root| {
+            let (lower, upper) = (root.join("lower"), root.join("upper"));
+            std::fs::create_dir_all(lower.join("dir")).unwrap();
+            std::fs::create_dir(&upper).unwrap();
+            std::fs::write(lower.join("file"), "data").unwrap();
+            std::fs::write(lower.join("gone"), "").unwrap();
+            std::fs::write(lower.join("dir/a"), "").unwrap();
+            Overlay::new(
+                ReadOnly::new(Unpfs::new(Root::open(&lower).unwrap(), identity.clone())),
+                Unpfs::new(Root::open(&upper).unwrap(), identity),
+            )
+        },
+        0,
+    )
+    .await;
+    let (lower, upper) = (s.root.join("lower"), s.root.join("upper"));
+
+    async fn names(s: &mut Session, path: &[&str]) -> Vec<String> {
+        s.rpc(FCall::TWalk {
+            fid: 0,
+            newfid: 9,
+            wnames: path.iter().map(|name| name.to_string()).collect(),
+        })
+        .await;
+        let flags = (P9OpenFlags::RDONLY | P9OpenFlags::DIRECTORY).bits();
+        s.rpc(FCall::TlOpen { fid: 9, flags }).await;
+        let reply = s
+            .rpc(FCall::TReadDir {
+                fid: 9,
+                offset: 0,
+                count: 4096,
+            })
+            .await;
+        s.rpc(FCall::TClunk { fid: 9 }).await;
+        let FCall::RReadDir { data } = reply else {
+            panic!("{}", reply)
+        };
+        let mut names: Vec<_> = data.data().iter().map(|e| e.name.clone()).collect();
+        names.sort();
+        names
+    }
+    assert_eq!(names(&mut s, &[]).await, [".", "..", "dir", "file", "gone"]);
+
+    // Writing copies the file up
+    s.walk(1, "file").await;
+    let reply = s
+        .rpc(FCall::TlOpen {
+            fid: 1,
+            flags: P9OpenFlags::RDWR.bits(),
+        })
+        .await;
+    assert!(matches!(reply, FCall::RlOpen { .. }), "{}", reply);
+    let reply = s
+        .rpc(FCall::TWrite {
+            fid: 1,
+            offset: 0,
+            data: Data(b"new".to_vec()),
+        })
+        .await;
+    assert_eq!(reply, FCall::RWrite { count: 3 });
+    assert_eq!(std::fs::read_to_string(upper.join("file")).unwrap(), "newa");
+    assert_eq!(std::fs::read_to_string(lower.join("file")).unwrap(), "data");
+
+    // Removing a lower file leaves a whiteout
+    let reply = s
+        .rpc(FCall::TUnlinkAt {
+            dirfd: 0,
+            name: "gone".to_owned(),
+            flags: 0,
+        })
+        .await;
+    assert_eq!(reply, FCall::RUnlinkAt);
+    assert!(lower.join("gone").exists());
+    assert!(upper.join(".wh.gone").exists());
+    for name in ["gone", ".wh.gone"] {
+        let reply = s
+            .rpc(FCall::TWalk {
+                fid: 0,
+                newfid: 2,
+                wnames: vec![name.to_owned()],
+            })
+            .await;
+        assert_eq!(
+            reply,
+            FCall::RlError {
+                ecode: errno::ENOENT as u32
+            }
+        );
+    }
+    assert_eq!(names(&mut s, &[]).await, [".", "..", "dir", "file"]);
+
+    // A directory made where a lower one was removed hides its contents
+    let rmdir = FCall::TUnlinkAt {
+        dirfd: 0,
+        name: "dir".to_owned(),
+        flags: 0x200,
+    };
+    let reply = s.rpc(rmdir.clone()).await;
+    assert_eq!(
+        reply,
+        FCall::RlError {
+            ecode: errno::ENOTEMPTY as u32
+        }
+    );
+    s.walk(2, "dir").await;
+    let reply = s
+        .rpc(FCall::TUnlinkAt {
+            dirfd: 2,
+            name: "a".to_owned(),
+            flags: 0,
+        })
+        .await;
+    assert_eq!(reply, FCall::RUnlinkAt);
+    assert_eq!(names(&mut s, &["dir"]).await, [".", ".."]);
+    assert_eq!(s.rpc(rmdir).await, FCall::RUnlinkAt);
+    let reply = s
+        .rpc(FCall::TMkDir {
+            dfid: 0,
+            name: "dir".to_owned(),
+            mode: 0o755,
+            gid: gid(),
+        })
+        .await;
+    assert!(matches!(reply, FCall::RMkDir { .. }), "{}", reply);
+    assert!(upper.join("dir/.wh..wh..opq").exists());
+    assert!(lower.join("dir/a").exists());
+    assert_eq!(names(&mut s, &["dir"]).await, [".", ".."]);
+}
+
+#[tokio::test]
+async fn conformance_proxy() {
+    // unpfs serves the directory upstream, and the session goes through a proxy
+    let dir = tempfile::tempdir().unwrap();
+    let export = dir.path().join("export");
+    std::fs::create_dir(&export).unwrap();
+    std::fs::write(export.join("keep"), "kept").unwrap();
+    let identity = Identity::new(IdentityMode::None, 0, 0).unwrap();
+    let addr = format!("unix!{}!0", dir.path().join("upstream").display());
+    let upstream = Unpfs::new(Root::open(&export).unwrap(), identity);
+    tokio::spawn({
+        let addr = addr.clone();
+        async move { srv_async(upstream, &addr).await }
+    });
+    let proxy = loop {
+        match Proxy::connect(&addr).await {
+            Ok(proxy) => break proxy,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let proxy = proxy.intercept(|msg| match msg {
+        FCall::TUnlinkAt { name, .. } if name == "keep" => Err(rs9p::Error::No(errno::EPERM)),
+        _ => Ok(()),
+    });
+    let mut s = Session::with_fs(|_| proxy, 0).await;
+
+    s.rpc(FCall::TWalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec![],
+    })
+    .await;
+    let reply = s
+        .rpc(FCall::TlCreate {
+            fid: 1,
+            name: "file".to_owned(),
+            flags: (P9OpenFlags::RDWR | P9OpenFlags::CREATE).bits(),
+            mode: 0o644,
+            gid: gid(),
+        })
+        .await;
+    assert!(
+        matches!(reply, FCall::RlCreate { iounit, .. } if iounit > 0),
+        "{}",
+        reply
+    );
+    let reply = s
+        .rpc(FCall::TWrite {
+            fid: 1,
+            offset: 0,
+            data: Data(b"through".to_vec()),
+        })
+        .await;
+    assert_eq!(reply, FCall::RWrite { count: 7 });
+    let reply = s
+        .rpc(FCall::TRead {
+            fid: 1,
+            offset: 0,
+            count: 100,
+        })
+        .await;
+    assert_eq!(
+        reply,
+        FCall::RRead {
+            data: Data(b"through".to_vec())
+        }
+    );
+    s.rpc(FCall::TClunk { fid: 1 }).await;
+    assert_eq!(std::fs::read(export.join("file")).unwrap(), b"through");
+
+    // Errors of the upstream server and of the hook reach the client
+    for (name, ecode) in [("missing", errno::ENOENT), ("keep", errno::EPERM)] {
+        let reply = s
+            .rpc(FCall::TUnlinkAt {
+                dirfd: 0,
+                name: name.to_owned(),
+                flags: 0,
+            })
+            .await;
+        assert_eq!(
+            reply,
+            FCall::RlError {
+                ecode: ecode as u32
+            }
+        );
+    }
+    assert!(export.join("keep").exists());
+    let reply = s
+        .rpc(FCall::TUnlinkAt {
+            dirfd: 0,
+            name: "file".to_owned(),
+            flags: 0,
+        })
+        .await;
+    assert_eq!(reply, FCall::RUnlinkAt);
+    assert!(!export.join("file").exists());
+}
+
+#[tokio::test]
+async fn reload_keeps_locks() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::parse(&format!("[export.\"\"]\npath = {:?}\n", dir.path())).unwrap();
+    let exports = build_exports(&config, &MultiExport::new()).await.unwrap();
+    let mut s = Session::with_fs(|_| exports.clone(), 0).await;
+    std::fs::write(dir.path().join("file"), "data").unwrap();
+    let lock = |fid, proc_id| FCall::TLock {
+        fid,
+        flock: Flock {
+            typ: LockType::WRLOCK,
+            flags: LockFlag::empty(),
+            start: 0,
+            length: 0,
+            proc_id,
+            client_id: "host".to_owned(),
+        },
+    };
+
+    s.walk(1, "file").await;
+    let open = FCall::TlOpen {
+        fid: 1,
+        flags: P9OpenFlags::RDWR.bits(),
+    };
+    assert!(matches!(s.rpc(open).await, FCall::RlOpen { .. }));
+    let reply = s.rpc(lock(1, 1)).await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::SUCCESS
+        }
+    );
+
+    // As done on SIGHUP
+    exports.reload(build_exports(&config, &exports).await.unwrap());
+    let reply = s
+        .rpc(FCall::TAttach {
+            fid: 10,
+            afid: NOFID,
+            uname: "test".to_owned(),
+            aname: String::new(),
+            n_uname: 0,
+        })
+        .await;
+    assert!(matches!(reply, FCall::RAttach { .. }), "{}", reply);
+    let reply = s
+        .rpc(FCall::TWalk {
+            fid: 10,
+            newfid: 11,
+            wnames: vec!["file".to_owned()],
+        })
+        .await;
+    assert!(matches!(reply, FCall::RWalk { .. }), "{}", reply);
+    let open = FCall::TlOpen {
+        fid: 11,
+        flags: P9OpenFlags::RDWR.bits(),
+    };
+    assert!(matches!(s.rpc(open).await, FCall::RlOpen { .. }));
+    let reply = s.rpc(lock(11, 2)).await;
+    assert_eq!(
+        reply,
+        FCall::RLock {
+            status: LockStatus::BLOCKED
+        }
+    );
+}
@@ -1,7 +1,15 @@
 use {
-    rs9p::fcall::*,
-    std::{fs::Metadata, os::unix::prelude::*, path::Path},
-    tokio::fs,
+    rs9p::{fcall::*, xattr::PendingXattr},
+    rustix::{fs as rfs, io::Errno},
+    std::{
+        fs::Metadata,
+        io,
+        os::{
+            fd::{AsFd, OwnedFd},
+            unix::prelude::*,
+        },
+        path::Path,
+    },
 };
 
 #[macro_export]
@@ -11,35 +19,202 @@ macro_rules! INVALID_FID {
     };
 }
 
-pub async fn get_qid<T: AsRef<Path> + ?Sized>(path: &T) -> rs9p::Result<QId> {
-    Ok(qid_from_attr(&fs::symlink_metadata(path.as_ref()).await?))
+/// Data version derived from mtime and ctime
+///
+/// Either timestamp changes whenever the file is written, and ctime also catches
+/// writes which were followed by resetting mtime with `utimensat`.
+pub fn data_version(attr: &Metadata) -> u64 {
+    let nanos = |sec: i64, nsec: i64| {
+        (sec as u64)
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(nsec as u64)
+    };
+    nanos(attr.mtime(), attr.mtime_nsec()) ^ nanos(attr.ctime(), attr.ctime_nsec()).rotate_left(32)
+}
+
+/// Attributes of `attr` including the statx-only fields unpfs can report
+pub fn stat_from_attr(attr: &Metadata) -> (GetAttrMask, Stat) {
+    let mut valid = GetAttrMask::BASIC | GetAttrMask::DATA_VERSION;
+    if attr.created().is_ok() {
+        valid |= GetAttrMask::BTIME;
+    }
+
+    let mut stat = Stat::from(attr);
+    stat.data_version = data_version(attr);
+    (valid, stat)
+}
+
+/// Inode generation number of the file `fd` refers to, from `FS_IOC_GETVERSION`
+///
+/// Only regular files and directories are opened to ask, and `None` is returned if
+/// the filesystem doesn't keep one.
+pub fn generation(fd: &OwnedFd, attr: &Metadata) -> Option<u64> {
+    use {
+        nix::fcntl::OFlag,
+        rustix::ioctl::{Opcode, Updater, ioctl, opcode},
+        std::ffi::c_long,
+    };
+
+    const FS_IOC_GETVERSION: Opcode = opcode::read::<c_long>(b'v', 1);
+
+    if !attr.is_file() && !attr.is_dir() {
+        return None;
+    }
+    // ioctls don't work through O_PATH descriptors
+    let file = crate::root::reopen(fd, OFlag::O_RDONLY | OFlag::O_NONBLOCK).ok()?;
+    // Declared as a long, but filesystems store an int at its start
+    let mut value: c_long = 0;
+    // SAFETY: the opcode is FS_IOC_GETVERSION, which writes at most a long
+    unsafe { ioctl(&file, Updater::<FS_IOC_GETVERSION, c_long>::new(&mut value)) }.ok()?;
+    let bytes = value.to_ne_bytes();
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64)
 }
 
-pub fn qid_from_attr(attr: &Metadata) -> QId {
-    QId {
-        typ: From::from(attr.file_type()),
-        version: 0,
-        path: attr.ino(),
+/// Read the value of an extended attribute, or the list of names if `name` is empty
+///
+/// Attributes of symlinks themselves are accessed, like the kernel's own server does.
+pub fn get_xattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    loop {
+        let size = if name.is_empty() {
+            rfs::llistxattr(path, &mut [0u8; 0][..])?
+        } else {
+            rfs::lgetxattr(path, name, &mut [0u8; 0][..])?
+        };
+
+        let mut buf = vec![0; size];
+        let res = if name.is_empty() {
+            rfs::llistxattr(path, &mut buf[..])
+        } else {
+            rfs::lgetxattr(path, name, &mut buf[..])
+        };
+        match res {
+            Ok(n) => {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+            // The attribute grew between the two calls
+            Err(Errno::RANGE) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Apply an extended attribute write committed on clunk
+pub fn set_xattr(path: &Path, xattr: &PendingXattr) -> io::Result<()> {
+    if xattr.is_remove() {
+        return Ok(rfs::lremovexattr(path, &xattr.name)?);
     }
+
+    let flags = rfs::XattrFlags::from_bits_truncate(xattr.flags.bits());
+    Ok(rfs::lsetxattr(path, &xattr.name, &xattr.value, flags)?)
 }
 
-pub async fn get_dirent_from<P: AsRef<Path> + ?Sized>(
-    p: &P,
-    offset: u64,
-) -> rs9p::Result<DirEntry> {
-    Ok(DirEntry {
-        qid: get_qid(p).await?,
-        offset,
-        typ: 0,
-        name: p.as_ref().to_string_lossy().into_owned(),
-    })
-}
-
-pub async fn get_dirent(entry: &fs::DirEntry, offset: u64) -> rs9p::Result<DirEntry> {
-    Ok(DirEntry {
-        qid: qid_from_attr(&entry.metadata().await?),
-        offset,
-        typ: 0,
-        name: entry.file_name().to_string_lossy().into_owned(),
-    })
+/// Mirror a 9P lock request with an open file description lock on `fd`
+///
+/// This makes locks taken by clients visible to processes on the server. Returns
+/// `false` if a conflicting lock is held.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_ofd_lock<Fd: AsFd>(fd: Fd, flock: &Flock) -> io::Result<bool> {
+    use nix::{
+        errno::Errno,
+        fcntl::{FcntlArg, fcntl},
+    };
+
+    let lock = ofd_flock(flock.typ, flock.start, flock.length);
+    match fcntl(fd, FcntlArg::F_OFD_SETLK(&lock)) {
+        Ok(_) => Ok(true),
+        Err(Errno::EAGAIN) | Err(Errno::EACCES) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Open a descriptor to hold the OFD locks of one lock owner on the file of `fd`
+///
+/// Write locks need it open for writing, which `file`, the file the client opened, is
+/// not always.
+pub fn ofd_file<Fd: AsFd>(fd: &OwnedFd, file: Fd) -> io::Result<OwnedFd> {
+    crate::root::reopen(fd, nix::fcntl::OFlag::O_RDWR)
+        .or_else(|_| file.as_fd().try_clone_to_owned())
+}
+
+/// Find a lock held by a process on the server which conflicts with `getlock`
+///
+/// The process id is 0 when the lock is an OFD lock, the client id is always empty.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_ofd_lock<Fd: AsFd>(fd: Fd, getlock: &Getlock) -> io::Result<Option<Getlock>> {
+    use nix::{
+        fcntl::{FcntlArg, fcntl},
+        libc,
+    };
+
+    let mut lock = ofd_flock(getlock.typ, getlock.start, getlock.length);
+    fcntl(fd, FcntlArg::F_OFD_GETLK(&mut lock))?;
+    let typ = match lock.l_type as libc::c_int {
+        libc::F_RDLCK => LockType::RDLOCK,
+        libc::F_WRLCK => LockType::WRLOCK,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Getlock {
+        typ,
+        start: lock.l_start as u64,
+        length: lock.l_len as u64,
+        proc_id: lock.l_pid.max(0) as u32,
+        client_id: String::new(),
+    }))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn ofd_flock(typ: LockType, start: u64, length: u64) -> nix::libc::flock {
+    use nix::libc;
+
+    let l_type = match typ {
+        LockType::RDLOCK => libc::F_RDLCK,
+        LockType::WRLOCK => libc::F_WRLCK,
+        _ => libc::F_UNLCK,
+    };
+    libc::flock {
+        l_type: l_type as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: start.min(i64::MAX as u64) as libc::off_t,
+        l_len: length.min(i64::MAX as u64) as libc::off_t,
+        // Must be 0 for OFD locks
+        l_pid: 0,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_ofd_lock<Fd: AsFd>(_fd: Fd, _flock: &Flock) -> io::Result<bool> {
+    Err(io::Error::from_raw_os_error(rs9p::errno::EOPNOTSUPP as i32))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_ofd_lock<Fd: AsFd>(_fd: Fd, _getlock: &Getlock) -> io::Result<Option<Getlock>> {
+    Err(io::Error::from_raw_os_error(rs9p::errno::EOPNOTSUPP as i32))
+}
+
+/// Give a newly created file the group requested by the client
+///
+/// Unless the server runs with `CAP_CHOWN` this only works for groups the server is
+/// a member of, and fails with `EPERM` for the others.
+pub fn set_gid<Fd: AsFd>(dirfd: Fd, name: &str, gid: u32) -> io::Result<()> {
+    use nix::{
+        fcntl::AtFlags,
+        unistd::{Gid, fchownat},
+    };
+
+    Ok(fchownat(
+        dirfd,
+        name,
+        None,
+        Some(Gid::from_raw(gid)),
+        AtFlags::AT_SYMLINK_NOFOLLOW,
+    )?)
+}
+
+/// Like [`set_gid`], for a file already opened as `fd`
+pub fn fset_gid<Fd: AsFd>(fd: Fd, gid: u32) -> io::Result<()> {
+    use nix::unistd::{Gid, fchown};
+
+    Ok(fchown(fd, None, Some(Gid::from_raw(gid)))?)
 }